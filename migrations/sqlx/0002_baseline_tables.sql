@@ -0,0 +1,17 @@
+-- 0001 only created the users table, the application created the shops and products itself.
+-- Databases migrated from 0001 get them here, the others already have them.
+CREATE TABLE IF NOT EXISTS shop_configurations
+(
+    domain             TEXT PRIMARY KEY NOT NULL,
+    name               TEXT NOT NULL,
+    product_type       TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS products
+(
+    product_id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT,
+    price DECIMAL NOT NULL,
+    in_stock BOOLEAN DEFAULT TRUE
+);
@@ -0,0 +1,11 @@
+-- Accounts from before email verification get a placeholder address that never receives
+-- mail and count as verified, so they can still log in
+ALTER TABLE users ADD COLUMN email TEXT NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN verified_at DATETIME;
+
+UPDATE users
+SET email = user_id || '@users.invalid',
+    verified_at = datetime('now','localtime')
+WHERE email = '';
+
+CREATE UNIQUE INDEX users_email ON users (email);
@@ -0,0 +1,8 @@
+CREATE TABLE audit_log
+(
+    audit_id                INTEGER PRIMARY KEY,
+    event                   TEXT NOT NULL,
+    subject                 TEXT NOT NULL,
+    ip_address              TEXT,
+    created_on              DATETIME DEFAULT (datetime('now','localtime'))
+);
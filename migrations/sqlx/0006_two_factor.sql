@@ -0,0 +1,16 @@
+CREATE TABLE two_factor
+(
+    user_id                 TEXT PRIMARY KEY NOT NULL,
+    encrypted_secret        TEXT NOT NULL,
+    enabled_on              DATETIME DEFAULT (datetime('now','localtime')),
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_codes
+(
+    code_id                 INTEGER PRIMARY KEY,
+    user_id                 TEXT NOT NULL,
+    hashed_code             TEXT NOT NULL,
+    used_on                 DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
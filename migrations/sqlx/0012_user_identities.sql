@@ -0,0 +1,12 @@
+-- Accounts at external OpenID Connect providers
+CREATE TABLE user_identities
+(
+    identity_id             INTEGER PRIMARY KEY,
+    user_id                 TEXT NOT NULL,
+    provider                TEXT NOT NULL,
+    subject                 TEXT NOT NULL,
+    email                   TEXT,
+    created_on              DATETIME DEFAULT (datetime('now','localtime')),
+    UNIQUE (provider, subject),
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
@@ -0,0 +1,15 @@
+-- Only a hash of the key is stored
+CREATE TABLE api_keys
+(
+    key_id                  INTEGER PRIMARY KEY,
+    shop_domain             TEXT NOT NULL,
+    user_id                 TEXT NOT NULL,
+    name                    TEXT NOT NULL,
+    prefix                  TEXT NOT NULL UNIQUE,
+    hashed_key              TEXT NOT NULL,
+    scopes                  TEXT NOT NULL,
+    created_on              DATETIME DEFAULT (datetime('now','localtime')),
+    last_used_on            DATETIME,
+    revoked_on              DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
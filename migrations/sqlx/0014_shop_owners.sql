@@ -0,0 +1,2 @@
+ALTER TABLE shop_configurations ADD COLUMN user_id TEXT
+    REFERENCES users(user_id) ON DELETE SET NULL;
@@ -0,0 +1,69 @@
+-- Every user belongs to one shop. Users from before that go to the shop they own,
+-- otherwise to the first shop.
+CREATE TABLE users_scoped
+(
+    user_id                 TEXT PRIMARY KEY NOT NULL,
+    shop_domain             TEXT NOT NULL,
+    username                TEXT NOT NULL,
+    email                   TEXT NOT NULL,
+    hashed_password         TEXT NOT NULL,
+    created_on              DATETIME DEFAULT (datetime('now','localtime')),
+    updated_on              DATETIME DEFAULT (datetime('now','localtime')),
+    verified_at             DATETIME,
+    active                  BOOLEAN NOT NULL DEFAULT 1,
+    role                    TEXT NOT NULL DEFAULT 'customer'
+                            CHECK (role IN ('customer', 'shop_owner', 'admin')),
+    UNIQUE (shop_domain, username),
+    UNIQUE (shop_domain, email)
+);
+
+INSERT INTO users_scoped
+    (user_id, shop_domain, username, email, hashed_password, created_on, updated_on,
+     verified_at, active, role)
+SELECT
+    u.user_id,
+    COALESCE(
+        (SELECT s.domain FROM shop_configurations s WHERE s.user_id = u.user_id ORDER BY s.domain LIMIT 1),
+        (SELECT s.domain FROM shop_configurations s ORDER BY s.domain LIMIT 1),
+        'localhost'
+    ),
+    u.username, u.email, u.hashed_password, u.created_on, u.updated_on,
+    u.verified_at, u.active, u.role
+FROM users u;
+
+DROP TABLE users;
+ALTER TABLE users_scoped RENAME TO users;
+
+-- Identities are unique per shop, they belong to the shop of their user
+CREATE TABLE user_identities_scoped
+(
+    identity_id             INTEGER PRIMARY KEY,
+    user_id                 TEXT NOT NULL,
+    shop_domain             TEXT NOT NULL,
+    provider                TEXT NOT NULL,
+    subject                 TEXT NOT NULL,
+    email                   TEXT,
+    created_on              DATETIME DEFAULT (datetime('now','localtime')),
+    UNIQUE (shop_domain, provider, subject),
+    FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+INSERT INTO user_identities_scoped
+    (identity_id, user_id, shop_domain, provider, subject, email, created_on)
+SELECT i.identity_id, i.user_id, u.shop_domain, i.provider, i.subject, i.email, i.created_on
+FROM user_identities i
+JOIN users u ON u.user_id = i.user_id;
+
+DROP TABLE user_identities;
+ALTER TABLE user_identities_scoped RENAME TO user_identities;
+
+-- Products belong to a shop as well
+ALTER TABLE products ADD COLUMN shop_domain TEXT NOT NULL DEFAULT '';
+UPDATE products
+SET shop_domain = COALESCE(
+    (SELECT s.domain FROM shop_configurations s ORDER BY s.domain LIMIT 1),
+    'localhost'
+);
+
+-- Audit entries are kept per shop, entries from before have none
+ALTER TABLE audit_log ADD COLUMN shop_domain TEXT;
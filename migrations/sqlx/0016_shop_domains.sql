@@ -0,0 +1,14 @@
+ALTER TABLE shop_configurations ADD COLUMN slug TEXT;
+CREATE UNIQUE INDEX shop_configurations_slug ON shop_configurations (slug);
+
+-- Custom domains of a shop
+CREATE TABLE shop_domains
+(
+    domain             TEXT PRIMARY KEY NOT NULL,
+    shop_domain        TEXT NOT NULL,
+    verification_token TEXT NOT NULL,
+    verified_on        TIMESTAMP,
+    is_canonical       BOOLEAN NOT NULL DEFAULT FALSE,
+    created_on         TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (shop_domain) REFERENCES shop_configurations(domain) ON DELETE CASCADE
+);
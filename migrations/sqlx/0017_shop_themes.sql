@@ -0,0 +1,3 @@
+ALTER TABLE shop_configurations ADD COLUMN primary_color TEXT;
+ALTER TABLE shop_configurations ADD COLUMN background_color TEXT;
+ALTER TABLE shop_configurations ADD COLUMN logo_url TEXT;
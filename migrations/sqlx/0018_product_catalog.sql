@@ -0,0 +1,22 @@
+-- Prices are in the smallest unit of the currency
+CREATE TABLE products_catalog
+(
+    product_id INTEGER PRIMARY KEY,
+    shop_domain TEXT NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT,
+    price INTEGER NOT NULL CHECK (price >= 0),
+    currency TEXT NOT NULL DEFAULT 'usd',
+    in_stock BOOLEAN NOT NULL DEFAULT TRUE,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Stored prices were decimal major units (12.99, '12.99'), they become minor units (1299)
+INSERT INTO products_catalog (product_id, shop_domain, name, description, price, in_stock)
+SELECT product_id, shop_domain, name, description,
+       MAX(CAST(ROUND(CAST(price AS REAL) * 100) AS INTEGER), 0),
+       COALESCE(in_stock, TRUE)
+FROM products;
+
+DROP TABLE products;
+ALTER TABLE products_catalog RENAME TO products;
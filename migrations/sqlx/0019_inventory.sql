@@ -0,0 +1,62 @@
+-- A NULL price uses the price of the product
+CREATE TABLE product_variants
+(
+    variant_id INTEGER PRIMARY KEY,
+    product_id INTEGER NOT NULL,
+    shop_domain TEXT NOT NULL,
+    sku TEXT NOT NULL,
+    attributes TEXT NOT NULL DEFAULT '{}',
+    price INTEGER CHECK (price IS NULL OR price >= 0),
+    stock INTEGER NOT NULL DEFAULT 0,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (shop_domain, sku),
+    FOREIGN KEY (product_id) REFERENCES products(product_id) ON DELETE CASCADE
+);
+
+-- The audit trail of every stock change
+CREATE TABLE stock_movements
+(
+    movement_id INTEGER PRIMARY KEY,
+    variant_id INTEGER NOT NULL,
+    shop_domain TEXT NOT NULL,
+    change INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    reference TEXT,
+    user_id TEXT,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (variant_id) REFERENCES product_variants(variant_id) ON DELETE CASCADE
+);
+
+-- Stock held for a checkout session
+CREATE TABLE stock_reservations
+(
+    reservation_id INTEGER PRIMARY KEY,
+    variant_id INTEGER NOT NULL,
+    shop_domain TEXT NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    checkout_session_id TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    released_on TIMESTAMP,
+    completed_on TIMESTAMP,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (variant_id) REFERENCES product_variants(variant_id) ON DELETE CASCADE
+);
+CREATE INDEX stock_reservations_session ON stock_reservations (checkout_session_id);
+
+-- What can still be sold is the stock minus the reservations that are neither
+-- released, completed nor expired
+CREATE VIEW variant_stock AS
+SELECT
+    v.variant_id, v.product_id, v.shop_domain, v.sku, v.attributes, v.price, v.stock,
+    COALESCE(v.price, p.price) AS unit_price,
+    p.currency,
+    v.stock - COALESCE((
+        SELECT SUM(r.quantity) FROM stock_reservations r
+        WHERE r.variant_id = v.variant_id
+        AND r.released_on IS NULL
+        AND r.completed_on IS NULL
+        AND r.expires_at > CURRENT_TIMESTAMP
+    ), 0) AS available,
+    v.created_on
+FROM product_variants v
+JOIN products p ON p.product_id = v.product_id;
@@ -0,0 +1,32 @@
+-- One order per Stripe checkout session
+CREATE TABLE orders
+(
+    order_id INTEGER PRIMARY KEY,
+    shop_domain TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'paid', 'fulfilled', 'refunded', 'cancelled')),
+    currency TEXT NOT NULL,
+    total INTEGER NOT NULL CHECK (total >= 0),
+    customer_email TEXT,
+    checkout_session_id TEXT UNIQUE,
+    payment_intent_id TEXT,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX orders_user ON orders (shop_domain, user_id);
+
+-- A copy of the cart lines at the time of the checkout
+CREATE TABLE order_items
+(
+    order_item_id INTEGER PRIMARY KEY,
+    order_id INTEGER NOT NULL,
+    product_id INTEGER NOT NULL,
+    variant_id INTEGER,
+    name TEXT NOT NULL,
+    sku TEXT,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    unit_price INTEGER NOT NULL,
+    line_total INTEGER NOT NULL,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE
+);
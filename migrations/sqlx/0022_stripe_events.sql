@@ -0,0 +1,15 @@
+-- Every webhook event is stored once and processed by a worker
+CREATE TABLE stripe_events
+(
+    event_id TEXT PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'processed', 'failed')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    received_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    processed_on TIMESTAMP
+);
+CREATE INDEX stripe_events_due ON stripe_events (status, next_attempt_at);
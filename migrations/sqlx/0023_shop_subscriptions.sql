@@ -0,0 +1,14 @@
+-- The plan of a shop as Stripe last reported it
+CREATE TABLE shop_subscriptions
+(
+    shop_domain            TEXT PRIMARY KEY NOT NULL,
+    plan                   TEXT NOT NULL CHECK (plan IN ('free', 'starter', 'pro')),
+    status                 TEXT NOT NULL,
+    stripe_customer_id     TEXT,
+    stripe_subscription_id TEXT UNIQUE,
+    current_period_end     TIMESTAMP,
+    cancel_at_period_end   BOOLEAN NOT NULL DEFAULT FALSE,
+    synced_at              INTEGER NOT NULL DEFAULT 0,
+    updated_on             TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (shop_domain) REFERENCES shop_configurations(domain) ON DELETE CASCADE
+);
@@ -0,0 +1,13 @@
+-- The Stripe Connect account a shop is paid out to
+CREATE TABLE shop_connect_accounts
+(
+    shop_domain            TEXT PRIMARY KEY NOT NULL,
+    stripe_account_id      TEXT UNIQUE NOT NULL,
+    charges_enabled        BOOLEAN NOT NULL DEFAULT FALSE,
+    payouts_enabled        BOOLEAN NOT NULL DEFAULT FALSE,
+    details_submitted      BOOLEAN NOT NULL DEFAULT FALSE,
+    synced_at              INTEGER NOT NULL DEFAULT 0,
+    created_on             TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_on             TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (shop_domain) REFERENCES shop_configurations(domain) ON DELETE CASCADE
+);
@@ -0,0 +1,24 @@
+-- How much of an order was paid back so far, refunds and lost disputes apart
+ALTER TABLE orders ADD COLUMN refunded_amount INTEGER NOT NULL DEFAULT 0
+    CHECK (refunded_amount >= 0);
+ALTER TABLE orders ADD COLUMN disputed_amount INTEGER NOT NULL DEFAULT 0
+    CHECK (disputed_amount >= 0);
+CREATE INDEX orders_payment_intent ON orders (payment_intent_id);
+
+-- The chargebacks on order payments as Stripe last reported them
+CREATE TABLE order_disputes
+(
+    dispute_id TEXT PRIMARY KEY,
+    shop_domain TEXT NOT NULL,
+    order_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    status TEXT NOT NULL,
+    evidence_due_by TIMESTAMP,
+    synced_at INTEGER NOT NULL DEFAULT 0,
+    created_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    updated_on TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (order_id) REFERENCES orders(order_id) ON DELETE CASCADE
+);
+CREATE INDEX order_disputes_shop ON order_disputes (shop_domain, evidence_due_by);
@@ -0,0 +1,81 @@
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{ApiKey, ApiKeyCreate, UserCookie, UserRole};
+use crate::modules::api_key;
+use actix_web::*;
+use serde::Serialize;
+
+// Response to a new key, the only time the full key is shown
+#[derive(Serialize)]
+struct CreatedApiKey {
+    key: String,
+    api_key: ApiKey,
+}
+
+// Admins manage every key of the shop, shop owners only their own
+fn owner_filter(user: &UserCookie) -> Option<&str> {
+    match user.role {
+        UserRole::Admin => None,
+        _ => Some(user.user_id.as_str()),
+    }
+}
+
+pub async fn create_api_key(db: TenantDB, user: UserCookie, request: ApiKeyCreate) -> HttpResponse {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().json("A name is required");
+    }
+    if request.scopes.is_empty() {
+        return HttpResponse::BadRequest().json("At least one scope is required");
+    }
+    if let Some(scope) = request
+        .scopes
+        .iter()
+        .find(|scope| !scope.allowed_for(&user.role))
+    {
+        return HttpResponse::Forbidden().json(format!("Scope {} is not allowed", scope.as_str()));
+    }
+
+    let new_key = api_key::generate_key();
+    let scopes = api_key::scopes_to_string(&request.scopes);
+
+    match db
+        .create_api_key(
+            &user.user_id,
+            name,
+            &new_key.prefix,
+            &new_key.hashed_key,
+            &scopes,
+        )
+        .await
+    {
+        Ok(api_key) => HttpResponse::Created().json(CreatedApiKey {
+            key: new_key.key,
+            api_key,
+        }),
+        Err(err) => {
+            eprintln!("Error creating API key: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_api_keys(db: TenantDB, user: UserCookie) -> HttpResponse {
+    match db.get_api_keys(owner_filter(&user)).await {
+        Ok(api_keys) => HttpResponse::Ok().json(api_keys),
+        Err(err) => {
+            eprintln!("Error listing API keys: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn revoke_api_key(db: TenantDB, user: UserCookie, key_id: i64) -> HttpResponse {
+    match db.revoke_api_key(key_id, owner_filter(&user)).await {
+        Ok(true) => HttpResponse::Ok().json("API key revoked"),
+        Ok(false) => HttpResponse::NotFound().json("API key not found"),
+        Err(err) => {
+            eprintln!("Error revoking API key: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
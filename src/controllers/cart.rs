@@ -0,0 +1,204 @@
+use crate::db::tenant::TenantDB;
+use crate::domain::cart::{Cart, CartItemIn, CartLineRef, PricedCart};
+use crate::modules::cart::{self, CartError, CartOwner};
+use crate::modules::redis::RedisDB;
+use crate::view;
+use actix_web::http::StatusCode;
+use actix_web::*;
+
+// How a cart is answered: JSON, the whole page or the HTMX fragment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CartView {
+    Json,
+    Page,
+    Fragment,
+}
+
+pub enum CartChange {
+    // Adds to the quantity already in the cart
+    Add(CartItemIn),
+    // Replaces the quantity, zero removes the line
+    Set(CartItemIn),
+    Remove(CartLineRef),
+}
+
+pub async fn show_cart(
+    db: TenantDB,
+    redis: &RedisDB,
+    request: &HttpRequest,
+    view: CartView,
+) -> HttpResponse {
+    let owner = cart::request_owner(request, redis, db.shop_domain());
+    let stored = match &owner {
+        Some(owner) => match cart::load_cart(redis, db.shop_domain(), owner) {
+            Ok(cart) => cart,
+            Err(err) => {
+                eprintln!("Error loading cart: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        None => Cart::default(),
+    };
+
+    match reprice(&db, redis, owner.as_ref(), &stored).await {
+        Ok(priced) => render(view, &priced),
+        Err(response) => response,
+    }
+}
+
+// Guests without a cart get one with their first change
+pub async fn change_cart(
+    db: TenantDB,
+    redis: &RedisDB,
+    request: &HttpRequest,
+    change: CartChange,
+    view: CartView,
+) -> HttpResponse {
+    let (owner, is_new) = match cart::request_owner(request, redis, db.shop_domain()) {
+        Some(owner) => (owner, false),
+        None => (cart::new_guest(), true),
+    };
+    let stored = match cart::load_cart(redis, db.shop_domain(), &owner) {
+        Ok(cart) => cart,
+        Err(err) => {
+            eprintln!("Error loading cart: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // The cart as it can still be bought, new lines have to match its currency
+    let (priced, mut cart) = match cart::price_cart(&db, &stored).await {
+        Ok(priced) => priced,
+        Err(err) => {
+            eprintln!("Error pricing cart: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let result = match change {
+        CartChange::Add(item) if item.quantity <= 0 => {
+            return error_response(
+                view,
+                StatusCode::BAD_REQUEST,
+                "The quantity must be positive",
+            );
+        }
+        CartChange::Add(item) => {
+            let quantity = cart.quantity(item.product_id, item.variant_id) + item.quantity;
+            set_line(
+                &db,
+                &mut cart,
+                &priced,
+                item.product_id,
+                item.variant_id,
+                quantity,
+            )
+            .await
+        }
+        CartChange::Set(item) => {
+            set_line(
+                &db,
+                &mut cart,
+                &priced,
+                item.product_id,
+                item.variant_id,
+                item.quantity,
+            )
+            .await
+        }
+        CartChange::Remove(line) => cart
+            .set_quantity(line.product_id, line.variant_id, 0)
+            .map_err(|message| (StatusCode::BAD_REQUEST, message.to_string())),
+    };
+    if let Err((status, message)) = result {
+        return error_response(view, status, &message);
+    }
+
+    let mut response = match reprice(&db, redis, Some(&owner), &cart).await {
+        Ok(priced) => render(view, &priced),
+        Err(response) => return response,
+    };
+    if let (CartOwner::Guest(cart_id), true) = (&owner, is_new) {
+        let _ = response.add_cookie(&cart::cart_cookie(cart_id));
+    }
+    response
+}
+
+async fn set_line(
+    db: &TenantDB,
+    cart: &mut Cart,
+    priced: &PricedCart,
+    product_id: i64,
+    variant_id: Option<i64>,
+    quantity: i64,
+) -> Result<(), (StatusCode, String)> {
+    cart.set_quantity(product_id, variant_id, quantity)
+        .map_err(|message| (StatusCode::BAD_REQUEST, message.to_string()))?;
+    if quantity == 0 {
+        return Ok(());
+    }
+
+    // Only the other lines decide the currency of the cart
+    let has_other_lines = priced
+        .lines
+        .iter()
+        .any(|line| line.product_id != product_id || line.variant_id != variant_id);
+    let currency = priced.currency.as_deref().filter(|_| has_other_lines);
+
+    cart::check_item(db, product_id, variant_id, quantity, currency)
+        .await
+        .map_err(|err| match err {
+            CartError::Database(err) => {
+                eprintln!("Error checking cart line: {:?}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+            }
+            err => (err.status_code(), err.to_string()),
+        })
+}
+
+// Price the cart and store it without the lines that can no longer be bought
+async fn reprice(
+    db: &TenantDB,
+    redis: &RedisDB,
+    owner: Option<&CartOwner>,
+    cart: &Cart,
+) -> Result<PricedCart, HttpResponse> {
+    let (priced, kept) = cart::price_cart(db, cart).await.map_err(|err| {
+        eprintln!("Error pricing cart: {:?}", err);
+        HttpResponse::InternalServerError().finish()
+    })?;
+
+    if let Some(owner) = owner {
+        if let Err(err) = cart::save_cart(redis, db.shop_domain(), owner, &kept) {
+            eprintln!("Error saving cart: {:?}", err);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    }
+    Ok(priced)
+}
+
+fn render(view: CartView, priced: &PricedCart) -> HttpResponse {
+    let template = match view {
+        CartView::Json => return HttpResponse::Ok().json(priced),
+        CartView::Page => "pages/cart/cart.html",
+        CartView::Fragment => "pages/cart/components/cart.html",
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("cart", priced);
+    match view::setup::TEMPLATES.render(template, &context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering {}: {}", template, err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn error_response(view: CartView, status: StatusCode, message: &str) -> HttpResponse {
+    match view {
+        CartView::Json => HttpResponse::build(status).json(message),
+        CartView::Page | CartView::Fragment => {
+            HttpResponse::build(status).body(message.to_string())
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{PageQuery, Paginated};
+use crate::view;
+use actix_web::*;
+
+// Disputes waiting for evidence come first, the closest due date on top
+pub async fn list_disputes(db: TenantDB, page: PageQuery) -> HttpResponse {
+    match db.get_disputes(&page).await {
+        Ok((disputes, total)) => HttpResponse::Ok().json(Paginated::new(disputes, &page, total)),
+        Err(err) => {
+            eprintln!("Error listing disputes: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub mod ui {
+    use super::*;
+
+    pub async fn disputes_page(db: TenantDB, page: PageQuery) -> HttpResponse {
+        let (disputes, total) = match db.get_disputes(&page).await {
+            Ok(disputes) => disputes,
+            Err(err) => {
+                eprintln!("Error listing disputes: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        let mut context = tera::Context::new();
+        context.insert("disputes", &Paginated::new(disputes, &page, total));
+        match view::setup::TEMPLATES.render("pages/account/disputes.html", &context) {
+            Ok(content) => HttpResponse::Ok().body(content),
+            Err(err) => {
+                eprintln!("Error rendering disputes page: {}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
@@ -1,70 +1,302 @@
-use crate::db::sqlite::SqliteDB;
-use crate::domain::datatypes::{CookieVariations, Settings, UserClientSignIn, UserServer};
+use crate::controllers::two_factor;
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{
+    CookieVariations, LoginClient, Settings, UserClientSignIn, UserServer,
+};
+use crate::modules::cart;
 use crate::modules::cookie::generate_cookie;
+use crate::modules::login_throttle;
+use crate::modules::oidc;
 use crate::modules::password_hash::Password;
-use crate::modules::token_pub::generete_public_token;
+use crate::modules::redis::{RedisDB, RedisDbError};
+use crate::modules::session;
+use crate::modules::token_pub::{self, generete_token_pair, TokenPair};
 use crate::view;
 use actix_web::*;
 
-pub async fn verify_login(db: web::Data<SqliteDB>, login_info: UserClientSignIn) -> HttpResponse {
+// Same message whether the username exists or not
+pub const TOO_MANY_ATTEMPTS_MSG: &str = "Too many login attempts. Please try again later.";
+
+pub async fn verify_login(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    login_info: UserClientSignIn,
+    client: &LoginClient,
+) -> HttpResponse {
+    // Locked out usernames and addresses are refused before the password is checked
+    match login_throttle::is_locked_out(&redis, db.shop_domain(), &login_info.username, &client.ip)
+    {
+        Ok(false) => {}
+        Ok(true) => return render_login_failed(&login_info, TOO_MANY_ATTEMPTS_MSG),
+        Err(err) => {
+            eprintln!("Error checking login lockout: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
     let user: Result<Option<UserServer>, sqlx::Error> =
         db.get_one_user_username(login_info.username.as_str()).await;
 
     match user {
         Ok(content) => match content {
-            Some(user) => {
+            Some(user) if user.active => {
                 let server_password = Password::new(&user.hashed_password);
 
                 match server_password.verify_password(&login_info.password.as_str()) {
                     Ok(true) => {
-                        let token = generete_public_token(&user);
-                        let cookie_settings =
-                            Settings::new(token.as_str(), login_info.remember.unwrap_or(false));
-                        let cookie = generate_cookie(&CookieVariations::Auth, cookie_settings);
-                        HttpResponse::SeeOther()
-                            .append_header(("Location", "/endpoints"))
-                            .cookie(cookie)
-                            .finish()
-                    }
-
-                    Ok(false) => {
-                        let mut context = tera::Context::new();
-
-                        context.insert("login_msg", "Please login to continue");
-                        context.insert("login_value_username", &login_info.username);
-                        context.insert("login_value_password", &login_info.password);
-                        context.insert("login_failed_msg", "Username or Password is incorrect");
-                        // context.insert("login_remember", "true");
-                        match view::setup::TEMPLATES.render("pages/login/login.html", &context) {
-                            Ok(content) => return HttpResponse::Ok().body(content),
-                            Err(err) => {
-                                eprintln!("Error rendering index page: {}", err);
-                                // Return 500 Internal Server Error
-                                return HttpResponse::InternalServerError().finish();
-                            }
+                        if server_password.needs_rehash() {
+                            rehash_password(&db, &user, &login_info.password).await;
+                        }
+                        // Accounts with two-factor authentication get a second login step first
+                        match db.get_two_factor_secret(&user.user_id).await {
+                            Ok(None) => complete_login(
+                                &redis,
+                                &user,
+                                login_info.remember.unwrap_or(false),
+                                client,
+                            ),
+                            Ok(Some(_)) => two_factor::start_second_step(
+                                &user,
+                                &redis,
+                                login_info.remember.unwrap_or(false),
+                            ),
+                            Err(_) => HttpResponse::InternalServerError().finish(),
                         }
                     }
+                    Ok(false) => login_failed(&db, &redis, &login_info, &client.ip).await,
                     Err(_) => HttpResponse::InternalServerError().finish(),
                 }
             }
-            None => {
-                let mut context = tera::Context::new();
-
-                context.insert("login_msg", "Please login to continue");
-                context.insert("login_value_username", &login_info.username);
-                context.insert("login_value_password", &login_info.password);
-                context.insert("login_failed_msg", "Username or Password is incorrect");
-                context.insert("login_remember", "true");
-                match view::setup::TEMPLATES.render("pages/login/login.html", &context) {
-                    Ok(content) => return HttpResponse::Ok().body(content),
-                    Err(err) => {
-                        eprintln!("Error rendering index page: {}", err);
-                        // Return 500 Internal Server Error
-                        return HttpResponse::InternalServerError().finish();
-                    }
-                }
-            }
+            // Unknown and deactivated users get the same answer
+            _ => login_failed(&db, &redis, &login_info, &client.ip).await,
         },
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
+
+// The password was just verified, so the hash can be upgraded to the current parameters
+async fn rehash_password(db: &TenantDB, user: &UserServer, password: &str) {
+    let hashed_password = match Password::hash_password(password) {
+        Ok(hashed_password) => hashed_password.get_password_string(),
+        Err(err) => {
+            eprintln!("Error rehashing password: {}", err);
+            return;
+        }
+    };
+    let upgraded_user = UserServer {
+        user_id: user.user_id.to_string(),
+        username: user.username.to_string(),
+        email: user.email.to_string(),
+        hashed_password,
+        active: user.active,
+        verified_at: user.verified_at,
+        role: user.role.clone(),
+        shop_domain: user.shop_domain.to_string(),
+    };
+
+    if let Err(err) = db.update_one_user_password(&upgraded_user).await {
+        eprintln!("Error storing rehashed password: {}", err);
+    }
+}
+
+// Issue the auth cookies once every login step has passed
+pub fn complete_login(
+    redis: &RedisDB,
+    user: &UserServer,
+    remember: bool,
+    client: &LoginClient,
+) -> HttpResponse {
+    if let Err(err) = login_throttle::clear_failures(redis, &user.shop_domain, &user.username) {
+        eprintln!("Error clearing failed logins: {:?}", err);
+    }
+
+    // In server-side session mode the auth cookie only holds the session id
+    let mut response = if session::sessions_enabled() {
+        match session::create_session(redis, user, client, remember) {
+            Ok(session_id) => HttpResponse::SeeOther()
+                .append_header(("Location", "/endpoints"))
+                .cookie(generate_cookie(
+                    &CookieVariations::Auth,
+                    Settings::new(session_id.as_str(), remember),
+                ))
+                .finish(),
+            Err(err) => {
+                eprintln!("Error creating session: {}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    } else {
+        match generete_token_pair(user, remember, redis) {
+            Ok(tokens) => login_response(tokens, remember, "/endpoints"),
+            Err(err) => {
+                eprintln!("Error generating tokens: {}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    };
+
+    // The guest cart of this browser becomes part of the cart of the user
+    if let (Some(cart_id), true) = (&client.cart_id, response.status().is_redirection()) {
+        match cart::merge_guest_cart(redis, &client.shop_domain, cart_id, &user.user_id) {
+            Ok(()) => {
+                let _ = response.add_cookie(&CookieVariations::ShoppingCarts.remove_cookie());
+            }
+            Err(err) => eprintln!("Error merging guest cart: {:?}", err),
+        }
+    }
+    response
+}
+
+// Count the failed attempt and audit every username or address that gets locked out.
+// Returns true when this attempt triggered a lockout.
+pub async fn record_failed_attempt(
+    db: &TenantDB,
+    redis: &RedisDB,
+    username: &str,
+    client_ip: &str,
+) -> Result<bool, RedisDbError> {
+    let locked_out =
+        login_throttle::register_failure(redis, db.shop_domain(), username, client_ip)?;
+
+    for subject in locked_out.iter() {
+        log::warn!("Login lockout triggered for {} from {}", subject, client_ip);
+        let _ = db
+            .create_audit_entry("login_lockout", subject, client_ip)
+            .await;
+    }
+
+    Ok(!locked_out.is_empty())
+}
+
+async fn login_failed(
+    db: &TenantDB,
+    redis: &RedisDB,
+    login_info: &UserClientSignIn,
+    client_ip: &str,
+) -> HttpResponse {
+    match record_failed_attempt(db, redis, &login_info.username, client_ip).await {
+        Ok(false) => render_login_failed(login_info, "Username or Password is incorrect"),
+        Ok(true) => render_login_failed(login_info, TOO_MANY_ATTEMPTS_MSG),
+        Err(err) => {
+            eprintln!("Error registering failed login: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn render_login_failed(login_info: &UserClientSignIn, failed_msg: &str) -> HttpResponse {
+    render_login_page(&login_info.username, failed_msg)
+}
+
+pub fn render_login_page(username: &str, failed_msg: &str) -> HttpResponse {
+    let mut context = tera::Context::new();
+
+    context.insert("login_msg", "Please login to continue");
+    context.insert("login_value_username", username);
+    context.insert("login_value_password", "");
+    context.insert("login_failed_msg", failed_msg);
+    context.insert("oidc_providers", &oidc::provider_names());
+    match view::setup::TEMPLATES.render("pages/login/login.html", &context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering login page: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Exchange the refresh cookie for a new token pair and send the user back where they were going
+pub async fn refresh_login(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    refresh_token: Option<String>,
+    redirect: Option<String>,
+) -> HttpResponse {
+    let to_login = HttpResponse::SeeOther()
+        .append_header(("Location", "/login"))
+        .cookie(CookieVariations::Auth.remove_cookie())
+        .cookie(CookieVariations::Refresh.remove_cookie())
+        .finish();
+
+    let the_user = match refresh_token {
+        Some(token) => token_pub::rotate_refresh_token(&token, &redis),
+        None => None,
+    };
+    let (the_user, remember) = match the_user {
+        Some(refreshed) => refreshed,
+        None => return to_login,
+    };
+
+    // The user may have been deactivated since the refresh token was issued
+    let user = match db.get_one_user(&the_user.user_id).await {
+        Ok(user) if user.active => user,
+        _ => return to_login,
+    };
+
+    let location = match redirect {
+        Some(path) if is_local_path(&path) => path,
+        _ => "/endpoints".to_string(),
+    };
+
+    match generete_token_pair(&user, remember, &redis) {
+        Ok(tokens) => login_response(tokens, remember, location.as_str()),
+        Err(err) => {
+            eprintln!("Error generating tokens: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Only redirect within this site. Browsers read a backslash as a slash,
+// so `/\evil.com` would end up on another host just like `//evil.com`.
+fn is_local_path(path: &str) -> bool {
+    if path.contains('\\') || path.starts_with("//") {
+        return false;
+    }
+    match path.parse::<http::Uri>() {
+        Ok(uri) => uri.scheme().is_none() && uri.authority().is_none() && path.starts_with('/'),
+        Err(_) => false,
+    }
+}
+
+fn login_response(tokens: TokenPair, remember: bool, location: &str) -> HttpResponse {
+    let auth_cookie = generate_cookie(
+        &CookieVariations::Auth,
+        Settings::new(tokens.access.as_str(), remember),
+    );
+    let refresh_cookie = generate_cookie(
+        &CookieVariations::Refresh,
+        Settings::new(tokens.refresh.as_str(), remember),
+    );
+
+    HttpResponse::SeeOther()
+        .append_header(("Location", location))
+        .cookie(auth_cookie)
+        .cookie(refresh_cookie)
+        .finish()
+}
+
+#[cfg(test)]
+mod login_tests {
+    use super::is_local_path;
+
+    #[test]
+    fn check_local_redirects() {
+        assert!(is_local_path("/orders"), "Path was refused");
+        assert!(
+            is_local_path("/products?page=2"),
+            "Path with query was refused"
+        );
+
+        for path in [
+            "//evil.com",
+            "/\\evil.com",
+            "/\\/evil.com",
+            "https://evil.com",
+            "evil.com",
+            "/\t/evil.com",
+        ] {
+            assert!(!is_local_path(path), "{} was accepted", path);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::controllers::login::{complete_login, render_login_page};
+use crate::controllers::two_factor;
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{
+    CookieVariations, LoginClient, OidcCallback, Settings, UserClientIn, UserCookie, UserServer,
+};
+use crate::modules::cookie::generate_cookie;
+use crate::modules::oidc::{self, IdTokenClaims, OidcError, OidcProvider, PendingLogin};
+use crate::modules::redis::RedisDB;
+use actix_web::*;
+
+const LOGIN_FAILED_MSG: &str = "Logging in with this provider failed, please try again";
+
+// Redirect to the provider, it sends the browser back to the callback on this shop's domain.
+// A logged in user starts linking an identity to the account instead of a login.
+pub async fn start(
+    redis: &RedisDB,
+    provider_name: &str,
+    remember: bool,
+    current_user: Option<UserCookie>,
+    request: &HttpRequest,
+) -> HttpResponse {
+    let provider = match OidcProvider::configured(provider_name) {
+        Some(provider) => provider,
+        None => return HttpResponse::NotFound().body("Unknown login provider"),
+    };
+    let redirect_uri = {
+        let connection = request.connection_info();
+        format!(
+            "{}://{}/login/oidc/{}/callback",
+            connection.scheme(),
+            connection.host(),
+            provider.name
+        )
+    };
+
+    let user_id = current_user.map(|user| user.user_id);
+    match oidc::start_login(redis, &provider, &redirect_uri, remember, user_id).await {
+        Ok((location, browser_token)) => HttpResponse::SeeOther()
+            .append_header(("Location", location))
+            .cookie(generate_cookie(
+                &CookieVariations::OidcState,
+                Settings::new(&browser_token, false),
+            ))
+            .finish(),
+        Err(err) => {
+            eprintln!("Error starting {} login: {}", provider.name, err);
+            render_login_page("", LOGIN_FAILED_MSG)
+        }
+    }
+}
+
+// The state cookie is used up by every callback, whatever the outcome
+pub async fn callback(
+    db: &TenantDB,
+    redis: &RedisDB,
+    provider_name: &str,
+    query: OidcCallback,
+    browser_token: Option<String>,
+    client: &LoginClient,
+    current_user: Option<UserCookie>,
+) -> HttpResponse {
+    let mut response = finish_login(
+        db,
+        redis,
+        provider_name,
+        query,
+        browser_token,
+        client,
+        current_user,
+    )
+    .await;
+    let _ = response.add_cookie(&CookieVariations::OidcState.remove_cookie());
+    response
+}
+
+async fn finish_login(
+    db: &TenantDB,
+    redis: &RedisDB,
+    provider_name: &str,
+    query: OidcCallback,
+    browser_token: Option<String>,
+    client: &LoginClient,
+    current_user: Option<UserCookie>,
+) -> HttpResponse {
+    let verified = verify_callback(redis, provider_name, &query, browser_token.as_deref()).await;
+    let (provider, claims, pending) = match verified {
+        Ok(verified) => verified,
+        Err(err) => {
+            log::warn!(
+                "OpenID Connect login with {} failed: {}",
+                provider_name,
+                err
+            );
+            return render_login_page("", LOGIN_FAILED_MSG);
+        }
+    };
+
+    // Linking only for the user that started it, still logged in in this browser
+    if let Some(started_by) = &pending.user_id {
+        let current_user_id = current_user.map(|user| user.user_id);
+        if current_user_id.as_ref() != Some(started_by) {
+            log::warn!(
+                "OpenID Connect link with {} finished by another user",
+                provider_name
+            );
+            return render_login_page("", LOGIN_FAILED_MSG);
+        }
+        return match link_identity(db, &provider, &claims, started_by, client).await {
+            Ok(true) => HttpResponse::SeeOther()
+                .append_header(("Location", "/endpoints"))
+                .finish(),
+            Ok(false) => {
+                HttpResponse::Conflict().body("This login is already linked to another account")
+            }
+            Err(err) => {
+                eprintln!("Error linking identity: {}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+
+    let user = match resolve_user(db, &provider, &claims, client).await {
+        Ok(Some(user)) if user.active => user,
+        Ok(Some(_)) => return render_login_page("", LOGIN_FAILED_MSG),
+        Ok(None) => {
+            return render_login_page(
+                "",
+                "An account with this email already exists. Log in with your password first, then log in with the provider to link it.",
+            )
+        }
+        Err(err) => {
+            eprintln!("Error resolving identity: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    // The provider replaces the password, not the second factor
+    match db.get_two_factor_secret(&user.user_id).await {
+        Ok(None) => complete_login(redis, &user, pending.remember, client),
+        Ok(Some(_)) => two_factor::start_second_step(&user, redis, pending.remember),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn verify_callback(
+    redis: &RedisDB,
+    provider_name: &str,
+    query: &OidcCallback,
+    browser_token: Option<&str>,
+) -> Result<(OidcProvider, IdTokenClaims, PendingLogin), OidcError> {
+    let provider = OidcProvider::configured(provider_name)
+        .ok_or_else(|| OidcError::UnknownProvider(provider_name.to_string()))?;
+    if let Some(error) = &query.error {
+        return Err(OidcError::ProviderError(error.to_string()));
+    }
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(OidcError::InvalidState),
+    };
+
+    let pending = oidc::take_pending(redis, &provider, state, browser_token)?;
+    let discovery = oidc::discover(&provider).await?;
+    let id_token = oidc::exchange_code(&provider, &discovery, code, &pending).await?;
+    let claims = oidc::verify_id_token(&provider, &discovery, &id_token, &pending.nonce).await?;
+
+    Ok((provider, claims, pending))
+}
+
+// Link the identity to the user, false when it already belongs to another account
+async fn link_identity(
+    db: &TenantDB,
+    provider: &OidcProvider,
+    claims: &IdTokenClaims,
+    user_id: &str,
+    client: &LoginClient,
+) -> Result<bool, sqlx::Error> {
+    if let Some(linked_user_id) = db.get_identity_user_id(&provider.name, &claims.sub).await? {
+        return Ok(linked_user_id == user_id);
+    }
+
+    db.create_identity(
+        user_id,
+        &provider.name,
+        &claims.sub,
+        claims.email.as_deref(),
+    )
+    .await?;
+    let _ = db
+        .create_audit_entry(
+            "oidc_link",
+            &format!("{}:{}", provider.name, user_id),
+            &client.ip,
+        )
+        .await;
+    Ok(true)
+}
+
+// Find the user of an identity, link it by verified email or create a new account.
+// None when the email belongs to an account that has to link the identity itself.
+async fn resolve_user(
+    db: &TenantDB,
+    provider: &OidcProvider,
+    claims: &IdTokenClaims,
+    client: &LoginClient,
+) -> Result<Option<UserServer>, sqlx::Error> {
+    if let Some(user_id) = db.get_identity_user_id(&provider.name, &claims.sub).await? {
+        return db.get_one_user(&user_id).await.map(Some);
+    }
+
+    let email = match &claims.email {
+        Some(email) if !email.trim().is_empty() => email.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let email_verified = claims.email_verified.unwrap_or(false);
+
+    // Only when both sides verified the address, otherwise anyone could claim an account
+    if let Some(user) = db.get_one_user_email(&email).await? {
+        if !email_verified || user.verified_at.is_none() {
+            return Ok(None);
+        }
+        link_identity(db, provider, claims, &user.user_id, client).await?;
+        return Ok(Some(user));
+    }
+
+    let user = UserServer::process_for_server(UserClientIn {
+        username: unique_username(db, claims, &email).await?,
+        email,
+        // Nobody knows this password, it can be set with a reset later
+        password: oidc::random_token(),
+    });
+    let user = db.create_one_user(&user).await?;
+    link_identity(db, provider, claims, &user.user_id, client).await?;
+    let _ = db
+        .create_audit_entry("oidc_signup", &user.user_id, &client.ip)
+        .await;
+
+    match email_verified {
+        true => db.verify_one_user(&user.user_id).await.map(Some),
+        false => Ok(Some(user)),
+    }
+}
+
+// Username from the provider's claims, with a number added when it is taken
+async fn unique_username(
+    db: &TenantDB,
+    claims: &IdTokenClaims,
+    email: &str,
+) -> Result<String, sqlx::Error> {
+    let wanted = claims
+        .preferred_username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let base: String = wanted
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+        .collect::<String>()
+        .to_lowercase();
+    let base = match base.is_empty() {
+        true => "user".to_string(),
+        false => base,
+    };
+
+    let mut username = base.to_string();
+    let mut number = 1;
+    while db.get_one_user_username(&username).await?.is_some() {
+        number += 1;
+        username = format!("{}{}", base, number);
+    }
+    Ok(username)
+}
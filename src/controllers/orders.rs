@@ -0,0 +1,385 @@
+use crate::db::tenant::TenantDB;
+use crate::domain::cart::Cart;
+use crate::domain::connect_accounts::PaymentDestination;
+use crate::domain::datatypes::{PageQuery, Paginated, UserCookie};
+use crate::domain::inventory::StockError;
+use crate::domain::orders::{Order, OrderDetails, OrderStatus, RefundIn};
+use crate::modules::cart::{self, CartError, CartOwner};
+use crate::modules::redis::RedisDB;
+use crate::modules::stripe::refunds::record_refund;
+use crate::modules::stripe::stripe::{Stripe, StripeCurrency};
+use crate::utils::constants::CHECKOUT_EXPIRY_MINUTES;
+use crate::view;
+use actix_web::*;
+
+// Turn the cart of the user into a pending order and send them to Stripe to pay for it.
+// The stock of the variants is held until the checkout session expires or completes.
+pub async fn checkout(
+    db: TenantDB,
+    redis: &RedisDB,
+    user: &UserCookie,
+    request: &HttpRequest,
+) -> HttpResponse {
+    let owner = CartOwner::User(user.user_id.to_string());
+    let stored = match cart::load_cart(redis, db.shop_domain(), &owner) {
+        Ok(cart) => cart,
+        Err(err) => {
+            eprintln!("Error loading cart: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let (priced, _) = match cart::price_cart(&db, &stored).await {
+        Ok(priced) => priced,
+        Err(err) => {
+            eprintln!("Error pricing cart: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let Some(currency) = priced.currency.as_deref().and_then(StripeCurrency::parse) else {
+        return HttpResponse::BadRequest().json("Your cart is empty");
+    };
+    // Owners are paid through their Connect account, so they must finish onboarding first
+    let destination = match db.get_payment_destination().await {
+        Ok(PaymentDestination::Unavailable) => {
+            return HttpResponse::Conflict().json("This shop cannot take payments yet");
+        }
+        Ok(destination) => destination,
+        Err(err) => {
+            eprintln!("Error getting payment destination: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    // The stock held by an earlier checkout of the user goes back on sale first
+    if let Err(err) = abandon_pending_orders(&db, &user.user_id, None).await {
+        eprintln!("Error closing pending orders: {:?}", err);
+        return HttpResponse::InternalServerError().finish();
+    }
+    for line in &priced.lines {
+        match cart::check_item(&db, line.product_id, line.variant_id, line.quantity, None).await {
+            Ok(()) => {}
+            Err(CartError::Database(err)) => {
+                eprintln!("Error checking cart line: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+            Err(err) => {
+                let message = format!("{}: {}", line.name, err);
+                return HttpResponse::build(err.status_code()).json(message);
+            }
+        }
+    }
+
+    let email = db
+        .get_one_user(&user.user_id)
+        .await
+        .ok()
+        .map(|user| user.email);
+    let order = match db
+        .create_order(&user.user_id, email.as_deref(), &priced)
+        .await
+    {
+        Ok(order) => order,
+        Err(err) => {
+            eprintln!("Error creating order: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::minutes(*CHECKOUT_EXPIRY_MINUTES);
+    let reserved: Vec<(i64, i64)> = priced
+        .lines
+        .iter()
+        .filter_map(|line| {
+            line.variant_id
+                .map(|variant_id| (variant_id, line.quantity))
+        })
+        .collect();
+    let reference = order.reservation_reference();
+    match db
+        .reserve_stock(&reference, &reserved, expires_at.naive_utc())
+        .await
+    {
+        Ok(()) => {}
+        Err(StockError::Database(err)) => {
+            eprintln!("Error reserving stock: {:?}", err);
+            cancel_order(&db, &order).await;
+            return HttpResponse::InternalServerError().finish();
+        }
+        Err(_) => {
+            cancel_order(&db, &order).await;
+            return HttpResponse::Conflict().json("Some items are no longer available");
+        }
+    }
+
+    let details = match db.order_details(order.clone()).await {
+        Ok(details) => details,
+        Err(err) => {
+            eprintln!("Error getting order lines: {:?}", err);
+            release_and_cancel(&db, &order).await;
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let base_url = {
+        let connection_info = request.connection_info();
+        format!("{}://{}", connection_info.scheme(), connection_info.host())
+    };
+    // Stripe fills in the session id, the order page uses it to empty the cart
+    let success_url = format!(
+        "{}/orders/show/{}?session_id={{CHECKOUT_SESSION_ID}}",
+        base_url, order.order_id
+    );
+    let cancel_url = format!("{}/orders/cancel/{}", base_url, order.order_id);
+
+    let session = match Stripe::new()
+        .create_order_checkout(
+            &details,
+            currency,
+            &success_url,
+            &cancel_url,
+            expires_at.timestamp(),
+            &destination,
+        )
+        .await
+    {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("Error creating checkout session: {:?}", err);
+            release_and_cancel(&db, &order).await;
+            return HttpResponse::BadGateway().json("The payment provider is not available");
+        }
+    };
+    if let Err(err) = db.set_checkout_session(&order, session.id.as_str()).await {
+        eprintln!("Error saving checkout session: {:?}", err);
+        if let Err(err) = Stripe::new()
+            .expire_checkout_session(session.id.as_str())
+            .await
+        {
+            eprintln!("Error expiring checkout session: {:?}", err);
+        }
+        release_and_cancel(&db, &order).await;
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let checkout_url = session.url.unwrap_or_default();
+    if request.headers().contains_key("HX-Request") {
+        return HttpResponse::Ok()
+            .insert_header(("HX-Redirect", checkout_url))
+            .finish();
+    }
+    HttpResponse::Created().json(serde_json::json!({
+        "order_id": order.order_id,
+        "checkout_url": checkout_url,
+    }))
+}
+
+async fn cancel_order(db: &TenantDB, order: &Order) {
+    if let Err(err) = db
+        .update_order_status(order, OrderStatus::Cancelled, None)
+        .await
+    {
+        eprintln!("Error cancelling order: {:?}", err);
+    }
+}
+
+// A user pays for one order at a time. Their earlier unpaid orders are closed together
+// with their Stripe sessions, or only the one order when it is given.
+async fn abandon_pending_orders(
+    db: &TenantDB,
+    user_id: &str,
+    order_id: Option<i64>,
+) -> Result<(), sqlx::Error> {
+    for order in db.get_pending_orders(user_id).await? {
+        if order_id.is_some_and(|order_id| order_id != order.order_id) {
+            continue;
+        }
+        // Stripe refuses to expire a session that is being paid, the webhook settles it
+        if let Some(checkout_session_id) = &order.checkout_session_id {
+            if let Err(err) = Stripe::new()
+                .expire_checkout_session(checkout_session_id)
+                .await
+            {
+                eprintln!(
+                    "Error expiring checkout session {}: {:?}",
+                    checkout_session_id, err
+                );
+                continue;
+            }
+        }
+        release_and_cancel(db, &order).await;
+    }
+    Ok(())
+}
+
+async fn release_and_cancel(db: &TenantDB, order: &Order) {
+    if let Err(err) = db.release_order_reservations(order).await {
+        eprintln!("Error releasing stock reservations: {:?}", err);
+    }
+    cancel_order(db, order).await;
+}
+
+pub async fn list_orders(db: TenantDB, user: &UserCookie, page: PageQuery) -> HttpResponse {
+    match db.get_user_orders(&user.user_id, &page).await {
+        Ok((orders, total)) => HttpResponse::Ok().json(Paginated::new(orders, &page, total)),
+        Err(err) => {
+            eprintln!("Error listing orders: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn get_order(db: TenantDB, user: &UserCookie, order_id: i64) -> HttpResponse {
+    match db.get_user_order(&user.user_id, order_id).await {
+        Ok(Some(order)) => HttpResponse::Ok().json(order),
+        Ok(None) => HttpResponse::NotFound().json("Order not found"),
+        Err(err) => {
+            eprintln!("Error getting order: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// The shop sent a paid order
+pub async fn fulfil_order(db: TenantDB, order_id: i64) -> HttpResponse {
+    let order = match db.get_one_order(order_id).await {
+        Ok(Some(order)) => order,
+        Ok(None) => return HttpResponse::NotFound().json("Order not found"),
+        Err(err) => {
+            eprintln!("Error getting order: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match db
+        .update_order_status(&order, OrderStatus::Fulfilled, None)
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().json("Order fulfilled"),
+        Ok(false) => HttpResponse::Conflict().json("Only paid orders can be fulfilled"),
+        Err(err) => {
+            eprintln!("Error fulfilling order: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Pay back part or all of a paid order. The charge.refunded event that follows finds it stored.
+pub async fn refund_order(db: TenantDB, order_id: i64, refund: RefundIn) -> HttpResponse {
+    let order = match db.get_one_order(order_id).await {
+        Ok(Some(order)) => order,
+        Ok(None) => return HttpResponse::NotFound().json("Order not found"),
+        Err(err) => {
+            eprintln!("Error getting order: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let Some(payment_intent_id) = order.payment_intent_id.as_deref() else {
+        return HttpResponse::Conflict().json("Only paid orders can be refunded");
+    };
+    let amount = match refund.validate(order.refundable_amount()) {
+        Ok(amount) => amount,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    let refund = match Stripe::new()
+        .refund_order_payment(payment_intent_id, amount, order.order_id)
+        .await
+    {
+        Ok(refund) => refund,
+        Err(err) => {
+            eprintln!("Error refunding order {}: {:?}", order.order_id, err);
+            return HttpResponse::BadGateway().json("The payment provider is not available");
+        }
+    };
+    if matches!(refund.status.as_deref(), Some("failed" | "canceled")) {
+        return HttpResponse::BadGateway().json("The payment provider declined the refund");
+    }
+    if let Err(err) = record_refund(&db, &order, order.refunded_amount + refund.amount).await {
+        eprintln!("Error saving refund: {:?}", err);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    match db.get_one_order(order_id).await {
+        Ok(Some(order)) => HttpResponse::Ok().json(order),
+        Ok(None) => HttpResponse::NotFound().json("Order not found"),
+        Err(err) => {
+            eprintln!("Error getting order: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub mod ui {
+    use super::*;
+
+    fn render(template: &str, context: &tera::Context) -> HttpResponse {
+        match view::setup::TEMPLATES.render(template, context) {
+            Ok(content) => HttpResponse::Ok().body(content),
+            Err(err) => {
+                eprintln!("Error rendering {}: {}", template, err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    pub async fn orders_page(db: TenantDB, user: &UserCookie, page: PageQuery) -> HttpResponse {
+        let (orders, total) = match db.get_user_orders(&user.user_id, &page).await {
+            Ok(orders) => orders,
+            Err(err) => {
+                eprintln!("Error listing orders: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        let mut context = tera::Context::new();
+        context.insert("orders", &Paginated::new(orders, &page, total));
+        render("pages/orders/orders.html", &context)
+    }
+
+    // Stripe sends the customer back here with the id of the checkout session,
+    // the cart that became this order is emptied then
+    pub async fn order_page(
+        db: TenantDB,
+        redis: &RedisDB,
+        user: &UserCookie,
+        order_id: i64,
+        session_id: Option<String>,
+    ) -> HttpResponse {
+        let details: OrderDetails = match db.get_user_order(&user.user_id, order_id).await {
+            Ok(Some(details)) => details,
+            Ok(None) => return HttpResponse::NotFound().body("Order not found"),
+            Err(err) => {
+                eprintln!("Error getting order: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        let returned_from_checkout =
+            session_id.is_some() && session_id == details.order.checkout_session_id;
+        if returned_from_checkout {
+            let owner = CartOwner::User(user.user_id.to_string());
+            if let Err(err) = cart::save_cart(redis, db.shop_domain(), &owner, &Cart::default()) {
+                eprintln!("Error emptying cart: {:?}", err);
+            }
+        }
+
+        let mut context = tera::Context::new();
+        context.insert("order", &details);
+        context.insert("returned_from_checkout", &returned_from_checkout);
+        render("pages/orders/order.html", &context)
+    }
+
+    // Stripe sends the customer back here when they leave the checkout,
+    // the order is closed and they are back at their cart
+    pub async fn checkout_cancelled(
+        db: TenantDB,
+        user: &UserCookie,
+        order_id: i64,
+    ) -> HttpResponse {
+        if let Err(err) = abandon_pending_orders(&db, &user.user_id, Some(order_id)).await {
+            eprintln!("Error closing order: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+        HttpResponse::SeeOther()
+            .append_header(("Location", "/cart/show"))
+            .finish()
+    }
+}
@@ -0,0 +1,167 @@
+use crate::controllers::shops::owned_shop;
+use crate::db::sqlite::SqliteDB;
+use crate::domain::connect_accounts::ConnectAccount;
+use crate::domain::datatypes::UserCookie;
+use crate::modules::shop_registry;
+use crate::modules::stripe::connect::sync_connect_account;
+use crate::modules::stripe::stripe::Stripe;
+use actix_web::http::header;
+use actix_web::*;
+
+// Stripe sends the owner back to the shop, on the domain its pages are served from
+fn onboarding_urls(request: &HttpRequest, shop_domain: &str) -> (String, String) {
+    let host = shop_registry::get_shop(shop_domain)
+        .and_then(|shop| shop.canonical_domain)
+        .unwrap_or_else(|| shop_domain.to_string());
+    let base_url = format!(
+        "{}://{}/account/shops/{}/payouts",
+        request.connection_info().scheme(),
+        host,
+        shop_domain
+    );
+    (
+        format!("{}/refresh", base_url),
+        format!("{}/return", base_url),
+    )
+}
+
+async fn connect_account(db: &SqliteDB, shop_domain: &str) -> Result<ConnectAccount, HttpResponse> {
+    match db.get_connect_account(shop_domain).await {
+        Ok(Some(account)) => Ok(account),
+        Ok(None) => Err(HttpResponse::NotFound().json("The shop has no payout account")),
+        Err(err) => {
+            eprintln!("Error getting connect account: {:?}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+async fn onboarding_link(
+    account: &ConnectAccount,
+    request: &HttpRequest,
+) -> Result<String, HttpResponse> {
+    let (refresh_url, return_url) = onboarding_urls(request, &account.shop_domain);
+    match Stripe::new()
+        .create_account_link_stripe_connect(&account.stripe_account_id, &refresh_url, &return_url)
+        .await
+    {
+        Ok(link) => Ok(link.url),
+        Err(err) => {
+            eprintln!("Error creating account link: {:?}", err);
+            Err(HttpResponse::BadGateway().json("The payment provider is not available"))
+        }
+    }
+}
+
+pub async fn get_payouts(db: &SqliteDB, user: &UserCookie, shop_domain: &str) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    match connect_account(db, shop_domain).await {
+        Ok(account) => HttpResponse::Ok().json(account),
+        Err(response) => response,
+    }
+}
+
+// The first call creates the Connect account of the shop, later calls continue its onboarding
+pub async fn start_onboarding(
+    db: &SqliteDB,
+    user: &UserCookie,
+    shop_domain: &str,
+    request: &HttpRequest,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let account = match db.get_connect_account(shop_domain).await {
+        Ok(Some(account)) => account,
+        Ok(None) => {
+            let created = match Stripe::new()
+                .create_account_stripe_connect(shop_domain)
+                .await
+            {
+                Ok(created) => created,
+                Err(err) => {
+                    eprintln!("Error creating connect account: {:?}", err);
+                    return HttpResponse::BadGateway()
+                        .json("The payment provider is not available");
+                }
+            };
+            match db
+                .create_connect_account(shop_domain, created.id.as_str())
+                .await
+            {
+                Ok(account) => account,
+                Err(err) => {
+                    eprintln!("Error saving connect account: {:?}", err);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        Err(err) => {
+            eprintln!("Error getting connect account: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match onboarding_link(&account, request).await {
+        Ok(url) => HttpResponse::Created().json(serde_json::json!({ "onboarding_url": url })),
+        Err(response) => response,
+    }
+}
+
+// Stripe sends the owner here when the onboarding link expired or was used already
+pub async fn refresh_onboarding(
+    db: &SqliteDB,
+    user: &UserCookie,
+    shop_domain: &str,
+    request: &HttpRequest,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let account = match connect_account(db, shop_domain).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match onboarding_link(&account, request).await {
+        Ok(url) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, url))
+            .finish(),
+        Err(response) => response,
+    }
+}
+
+// Back from onboarding, the account.updated event may still be on its way
+pub async fn onboarding_return(
+    db: &SqliteDB,
+    user: &UserCookie,
+    shop_domain: &str,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let account = match connect_account(db, shop_domain).await {
+        Ok(account) => account,
+        Err(response) => return response,
+    };
+
+    match Stripe::new()
+        .retrieve_account(&account.stripe_account_id)
+        .await
+    {
+        Ok(stripe_account) => {
+            let synced_at = chrono::Utc::now().timestamp();
+            if let Err(err) = sync_connect_account(db, &stripe_account, synced_at).await {
+                eprintln!("Error saving connect account: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+        Err(err) => eprintln!("Error retrieving connect account: {:?}", err),
+    }
+    match connect_account(db, shop_domain).await {
+        Ok(account) => HttpResponse::Ok().json(account),
+        Err(response) => response,
+    }
+}
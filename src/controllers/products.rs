@@ -0,0 +1,275 @@
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{PageQuery, Paginated};
+use crate::domain::inventory::{StockChangeIn, VariantIn};
+use crate::domain::products::ProductIn;
+use crate::view;
+use actix_web::*;
+
+pub async fn list_products(db: TenantDB, page: PageQuery) -> HttpResponse {
+    match db.get_products(&page).await {
+        Ok((products, total)) => HttpResponse::Ok().json(Paginated::new(products, &page, total)),
+        Err(err) => {
+            eprintln!("Error listing products: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn get_product(db: TenantDB, product_id: i64) -> HttpResponse {
+    match db.get_one_product(product_id).await {
+        Ok(Some(product)) => HttpResponse::Ok().json(product),
+        Ok(None) => HttpResponse::NotFound().json("Product not found"),
+        Err(err) => {
+            eprintln!("Error getting product: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn create_product(db: TenantDB, product: ProductIn) -> HttpResponse {
+    let product = match product.validate() {
+        Ok(product) => product,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    // The plan of the shop limits the size of its catalog
+    let limit = match db.get_shop_plan().await {
+        Ok(plan) => plan.max_products(),
+        Err(err) => {
+            eprintln!("Error getting shop plan: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if let Some(limit) = limit {
+        match db.count_products().await {
+            Ok(count) if count >= limit => {
+                return HttpResponse::PaymentRequired()
+                    .json(format!("The plan of the shop allows {} products", limit))
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Error counting products: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    match db.create_product(&product).await {
+        Ok(product) => HttpResponse::Created().json(product),
+        Err(err) => {
+            eprintln!("Error creating product: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn update_product(db: TenantDB, product_id: i64, product: ProductIn) -> HttpResponse {
+    let product = match product.validate() {
+        Ok(product) => product,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    match db.update_product(product_id, &product).await {
+        Ok(Some(product)) => HttpResponse::Ok().json(product),
+        Ok(None) => HttpResponse::NotFound().json("Product not found"),
+        Err(err) => {
+            eprintln!("Error updating product: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn delete_product(db: TenantDB, product_id: i64) -> HttpResponse {
+    match db.delete_product(product_id).await {
+        Ok(true) => HttpResponse::Ok().json("Product deleted"),
+        Ok(false) => HttpResponse::NotFound().json("Product not found"),
+        Err(err) => {
+            eprintln!("Error deleting product: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_variants(db: TenantDB, product_id: i64) -> HttpResponse {
+    match db.get_one_product(product_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("Product not found"),
+        Err(err) => {
+            eprintln!("Error getting product: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match db.get_variants(product_id).await {
+        Ok(variants) => HttpResponse::Ok().json(variants),
+        Err(err) => {
+            eprintln!("Error listing variants: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn create_variant(db: TenantDB, product_id: i64, variant: VariantIn) -> HttpResponse {
+    let variant = match variant.validate() {
+        Ok(variant) => variant,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    match db.create_variant(product_id, &variant).await {
+        Ok(Some(variant)) => HttpResponse::Created().json(variant),
+        Ok(None) => HttpResponse::NotFound().json("Product not found"),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponse::Conflict().json("This SKU is already in use")
+        }
+        Err(err) => {
+            eprintln!("Error creating variant: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn update_variant(
+    db: TenantDB,
+    product_id: i64,
+    variant_id: i64,
+    variant: VariantIn,
+) -> HttpResponse {
+    let variant = match variant.validate() {
+        Ok(variant) => variant,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    match db.update_variant(product_id, variant_id, &variant).await {
+        Ok(Some(variant)) => HttpResponse::Ok().json(variant),
+        Ok(None) => HttpResponse::NotFound().json("Variant not found"),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponse::Conflict().json("This SKU is already in use")
+        }
+        Err(err) => {
+            eprintln!("Error updating variant: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn delete_variant(db: TenantDB, product_id: i64, variant_id: i64) -> HttpResponse {
+    match db.delete_variant(product_id, variant_id).await {
+        Ok(true) => HttpResponse::Ok().json("Variant deleted"),
+        Ok(false) => HttpResponse::NotFound().json("Variant not found"),
+        Err(err) => {
+            eprintln!("Error deleting variant: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn adjust_stock(
+    db: TenantDB,
+    product_id: i64,
+    variant_id: i64,
+    change: StockChangeIn,
+    user_id: Option<String>,
+) -> HttpResponse {
+    if let Err(message) = change.validate() {
+        return HttpResponse::BadRequest().json(message);
+    }
+
+    match db
+        .adjust_stock(product_id, variant_id, &change, user_id.as_deref())
+        .await
+    {
+        Ok(Some(variant)) => HttpResponse::Ok().json(variant),
+        // Either the variant is unknown or there is not enough to take away
+        Ok(None) => match db.get_one_variant(product_id, variant_id).await {
+            Ok(Some(_)) => HttpResponse::Conflict().json("The stock cannot go below zero"),
+            Ok(None) => HttpResponse::NotFound().json("Variant not found"),
+            Err(err) => {
+                eprintln!("Error getting variant: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Err(err) => {
+            eprintln!("Error adjusting stock: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_stock_movements(
+    db: TenantDB,
+    product_id: i64,
+    variant_id: i64,
+    page: PageQuery,
+) -> HttpResponse {
+    match db.get_one_variant(product_id, variant_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().json("Variant not found"),
+        Err(err) => {
+            eprintln!("Error getting variant: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match db.get_stock_movements(product_id, variant_id, &page).await {
+        Ok((movements, total)) => HttpResponse::Ok().json(Paginated::new(movements, &page, total)),
+        Err(err) => {
+            eprintln!("Error listing stock movements: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub mod ui {
+    use super::*;
+
+    fn render(template: &str, context: &tera::Context) -> HttpResponse {
+        match view::setup::TEMPLATES.render(template, context) {
+            Ok(content) => HttpResponse::Ok().body(content),
+            Err(err) => {
+                eprintln!("Error rendering {}: {}", template, err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    // The whole page, or only the list when HTMX asks for another page
+    pub async fn products_page(db: TenantDB, page: PageQuery, list_only: bool) -> HttpResponse {
+        let (products, total) = match db.get_products(&page).await {
+            Ok(products) => products,
+            Err(err) => {
+                eprintln!("Error listing products: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        let mut context = tera::Context::new();
+        context.insert("products", &Paginated::new(products, &page, total));
+        match list_only {
+            true => render("pages/products/components/product_list.html", &context),
+            false => render("pages/products/products.html", &context),
+        }
+    }
+
+    pub async fn product_page(db: TenantDB, product_id: i64) -> HttpResponse {
+        match db.get_one_product(product_id).await {
+            Ok(Some(product)) => {
+                let variants = match db.get_variants(product_id).await {
+                    Ok(variants) => variants,
+                    Err(err) => {
+                        eprintln!("Error listing variants: {:?}", err);
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                };
+                let mut context = tera::Context::new();
+                context.insert("product", &product);
+                context.insert("variants", &variants);
+                render("pages/products/product.html", &context)
+            }
+            Ok(None) => HttpResponse::NotFound().body("Product not found"),
+            Err(err) => {
+                eprintln!("Error getting product: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
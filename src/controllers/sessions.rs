@@ -0,0 +1,120 @@
+use crate::domain::datatypes::{CookieVariations, UserCookie};
+use crate::modules::redis::RedisDB;
+use crate::modules::session::{self, Session};
+use crate::modules::token_pub;
+use crate::view;
+use actix_web::*;
+use chrono::DateTime;
+use serde::Serialize;
+
+// One row of the sessions page
+#[derive(Serialize)]
+struct SessionRow {
+    handle: String,
+    shop_domain: String,
+    user_agent: String,
+    created_on: String,
+    last_seen: String,
+    current: bool,
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+pub fn sessions_page(
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    current: Option<Session>,
+    message: &str,
+) -> HttpResponse {
+    let mut context = tera::Context::new();
+    context.insert("sessions_enabled", &session::sessions_enabled());
+    context.insert("sessions_msg", message);
+
+    // Without server-side sessions there is nothing to list, only to revoke
+    let mut rows = Vec::new();
+    if session::sessions_enabled() {
+        let current_handle = current.map(|current| session::session_handle(&current.id));
+
+        match session::user_sessions(&redis, &user.user_id) {
+            Ok(sessions) => {
+                for (handle, data) in sessions {
+                    rows.push(SessionRow {
+                        current: current_handle.as_deref() == Some(handle.as_str()),
+                        handle,
+                        shop_domain: data.shop_domain,
+                        user_agent: data.user_agent,
+                        created_on: format_timestamp(data.created_on),
+                        last_seen: format_timestamp(data.last_seen),
+                    });
+                }
+            }
+            Err(err) => {
+                eprintln!("Error listing sessions: {}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    context.insert("sessions", &rows);
+
+    match view::setup::TEMPLATES.render("pages/account/sessions.html", &context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering sessions page: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn terminate_session(
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    current: Option<Session>,
+    handle: String,
+) -> HttpResponse {
+    match session::delete_session_by_handle(&redis, &user.user_id, &handle) {
+        Ok(true) => sessions_page(redis, user, current, "The session has been terminated"),
+        Ok(false) => sessions_page(redis, user, current, "The session was not found"),
+        Err(err) => {
+            eprintln!("Error terminating session: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Log out every other browser, with tokens that means this one as well
+pub fn terminate_other_sessions(
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    current: Option<Session>,
+) -> HttpResponse {
+    if !session::sessions_enabled() {
+        if let Err(err) = token_pub::revoke_user_tokens(&redis, &user.user_id) {
+            eprintln!("Error revoking tokens: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        return HttpResponse::SeeOther()
+            .append_header(("Location", "/login"))
+            .cookie(CookieVariations::Auth.remove_cookie())
+            .cookie(CookieVariations::Refresh.remove_cookie())
+            .finish();
+    }
+
+    let keep = current.as_ref().map(|current| current.id.as_str());
+    match session::delete_user_sessions(&redis, &user.user_id, keep) {
+        Ok(()) => sessions_page(
+            redis,
+            user,
+            current,
+            "All other sessions have been terminated",
+        ),
+        Err(err) => {
+            eprintln!("Error terminating sessions: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use crate::controllers::subscriptions::require_feature;
+use crate::db::sqlite::SqliteDB;
+use crate::domain::datatypes::UserCookie;
+use crate::domain::shops::{
+    normalize_host, ShopConfig, ShopConfigIn, ShopDomain, ShopDomainIn, ShopOwnerUpdate, ShopTheme,
+};
+use crate::domain::subscriptions::PlanFeature;
+use crate::modules::redis::RedisDB;
+use crate::modules::{domain_verification, session, shop_registry};
+use crate::utils::constants::{DNS_RESOLVER, PLATFORM_DOMAIN};
+use crate::view;
+use actix_web::*;
+use serde::Serialize;
+
+// Unique and foreign key violations are mistakes of the caller
+fn write_error(err: sqlx::Error) -> HttpResponse {
+    match &err {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            HttpResponse::Conflict().json("A shop with this domain or slug already exists")
+        }
+        sqlx::Error::Database(db_err) if db_err.is_foreign_key_violation() => {
+            HttpResponse::BadRequest().json("The owner does not exist")
+        }
+        _ => {
+            eprintln!("Error saving shop: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_shops(db: web::Data<SqliteDB>) -> HttpResponse {
+    match db.get_all_shop_domains().await {
+        Ok(shops) => HttpResponse::Ok().json(shops),
+        Err(err) => {
+            eprintln!("Error listing shops: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn create_shop(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    shop: ShopConfigIn,
+) -> HttpResponse {
+    let shop = match shop.validate() {
+        Ok(shop) => shop,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    match db.create_shop(&shop).await {
+        Ok(shop) => {
+            shop_registry::shop_changed(&db, &redis, &shop.domain).await;
+            HttpResponse::Created().json(shop)
+        }
+        Err(err) => write_error(err),
+    }
+}
+
+pub async fn update_shop(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    domain: String,
+    shop: ShopConfigIn,
+) -> HttpResponse {
+    // The domain in the path is the shop being changed
+    let shop = match (ShopConfigIn { domain, ..shop }).validate() {
+        Ok(shop) => shop,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    save_update(db, redis, shop).await
+}
+
+async fn save_update(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    shop: ShopConfig,
+) -> HttpResponse {
+    match db.update_shop(&shop).await {
+        Ok(Some(shop)) => {
+            shop_registry::shop_changed(&db, &redis, &shop.domain).await;
+            HttpResponse::Ok().json(shop)
+        }
+        Ok(None) => HttpResponse::NotFound().json("Shop not found"),
+        Err(err) => write_error(err),
+    }
+}
+
+pub async fn delete_shop(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    domain: String,
+) -> HttpResponse {
+    match db.delete_shop(&domain).await {
+        Ok(true) => {
+            shop_registry::shop_changed(&db, &redis, &domain).await;
+            HttpResponse::Ok().json("Shop deleted")
+        }
+        Ok(false) => HttpResponse::NotFound().json("Shop not found"),
+        Err(err) => {
+            eprintln!("Error deleting shop: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn list_own_shops(db: web::Data<SqliteDB>, user: UserCookie) -> HttpResponse {
+    match db.get_user_shops(&user.user_id).await {
+        Ok(shops) => HttpResponse::Ok().json(shops),
+        Err(err) => {
+            eprintln!("Error listing shops: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Owners can only manage their own shops
+pub(crate) async fn owned_shop(
+    db: &SqliteDB,
+    user: &UserCookie,
+    domain: &str,
+) -> Result<ShopConfig, HttpResponse> {
+    match db.get_one_shop_domain(domain).await {
+        Ok(shop) if shop.user_id.as_deref() == Some(user.user_id.as_str()) => Ok(shop),
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            Err(HttpResponse::NotFound().json("Shop not found"))
+        }
+        Err(err) => {
+            eprintln!("Error getting shop: {:?}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// Owners can change how their shop is presented, not who owns it
+pub async fn update_own_shop(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    domain: String,
+    update: ShopOwnerUpdate,
+) -> HttpResponse {
+    let current = match owned_shop(&db, &user, &domain).await {
+        Ok(shop) => shop,
+        Err(response) => return response,
+    };
+
+    let shop = ShopConfigIn {
+        domain: current.domain,
+        name: update.name,
+        product_type: update.product_type,
+        user_id: current.user_id,
+        slug: current.slug,
+    };
+    match shop.validate() {
+        Ok(shop) => save_update(db, redis, shop).await,
+        Err(message) => HttpResponse::BadRequest().json(message),
+    }
+}
+
+// The theme is used by every page of the shop, see view::setup
+pub async fn update_shop_theme(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    domain: String,
+    theme: ShopTheme,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &domain).await {
+        return response;
+    }
+    let theme = match theme.validate() {
+        Ok(theme) => theme,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+    // Any plan may go back to the base styling
+    let is_reset = theme.primary_color.is_none()
+        && theme.background_color.is_none()
+        && theme.logo_url.is_none();
+    if !is_reset {
+        if let Err(response) = require_feature(&db, &domain, PlanFeature::Themes).await {
+            return response;
+        }
+    }
+
+    match db.update_shop_theme(&domain, &theme).await {
+        Ok(Some(shop)) => {
+            shop_registry::shop_changed(&db, &redis, &shop.domain).await;
+            HttpResponse::Ok().json(shop)
+        }
+        Ok(None) => HttpResponse::NotFound().json("Shop not found"),
+        Err(err) => {
+            eprintln!("Error saving shop theme: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// A custom domain with the TXT record that proves it belongs to the shop
+#[derive(Serialize)]
+struct CustomDomain {
+    #[serde(flatten)]
+    domain: ShopDomain,
+    record_name: String,
+    record_value: String,
+}
+impl CustomDomain {
+    fn new(domain: ShopDomain) -> Self {
+        CustomDomain {
+            record_name: domain_verification::record_name(&domain.domain),
+            record_value: domain_verification::record_value(&domain.verification_token),
+            domain,
+        }
+    }
+}
+
+pub async fn list_shop_domains(
+    db: web::Data<SqliteDB>,
+    user: UserCookie,
+    shop_domain: String,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &shop_domain).await {
+        return response;
+    }
+
+    match db.get_shop_domains(&shop_domain).await {
+        Ok(domains) => {
+            let domains: Vec<CustomDomain> = domains.into_iter().map(CustomDomain::new).collect();
+            HttpResponse::Ok().json(domains)
+        }
+        Err(err) => {
+            eprintln!("Error listing shop domains: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// New domains are only served after verification
+pub async fn add_shop_domain(
+    db: web::Data<SqliteDB>,
+    user: UserCookie,
+    shop_domain: String,
+    request: ShopDomainIn,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &shop_domain).await {
+        return response;
+    }
+    if let Err(response) = require_feature(&db, &shop_domain, PlanFeature::CustomDomains).await {
+        return response;
+    }
+    let domain = match request.validate() {
+        Ok(domain) => domain,
+        Err(message) => return HttpResponse::BadRequest().json(message),
+    };
+
+    // Platform subdomains belong to the shop slugs, shop domains to their shops
+    let platform_domain = normalize_host(&PLATFORM_DOMAIN);
+    if !platform_domain.is_empty() && domain.ends_with(&format!(".{}", platform_domain)) {
+        return HttpResponse::BadRequest().json("Subdomains of the platform use the shop slug");
+    }
+    match db.get_one_shop_domain(&domain).await {
+        Ok(_) => return HttpResponse::Conflict().json("This domain is already in use"),
+        Err(sqlx::Error::RowNotFound) => {}
+        Err(err) => {
+            eprintln!("Error getting shop: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match db
+        .create_shop_domain(&shop_domain, &domain, &session::random_hex(16))
+        .await
+    {
+        Ok(domain) => HttpResponse::Created().json(CustomDomain::new(domain)),
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+            HttpResponse::Conflict().json("This domain is already in use")
+        }
+        Err(err) => {
+            eprintln!("Error adding shop domain: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Look up the TXT record of the domain and start serving it when the token matches
+pub async fn verify_shop_domain(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    shop_domain: String,
+    domain: String,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &shop_domain).await {
+        return response;
+    }
+    let custom_domain = match db.get_shop_domain(&shop_domain, &domain).await {
+        Ok(Some(custom_domain)) => custom_domain,
+        Ok(None) => return HttpResponse::NotFound().json("Domain not found"),
+        Err(err) => {
+            eprintln!("Error getting shop domain: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match domain_verification::verify_domain(
+        &DNS_RESOLVER,
+        &custom_domain.domain,
+        &custom_domain.verification_token,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::BadRequest().json(format!(
+                "The TXT record {} was not found",
+                domain_verification::record_name(&custom_domain.domain)
+            ))
+        }
+        Err(err) => {
+            log::warn!("Verification lookup for {} failed: {}", domain, err);
+            return HttpResponse::BadGateway().json("The DNS lookup failed, try again later");
+        }
+    }
+
+    match db.verify_shop_domain(&shop_domain, &domain).await {
+        Ok(Some(custom_domain)) => {
+            shop_registry::shop_changed(&db, &redis, &shop_domain).await;
+            HttpResponse::Ok().json(CustomDomain::new(custom_domain))
+        }
+        Ok(None) => HttpResponse::NotFound().json("Domain not found"),
+        Err(err) => {
+            eprintln!("Error verifying shop domain: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Every other host of the shop redirects to the canonical domain, the shop
+// domain itself makes it canonical again
+pub async fn set_canonical_domain(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    shop_domain: String,
+    domain: String,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &shop_domain).await {
+        return response;
+    }
+    let canonical = (domain != shop_domain).then_some(domain.as_str());
+
+    match db.set_canonical_domain(&shop_domain, canonical).await {
+        Ok(true) => {
+            shop_registry::shop_changed(&db, &redis, &shop_domain).await;
+            HttpResponse::Ok().json("Canonical domain updated")
+        }
+        Ok(false) => HttpResponse::BadRequest().json("Only verified domains can be canonical"),
+        Err(err) => {
+            eprintln!("Error setting canonical domain: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn delete_shop_domain(
+    db: web::Data<SqliteDB>,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    shop_domain: String,
+    domain: String,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(&db, &user, &shop_domain).await {
+        return response;
+    }
+
+    match db.delete_shop_domain(&shop_domain, &domain).await {
+        Ok(true) => {
+            shop_registry::shop_changed(&db, &redis, &shop_domain).await;
+            HttpResponse::Ok().json("Domain removed")
+        }
+        Ok(false) => HttpResponse::NotFound().json("Domain not found"),
+        Err(err) => {
+            eprintln!("Error removing shop domain: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub mod ui {
+    use super::*;
+
+    pub async fn shops_page(db: web::Data<SqliteDB>, message: &str) -> HttpResponse {
+        let mut context = tera::Context::new();
+        context.insert("shops_msg", message);
+
+        match db.get_all_shop_domains().await {
+            Ok(shops) => context.insert("shops", &shops),
+            Err(err) => {
+                eprintln!("Error listing shops: {:?}", err);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+
+        match view::setup::TEMPLATES.render("pages/admin/shops.html", &context) {
+            Ok(content) => HttpResponse::Ok().body(content),
+            Err(err) => {
+                eprintln!("Error rendering shops page: {}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    pub async fn create_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        shop: ShopConfigIn,
+    ) -> HttpResponse {
+        let shop = match shop.validate() {
+            Ok(shop) => shop,
+            Err(message) => return shops_page(db, message).await,
+        };
+
+        match db.create_shop(&shop).await {
+            Ok(shop) => {
+                shop_registry::shop_changed(&db, &redis, &shop.domain).await;
+                shops_page(db, "The shop has been created").await
+            }
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() => {
+                shops_page(db, "A shop with this domain or slug already exists").await
+            }
+            Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => {
+                shops_page(db, "The owner does not exist").await
+            }
+            Err(err) => {
+                eprintln!("Error creating shop: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+
+    // HTMX swaps the row out, an empty body removes it
+    pub async fn delete_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        domain: String,
+    ) -> HttpResponse {
+        match db.delete_shop(&domain).await {
+            Ok(_) => {
+                shop_registry::shop_changed(&db, &redis, &domain).await;
+                HttpResponse::Ok().finish()
+            }
+            Err(err) => {
+                eprintln!("Error deleting shop: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    }
+}
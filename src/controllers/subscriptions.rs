@@ -0,0 +1,239 @@
+use crate::controllers::shops::owned_shop;
+use crate::db::sqlite::SqliteDB;
+use crate::domain::datatypes::UserCookie;
+use crate::domain::subscriptions::{PlanFeature, ShopPlan, ShopSubscription};
+use crate::modules::redis::RedisDB;
+use crate::modules::stripe::stripe::{Stripe, SubscriptionProrationBehavior};
+use crate::modules::stripe::subscriptions::{last_changed_at, mirror_subscription, plan_price};
+use actix_web::*;
+use serde::Serialize;
+
+// A plan as the owners choose it
+#[derive(Serialize)]
+struct PlanInfo {
+    plan: ShopPlan,
+    available: bool,
+    max_products: Option<i64>,
+    themes: bool,
+    custom_domains: bool,
+}
+impl PlanInfo {
+    fn new(plan: ShopPlan) -> Self {
+        PlanInfo {
+            plan,
+            available: plan == ShopPlan::Free || plan_price(plan).is_some(),
+            max_products: plan.max_products(),
+            themes: plan.allows(PlanFeature::Themes),
+            custom_domains: plan.allows(PlanFeature::CustomDomains),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SubscriptionInfo {
+    plan: ShopPlan,
+    subscription: Option<ShopSubscription>,
+    plans: Vec<PlanInfo>,
+}
+
+// 402 when the plan of the shop does not include the feature
+pub async fn require_feature(
+    db: &SqliteDB,
+    shop_domain: &str,
+    feature: PlanFeature,
+) -> Result<(), HttpResponse> {
+    match db.get_shop_plan(shop_domain).await {
+        Ok(plan) if plan.allows(feature) => Ok(()),
+        Ok(plan) => Err(HttpResponse::PaymentRequired().json(format!(
+            "The {} plan does not include {}",
+            plan.as_str(),
+            feature.as_str()
+        ))),
+        Err(err) => {
+            eprintln!("Error getting shop plan: {:?}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+// The running subscription of a shop, ended ones do not count
+async fn active_subscription(
+    db: &SqliteDB,
+    shop_domain: &str,
+) -> Result<Option<ShopSubscription>, HttpResponse> {
+    match db.get_shop_subscription(shop_domain).await {
+        Ok(subscription) => Ok(subscription.filter(|subscription| {
+            subscription.active_plan() != ShopPlan::Free
+                && subscription.stripe_subscription_id.is_some()
+        })),
+        Err(err) => {
+            eprintln!("Error getting subscription: {:?}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn get_subscription(db: &SqliteDB, user: &UserCookie, shop_domain: &str) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let subscription = match db.get_shop_subscription(shop_domain).await {
+        Ok(subscription) => subscription,
+        Err(err) => {
+            eprintln!("Error getting subscription: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    HttpResponse::Ok().json(SubscriptionInfo {
+        plan: subscription
+            .as_ref()
+            .map(|subscription| subscription.active_plan())
+            .unwrap_or(ShopPlan::Free),
+        subscription,
+        plans: ShopPlan::ALL.into_iter().map(PlanInfo::new).collect(),
+    })
+}
+
+// The first subscription is paid at Stripe Checkout, the webhooks store it
+pub async fn subscribe(
+    db: &SqliteDB,
+    user: &UserCookie,
+    shop_domain: &str,
+    plan: ShopPlan,
+    request: &HttpRequest,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let Some(price_id) = plan_price(plan) else {
+        return HttpResponse::BadRequest().json("This plan cannot be subscribed to");
+    };
+    let previous = match active_subscription(db, shop_domain).await {
+        Ok(None) => db.get_shop_subscription(shop_domain).await.ok().flatten(),
+        Ok(Some(_)) => {
+            return HttpResponse::Conflict()
+                .json("The shop already has a subscription, change its plan instead")
+        }
+        Err(response) => return response,
+    };
+
+    // Shops that were subscribed before stay the same Stripe customer
+    let customer_id = previous.and_then(|previous| previous.stripe_customer_id);
+    let email = db
+        .for_shop(&user.shop_domain)
+        .get_one_user(&user.user_id)
+        .await
+        .ok()
+        .map(|user| user.email);
+    let return_url = {
+        let connection_info = request.connection_info();
+        format!(
+            "{}://{}/account/shops/{}/subscription",
+            connection_info.scheme(),
+            connection_info.host(),
+            shop_domain
+        )
+    };
+
+    match Stripe::new()
+        .create_subscription_checkout(
+            shop_domain,
+            plan,
+            price_id,
+            customer_id.as_deref(),
+            email.as_deref(),
+            &return_url,
+        )
+        .await
+    {
+        Ok(session) => HttpResponse::Created().json(serde_json::json!({
+            "checkout_url": session.url.unwrap_or_default(),
+        })),
+        Err(err) => {
+            eprintln!("Error creating subscription checkout: {:?}", err);
+            HttpResponse::BadGateway().json("The payment provider is not available")
+        }
+    }
+}
+
+// Upgrades are charged right away, downgrades are credited on the next invoice
+pub async fn change_plan(
+    db: &SqliteDB,
+    redis: &RedisDB,
+    user: &UserCookie,
+    shop_domain: &str,
+    plan: ShopPlan,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let Some(price_id) = plan_price(plan) else {
+        return HttpResponse::BadRequest()
+            .json("Cancel the subscription to go back to the free plan");
+    };
+    let current = match active_subscription(db, shop_domain).await {
+        Ok(Some(current)) => current,
+        Ok(None) => return HttpResponse::NotFound().json("The shop has no subscription"),
+        Err(response) => return response,
+    };
+
+    let proration = match plan > current.plan {
+        true => SubscriptionProrationBehavior::AlwaysInvoice,
+        false => SubscriptionProrationBehavior::CreateProrations,
+    };
+    let subscription_id = current.stripe_subscription_id.unwrap_or_default();
+    let result = Stripe::new()
+        .change_subscription_plan(&subscription_id, price_id, proration)
+        .await;
+    mirror_response(db, redis, result).await
+}
+
+pub async fn cancel_subscription(
+    db: &SqliteDB,
+    redis: &RedisDB,
+    user: &UserCookie,
+    shop_domain: &str,
+    immediately: bool,
+) -> HttpResponse {
+    if let Err(response) = owned_shop(db, user, shop_domain).await {
+        return response;
+    }
+    let current = match active_subscription(db, shop_domain).await {
+        Ok(Some(current)) => current,
+        Ok(None) => return HttpResponse::NotFound().json("The shop has no subscription"),
+        Err(response) => return response,
+    };
+
+    let subscription_id = current.stripe_subscription_id.unwrap_or_default();
+    let result = Stripe::new()
+        .cancel_subscription(&subscription_id, immediately)
+        .await;
+    mirror_response(db, redis, result).await
+}
+
+// Store what Stripe answered right away, as of the last change Stripe records on the
+// subscription. Events Stripe sent since are newer, and the webhook that follows changes nothing.
+async fn mirror_response(
+    db: &SqliteDB,
+    redis: &RedisDB,
+    result: Result<stripe::Subscription, stripe::StripeError>,
+) -> HttpResponse {
+    let subscription = match result {
+        Ok(subscription) => subscription,
+        Err(err) => {
+            eprintln!("Error updating subscription: {:?}", err);
+            return HttpResponse::BadGateway().json("The payment provider is not available");
+        }
+    };
+
+    let synced_at = last_changed_at(&subscription);
+    match mirror_subscription(db, &redis.get_client(), &subscription, synced_at).await {
+        Ok(Some(subscription)) => HttpResponse::Ok().json(subscription),
+        Ok(None) => HttpResponse::InternalServerError().finish(),
+        Err(err) => {
+            eprintln!("Error saving subscription: {:?}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use crate::controllers::login::{complete_login, record_failed_attempt, TOO_MANY_ATTEMPTS_MSG};
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{
+    CookieVariations, LoginClient, Settings, TwoFactorCode, UserCookie, UserServer,
+};
+use crate::modules::cookie::generate_cookie;
+use crate::modules::login_throttle;
+use crate::modules::password_hash::Password;
+use crate::modules::redis::{RedisDB, RedisKeyNames};
+use crate::modules::token_pub::{self, TokenPurpose};
+use crate::modules::two_factor;
+use crate::view;
+use actix_web::*;
+
+// How long a scanned but unconfirmed secret is kept around
+const ENROLMENT_TTL_SECONDS: u64 = 10 * 60;
+
+// The password was correct, park the login until the second factor is checked
+pub fn start_second_step(user: &UserServer, redis: &RedisDB, remember: bool) -> HttpResponse {
+    match token_pub::generete_single_use_token(user, TokenPurpose::TwoFactor, redis) {
+        Ok(token) => {
+            let location = match remember {
+                true => "/login/two-factor?remember=true",
+                false => "/login/two-factor",
+            };
+
+            HttpResponse::SeeOther()
+                .append_header(("Location", location))
+                .cookie(generate_cookie(
+                    &CookieVariations::TwoFactor,
+                    Settings::new(token.as_str(), false),
+                ))
+                .finish()
+        }
+        Err(err) => {
+            eprintln!("Error generating two-factor token: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn render_second_step(remember: bool, failed_msg: &str) -> HttpResponse {
+    let mut context = tera::Context::new();
+    context.insert("two_factor_remember", &remember);
+    context.insert("two_factor_failed_msg", failed_msg);
+
+    match view::setup::TEMPLATES.render("pages/login/two_factor.html", &context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering two-factor page: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Check the code of the second login step and issue the auth cookies when it matches
+pub async fn verify_second_step(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    pending_token: Option<String>,
+    form: TwoFactorCode,
+    client: &LoginClient,
+) -> HttpResponse {
+    let to_login = HttpResponse::SeeOther()
+        .append_header(("Location", "/login"))
+        .cookie(CookieVariations::TwoFactor.remove_cookie())
+        .finish();
+    let remember = form.remember.unwrap_or(false);
+
+    let pending = pending_token.as_deref().and_then(|token| {
+        token_pub::verify_single_use_token(token, TokenPurpose::TwoFactor, &redis)
+    });
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return to_login,
+    };
+
+    let user = match db.get_one_user(&pending.user_id).await {
+        Ok(user) if user.active => user,
+        _ => return to_login,
+    };
+
+    // Codes are throttled together with passwords
+    match login_throttle::is_locked_out(&redis, db.shop_domain(), &user.username, &client.ip) {
+        Ok(false) => {}
+        Ok(true) => return render_second_step(remember, TOO_MANY_ATTEMPTS_MSG),
+        Err(err) => {
+            eprintln!("Error checking login lockout: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match check_second_factor(&db, &redis, &user.user_id, &form.code).await {
+        Ok(true) => {
+            // Only one request may finish the login with this token
+            let token = pending_token.unwrap_or_default();
+            if token_pub::consume_single_use_token(&token, TokenPurpose::TwoFactor, &redis)
+                .is_none()
+            {
+                return to_login;
+            }
+
+            let mut response = complete_login(&redis, &user, remember, client);
+            let _ = response.add_cookie(&CookieVariations::TwoFactor.remove_cookie());
+            response
+        }
+        Ok(false) => match record_failed_attempt(&db, &redis, &user.username, &client.ip).await {
+            Ok(false) => render_second_step(remember, "The code is incorrect"),
+            Ok(true) => render_second_step(remember, TOO_MANY_ATTEMPTS_MSG),
+            Err(err) => {
+                eprintln!("Error registering failed login: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Err(err) => {
+            eprintln!("Error checking two-factor code: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Accept a code from the authenticator app or one of the unused recovery codes
+async fn check_second_factor(
+    db: &TenantDB,
+    redis: &RedisDB,
+    user_id: &str,
+    code: &str,
+) -> Result<bool, String> {
+    let encrypted_secret = match db.get_two_factor_secret(user_id).await {
+        Ok(Some(encrypted_secret)) => encrypted_secret,
+        Ok(None) => return Ok(false),
+        Err(err) => return Err(err.to_string()),
+    };
+    let secret = two_factor::decrypt_secret(&encrypted_secret).map_err(|err| err.to_string())?;
+
+    if two_factor::verify_code(redis, user_id, &secret, code).map_err(|err| err.to_string())? {
+        return Ok(true);
+    }
+
+    let recovery_codes = db
+        .get_unused_recovery_codes(user_id)
+        .await
+        .map_err(|err| err.to_string())?;
+    for (code_id, hashed_code) in recovery_codes {
+        if let Ok(true) = Password::new(&hashed_code).verify_password(code.trim()) {
+            return db
+                .use_recovery_code(code_id)
+                .await
+                .map_err(|err| err.to_string());
+        }
+    }
+
+    Ok(false)
+}
+
+fn settings_context(enabled: bool) -> tera::Context {
+    let mut context = tera::Context::new();
+    context.insert("two_factor_enabled", &enabled);
+    context.insert("two_factor_qr", "");
+    context.insert("two_factor_secret", "");
+    context.insert("two_factor_recovery_codes", &Vec::<String>::new());
+    context.insert("two_factor_msg", "");
+    context.insert("two_factor_failed_msg", "");
+    context
+}
+
+fn render_settings(context: &tera::Context) -> HttpResponse {
+    match view::setup::TEMPLATES.render("pages/account/two_factor.html", context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering two-factor settings page: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub async fn settings_page(db: TenantDB, user: UserCookie) -> HttpResponse {
+    match db.get_two_factor_secret(&user.user_id).await {
+        Ok(secret) => render_settings(&settings_context(secret.is_some())),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Generate a secret and show it as QR code, it is only stored once a code from it is confirmed
+pub async fn start_enrolment(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+) -> HttpResponse {
+    let user = match db.get_one_user(&user.user_id).await {
+        Ok(user) => user,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let secret = two_factor::generate_secret();
+    let enrolment = two_factor::encrypt_secret(&secret).and_then(|encrypted_secret| {
+        let uri = two_factor::provisioning_uri(&secret, &user.email)?;
+        Ok((encrypted_secret, two_factor::qr_code_svg(&uri)?))
+    });
+    let (encrypted_secret, qr_code) = match enrolment {
+        Ok(enrolment) => enrolment,
+        Err(err) => {
+            eprintln!("Error starting two-factor enrolment: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let enrolment_key = RedisKeyNames::TwoFactorEnrolment.get_key(&user.user_id);
+    if let Err(err) = redis.set_value_ex(&enrolment_key, &encrypted_secret, ENROLMENT_TTL_SECONDS) {
+        eprintln!("Error storing two-factor enrolment: {}", err);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mut context = settings_context(false);
+    context.insert("two_factor_qr", &qr_code);
+    context.insert("two_factor_secret", &secret);
+    context.insert(
+        "two_factor_msg",
+        "Scan the QR code with your authenticator app and enter the code it shows",
+    );
+    render_settings(&context)
+}
+
+// Enable two-factor authentication once the user proves the app generates valid codes
+pub async fn confirm_enrolment(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    form: TwoFactorCode,
+) -> HttpResponse {
+    let enrolment_key = RedisKeyNames::TwoFactorEnrolment.get_key(&user.user_id);
+    let encrypted_secret = match redis.get_value::<Option<String>>(&enrolment_key) {
+        Ok(Some(encrypted_secret)) => encrypted_secret,
+        _ => {
+            let mut context = settings_context(false);
+            context.insert(
+                "two_factor_failed_msg",
+                "The enrolment has expired, please start again",
+            );
+            return render_settings(&context);
+        }
+    };
+
+    let verified = two_factor::decrypt_secret(&encrypted_secret)
+        .and_then(|secret| two_factor::verify_code(&redis, &user.user_id, &secret, &form.code));
+    match verified {
+        Ok(true) => {}
+        Ok(false) => {
+            let mut context = settings_context(false);
+            context.insert(
+                "two_factor_failed_msg",
+                "The code is incorrect, please start again",
+            );
+            return render_settings(&context);
+        }
+        Err(err) => {
+            eprintln!("Error confirming two-factor enrolment: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let (codes, hashed_codes) = match two_factor::generate_recovery_codes() {
+        Ok(codes) => codes,
+        Err(err) => {
+            eprintln!("Error generating recovery codes: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if let Err(err) = db
+        .enable_two_factor(&user.user_id, &encrypted_secret, &hashed_codes)
+        .await
+    {
+        eprintln!("Error enabling two-factor: {}", err);
+        return HttpResponse::InternalServerError().finish();
+    }
+    let _ = redis.delete_key(&enrolment_key);
+
+    // The recovery codes are only shown this one time
+    let mut context = settings_context(true);
+    context.insert("two_factor_recovery_codes", &codes);
+    context.insert(
+        "two_factor_msg",
+        "Two-factor authentication is enabled. Store these recovery codes somewhere safe, each can be used once.",
+    );
+    render_settings(&context)
+}
+
+// Turning two-factor authentication off needs a valid code as well,
+// throttled like the codes of the login
+pub async fn disable(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    user: UserCookie,
+    form: TwoFactorCode,
+    client: &LoginClient,
+) -> HttpResponse {
+    let too_many_attempts = || {
+        let mut context = settings_context(true);
+        context.insert("two_factor_failed_msg", TOO_MANY_ATTEMPTS_MSG);
+        render_settings(&context)
+    };
+    match login_throttle::is_locked_out(&redis, db.shop_domain(), &user.username, &client.ip) {
+        Ok(false) => {}
+        Ok(true) => return too_many_attempts(),
+        Err(err) => {
+            eprintln!("Error checking login lockout: {:?}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match check_second_factor(&db, &redis, &user.user_id, &form.code).await {
+        Ok(true) => match db.disable_two_factor(&user.user_id).await {
+            Ok(()) => {
+                let mut context = settings_context(false);
+                context.insert("two_factor_msg", "Two-factor authentication is disabled");
+                render_settings(&context)
+            }
+            Err(_) => HttpResponse::InternalServerError().finish(),
+        },
+        Ok(false) => match record_failed_attempt(&db, &redis, &user.username, &client.ip).await {
+            Ok(false) => {
+                let mut context = settings_context(true);
+                context.insert("two_factor_failed_msg", "The code is incorrect");
+                render_settings(&context)
+            }
+            Ok(true) => too_many_attempts(),
+            Err(err) => {
+                eprintln!("Error registering failed attempt: {:?}", err);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Err(err) => {
+            eprintln!("Error checking two-factor code: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
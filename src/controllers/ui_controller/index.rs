@@ -4,7 +4,7 @@ use crate::view::setup;
 use actix_web::*;
 
 pub mod index_ui_controller {
-    use self::db::sqlite::SqliteDB;
+    use self::db::tenant::TenantDB;
 
     use super::*;
 
@@ -37,7 +37,7 @@ pub mod index_ui_controller {
         HttpResponse::Ok().body(format!("POST User detail: {}", "New User"))
     }
 
-    pub async fn show_all_user_list(db: web::Data<SqliteDB>) -> HttpResponse {
+    pub async fn show_all_user_list(db: TenantDB) -> HttpResponse {
         let all_users = db.get_all_users().await;
 
         let mut context = tera::Context::new();
@@ -101,7 +101,7 @@ pub mod index_ui_controller {
         }
     }
 
-    pub async fn deleted_user_sqlite(user_id: String, db: web::Data<SqliteDB>) -> HttpResponse {
+    pub async fn deleted_user_sqlite(user_id: String, db: TenantDB) -> HttpResponse {
         match db.get_one_user(user_id.as_str()).await {
             Ok(the_user) => {
                 let deleted_user = db.delete_one_user(user_id.as_str()).await;
@@ -0,0 +1,46 @@
+use crate::domain::datatypes::UserServer;
+use crate::modules::email::{EmailSettings, EmailType};
+use crate::modules::redis::RedisDB;
+use crate::modules::token_pub::{self, TokenPurpose};
+use crate::view;
+use actix_web::*;
+
+// Send the verification link to the email address of the user
+pub async fn send_verification_email(user: &UserServer, redis: &RedisDB, domain: String) -> bool {
+    let token = match token_pub::generete_single_use_token(user, TokenPurpose::Verification, redis)
+    {
+        Ok(token) => token,
+        Err(err) => {
+            eprintln!("Error generating verification token: {}", err);
+            return false;
+        }
+    };
+
+    let settings = crate::utils::constants::get_email_settings();
+    let email_settings = EmailSettings::user_verification_reset_template(
+        user.email.to_string(),
+        settings.email,
+        domain,
+        token,
+    );
+
+    EmailType::UserVerification
+        .send_email(&email_settings)
+        .await
+        .is_ok()
+}
+
+pub fn render_verify_page(message: &str, resend: bool, failed_message: &str) -> HttpResponse {
+    let mut context = tera::Context::new();
+    context.insert("verify_msg", message);
+    context.insert("verify_resend", &resend);
+    context.insert("verify_failed_msg", failed_message);
+
+    match view::setup::TEMPLATES.render("pages/verify/verify.html", &context) {
+        Ok(content) => HttpResponse::Ok().body(content),
+        Err(err) => {
+            eprintln!("Error rendering verify page: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
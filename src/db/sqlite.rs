@@ -1,7 +1,22 @@
 use actix_web::*;
-use sqlx::{self, sqlite::SqlitePoolOptions, Pool, Sqlite};
+use sqlx::{
+    self,
+    query::QueryAs,
+    sqlite::{SqliteArguments, SqlitePoolOptions, SqliteRow},
+    FromRow, Pool, Sqlite,
+};
+use std::collections::HashMap;
 
-use crate::domain::{datatypes::UserServer, shops::ShopConfig};
+use crate::domain::{
+    connect_accounts::{ConnectAccount, PaymentDestination},
+    datatypes::{ApiKey, ApiKeyWithOwner},
+    disputes::OrderDispute,
+    inventory::MovementReason,
+    orders::{Order, OrderDetails, OrderItem, OrderStatus},
+    shops::{ShopConfig, ShopDomain, ShopTheme},
+    stripe_events::{StripeEvent, StripeEventStatus},
+    subscriptions::{ShopPlan, ShopSubscription},
+};
 use crate::models::queries;
 
 #[derive(Debug, thiserror::Error)]
@@ -10,6 +25,18 @@ pub enum DbError {
     DatabaseError(#[from] sqlx::Error),
 }
 
+// Writes with RETURNING are read to the end. Dropping the statement after the first row
+// leaves the commit to the database worker, so another connection could still read the old row.
+pub(crate) async fn fetch_returning<'q, T>(
+    query: QueryAs<'q, Sqlite, T, SqliteArguments<'q>>,
+    pool: &Pool<Sqlite>,
+) -> Result<Option<T>, sqlx::Error>
+where
+    T: Send + Unpin + for<'r> FromRow<'r, SqliteRow>,
+{
+    Ok(query.fetch_all(pool).await?.pop())
+}
+
 #[derive(Debug, Clone)]
 pub struct SqliteDB {
     pub db: Pool<Sqlite>,
@@ -42,187 +69,633 @@ impl SqliteDB {
         return self.db.clone();
     }
 
-    pub async fn get_one_user(&self, user_id: &str) -> Result<UserServer, sqlx::Error> {
-        // Use the enum to get the query string
-        let sql = queries::UserQueries::GetOneUser.convert_to_str();
+    // GET One API Key by its prefix, of any shop: the caller checks the shop
+    pub async fn get_api_key_by_prefix(&self, prefix: &str) -> Result<Option<ApiKey>, sqlx::Error> {
+        let sql = queries::ApiKeyQueries::GetByPrefix.convert_to_str();
 
-        // Execute the query and return the result
-        let result = sqlx::query_as::<_, UserServer>(sql)
-            .bind(user_id)
-            .fetch_one(&self.db)
+        return sqlx::query_as::<_, ApiKey>(sql)
+            .bind(prefix)
+            .fetch_optional(&self.db)
             .await;
-
-        println!("Query result: {:?}", result); // Debug print the result or error
-        result
     }
 
-    // GET One User with Username
-    pub async fn get_one_user_username(
+    // GET One API Key with its owner, checked on every use
+    pub async fn get_api_key_with_owner(
         &self,
-        username: &str,
-    ) -> Result<Option<UserServer>, sqlx::Error> {
-        // SQL query select one user from the database using id
-        let sql = queries::UserQueries::GetOneUserWithUsername.convert_to_str();
+        prefix: &str,
+    ) -> Result<Option<ApiKeyWithOwner>, sqlx::Error> {
+        let sql = queries::ApiKeyQueries::GetWithOwnerByPrefix.convert_to_str();
 
-        return sqlx::query_as::<_, UserServer>(sql)
-            .bind(username)
+        return sqlx::query_as::<_, ApiKeyWithOwner>(sql)
+            .bind(prefix)
             .fetch_optional(&self.db)
             .await;
     }
 
-    // GET All Users
-    pub async fn get_all_users(&self) -> Result<Vec<UserServer>, sqlx::Error> {
+    // PUT Last Used timestamp of One API Key
+    pub async fn touch_api_key(&self, key_id: i64) -> Result<(), sqlx::Error> {
+        let sql = queries::ApiKeyQueries::TouchLastUsed.convert_to_str();
+
+        sqlx::query(sql).bind(key_id).execute(&self.db).await?;
+        Ok(())
+    }
+
+    // GET All Shop Domains
+    pub async fn get_all_shop_domains(&self) -> Result<Vec<ShopConfig>, sqlx::Error> {
         // SQL query select one user from the database using id
-        let sql = queries::UserQueries::GetAllUsers.convert_to_str();
+        let sql = queries::ShopQueries::GetAllShops.convert_to_str();
 
-        return sqlx::query_as::<_, UserServer>(sql)
+        return sqlx::query_as::<_, ShopConfig>(sql)
             .fetch_all(&self.db)
             .await;
     }
+    // GET One Shop Domain
+    pub async fn get_one_shop_domain(&self, shop: &str) -> Result<ShopConfig, sqlx::Error> {
+        // SQL query select one user from the database using id
+        let sql = queries::ShopQueries::GetOneShop.convert_to_str();
+
+        return sqlx::query_as::<_, ShopConfig>(sql)
+            .bind(shop)
+            .fetch_one(&self.db)
+            .await;
+    }
 
-    // POST One User
-    pub async fn create_one_user(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
-        // SQL query to insert the user into the database and return the inserted user
-        let sql = queries::UserQueries::CreateOneUser.convert_to_str();
+    // GET Shops owned by One User
+    pub async fn get_user_shops(&self, user_id: &str) -> Result<Vec<ShopConfig>, sqlx::Error> {
+        let sql = queries::ShopQueries::GetUserShops.convert_to_str();
 
-        match sqlx::query(sql)
-            .bind(&user.user_id)
-            .bind(&user.username)
-            .bind(&user.hashed_password)
-            .bind(&user.active)
-            .execute(&self.db)
+        return sqlx::query_as::<_, ShopConfig>(sql)
+            .bind(user_id)
+            .fetch_all(&self.db)
+            .await;
+    }
+
+    // POST One Shop
+    pub async fn create_shop(&self, shop: &ShopConfig) -> Result<ShopConfig, sqlx::Error> {
+        let sql = queries::ShopQueries::CreateOneShop.convert_to_str();
+
+        let query = sqlx::query_as::<_, ShopConfig>(sql)
+            .bind(&shop.domain)
+            .bind(&shop.name)
+            .bind(&shop.product_type)
+            .bind(&shop.user_id)
+            .bind(&shop.slug);
+        match fetch_returning(query, &self.db)
             .await
+            .and_then(|shop| shop.ok_or(sqlx::Error::RowNotFound))
         {
-            Ok(_) => {
-                // Await the result of get_one_user before returning it
-                match self.get_one_user(&user.user_id).await {
-                    Ok(created_user) => Ok(created_user),
-                    Err(err) => {
-                        eprintln!("Error retrieving created user: {:?}", err);
-                        Err(err)
-                    }
-                }
-            }
+            Ok(shop) => Ok(shop),
             Err(err) => {
-                eprintln!("Error creating user: {:?}", err);
+                eprintln!("Error creating shop: {:?}", err);
                 Err(err)
             }
         }
     }
 
-    // PUT One User
-    pub async fn update_one_user(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
-        // SQL query to insert the user into the database and return the inserted user
-        let sql = queries::UserQueries::UpdateOneUser.convert_to_str();
+    // PUT One Shop, the domain is the key and cannot change
+    pub async fn update_shop(&self, shop: &ShopConfig) -> Result<Option<ShopConfig>, sqlx::Error> {
+        let sql = queries::ShopQueries::UpdateOneShop.convert_to_str();
 
-        match sqlx::query(sql)
-            .bind(&user.username)
-            .bind(&user.hashed_password)
-            .bind(&user.active)
-            .bind(&user.user_id)
-            .execute(&self.db)
-            .await
-        {
-            Ok(_) => {
-                // Await the result of get_one_user before returning it
-                return self.get_one_user(&user.user_id).await;
-            }
-            Err(err) => {
-                eprintln!("Error updating user: {:?}", err);
-                Err(err)
+        let query = sqlx::query_as::<_, ShopConfig>(sql)
+            .bind(&shop.name)
+            .bind(&shop.product_type)
+            .bind(&shop.user_id)
+            .bind(&shop.slug)
+            .bind(&shop.domain);
+        return fetch_returning(query, &self.db).await;
+    }
+
+    // PUT Theme of One Shop
+    pub async fn update_shop_theme(
+        &self,
+        domain: &str,
+        theme: &ShopTheme,
+    ) -> Result<Option<ShopConfig>, sqlx::Error> {
+        let sql = queries::ShopQueries::UpdateShopTheme.convert_to_str();
+
+        let query = sqlx::query_as::<_, ShopConfig>(sql)
+            .bind(&theme.primary_color)
+            .bind(&theme.background_color)
+            .bind(&theme.logo_url)
+            .bind(domain);
+        return fetch_returning(query, &self.db).await;
+    }
+
+    // DELETE One Shop
+    pub async fn delete_shop(&self, domain: &str) -> Result<bool, sqlx::Error> {
+        let sql = queries::ShopQueries::DeleteOneShop.convert_to_str();
+
+        let result = sqlx::query(sql).bind(domain).execute(&self.db).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // GET Verified Custom Domains of every shop
+    pub async fn get_verified_domains(&self) -> Result<Vec<ShopDomain>, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::GetVerifiedDomains.convert_to_str();
+
+        return sqlx::query_as::<_, ShopDomain>(sql)
+            .fetch_all(&self.db)
+            .await;
+    }
+
+    // GET Custom Domains of One Shop
+    pub async fn get_shop_domains(
+        &self,
+        shop_domain: &str,
+    ) -> Result<Vec<ShopDomain>, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::GetShopDomains.convert_to_str();
+
+        return sqlx::query_as::<_, ShopDomain>(sql)
+            .bind(shop_domain)
+            .fetch_all(&self.db)
+            .await;
+    }
+
+    // GET One Custom Domain of a shop
+    pub async fn get_shop_domain(
+        &self,
+        shop_domain: &str,
+        domain: &str,
+    ) -> Result<Option<ShopDomain>, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::GetOneDomain.convert_to_str();
+
+        return sqlx::query_as::<_, ShopDomain>(sql)
+            .bind(domain)
+            .bind(shop_domain)
+            .fetch_optional(&self.db)
+            .await;
+    }
+
+    // POST One Custom Domain, unverified until its TXT record is checked
+    pub async fn create_shop_domain(
+        &self,
+        shop_domain: &str,
+        domain: &str,
+        verification_token: &str,
+    ) -> Result<ShopDomain, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::CreateDomain.convert_to_str();
+
+        let query = sqlx::query_as::<_, ShopDomain>(sql)
+            .bind(domain)
+            .bind(shop_domain)
+            .bind(verification_token);
+        fetch_returning(query, &self.db)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // PUT Verified Custom Domain
+    pub async fn verify_shop_domain(
+        &self,
+        shop_domain: &str,
+        domain: &str,
+    ) -> Result<Option<ShopDomain>, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::VerifyDomain.convert_to_str();
+
+        let query = sqlx::query_as::<_, ShopDomain>(sql)
+            .bind(domain)
+            .bind(shop_domain);
+        return fetch_returning(query, &self.db).await;
+    }
+
+    // PUT Canonical Domain, None goes back to the shop domain itself.
+    // False when the domain is unknown or not verified.
+    pub async fn set_canonical_domain(
+        &self,
+        shop_domain: &str,
+        domain: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut txn = self.db.begin().await?;
+
+        sqlx::query(queries::ShopDomainQueries::ClearCanonical.convert_to_str())
+            .bind(shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        if let Some(domain) = domain {
+            let result = sqlx::query(queries::ShopDomainQueries::SetCanonical.convert_to_str())
+                .bind(domain)
+                .bind(shop_domain)
+                .execute(&mut *txn)
+                .await?;
+            if result.rows_affected() != 1 {
+                txn.rollback().await?;
+                return Ok(false);
             }
         }
+
+        txn.commit().await?;
+        Ok(true)
     }
 
-    // PUT One User Password
-    pub async fn update_one_user_password(
+    // DELETE One Custom Domain
+    pub async fn delete_shop_domain(
         &self,
-        user: &UserServer,
-    ) -> Result<UserServer, sqlx::Error> {
-        // SQL query to insert the user into the database and return the inserted user
-        let sql = queries::UserQueries::UpdateOneUserPwd.convert_to_str();
+        shop_domain: &str,
+        domain: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::ShopDomainQueries::DeleteDomain.convert_to_str();
 
-        match sqlx::query(sql)
-            .bind(&user.hashed_password)
-            .bind(&user.user_id)
+        let result = sqlx::query(sql)
+            .bind(domain)
+            .bind(shop_domain)
             .execute(&self.db)
-            .await
-        {
-            Ok(_) => {
-                // Await the result of get_one_user before returning it
-                return self.get_one_user(user.user_id.as_str()).await;
-            }
-            Err(err) => {
-                eprintln!("Error updating user: {:?}", err);
-                Err(err)
-            }
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // PUT Release the Stock Reservations of a checkout session that ended unpaid.
+    // Webhooks know the session but not the shop, so this works across shops.
+    pub async fn release_reservations(
+        &self,
+        checkout_session_id: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let sql = queries::StockQueries::ReleaseSession.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(checkout_session_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // PUT Complete the Stock Reservations of a paid checkout session: the reserved
+    // quantities leave the stock and are recorded as sales. Doing it twice changes nothing.
+    pub async fn complete_reservations(
+        &self,
+        checkout_session_id: &str,
+    ) -> Result<u64, sqlx::Error> {
+        let mut txn = self.db.begin().await?;
+
+        let reservations = sqlx::query_as::<_, (i64, String, i64)>(
+            queries::StockQueries::CompleteSession.convert_to_str(),
+        )
+        .bind(checkout_session_id)
+        .fetch_all(&mut *txn)
+        .await?;
+
+        for (variant_id, shop_domain, quantity) in &reservations {
+            sqlx::query(queries::StockQueries::Sell.convert_to_str())
+                .bind(quantity)
+                .bind(variant_id)
+                .bind(shop_domain)
+                .execute(&mut *txn)
+                .await?;
+            sqlx::query(queries::StockQueries::CreateMovement.convert_to_str())
+                .bind(variant_id)
+                .bind(shop_domain)
+                .bind(-quantity)
+                .bind(MovementReason::Sale.as_str())
+                .bind(checkout_session_id)
+                .bind(None::<String>)
+                .execute(&mut *txn)
+                .await?;
         }
+
+        txn.commit().await?;
+        Ok(reservations.len() as u64)
     }
 
-    // DELETE One User
-    pub async fn delete_one_user(&self, user_id: &str) -> Result<String, sqlx::Error> {
-        let sql = queries::UserQueries::DeleteOneUser.convert_to_str();
+    // GET The Order of a Checkout Session, for webhooks that do not know the shop
+    pub async fn get_order_by_session(
+        &self,
+        checkout_session_id: &str,
+    ) -> Result<Option<Order>, sqlx::Error> {
+        let sql = queries::OrderQueries::GetBySession.convert_to_str();
 
-        match sqlx::query(sql).bind(user_id).execute(&self.db).await {
-            Ok(_) => return Ok("succesfully".to_string()),
-            Err(err) => {
-                eprintln!("Error deleting user: {:?}", err);
-                return Err(err);
-            }
+        return sqlx::query_as::<_, Order>(sql)
+            .bind(checkout_session_id)
+            .fetch_optional(&self.db)
+            .await;
+    }
+
+    // GET An Order with its lines
+    pub async fn order_details(&self, order: Order) -> Result<OrderDetails, sqlx::Error> {
+        let sql = queries::OrderQueries::GetItems.convert_to_str();
+        let items = sqlx::query_as::<_, OrderItem>(sql)
+            .bind(order.order_id)
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(OrderDetails { order, items })
+    }
+
+    // PUT The Status of an Order. False when the move is not allowed
+    // or another request changed the order first.
+    pub async fn update_order_status(
+        &self,
+        order: &Order,
+        status: OrderStatus,
+        payment_intent_id: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        if !order.status.can_become(status) {
+            return Ok(false);
         }
+        let sql = queries::OrderQueries::UpdateStatus.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(status)
+            .bind(payment_intent_id)
+            .bind(order.order_id)
+            .bind(order.status)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
     }
 
-    // Transaction
-    pub async fn transaction(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
-        // Start a new transaction
-        let mut txn = self.db.begin().await?;
+    // GET The Order paid by a Payment Intent, whatever shop it belongs to
+    pub async fn get_order_by_payment_intent(
+        &self,
+        payment_intent_id: &str,
+    ) -> Result<Option<Order>, sqlx::Error> {
+        let sql = queries::OrderQueries::GetByPaymentIntent.convert_to_str();
+
+        return sqlx::query_as::<_, Order>(sql)
+            .bind(payment_intent_id)
+            .fetch_optional(&self.db)
+            .await;
+    }
 
-        // Define SQL queries
-        let create_sql = queries::UserQueries::CreateOneUser.convert_to_str();
-        let update_sql = queries::UserQueries::UpdateOneUser.convert_to_str();
+    // PUT How much of an Order was paid back so far, the whole total refunds it.
+    // False when as much or more was already stored.
+    pub async fn set_order_refunded(
+        &self,
+        order_id: i64,
+        refunded_amount: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::OrderQueries::SetRefunded.convert_to_str();
 
-        // Execute the first query to create a new user
-        sqlx::query(create_sql)
-            .bind(&user.user_id)
-            .bind(&user.username)
-            .bind(&user.hashed_password)
-            .bind(&user.active)
-            .execute(&mut *txn)
+        let result = sqlx::query(sql)
+            .bind(refunded_amount)
+            .bind(refunded_amount)
+            .bind(order_id)
+            .bind(refunded_amount)
+            .execute(&self.db)
             .await?;
+        Ok(result.rows_affected() == 1)
+    }
 
-        // Execute the second query to update the user
-        sqlx::query(update_sql)
-            .bind("TXN_Username")
-            .bind("TXN_Password")
-            .bind(false)
-            .bind(&user.user_id)
-            .execute(&mut *txn)
+    // PUT How much of an Order lost disputes took back, from its stored disputes.
+    // False when that did not change.
+    pub async fn set_order_disputed(&self, order_id: i64) -> Result<bool, sqlx::Error> {
+        let sql = queries::OrderQueries::SetDisputed.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(order_id)
+            .bind(order_id)
+            .execute(&self.db)
             .await?;
+        Ok(result.rows_affected() == 1)
+    }
 
-        // Commit the transaction
-        txn.commit().await?;
+    // PUT One Dispute of an Order. False when newer data was already stored.
+    pub async fn upsert_order_dispute(&self, dispute: &OrderDispute) -> Result<bool, sqlx::Error> {
+        let sql = queries::DisputeQueries::Upsert.convert_to_str();
 
-        // Fetch the updated user from the database
-        return self.get_one_user(&user.user_id).await;
+        let result = sqlx::query(sql)
+            .bind(&dispute.dispute_id)
+            .bind(&dispute.shop_domain)
+            .bind(dispute.order_id)
+            .bind(dispute.amount)
+            .bind(&dispute.currency)
+            .bind(&dispute.reason)
+            .bind(&dispute.status)
+            .bind(dispute.evidence_due_by)
+            .bind(dispute.synced_at)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
     }
 
-    // GET All Shop Domains
-    pub async fn get_all_shop_domains(&self) -> Result<Vec<ShopConfig>, sqlx::Error> {
-        // SQL query select one user from the database using id
-        let sql = queries::ShopQueries::GetAllShops.convert_to_str();
+    // POST One Stripe Event. False when Stripe already delivered it
+    pub async fn create_stripe_event(
+        &self,
+        event_id: &str,
+        event_type: &str,
+        payload: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::StripeEventQueries::CreateOne.convert_to_str();
 
-        return sqlx::query_as::<_, ShopConfig>(sql)
+        let result = sqlx::query(sql)
+            .bind(event_id)
+            .bind(event_type)
+            .bind(payload)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // GET The Stripe Events waiting to be processed, oldest first
+    pub async fn get_due_stripe_events(&self, limit: i64) -> Result<Vec<StripeEvent>, sqlx::Error> {
+        let sql = queries::StripeEventQueries::GetDue.convert_to_str();
+
+        return sqlx::query_as::<_, StripeEvent>(sql)
+            .bind(limit)
             .fetch_all(&self.db)
             .await;
     }
-    // GET One Shop Domain
-    pub async fn get_one_shop_domain(&self, shop: &str) -> Result<ShopConfig, sqlx::Error> {
-        // SQL query select one user from the database using id
-        let sql = queries::ShopQueries::GetOneShop.convert_to_str();
 
-        return sqlx::query_as::<_, ShopConfig>(sql)
-            .bind(shop)
-            .fetch_one(&self.db)
+    // PUT Claim a Stripe Event for one attempt. The next attempt is planned right away,
+    // so other workers leave the event alone. False when another worker was first.
+    pub async fn claim_stripe_event(
+        &self,
+        event: &StripeEvent,
+        retry_delay_seconds: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::StripeEventQueries::Claim.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(format!("+{} seconds", retry_delay_seconds))
+            .bind(&event.event_id)
+            .bind(event.attempts)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // PUT A Stripe Event as processed
+    pub async fn set_stripe_event_processed(&self, event_id: &str) -> Result<(), sqlx::Error> {
+        let sql = queries::StripeEventQueries::SetProcessed.convert_to_str();
+
+        sqlx::query(sql).bind(event_id).execute(&self.db).await?;
+        Ok(())
+    }
+
+    // PUT The Error of a failed attempt, the event is given up after the last one
+    pub async fn set_stripe_event_failed(
+        &self,
+        event_id: &str,
+        error: &str,
+        give_up: bool,
+    ) -> Result<(), sqlx::Error> {
+        let sql = queries::StripeEventQueries::SetFailed.convert_to_str();
+        let status = match give_up {
+            true => StripeEventStatus::Failed,
+            false => StripeEventStatus::Pending,
+        };
+
+        sqlx::query(sql)
+            .bind(status)
+            .bind(error)
+            .bind(event_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    // PUT Failed Stripe Events back in the queue, all of them or only one
+    pub async fn replay_stripe_events(&self, event_id: Option<&str>) -> Result<u64, sqlx::Error> {
+        let result = match event_id {
+            Some(event_id) => {
+                sqlx::query(queries::StripeEventQueries::ReplayOne.convert_to_str())
+                    .bind(event_id)
+                    .execute(&self.db)
+                    .await?
+            }
+            None => {
+                sqlx::query(queries::StripeEventQueries::ReplayFailed.convert_to_str())
+                    .execute(&self.db)
+                    .await?
+            }
+        };
+        Ok(result.rows_affected())
+    }
+
+    // GET The Subscription of a Shop
+    pub async fn get_shop_subscription(
+        &self,
+        shop_domain: &str,
+    ) -> Result<Option<ShopSubscription>, sqlx::Error> {
+        let sql = queries::SubscriptionQueries::GetOne.convert_to_str();
+
+        return sqlx::query_as::<_, ShopSubscription>(sql)
+            .bind(shop_domain)
+            .fetch_optional(&self.db)
+            .await;
+    }
+
+    // GET The Plan a Shop is on. Shops without an owner are run by the platform and get everything.
+    pub async fn get_shop_plan(&self, shop_domain: &str) -> Result<ShopPlan, sqlx::Error> {
+        let sql = queries::SubscriptionQueries::GetPlan.convert_to_str();
+
+        let row = sqlx::query_as::<_, (bool, Option<ShopPlan>, Option<String>)>(sql)
+            .bind(shop_domain)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(match row {
+            Some((platform_shop, plan, status)) => {
+                ShopPlan::for_shop(platform_shop, plan, status.as_deref())
+            }
+            None => ShopPlan::Free,
+        })
+    }
+
+    // GET The Plan every Shop is on, by shop domain
+    pub async fn get_shop_plans(&self) -> Result<HashMap<String, ShopPlan>, sqlx::Error> {
+        let sql = queries::SubscriptionQueries::GetAllPlans.convert_to_str();
+
+        let rows = sqlx::query_as::<_, (String, bool, Option<ShopPlan>, Option<String>)>(sql)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(domain, platform_shop, plan, status)| {
+                let plan = ShopPlan::for_shop(platform_shop, plan, status.as_deref());
+                (domain, plan)
+            })
+            .collect())
+    }
+
+    // PUT The Subscription of a Shop. False when newer data was already stored.
+    pub async fn upsert_shop_subscription(
+        &self,
+        subscription: &ShopSubscription,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::SubscriptionQueries::Upsert.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(&subscription.shop_domain)
+            .bind(subscription.plan)
+            .bind(&subscription.status)
+            .bind(&subscription.stripe_customer_id)
+            .bind(&subscription.stripe_subscription_id)
+            .bind(subscription.current_period_end)
+            .bind(subscription.cancel_at_period_end)
+            .bind(subscription.synced_at)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // GET The Connect Account of a Shop
+    pub async fn get_connect_account(
+        &self,
+        shop_domain: &str,
+    ) -> Result<Option<ConnectAccount>, sqlx::Error> {
+        let sql = queries::ConnectAccountQueries::GetOne.convert_to_str();
+
+        return sqlx::query_as::<_, ConnectAccount>(sql)
+            .bind(shop_domain)
+            .fetch_optional(&self.db)
             .await;
     }
+
+    // GET Where the payments of a Shop go
+    pub async fn get_payment_destination(
+        &self,
+        shop_domain: &str,
+    ) -> Result<PaymentDestination, sqlx::Error> {
+        let sql = queries::ConnectAccountQueries::GetDestination.convert_to_str();
+
+        let row = sqlx::query_as::<_, (bool, Option<String>, Option<bool>)>(sql)
+            .bind(shop_domain)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(match row {
+            Some((true, _, _)) => PaymentDestination::Platform,
+            Some((false, Some(account_id), Some(true))) => PaymentDestination::Account(account_id),
+            _ => PaymentDestination::Unavailable,
+        })
+    }
+
+    // POST The Connect Account of a Shop, returns the account the shop ends up with
+    pub async fn create_connect_account(
+        &self,
+        shop_domain: &str,
+        stripe_account_id: &str,
+    ) -> Result<ConnectAccount, sqlx::Error> {
+        let sql = queries::ConnectAccountQueries::CreateOne.convert_to_str();
+
+        sqlx::query(sql)
+            .bind(shop_domain)
+            .bind(stripe_account_id)
+            .execute(&self.db)
+            .await?;
+        self.get_connect_account(shop_domain)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // PUT The Status of a Connect Account. False for unknown accounts and older data.
+    pub async fn update_connect_account_status(
+        &self,
+        stripe_account_id: &str,
+        charges_enabled: bool,
+        payouts_enabled: bool,
+        details_submitted: bool,
+        synced_at: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::ConnectAccountQueries::UpdateStatus.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(charges_enabled)
+            .bind(payouts_enabled)
+            .bind(details_submitted)
+            .bind(synced_at)
+            .bind(stripe_account_id)
+            .bind(synced_at)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
 }
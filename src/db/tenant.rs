@@ -0,0 +1,1378 @@
+use actix_web::{dev::Payload, error, web, FromRequest, HttpMessage, HttpRequest};
+use chrono::NaiveDateTime;
+use std::future::{ready, Ready};
+
+use crate::db::sqlite::{fetch_returning, SqliteDB};
+use crate::domain::cart::PricedCart;
+use crate::domain::connect_accounts::PaymentDestination;
+use crate::domain::datatypes::{ApiKey, PageQuery, UserServer};
+use crate::domain::disputes::OrderDispute;
+use crate::domain::inventory::{NewVariant, StockChangeIn, StockError, StockMovement, Variant};
+use crate::domain::orders::{Order, OrderDetails, OrderStatus};
+use crate::domain::products::{NewProduct, Product};
+use crate::domain::subscriptions::ShopPlan;
+use crate::models::queries;
+use crate::modules::middleware_domain::Shop;
+
+// The database as seen by one shop. Every query on tenant data is limited to that shop,
+// tables shared by all shops are reached through SqliteDB.
+#[derive(Debug, Clone)]
+pub struct TenantDB {
+    database: SqliteDB,
+    shop_domain: String,
+}
+
+impl SqliteDB {
+    // Outside of a request, e.g. in jobs, the shop is named explicitly
+    pub fn for_shop(&self, shop_domain: &str) -> TenantDB {
+        TenantDB {
+            database: self.clone(),
+            shop_domain: shop_domain.to_string(),
+        }
+    }
+}
+
+// Handlers get the database of the shop AddShopDomain resolved for the request
+impl FromRequest for TenantDB {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let database = request.app_data::<web::Data<SqliteDB>>();
+        let shop = request.extensions().get::<Shop>().cloned();
+
+        ready(match (database, shop) {
+            (Some(database), Some(shop)) => Ok(database.for_shop(&shop.domain)),
+            (None, _) => Err(error::ErrorInternalServerError("No database connection")),
+            (_, None) => Err(error::ErrorNotFound("Unknown shop")),
+        })
+    }
+}
+
+impl TenantDB {
+    pub fn shop_domain(&self) -> &str {
+        &self.shop_domain
+    }
+
+    // GET One User
+    pub async fn get_one_user(&self, user_id: &str) -> Result<UserServer, sqlx::Error> {
+        let sql = queries::UserQueries::GetOneUser.convert_to_str();
+
+        return sqlx::query_as::<_, UserServer>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_one(&self.database.db)
+            .await;
+    }
+
+    // GET One User with Username
+    pub async fn get_one_user_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<UserServer>, sqlx::Error> {
+        let sql = queries::UserQueries::GetOneUserWithUsername.convert_to_str();
+
+        return sqlx::query_as::<_, UserServer>(sql)
+            .bind(username)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // GET One User with Email
+    pub async fn get_one_user_email(&self, email: &str) -> Result<Option<UserServer>, sqlx::Error> {
+        let sql = queries::UserQueries::GetOneUserWithEmail.convert_to_str();
+
+        return sqlx::query_as::<_, UserServer>(sql)
+            .bind(email)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // GET All Users
+    pub async fn get_all_users(&self) -> Result<Vec<UserServer>, sqlx::Error> {
+        let sql = queries::UserQueries::GetAllUsers.convert_to_str();
+
+        return sqlx::query_as::<_, UserServer>(sql)
+            .bind(&self.shop_domain)
+            .fetch_all(&self.database.db)
+            .await;
+    }
+
+    // POST One User, always in the shop of this TenantDB
+    pub async fn create_one_user(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
+        let sql = queries::UserQueries::CreateOneUser.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(&user.user_id)
+            .bind(&user.username)
+            .bind(&user.email)
+            .bind(&user.hashed_password)
+            .bind(user.active)
+            .bind(&user.role)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => match self.get_one_user(&user.user_id).await {
+                Ok(created_user) => Ok(created_user),
+                Err(err) => {
+                    eprintln!("Error retrieving created user: {:?}", err);
+                    Err(err)
+                }
+            },
+            Err(err) => {
+                eprintln!("Error creating user: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // PUT One User
+    pub async fn update_one_user(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
+        let sql = queries::UserQueries::UpdateOneUser.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(&user.username)
+            .bind(&user.email)
+            .bind(&user.hashed_password)
+            .bind(user.active)
+            .bind(&user.role)
+            .bind(&user.user_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => return self.get_one_user(&user.user_id).await,
+            Err(err) => {
+                eprintln!("Error updating user: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // PUT One User Verified
+    pub async fn verify_one_user(&self, user_id: &str) -> Result<UserServer, sqlx::Error> {
+        let sql = queries::UserQueries::VerifyOneUser.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => return self.get_one_user(user_id).await,
+            Err(err) => {
+                eprintln!("Error verifying user: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // PUT One User Password
+    pub async fn update_one_user_password(
+        &self,
+        user: &UserServer,
+    ) -> Result<UserServer, sqlx::Error> {
+        let sql = queries::UserQueries::UpdateOneUserPwd.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(&user.hashed_password)
+            .bind(&user.user_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => return self.get_one_user(user.user_id.as_str()).await,
+            Err(err) => {
+                eprintln!("Error updating user: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // DELETE One User
+    pub async fn delete_one_user(&self, user_id: &str) -> Result<String, sqlx::Error> {
+        let sql = queries::UserQueries::DeleteOneUser.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => Ok("succesfully".to_string()),
+            Err(err) => {
+                eprintln!("Error deleting user: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // Transaction
+    pub async fn transaction(&self, user: &UserServer) -> Result<UserServer, sqlx::Error> {
+        // Start a new transaction
+        let mut txn = self.database.db.begin().await?;
+
+        // Define SQL queries
+        let create_sql = queries::UserQueries::CreateOneUser.convert_to_str();
+        let update_sql = queries::UserQueries::UpdateOneUser.convert_to_str();
+
+        // Execute the first query to create a new user
+        sqlx::query(create_sql)
+            .bind(&user.user_id)
+            .bind(&user.username)
+            .bind(&user.email)
+            .bind(&user.hashed_password)
+            .bind(user.active)
+            .bind(&user.role)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        // Execute the second query to update the user
+        sqlx::query(update_sql)
+            .bind("TXN_Username")
+            .bind(&user.email)
+            .bind("TXN_Password")
+            .bind(false)
+            .bind(&user.role)
+            .bind(&user.user_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        // Commit the transaction
+        txn.commit().await?;
+
+        // Fetch the updated user from the database
+        return self.get_one_user(&user.user_id).await;
+    }
+
+    // POST One API Key
+    pub async fn create_api_key(
+        &self,
+        user_id: &str,
+        name: &str,
+        prefix: &str,
+        hashed_key: &str,
+        scopes: &str,
+    ) -> Result<ApiKey, sqlx::Error> {
+        let sql = queries::ApiKeyQueries::CreateKey.convert_to_str();
+
+        let query = sqlx::query_as::<_, ApiKey>(sql)
+            .bind(&self.shop_domain)
+            .bind(user_id)
+            .bind(name)
+            .bind(prefix)
+            .bind(hashed_key)
+            .bind(scopes);
+        match fetch_returning(query, &self.database.db)
+            .await
+            .and_then(|api_key| api_key.ok_or(sqlx::Error::RowNotFound))
+        {
+            Ok(api_key) => Ok(api_key),
+            Err(err) => {
+                eprintln!("Error creating API key: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // GET API Keys, only those of one user when given
+    pub async fn get_api_keys(&self, user_id: Option<&str>) -> Result<Vec<ApiKey>, sqlx::Error> {
+        let query = match user_id {
+            Some(user_id) => {
+                sqlx::query_as::<_, ApiKey>(queries::ApiKeyQueries::GetAllForUser.convert_to_str())
+                    .bind(&self.shop_domain)
+                    .bind(user_id)
+            }
+            None => {
+                sqlx::query_as::<_, ApiKey>(queries::ApiKeyQueries::GetAllForShop.convert_to_str())
+                    .bind(&self.shop_domain)
+            }
+        };
+
+        return query.fetch_all(&self.database.db).await;
+    }
+
+    // PUT Revoke One API Key, only one of the given user when given
+    pub async fn revoke_api_key(
+        &self,
+        key_id: i64,
+        user_id: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let query = match user_id {
+            Some(user_id) => sqlx::query(queries::ApiKeyQueries::RevokeUserKey.convert_to_str())
+                .bind(key_id)
+                .bind(&self.shop_domain)
+                .bind(user_id),
+            None => sqlx::query(queries::ApiKeyQueries::RevokeKey.convert_to_str())
+                .bind(key_id)
+                .bind(&self.shop_domain),
+        };
+
+        let result = query.execute(&self.database.db).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // GET User Id linked to an external identity
+    pub async fn get_identity_user_id(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let sql = queries::IdentityQueries::GetUserId.convert_to_str();
+
+        return sqlx::query_scalar::<_, String>(sql)
+            .bind(provider)
+            .bind(subject)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // POST Link an external identity to a user
+    pub async fn create_identity(
+        &self,
+        user_id: &str,
+        provider: &str,
+        subject: &str,
+        email: Option<&str>,
+    ) -> Result<(), sqlx::Error> {
+        let sql = queries::IdentityQueries::CreateIdentity.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(user_id)
+            .bind(provider)
+            .bind(subject)
+            .bind(email)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                eprintln!("Error linking identity: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    // GET One Page of Products
+    pub async fn get_products(&self, page: &PageQuery) -> Result<(Vec<Product>, i64), sqlx::Error> {
+        let sql = queries::ProductQueries::GetPage.convert_to_str();
+        let products = sqlx::query_as::<_, Product>(sql)
+            .bind(&self.shop_domain)
+            .bind(page.per_page())
+            .bind(page.offset())
+            .fetch_all(&self.database.db)
+            .await?;
+
+        let total = self.count_products().await?;
+
+        Ok((products, total))
+    }
+
+    // GET The Number of Products of the Shop
+    pub async fn count_products(&self) -> Result<i64, sqlx::Error> {
+        let sql = queries::ProductQueries::CountAll.convert_to_str();
+
+        return sqlx::query_scalar::<_, i64>(sql)
+            .bind(&self.shop_domain)
+            .fetch_one(&self.database.db)
+            .await;
+    }
+
+    // GET One Product
+    pub async fn get_one_product(&self, product_id: i64) -> Result<Option<Product>, sqlx::Error> {
+        let sql = queries::ProductQueries::GetOne.convert_to_str();
+
+        return sqlx::query_as::<_, Product>(sql)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // POST One Product
+    pub async fn create_product(&self, product: &NewProduct) -> Result<Product, sqlx::Error> {
+        let sql = queries::ProductQueries::CreateOne.convert_to_str();
+        let query = sqlx::query_as::<_, Product>(sql)
+            .bind(&product.name)
+            .bind(&product.description)
+            .bind(product.price)
+            .bind(product.currency.as_str())
+            .bind(product.in_stock)
+            .bind(&self.shop_domain);
+
+        fetch_returning(query, &self.database.db)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    // PUT One Product
+    pub async fn update_product(
+        &self,
+        product_id: i64,
+        product: &NewProduct,
+    ) -> Result<Option<Product>, sqlx::Error> {
+        let sql = queries::ProductQueries::UpdateOne.convert_to_str();
+        let query = sqlx::query_as::<_, Product>(sql)
+            .bind(&product.name)
+            .bind(&product.description)
+            .bind(product.price)
+            .bind(product.currency.as_str())
+            .bind(product.in_stock)
+            .bind(product_id)
+            .bind(&self.shop_domain);
+
+        return fetch_returning(query, &self.database.db).await;
+    }
+
+    // DELETE One Product
+    pub async fn delete_product(&self, product_id: i64) -> Result<bool, sqlx::Error> {
+        let sql = queries::ProductQueries::DeleteOne.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // GET All Variants of a Product
+    pub async fn get_variants(&self, product_id: i64) -> Result<Vec<Variant>, sqlx::Error> {
+        let sql = queries::VariantQueries::GetForProduct.convert_to_str();
+
+        return sqlx::query_as::<_, Variant>(sql)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .fetch_all(&self.database.db)
+            .await;
+    }
+
+    // GET One Variant
+    pub async fn get_one_variant(
+        &self,
+        product_id: i64,
+        variant_id: i64,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        let sql = queries::VariantQueries::GetOne.convert_to_str();
+
+        return sqlx::query_as::<_, Variant>(sql)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // POST One Variant, None when the product is not in this shop
+    pub async fn create_variant(
+        &self,
+        product_id: i64,
+        variant: &NewVariant,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        let sql = queries::VariantQueries::CreateOne.convert_to_str();
+        let query = sqlx::query_as::<_, (i64,)>(sql)
+            .bind(&variant.sku)
+            .bind(&variant.attributes)
+            .bind(variant.price)
+            .bind(product_id)
+            .bind(&self.shop_domain);
+
+        match fetch_returning(query, &self.database.db).await? {
+            Some((variant_id,)) => self.get_one_variant(product_id, variant_id).await,
+            None => Ok(None),
+        }
+    }
+
+    // PUT One Variant
+    pub async fn update_variant(
+        &self,
+        product_id: i64,
+        variant_id: i64,
+        variant: &NewVariant,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        let sql = queries::VariantQueries::UpdateOne.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(&variant.sku)
+            .bind(&variant.attributes)
+            .bind(variant.price)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await?;
+        if result.rows_affected() != 1 {
+            return Ok(None);
+        }
+        self.get_one_variant(product_id, variant_id).await
+    }
+
+    // DELETE One Variant, its movements and reservations go with it
+    pub async fn delete_variant(
+        &self,
+        product_id: i64,
+        variant_id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let sql = queries::VariantQueries::DeleteOne.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // POST One Stock Movement. The stock and its audit trail change together,
+    // None when the variant is unknown or the stock would go below zero.
+    pub async fn adjust_stock(
+        &self,
+        product_id: i64,
+        variant_id: i64,
+        change: &StockChangeIn,
+        user_id: Option<&str>,
+    ) -> Result<Option<Variant>, sqlx::Error> {
+        let mut txn = self.database.db.begin().await?;
+
+        let result = sqlx::query(queries::StockQueries::AdjustStock.convert_to_str())
+            .bind(change.change)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .bind(change.change)
+            .execute(&mut *txn)
+            .await?;
+        if result.rows_affected() != 1 {
+            txn.rollback().await?;
+            return Ok(None);
+        }
+
+        sqlx::query(queries::StockQueries::CreateMovement.convert_to_str())
+            .bind(variant_id)
+            .bind(&self.shop_domain)
+            .bind(change.change)
+            .bind(change.reason.as_str())
+            .bind(change.note.as_deref().map(str::trim))
+            .bind(user_id)
+            .execute(&mut *txn)
+            .await?;
+
+        txn.commit().await?;
+        self.get_one_variant(product_id, variant_id).await
+    }
+
+    // GET One Page of Stock Movements, newest first
+    pub async fn get_stock_movements(
+        &self,
+        product_id: i64,
+        variant_id: i64,
+        page: &PageQuery,
+    ) -> Result<(Vec<StockMovement>, i64), sqlx::Error> {
+        let sql = queries::StockQueries::GetMovements.convert_to_str();
+        let movements = sqlx::query_as::<_, StockMovement>(sql)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .bind(page.per_page())
+            .bind(page.offset())
+            .fetch_all(&self.database.db)
+            .await?;
+
+        let sql = queries::StockQueries::CountMovements.convert_to_str();
+        let total = sqlx::query_scalar::<_, i64>(sql)
+            .bind(variant_id)
+            .bind(product_id)
+            .bind(&self.shop_domain)
+            .fetch_one(&self.database.db)
+            .await?;
+
+        Ok((movements, total))
+    }
+
+    // POST Stock Reservations for a checkout session, all lines or none.
+    // They count against the available stock until they expire, are released or completed.
+    pub async fn reserve_stock(
+        &self,
+        checkout_session_id: &str,
+        lines: &[(i64, i64)],
+        expires_at: NaiveDateTime,
+    ) -> Result<(), StockError> {
+        let mut txn = self.database.db.begin().await?;
+
+        for &(variant_id, quantity) in lines {
+            if quantity <= 0 {
+                txn.rollback().await?;
+                return Err(StockError::InvalidQuantity);
+            }
+            let result = sqlx::query(queries::StockQueries::Reserve.convert_to_str())
+                .bind(quantity)
+                .bind(checkout_session_id)
+                .bind(expires_at)
+                .bind(variant_id)
+                .bind(&self.shop_domain)
+                .bind(quantity)
+                .execute(&mut *txn)
+                .await?;
+            if result.rows_affected() != 1 {
+                txn.rollback().await?;
+                return Err(StockError::Unavailable(variant_id));
+            }
+        }
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    // POST One Order with its lines, priced by the cart
+    pub async fn create_order(
+        &self,
+        user_id: &str,
+        customer_email: Option<&str>,
+        cart: &PricedCart,
+    ) -> Result<Order, sqlx::Error> {
+        let mut txn = self.database.db.begin().await?;
+
+        let order = sqlx::query_as::<_, Order>(queries::OrderQueries::CreateOne.convert_to_str())
+            .bind(&self.shop_domain)
+            .bind(user_id)
+            .bind(cart.currency.as_deref().unwrap_or_default())
+            .bind(cart.total)
+            .bind(customer_email)
+            .fetch_one(&mut *txn)
+            .await?;
+
+        for line in &cart.lines {
+            sqlx::query(queries::OrderQueries::CreateItem.convert_to_str())
+                .bind(order.order_id)
+                .bind(line.product_id)
+                .bind(line.variant_id)
+                .bind(&line.name)
+                .bind(&line.sku)
+                .bind(line.quantity)
+                .bind(line.unit_price)
+                .bind(line.line_total)
+                .execute(&mut *txn)
+                .await?;
+        }
+
+        txn.commit().await?;
+        Ok(order)
+    }
+
+    // PUT The Checkout Session of an Order, the stock reserved for the order moves along
+    pub async fn set_checkout_session(
+        &self,
+        order: &Order,
+        checkout_session_id: &str,
+    ) -> Result<(), sqlx::Error> {
+        let mut txn = self.database.db.begin().await?;
+
+        sqlx::query(queries::OrderQueries::SetCheckoutSession.convert_to_str())
+            .bind(checkout_session_id)
+            .bind(order.order_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+        sqlx::query(queries::StockQueries::MoveReservations.convert_to_str())
+            .bind(checkout_session_id)
+            .bind(order.reservation_reference())
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        txn.commit().await
+    }
+
+    // GET One Page of the Orders of a User, newest first
+    pub async fn get_user_orders(
+        &self,
+        user_id: &str,
+        page: &PageQuery,
+    ) -> Result<(Vec<Order>, i64), sqlx::Error> {
+        let sql = queries::OrderQueries::GetPageForUser.convert_to_str();
+        let orders = sqlx::query_as::<_, Order>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .bind(page.per_page())
+            .bind(page.offset())
+            .fetch_all(&self.database.db)
+            .await?;
+
+        let sql = queries::OrderQueries::CountForUser.convert_to_str();
+        let total = sqlx::query_scalar::<_, i64>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_one(&self.database.db)
+            .await?;
+
+        Ok((orders, total))
+    }
+
+    // GET One Order of a User with its lines
+    pub async fn get_user_order(
+        &self,
+        user_id: &str,
+        order_id: i64,
+    ) -> Result<Option<OrderDetails>, sqlx::Error> {
+        let sql = queries::OrderQueries::GetOneForUser.convert_to_str();
+        let order = sqlx::query_as::<_, Order>(sql)
+            .bind(order_id)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await?;
+
+        match order {
+            Some(order) => self.order_details(order).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // GET The Orders of a User still waiting to be paid
+    pub async fn get_pending_orders(&self, user_id: &str) -> Result<Vec<Order>, sqlx::Error> {
+        let sql = queries::OrderQueries::GetPendingForUser.convert_to_str();
+
+        return sqlx::query_as::<_, Order>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_all(&self.database.db)
+            .await;
+    }
+
+    // GET One Order of the Shop
+    pub async fn get_one_order(&self, order_id: i64) -> Result<Option<Order>, sqlx::Error> {
+        let sql = queries::OrderQueries::GetOne.convert_to_str();
+
+        return sqlx::query_as::<_, Order>(sql)
+            .bind(order_id)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // GET One Page of the Disputes of the shop
+    pub async fn get_disputes(
+        &self,
+        page: &PageQuery,
+    ) -> Result<(Vec<OrderDispute>, i64), sqlx::Error> {
+        let sql = queries::DisputeQueries::GetPage.convert_to_str();
+        let disputes = sqlx::query_as::<_, OrderDispute>(sql)
+            .bind(&self.shop_domain)
+            .bind(page.per_page())
+            .bind(page.offset())
+            .fetch_all(&self.database.db)
+            .await?;
+
+        let sql = queries::DisputeQueries::Count.convert_to_str();
+        let total = sqlx::query_scalar::<_, i64>(sql)
+            .bind(&self.shop_domain)
+            .fetch_one(&self.database.db)
+            .await?;
+
+        Ok((disputes, total))
+    }
+
+    // Shop-scoped forwards to SqliteDB, for the shared queries handlers need
+
+    // GET The Plan of the Shop
+    pub async fn get_shop_plan(&self) -> Result<ShopPlan, sqlx::Error> {
+        self.database.get_shop_plan(&self.shop_domain).await
+    }
+
+    // GET Where the payments of the Shop go
+    pub async fn get_payment_destination(&self) -> Result<PaymentDestination, sqlx::Error> {
+        self.database
+            .get_payment_destination(&self.shop_domain)
+            .await
+    }
+
+    // GET An Order of the Shop with its lines
+    pub async fn order_details(&self, order: Order) -> Result<OrderDetails, sqlx::Error> {
+        if order.shop_domain != self.shop_domain {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        self.database.order_details(order).await
+    }
+
+    // PUT The Status of an Order of the Shop
+    pub async fn update_order_status(
+        &self,
+        order: &Order,
+        status: OrderStatus,
+        payment_intent_id: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        if order.shop_domain != self.shop_domain {
+            return Ok(false);
+        }
+        self.database
+            .update_order_status(order, status, payment_intent_id)
+            .await
+    }
+
+    // PUT How much of an Order of the Shop was paid back so far
+    pub async fn set_order_refunded(
+        &self,
+        order: &Order,
+        refunded_amount: i64,
+    ) -> Result<bool, sqlx::Error> {
+        if order.shop_domain != self.shop_domain {
+            return Ok(false);
+        }
+        self.database
+            .set_order_refunded(order.order_id, refunded_amount)
+            .await
+    }
+
+    // PUT Release the Stock Reservations of an Order of the Shop, also once they
+    // moved to its checkout session
+    pub async fn release_order_reservations(&self, order: &Order) -> Result<u64, sqlx::Error> {
+        if order.shop_domain != self.shop_domain {
+            return Ok(0);
+        }
+        let mut released = self
+            .database
+            .release_reservations(&order.reservation_reference())
+            .await?;
+        if let Some(checkout_session_id) = &order.checkout_session_id {
+            released += self
+                .database
+                .release_reservations(checkout_session_id)
+                .await?;
+        }
+        Ok(released)
+    }
+
+    // GET Two-Factor Secret of a User of the Shop, None when two-factor authentication is off
+    pub async fn get_two_factor_secret(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let sql = queries::TwoFactorQueries::GetSecret.convert_to_str();
+
+        return sqlx::query_scalar::<_, String>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_optional(&self.database.db)
+            .await;
+    }
+
+    // POST Enable Two-Factor for a User of the Shop, replacing any previous secret and recovery codes
+    pub async fn enable_two_factor(
+        &self,
+        user_id: &str,
+        encrypted_secret: &str,
+        hashed_recovery_codes: &[String],
+    ) -> Result<(), sqlx::Error> {
+        let mut txn = self.database.db.begin().await?;
+
+        let stored = sqlx::query(queries::TwoFactorQueries::SetSecret.convert_to_str())
+            .bind(encrypted_secret)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+        if stored.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(queries::TwoFactorQueries::DeleteRecoveryCodes.convert_to_str())
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        for hashed_code in hashed_recovery_codes {
+            sqlx::query(queries::TwoFactorQueries::CreateRecoveryCode.convert_to_str())
+                .bind(hashed_code)
+                .bind(user_id)
+                .bind(&self.shop_domain)
+                .execute(&mut *txn)
+                .await?;
+        }
+
+        txn.commit().await
+    }
+
+    // DELETE Two-Factor secret and recovery codes of a User of the Shop
+    pub async fn disable_two_factor(&self, user_id: &str) -> Result<(), sqlx::Error> {
+        let mut txn = self.database.db.begin().await?;
+
+        sqlx::query(queries::TwoFactorQueries::DeleteSecret.convert_to_str())
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        sqlx::query(queries::TwoFactorQueries::DeleteRecoveryCodes.convert_to_str())
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .execute(&mut *txn)
+            .await?;
+
+        txn.commit().await
+    }
+
+    // GET Unused Recovery Codes of a User of the Shop as (code_id, hashed_code)
+    pub async fn get_unused_recovery_codes(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(i64, String)>, sqlx::Error> {
+        let sql = queries::TwoFactorQueries::GetUnusedRecoveryCodes.convert_to_str();
+
+        return sqlx::query_as::<_, (i64, String)>(sql)
+            .bind(user_id)
+            .bind(&self.shop_domain)
+            .fetch_all(&self.database.db)
+            .await;
+    }
+
+    // PUT Use Recovery Code of a User of the Shop, false when another request used it first
+    pub async fn use_recovery_code(&self, code_id: i64) -> Result<bool, sqlx::Error> {
+        let sql = queries::TwoFactorQueries::UseRecoveryCode.convert_to_str();
+
+        let result = sqlx::query(sql)
+            .bind(code_id)
+            .bind(&self.shop_domain)
+            .execute(&self.database.db)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // POST One Audit Entry of the Shop
+    pub async fn create_audit_entry(
+        &self,
+        event: &str,
+        subject: &str,
+        ip_address: &str,
+    ) -> Result<(), sqlx::Error> {
+        let sql = queries::AuditQueries::CreateOneEntry.convert_to_str();
+
+        match sqlx::query(sql)
+            .bind(&self.shop_domain)
+            .bind(event)
+            .bind(subject)
+            .bind(ip_address)
+            .execute(&self.database.db)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                eprintln!("Error creating audit entry: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tenant_tests {
+    use super::*;
+    use crate::domain::datatypes::UserRole;
+
+    fn test_user(user_id: &str) -> UserServer {
+        UserServer {
+            user_id: user_id.to_string(),
+            username: "eve".to_string(),
+            email: "eve@example.com".to_string(),
+            hashed_password: "".to_string(),
+            active: true,
+            verified_at: None,
+            role: UserRole::Customer,
+            shop_domain: String::new(),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn check_tenant_isolation() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "tenants_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let database = SqliteDB::new(&url).await;
+        let shop_a = database.for_shop("a.test");
+        let shop_b = database.for_shop("b.test");
+
+        // The same username can exist once in every shop
+        let eve_a = shop_a.create_one_user(&test_user("user_a")).await.unwrap();
+        let eve_b = shop_b.create_one_user(&test_user("user_b")).await.unwrap();
+        assert_eq!(eve_a.shop_domain, "a.test");
+        assert_eq!(eve_b.shop_domain, "b.test");
+
+        // A shop only sees its own users
+        let found = shop_a.get_one_user_username("eve").await.unwrap().unwrap();
+        assert_eq!(found.user_id, "user_a");
+        assert!(shop_a.get_one_user("user_b").await.is_err());
+        assert_eq!(shop_b.get_all_users().await.unwrap().len(), 1);
+
+        // And cannot change or delete users of another shop
+        let mut stolen = test_user("user_b");
+        stolen.hashed_password = "changed".to_string();
+        assert!(shop_a.update_one_user_password(&stolen).await.is_err());
+        shop_a.delete_one_user("user_b").await.unwrap();
+        let kept = shop_b.get_one_user("user_b").await.unwrap();
+        assert_eq!(kept.hashed_password, "");
+
+        // Two-factor settings only reach users of the shop
+        let codes = vec!["hashed".to_string()];
+        assert!(shop_a
+            .enable_two_factor("user_b", "secret", &codes)
+            .await
+            .is_err());
+        shop_b
+            .enable_two_factor("user_b", "secret", &codes)
+            .await
+            .unwrap();
+        assert!(shop_a
+            .get_two_factor_secret("user_b")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(shop_a
+            .get_unused_recovery_codes("user_b")
+            .await
+            .unwrap()
+            .is_empty());
+        let (code_id, _) = shop_b.get_unused_recovery_codes("user_b").await.unwrap()[0].clone();
+        assert!(!shop_a.use_recovery_code(code_id).await.unwrap());
+        shop_a.disable_two_factor("user_b").await.unwrap();
+        assert_eq!(
+            shop_b.get_two_factor_secret("user_b").await.unwrap(),
+            Some("secret".to_string())
+        );
+        assert!(shop_b.use_recovery_code(code_id).await.unwrap());
+
+        // Products are paged per shop and out of reach of the other shop
+        let product: crate::domain::products::ProductIn =
+            serde_json::from_str(r#"{"name":"Tea","price":"4.20","currency":"usd"}"#).unwrap();
+        let product = product.validate().unwrap();
+        let mut tea = shop_a.create_product(&product).await.unwrap();
+        shop_a.create_product(&product).await.unwrap();
+        shop_b.create_product(&product).await.unwrap();
+        let first_page = PageQuery {
+            page: Some(1),
+            per_page: Some(1),
+        };
+        let (products, total) = shop_a.get_products(&first_page).await.unwrap();
+        assert_eq!((products.len(), total), (1, 2));
+        assert_eq!(products[0].product_id, tea.product_id);
+        assert!(shop_b
+            .get_one_product(tea.product_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(shop_b
+            .update_product(tea.product_id, &product)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!shop_b.delete_product(tea.product_id).await.unwrap());
+        tea = shop_a
+            .get_one_product(tea.product_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tea.price, 420);
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_rt::test]
+    async fn check_stock_reservations() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "stock_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let database = SqliteDB::new(&url).await;
+        let shop = database.for_shop("a.test");
+        let other_shop = database.for_shop("b.test");
+
+        let product: crate::domain::products::ProductIn =
+            serde_json::from_str(r#"{"name":"Tea","price":"4.20","currency":"usd"}"#).unwrap();
+        let tea = shop
+            .create_product(&product.validate().unwrap())
+            .await
+            .unwrap();
+        let variant: crate::domain::inventory::VariantIn =
+            serde_json::from_str(r#"{"sku":"TEA-1KG","attributes":{"size":"1kg"},"price":"15"}"#)
+                .unwrap();
+        let variant = variant.validate().unwrap();
+        assert!(other_shop
+            .create_variant(tea.product_id, &variant)
+            .await
+            .unwrap()
+            .is_none());
+        let kilo = shop
+            .create_variant(tea.product_id, &variant)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((kilo.unit_price, kilo.stock, kilo.available), (1500, 0, 0));
+
+        // Stock changes are recorded and never go below zero
+        let restock: StockChangeIn =
+            serde_json::from_str(r#"{"change":5,"reason":"restock","note":"delivery"}"#).unwrap();
+        let kilo = shop
+            .adjust_stock(tea.product_id, kilo.variant_id, &restock, Some("owner"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(kilo.stock, 5);
+        let too_much: StockChangeIn =
+            serde_json::from_str(r#"{"change":-6,"reason":"adjustment"}"#).unwrap();
+        assert!(shop
+            .adjust_stock(tea.product_id, kilo.variant_id, &too_much, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(other_shop
+            .adjust_stock(tea.product_id, kilo.variant_id, &restock, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Reservations hold stock until their time is up, all lines or none
+        let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+        let earlier = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(1);
+        shop.reserve_stock("cs_abandoned", &[(kilo.variant_id, 5)], earlier)
+            .await
+            .unwrap();
+        shop.reserve_stock("cs_paid", &[(kilo.variant_id, 2)], later)
+            .await
+            .unwrap();
+        assert!(matches!(
+            shop.reserve_stock(
+                "cs_late",
+                &[(kilo.variant_id, 1), (kilo.variant_id, 3)],
+                later
+            )
+            .await,
+            Err(StockError::Unavailable(_))
+        ));
+        shop.reserve_stock("cs_expired", &[(kilo.variant_id, 3)], later)
+            .await
+            .unwrap();
+        assert!(matches!(
+            other_shop
+                .reserve_stock("cs_other", &[(kilo.variant_id, 1)], later)
+                .await,
+            Err(StockError::Unavailable(_))
+        ));
+        let available = |variant: Option<Variant>| variant.unwrap().available;
+        assert_eq!(
+            available(
+                shop.get_one_variant(tea.product_id, kilo.variant_id)
+                    .await
+                    .unwrap()
+            ),
+            0
+        );
+
+        // An expired session gives its stock back, a paid one sells it once
+        assert_eq!(
+            database.release_reservations("cs_expired").await.unwrap(),
+            1
+        );
+        assert_eq!(database.complete_reservations("cs_paid").await.unwrap(), 1);
+        assert_eq!(database.complete_reservations("cs_paid").await.unwrap(), 0);
+        let kilo = shop
+            .get_one_variant(tea.product_id, kilo.variant_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((kilo.stock, kilo.available), (3, 3));
+
+        let first_page = PageQuery {
+            page: None,
+            per_page: None,
+        };
+        let (movements, total) = shop
+            .get_stock_movements(tea.product_id, kilo.variant_id, &first_page)
+            .await
+            .unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(
+            (movements[0].change, movements[0].reason.as_str()),
+            (-2, "sale")
+        );
+        assert_eq!(movements[0].reference.as_deref(), Some("cs_paid"));
+        assert_eq!(movements[1].user_id.as_deref(), Some("owner"));
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[actix_rt::test]
+    async fn check_orders() {
+        use crate::domain::cart::Cart;
+        use crate::domain::orders::OrderStatus;
+
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "orders_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let database = SqliteDB::new(&url).await;
+        let shop = database.for_shop("a.test");
+        let other_shop = database.for_shop("b.test");
+
+        let product: crate::domain::products::ProductIn =
+            serde_json::from_str(r#"{"name":"Tea","price":"4.20","currency":"usd"}"#).unwrap();
+        let tea = shop
+            .create_product(&product.validate().unwrap())
+            .await
+            .unwrap();
+        let variant: crate::domain::inventory::VariantIn =
+            serde_json::from_str(r#"{"sku":"TEA-1KG","price":"15"}"#).unwrap();
+        let kilo = shop
+            .create_variant(tea.product_id, &variant.validate().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let restock: StockChangeIn =
+            serde_json::from_str(r#"{"change":3,"reason":"restock"}"#).unwrap();
+        shop.adjust_stock(tea.product_id, kilo.variant_id, &restock, None)
+            .await
+            .unwrap();
+
+        // The order copies the priced cart
+        let mut cart = Cart::default();
+        cart.set_quantity(tea.product_id, Some(kilo.variant_id), 2)
+            .unwrap();
+        let (priced, _) = crate::modules::cart::price_cart(&shop, &cart)
+            .await
+            .unwrap();
+        let order = shop
+            .create_order("customer", Some("eve@example.com"), &priced)
+            .await
+            .unwrap();
+        assert_eq!(
+            (order.status, order.total, order.currency.as_str()),
+            (OrderStatus::Pending, 3000, "usd")
+        );
+
+        // Stock reserved for the order follows it to the checkout session
+        let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+        shop.reserve_stock(
+            &order.reservation_reference(),
+            &[(kilo.variant_id, 2)],
+            later,
+        )
+        .await
+        .unwrap();
+        shop.set_checkout_session(&order, "cs_order").await.unwrap();
+        let order = database
+            .get_order_by_session("cs_order")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(shop.get_pending_orders("customer").await.unwrap().len(), 1);
+        assert!(other_shop
+            .get_pending_orders("customer")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(database.complete_reservations("cs_order").await.unwrap(), 1);
+
+        // An abandoned order gives back its stock, wherever the reservation is
+        let abandoned = shop.create_order("customer", None, &priced).await.unwrap();
+        shop.reserve_stock(
+            &abandoned.reservation_reference(),
+            &[(kilo.variant_id, 1)],
+            later,
+        )
+        .await
+        .unwrap();
+        shop.set_checkout_session(&abandoned, "cs_abandoned")
+            .await
+            .unwrap();
+        let abandoned = shop
+            .get_one_order(abandoned.order_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            other_shop
+                .release_order_reservations(&abandoned)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            shop.release_order_reservations(&abandoned).await.unwrap(),
+            1
+        );
+        assert!(shop
+            .update_order_status(&abandoned, OrderStatus::Cancelled, None)
+            .await
+            .unwrap());
+
+        // Customers only see their own orders
+        let details = shop
+            .get_user_order("customer", order.order_id)
+            .await
+            .unwrap();
+        assert_eq!(details.unwrap().items[0].sku.as_deref(), Some("TEA-1KG"));
+        assert!(shop
+            .get_user_order("someone", order.order_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(other_shop
+            .get_user_order("customer", order.order_id)
+            .await
+            .unwrap()
+            .is_none());
+        let first_page = PageQuery {
+            page: None,
+            per_page: None,
+        };
+        assert_eq!(
+            shop.get_user_orders("customer", &first_page)
+                .await
+                .unwrap()
+                .1,
+            2
+        );
+
+        // Status moves forward once, a stale copy of the order cannot move it again
+        assert!(database
+            .update_order_status(&order, OrderStatus::Paid, Some("pi_123"))
+            .await
+            .unwrap());
+        assert!(!database
+            .update_order_status(&order, OrderStatus::Cancelled, None)
+            .await
+            .unwrap());
+        let paid = shop.get_one_order(order.order_id).await.unwrap().unwrap();
+        assert_eq!(paid.status, OrderStatus::Paid);
+        assert_eq!(paid.payment_intent_id.as_deref(), Some("pi_123"));
+        assert!(!database
+            .update_order_status(&paid, OrderStatus::Pending, None)
+            .await
+            .unwrap());
+        assert!(database
+            .update_order_status(&paid, OrderStatus::Fulfilled, None)
+            .await
+            .unwrap());
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
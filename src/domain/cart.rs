@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::domain::products::format_price;
+
+pub const MAX_CART_LINES: usize = 50;
+pub const MAX_LINE_QUANTITY: i64 = 99;
+
+// What the customer picked, prices are never stored and always looked up again
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Cart {
+    pub items: Vec<CartItem>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CartItem {
+    pub product_id: i64,
+    pub variant_id: Option<i64>,
+    pub quantity: i64,
+}
+
+impl Cart {
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn quantity(&self, product_id: i64, variant_id: Option<i64>) -> i64 {
+        self.items
+            .iter()
+            .find(|item| item.product_id == product_id && item.variant_id == variant_id)
+            .map(|item| item.quantity)
+            .unwrap_or(0)
+    }
+
+    // Zero removes the line
+    pub fn set_quantity(
+        &mut self,
+        product_id: i64,
+        variant_id: Option<i64>,
+        quantity: i64,
+    ) -> Result<(), &'static str> {
+        if !(0..=MAX_LINE_QUANTITY).contains(&quantity) {
+            return Err("The quantity must be between 0 and 99");
+        }
+
+        let line = self
+            .items
+            .iter()
+            .position(|item| item.product_id == product_id && item.variant_id == variant_id);
+        match (line, quantity) {
+            (Some(line), 0) => {
+                self.items.remove(line);
+            }
+            (Some(line), quantity) => self.items[line].quantity = quantity,
+            (None, 0) => {}
+            (None, _) if self.items.len() >= MAX_CART_LINES => {
+                return Err("The cart is full");
+            }
+            (None, quantity) => self.items.push(CartItem {
+                product_id,
+                variant_id,
+                quantity,
+            }),
+        }
+        Ok(())
+    }
+
+    // The lines of a guest cart join the cart of the user who logs in,
+    // quantities add up to the maximum and lines beyond the limit are dropped
+    pub fn merge(&mut self, other: Cart) {
+        for item in other.items {
+            let quantity = (self.quantity(item.product_id, item.variant_id) + item.quantity)
+                .min(MAX_LINE_QUANTITY);
+            let _ = self.set_quantity(item.product_id, item.variant_id, quantity);
+        }
+    }
+}
+
+// A line as sent by the API and forms, only what to buy and how many
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CartItemIn {
+    pub product_id: i64,
+    pub variant_id: Option<i64>,
+    pub quantity: i64,
+}
+
+// Which line to remove
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CartLineRef {
+    pub product_id: i64,
+    pub variant_id: Option<i64>,
+}
+
+// A cart line priced from the catalog
+#[derive(Debug, Clone, Serialize)]
+pub struct CartLine {
+    pub product_id: i64,
+    pub variant_id: Option<i64>,
+    pub name: String,
+    pub sku: Option<String>,
+    pub quantity: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub unit_price: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub line_total: i64,
+}
+
+// The cart as shown to the customer, every amount computed on the server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PricedCart {
+    pub lines: Vec<CartLine>,
+    pub currency: Option<String>,
+    pub item_count: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub total: i64,
+}
+
+fn serialize_price<S: Serializer>(amount: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_price(*amount))
+}
+
+#[cfg(test)]
+mod cart_tests {
+    use super::*;
+
+    #[test]
+    fn check_cart_operations() {
+        let mut cart = Cart::default();
+        cart.set_quantity(1, None, 2).unwrap();
+        cart.set_quantity(2, Some(7), 1).unwrap();
+        cart.set_quantity(2, Some(8), 3).unwrap();
+        assert_eq!(cart.quantity(2, Some(8)), 3);
+        assert!(cart.set_quantity(1, None, 100).is_err());
+        assert!(cart.set_quantity(1, None, -1).is_err());
+
+        // Zero removes a line
+        cart.set_quantity(2, Some(8), 0).unwrap();
+        assert_eq!(cart.items.len(), 2);
+
+        // Merging adds quantities up to the maximum
+        let mut guest = Cart::default();
+        guest.set_quantity(1, None, 98).unwrap();
+        guest.set_quantity(3, None, 1).unwrap();
+        cart.merge(guest);
+        assert_eq!(cart.quantity(1, None), MAX_LINE_QUANTITY);
+        assert_eq!(cart.quantity(2, Some(7)), 1);
+        assert_eq!(cart.quantity(3, None), 1);
+
+        // The number of lines is limited
+        let mut full = Cart::default();
+        for product_id in 0..MAX_CART_LINES as i64 {
+            full.set_quantity(product_id, None, 1).unwrap();
+        }
+        assert_eq!(full.set_quantity(999, None, 1), Err("The cart is full"));
+        full.merge(cart);
+        assert_eq!(full.items.len(), MAX_CART_LINES);
+    }
+}
@@ -0,0 +1,47 @@
+use serde::Serialize;
+use sqlx::prelude::FromRow;
+
+// The Stripe Connect account a shop is paid out to, the status is mirrored from account.updated
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ConnectAccount {
+    pub shop_domain: String,
+    pub stripe_account_id: String,
+    // Whether customers can pay the shop
+    pub charges_enabled: bool,
+    // Whether Stripe pays the balance out to the bank of the owner
+    pub payouts_enabled: bool,
+    pub details_submitted: bool,
+    // Unix time of the Stripe data, older data never replaces newer
+    #[serde(skip_serializing)]
+    pub synced_at: i64,
+}
+
+// Where the money of an order goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaymentDestination {
+    // Shops without an owner are run by the platform and keep everything
+    Platform,
+    // The Connect account of the owner, minus the application fee
+    Account(String),
+    // The owner has not finished onboarding, the shop cannot take payments
+    Unavailable,
+}
+
+// The fee in the smallest unit of the currency, rounded down in favour of the shop
+pub fn application_fee(total: i64, basis_points: i64) -> i64 {
+    (total.max(0) * basis_points.clamp(0, 10_000)) / 10_000
+}
+
+#[cfg(test)]
+mod connect_accounts_tests {
+    use super::*;
+
+    #[test]
+    fn check_application_fee() {
+        assert_eq!(application_fee(10_000, 500), 500);
+        assert_eq!(application_fee(1_999, 500), 99);
+        assert_eq!(application_fee(1_000, 0), 0);
+        assert_eq!(application_fee(1_000, 20_000), 1_000);
+        assert_eq!(application_fee(-5, 500), 0);
+    }
+}
@@ -1,6 +1,12 @@
-use crate::modules::password_hash;
+use crate::modules::api_key::ApiScope;
+use crate::modules::middleware_domain::Shop;
+use crate::modules::password_policy::{PasswordPolicy, ValidationErrors};
+use crate::modules::{cookie, login_throttle, password_hash};
 use actix_web::cookie::time;
 use actix_web::cookie::Cookie;
+use actix_web::http::header;
+use actix_web::{HttpMessage, HttpRequest};
+use chrono::NaiveDateTime;
 use pasetors::claims::Claims;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
@@ -10,8 +16,15 @@ use uuid::Uuid;
 pub struct UserServer {
     pub user_id: String,
     pub username: String,
+    pub email: String,
     pub hashed_password: String,
     pub active: bool,
+    pub verified_at: Option<NaiveDateTime>,
+    pub role: UserRole,
+    // Filled in from the database, the TenantDB that stores a user decides its shop
+    #[sqlx(default)]
+    #[serde(default)]
+    pub shop_domain: String,
 }
 impl UserServer {
     pub fn process_for_server(user_client_in: UserClientIn) -> Self {
@@ -23,8 +36,12 @@ impl UserServer {
         return UserServer {
             user_id,
             username: user_client_in.username.to_string(),
+            email: user_client_in.email.to_string(),
             hashed_password: password.get_password_string(),
             active: user_active,
+            verified_at: None,
+            role: UserRole::Customer,
+            shop_domain: String::new(),
         };
     }
 
@@ -32,19 +49,27 @@ impl UserServer {
         return UserClientOut {
             user_id: self.user_id.to_string(),
             username: self.username.to_string(),
+            email: self.email.to_string(),
             hashed_password: self.hashed_password.to_string(),
             active: self.active,
+            verified_at: self.verified_at,
+            role: self.role.clone(),
         };
     }
 
+    // Only the user id and password are used by update_one_user_password
     pub fn process_for_reset(user: &UserCookie, password: String, act: bool) -> Self {
         return UserServer {
             user_id: user.user_id.to_string(),
             username: user.username.to_string(),
+            email: String::new(),
             hashed_password: password_hash::Password::hash_password(password.as_str())
                 .expect("Error hashing the password")
                 .get_password_string(),
             active: act,
+            verified_at: None,
+            role: user.role.clone(),
+            shop_domain: user.shop_domain.to_string(),
         };
     }
 }
@@ -56,6 +81,147 @@ pub struct UserClientSignIn {
     pub remember: Option<bool>,
 }
 
+// Code from the authenticator app or a recovery code
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorCode {
+    pub code: String,
+    pub remember: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RefreshRedirect {
+    pub redirect: Option<String>,
+}
+
+// Who is logging in, taken from the request
+#[derive(Debug, Clone)]
+pub struct LoginClient {
+    pub ip: String,
+    pub user_agent: String,
+    pub shop_domain: String,
+    // The guest cart to merge into the cart of the user
+    pub cart_id: Option<String>,
+}
+impl LoginClient {
+    pub fn from_request(request: &HttpRequest) -> Self {
+        let connection_info = request.connection_info();
+
+        LoginClient {
+            ip: login_throttle::client_ip(request),
+            user_agent: request
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|agent| agent.to_str().ok())
+                .unwrap_or("unknown")
+                .to_string(),
+            shop_domain: request
+                .extensions()
+                .get::<Shop>()
+                .map(|shop| shop.domain.to_string())
+                .unwrap_or_else(|| connection_info.host().to_string()),
+            cart_id: request
+                .cookie(CookieVariations::ShoppingCarts.get_name().as_str())
+                .and_then(|cookie| CookieVariations::ShoppingCarts.open_value(cookie.value())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TwoFactorPending {
+    pub remember: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcStart {
+    pub remember: Option<bool>,
+}
+
+// Query of the redirect back from the identity provider
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+// An API key as stored, the hash never leaves the server
+#[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
+pub struct ApiKey {
+    pub key_id: i64,
+    pub shop_domain: String,
+    pub user_id: String,
+    pub name: String,
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub hashed_key: String,
+    pub scopes: String,
+    pub created_on: Option<NaiveDateTime>,
+    pub last_used_on: Option<NaiveDateTime>,
+    pub revoked_on: Option<NaiveDateTime>,
+}
+
+// A key together with the current state of its owner
+#[derive(FromRow, Debug)]
+pub struct ApiKeyWithOwner {
+    #[sqlx(flatten)]
+    pub key: ApiKey,
+    pub owner_active: bool,
+    pub owner_role: UserRole,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiKeyCreate {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+}
+
+// ?page=2&per_page=20, pages start at 1
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PageQuery {
+    pub page: Option<u32>,
+    pub per_page: Option<u32>,
+}
+impl PageQuery {
+    const DEFAULT_PER_PAGE: u32 = 20;
+    const MAX_PER_PAGE: u32 = 100;
+
+    pub fn page(&self) -> u32 {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> u32 {
+        self.per_page
+            .unwrap_or(Self::DEFAULT_PER_PAGE)
+            .clamp(1, Self::MAX_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> i64 {
+        (self.page() as i64 - 1) * self.per_page() as i64
+    }
+}
+
+// One page of a list with what is needed to ask for the others
+#[derive(Serialize, Debug)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+    pub total_pages: i64,
+}
+impl<T> Paginated<T> {
+    pub fn new(items: Vec<T>, query: &PageQuery, total: i64) -> Self {
+        let per_page = query.per_page();
+        Paginated {
+            items,
+            page: query.page(),
+            per_page,
+            total,
+            total_pages: (total + per_page as i64 - 1) / per_page as i64,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, FromRow, Debug)]
 pub struct UserClientForgot {
     pub username: String,
@@ -70,20 +236,29 @@ impl UserPassWordReset {
     fn convert_to_register(&self, user: UserCookie) -> UserClientRegister {
         UserClientRegister {
             username: user.username.to_string(),
+            email: String::new(),
             password: self.password.to_string(),
             confirm_password: self.confirm_password.to_string(),
         }
     }
 
-    pub fn verify_password(&self, user: &UserCookie) -> Result<UserServer, String> {
-        if self.password == self.confirm_password {
+    pub fn verify_password(&self, user: &UserCookie) -> Result<UserServer, ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        PasswordPolicy::configured().validate(
+            &user.username,
+            &self.password,
+            &self.confirm_password,
+            &mut errors,
+        );
+
+        if errors.is_empty() {
             Ok(UserServer::process_for_reset(
                 user,
                 self.password.to_string(),
                 true,
             ))
         } else {
-            Err("Password not the same".to_string())
+            Err(errors)
         }
     }
 }
@@ -91,19 +266,35 @@ impl UserPassWordReset {
 #[derive(Serialize, Deserialize, FromRow, Debug)]
 pub struct UserClientRegister {
     pub username: String,
+    pub email: String,
     pub password: String,
     pub confirm_password: String,
 }
 impl UserClientRegister {
-    pub fn verify_password(&self) -> Result<UserServer, ()> {
-        if self.password == self.confirm_password {
+    pub fn verify_password(&self) -> Result<UserServer, ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        if self.username.trim().is_empty() {
+            errors.add("username", "Username is required");
+        }
+        if self.email.trim().is_empty() {
+            errors.add("email", "Email is required");
+        }
+        PasswordPolicy::configured().validate(
+            &self.username,
+            &self.password,
+            &self.confirm_password,
+            &mut errors,
+        );
+
+        if errors.is_empty() {
             let user = UserClientIn {
                 username: self.username.to_string(),
+                email: self.email.to_string(),
                 password: self.password.to_string(),
             };
             Ok(UserServer::process_for_server(user))
         } else {
-            Err(())
+            Err(errors)
         }
     }
 }
@@ -111,6 +302,7 @@ impl UserClientRegister {
 #[derive(Serialize, Deserialize, FromRow, Debug)]
 pub struct UserClientIn {
     pub username: String,
+    pub email: String,
     pub password: String,
 }
 
@@ -118,13 +310,47 @@ pub struct UserClientIn {
 pub struct UserClientOut {
     pub user_id: String,
     pub username: String,
+    pub email: String,
     pub hashed_password: String,
     pub active: bool,
+    pub verified_at: Option<NaiveDateTime>,
+    pub role: UserRole,
 }
-#[derive(Serialize, Deserialize, Debug)]
+
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum UserRole {
+    Customer,
+    ShopOwner,
+    Admin,
+}
+impl UserRole {
+    pub fn as_str(&self) -> &str {
+        match self {
+            UserRole::Customer => "customer",
+            UserRole::ShopOwner => "shop_owner",
+            UserRole::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "customer" => Some(UserRole::Customer),
+            "shop_owner" => Some(UserRole::ShopOwner),
+            "admin" => Some(UserRole::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UserCookie {
     pub user_id: String,
     pub username: String,
+    pub verified: bool,
+    pub role: UserRole,
+    pub shop_domain: String,
 }
 impl UserCookie {
     pub fn new(cookie: &Claims) -> Self {
@@ -136,11 +362,29 @@ impl UserCookie {
             .get_claim("username")
             .expect("Failed to get user_id")
             .to_string();
+        let verified = cookie
+            .get_claim("verified")
+            .and_then(|verified| verified.as_bool())
+            .unwrap_or(false);
+        // Tokens without a known role get the least privileged one
+        let role = cookie
+            .get_claim("role")
+            .and_then(|role| role.as_str())
+            .and_then(UserRole::parse)
+            .unwrap_or(UserRole::Customer);
+        // Tokens without a shop are not valid in any shop
+        let shop_domain = cookie
+            .get_claim("shop_domain")
+            .and_then(|shop_domain| shop_domain.as_str())
+            .unwrap_or_default();
 
         // After parsing the cookie, it comes with quotes, so we need to remove them
         UserCookie {
             user_id: user_id.trim_matches('"').to_string(),
             username: username.trim_matches('"').to_string(),
+            verified,
+            role,
+            shop_domain: shop_domain.to_string(),
         }
     }
 }
@@ -152,6 +396,10 @@ pub enum LoginTypes {
 
 pub enum CookieVariations {
     Auth,
+    Refresh,
+    TwoFactor,
+    Csrf,
+    OidcState,
     ShoppingCarts,
     Personalization,
     Payment,
@@ -161,64 +409,55 @@ impl CookieVariations {
     pub fn get_name(&self) -> String {
         match self {
             CookieVariations::Auth => "auth".to_string(),
+            CookieVariations::Refresh => "refresh".to_string(),
+            CookieVariations::TwoFactor => "two_factor".to_string(),
+            CookieVariations::Csrf => "csrf".to_string(),
+            CookieVariations::OidcState => "oidc_state".to_string(),
             CookieVariations::ShoppingCarts => "shopping_cart".to_string(),
             CookieVariations::Personalization => "personalization".to_string(),
             CookieVariations::Payment => "payment".to_string(),
             CookieVariations::NAW => "naw".to_string(),
         }
     }
-    pub fn generate_cookie(&self, setting: Settings) -> Cookie {
-        match self {
-            &CookieVariations::Auth => Cookie::build(self.get_name(), setting.value)
-                .path("/")
-                // .domain(domain.to_string())
-                .expires(setting.time.to_owned())
-                .secure(true)
-                .http_only(false) // should be true, so js cannot access the cookie
-                .finish(),
-            &CookieVariations::ShoppingCarts => Cookie::build(self.get_name(), setting.value)
-                .path("/")
-                .expires(setting.time.to_owned())
-                .secure(true)
-                .http_only(false) // should be true, so js cannot access the cookie
-                .finish(),
-            _ => todo!("Generate the rest of the cookies"),
+    pub fn from_name(name: &str) -> Option<CookieVariations> {
+        match name {
+            "auth" => Some(CookieVariations::Auth),
+            "refresh" => Some(CookieVariations::Refresh),
+            "two_factor" => Some(CookieVariations::TwoFactor),
+            "csrf" => Some(CookieVariations::Csrf),
+            "oidc_state" => Some(CookieVariations::OidcState),
+            "shopping_cart" => Some(CookieVariations::ShoppingCarts),
+            "personalization" => Some(CookieVariations::Personalization),
+            "payment" => Some(CookieVariations::Payment),
+            "naw" => Some(CookieVariations::NAW),
+            _ => None,
         }
     }
-    pub fn create_user_info(&self, cookie: &Claims) -> UserCookie {
-        match self {
-            &CookieVariations::Auth => UserCookie {
-                user_id: cookie
-                    .get_claim("user_id")
-                    .expect("Failed to get user_id")
-                    .to_string(),
-                username: cookie
-                    .get_claim("username")
-                    .expect("Failed to get user_id")
-                    .to_string(),
-            },
-            _ => todo!("Create the rest of the cookies"),
-        }
+    // The attributes of every cookie come from the policy table in modules::cookie
+    pub fn generate_cookie(&self, setting: Settings) -> Cookie<'static> {
+        cookie::generate_cookie(self, setting)
     }
-    pub fn remove_cookie(&self) -> Cookie {
+    // Only the auth cookie carries the claims of a user, None for every other cookie
+    pub fn create_user_info(&self, cookie: &Claims) -> Option<UserCookie> {
         match self {
-            &CookieVariations::Auth => Cookie::build(self.get_name(), "")
-                .path("/")
-                .expires(time::OffsetDateTime::now_utc())
-                .max_age(time::Duration::seconds(0))
-                .secure(true)
-                .http_only(false) // should be true, so js cannot access the cookie
-                .finish(),
-            &CookieVariations::ShoppingCarts => Cookie::build(self.get_name(), "")
-                .path("/")
-                .expires(time::OffsetDateTime::now_utc())
-                .max_age(time::Duration::seconds(0))
-                .secure(true)
-                .http_only(false) // should be true, so js cannot access the cookie
-                .finish(),
-            _ => todo!("Remove the rest of the cookies"),
+            CookieVariations::Auth => Some(UserCookie::new(cookie)),
+            CookieVariations::Refresh
+            | CookieVariations::TwoFactor
+            | CookieVariations::Csrf
+            | CookieVariations::OidcState
+            | CookieVariations::ShoppingCarts
+            | CookieVariations::Personalization
+            | CookieVariations::Payment
+            | CookieVariations::NAW => None,
         }
     }
+    pub fn remove_cookie(&self) -> Cookie<'static> {
+        cookie::remove_cookie(self)
+    }
+    // Value of a signed or encrypted cookie, None when it was tampered with
+    pub fn open_value(&self, raw: &str) -> Option<String> {
+        cookie::open_value(self, raw)
+    }
 }
 
 pub struct Settings {
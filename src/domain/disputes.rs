@@ -0,0 +1,31 @@
+use chrono::NaiveDateTime;
+use serde::{Serialize, Serializer};
+use sqlx::prelude::FromRow;
+
+use crate::domain::products::format_price;
+
+// A chargeback on the payment of an order, mirrored from the charge.dispute.* events
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct OrderDispute {
+    pub dispute_id: String,
+    #[serde(skip_serializing)]
+    pub shop_domain: String,
+    pub order_id: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub amount: i64,
+    pub currency: String,
+    // As Stripe names them: fraudulent, product_not_received, ...
+    pub reason: String,
+    // As Stripe names them: needs_response, under_review, won, lost, ...
+    pub status: String,
+    // The shop loses the dispute when no evidence was submitted by then
+    pub evidence_due_by: Option<NaiveDateTime>,
+    // Unix time of the Stripe data, older data never replaces newer
+    #[serde(skip_serializing)]
+    pub synced_at: i64,
+    pub created_on: Option<NaiveDateTime>,
+}
+
+fn serialize_price<S: Serializer>(amount: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_price(*amount))
+}
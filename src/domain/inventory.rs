@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::prelude::FromRow;
+
+use crate::domain::products::{format_price, parse_price};
+
+const MAX_SKU_LENGTH: usize = 64;
+const MAX_ATTRIBUTES: usize = 20;
+const MAX_ATTRIBUTE_LENGTH: usize = 100;
+const MAX_NOTE_LENGTH: usize = 200;
+
+// A sellable version of a product, like "Green Tea, 250g"
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct Variant {
+    pub variant_id: i64,
+    pub product_id: i64,
+    #[serde(skip_serializing)]
+    pub shop_domain: String,
+    pub sku: String,
+    // Stored as a JSON object of text values
+    #[serde(serialize_with = "serialize_attributes")]
+    pub attributes: String,
+    // Overrides the price of the product when set
+    #[serde(serialize_with = "serialize_optional_price")]
+    pub price: Option<i64>,
+    // The price the variant sells for
+    #[serde(serialize_with = "serialize_price")]
+    pub unit_price: i64,
+    pub currency: String,
+    pub stock: i64,
+    // The stock minus what is reserved by open checkouts
+    pub available: i64,
+    pub created_on: Option<NaiveDateTime>,
+}
+
+// A variant as sent by the API, the stock only changes through stock movements
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VariantIn {
+    pub sku: String,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    // "12.50", leave out to use the price of the product
+    pub price: Option<String>,
+}
+impl VariantIn {
+    pub fn validate(&self) -> Result<NewVariant, &'static str> {
+        let sku = self.sku.trim();
+        if sku.is_empty()
+            || sku.len() > MAX_SKU_LENGTH
+            || !sku
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err("The SKU must be 1 to 64 letters, digits, '-', '_' or '.'");
+        }
+
+        if self.attributes.len() > MAX_ATTRIBUTES {
+            return Err("A variant has at most 20 attributes");
+        }
+        let mut attributes = BTreeMap::new();
+        for (name, value) in &self.attributes {
+            let (name, value) = (name.trim(), value.trim());
+            if name.is_empty()
+                || name.chars().count() > MAX_ATTRIBUTE_LENGTH
+                || value.chars().count() > MAX_ATTRIBUTE_LENGTH
+            {
+                return Err("Attribute names and values are at most 100 characters");
+            }
+            attributes.insert(name.to_string(), value.to_string());
+        }
+
+        let price = match self.price.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(price) => Some(parse_price(price)?),
+        };
+
+        Ok(NewVariant {
+            sku: sku.to_string(),
+            attributes: serde_json::to_string(&attributes).map_err(|_| "Invalid attributes")?,
+            price,
+        })
+    }
+}
+
+// A validated variant, ready to be saved
+#[derive(Debug, Clone)]
+pub struct NewVariant {
+    pub sku: String,
+    pub attributes: String,
+    pub price: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementReason {
+    // Goods came in
+    Restock,
+    // Counting, damage, returns to the supplier
+    Adjustment,
+    // A completed checkout
+    Sale,
+}
+impl MovementReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MovementReason::Restock => "restock",
+            MovementReason::Adjustment => "adjustment",
+            MovementReason::Sale => "sale",
+        }
+    }
+}
+
+// A change of stock by the shop, sales are recorded by the checkout
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StockChangeIn {
+    pub change: i64,
+    pub reason: MovementReason,
+    pub note: Option<String>,
+}
+impl StockChangeIn {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.change == 0 {
+            return Err("The change cannot be zero");
+        }
+        if self.reason == MovementReason::Sale {
+            return Err("Sales are recorded by the checkout");
+        }
+        if self.reason == MovementReason::Restock && self.change < 0 {
+            return Err("A restock adds stock");
+        }
+        if self
+            .note
+            .as_deref()
+            .is_some_and(|note| note.chars().count() > MAX_NOTE_LENGTH)
+        {
+            return Err("The note is too long");
+        }
+        Ok(())
+    }
+}
+
+// One line of the audit trail of a variant
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct StockMovement {
+    pub movement_id: i64,
+    pub variant_id: i64,
+    pub change: i64,
+    pub reason: String,
+    // The note of the shop or the checkout session of a sale
+    pub reference: Option<String>,
+    pub user_id: Option<String>,
+    pub created_on: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StockError {
+    #[error("Not enough stock of variant {0}")]
+    Unavailable(i64),
+    #[error("The quantity must be positive")]
+    InvalidQuantity,
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+fn serialize_attributes<S: Serializer>(attributes: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    let attributes: BTreeMap<String, String> = serde_json::from_str(attributes).unwrap_or_default();
+    attributes.serialize(serializer)
+}
+
+fn serialize_price<S: Serializer>(amount: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_price(*amount))
+}
+
+fn serialize_optional_price<S: Serializer>(
+    amount: &Option<i64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serializer.serialize_str(&format_price(*amount)),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod inventory_tests {
+    use super::*;
+
+    #[test]
+    fn check_variant_validation() {
+        let variant: VariantIn = serde_json::from_str(
+            r#"{"sku":" TEA-250g ","attributes":{"size":" 250g ","colour":"green"}}"#,
+        )
+        .unwrap();
+        let variant = variant.validate().unwrap();
+        assert_eq!(variant.sku, "TEA-250g");
+        assert_eq!(variant.attributes, r#"{"colour":"green","size":"250g"}"#);
+        assert_eq!(variant.price, None);
+
+        let variant: VariantIn =
+            serde_json::from_str(r#"{"sku":"TEA-1KG","price":"12.5"}"#).unwrap();
+        assert_eq!(variant.validate().unwrap().price, Some(1250));
+
+        for invalid in [
+            r#"{"sku":""}"#,
+            r#"{"sku":"tea 250g"}"#,
+            r#"{"sku":"TEA","price":"-1"}"#,
+            r#"{"sku":"TEA","attributes":{"":"x"}}"#,
+        ] {
+            let variant: VariantIn = serde_json::from_str(invalid).unwrap();
+            assert!(variant.validate().is_err(), "{} was accepted", invalid);
+        }
+
+        let change: StockChangeIn =
+            serde_json::from_str(r#"{"change":-2,"reason":"adjustment","note":"broken"}"#).unwrap();
+        assert!(change.validate().is_ok());
+        for invalid in [
+            r#"{"change":0,"reason":"adjustment"}"#,
+            r#"{"change":-1,"reason":"restock"}"#,
+            r#"{"change":1,"reason":"sale"}"#,
+        ] {
+            let change: StockChangeIn = serde_json::from_str(invalid).unwrap();
+            assert!(change.validate().is_err(), "{} was accepted", invalid);
+        }
+    }
+}
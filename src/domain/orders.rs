@@ -0,0 +1,177 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::prelude::FromRow;
+
+use crate::domain::products::{format_price, parse_price};
+
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum OrderStatus {
+    // Waiting for the payment at Stripe
+    Pending,
+    Paid,
+    // Sent to the customer by the shop
+    Fulfilled,
+    Refunded,
+    // Expired or failed checkout
+    Cancelled,
+}
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Fulfilled => "fulfilled",
+            OrderStatus::Refunded => "refunded",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+
+    // Orders only move forward: paid or cancelled, then fulfilled, refunded at any point after paying
+    pub fn can_become(&self, next: OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::Pending, OrderStatus::Paid)
+                | (OrderStatus::Pending, OrderStatus::Cancelled)
+                | (OrderStatus::Paid, OrderStatus::Fulfilled)
+                | (OrderStatus::Paid, OrderStatus::Refunded)
+                | (OrderStatus::Fulfilled, OrderStatus::Refunded)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct Order {
+    pub order_id: i64,
+    #[serde(skip_serializing)]
+    pub shop_domain: String,
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub status: OrderStatus,
+    pub currency: String,
+    #[serde(serialize_with = "serialize_price")]
+    pub total: i64,
+    // Refunds and lost disputes so far, the order is refunded once both reach the total
+    #[serde(serialize_with = "serialize_price")]
+    pub refunded_amount: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub disputed_amount: i64,
+    pub customer_email: Option<String>,
+    #[serde(skip_serializing)]
+    pub checkout_session_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub payment_intent_id: Option<String>,
+    pub created_on: Option<NaiveDateTime>,
+    pub updated_on: Option<NaiveDateTime>,
+}
+impl Order {
+    // Stock is reserved under this name until Stripe gives the checkout session an id
+    pub fn reservation_reference(&self) -> String {
+        format!("order_{}", self.order_id)
+    }
+
+    // What can still be refunded, nothing before the order is paid
+    pub fn refundable_amount(&self) -> i64 {
+        match self.status {
+            OrderStatus::Paid | OrderStatus::Fulfilled => {
+                (self.total - self.refunded_amount - self.disputed_amount).max(0)
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefundIn {
+    // Decimal like the prices, everything that is left when not given
+    pub amount: Option<String>,
+}
+impl RefundIn {
+    pub fn validate(&self, refundable: i64) -> Result<i64, &'static str> {
+        if refundable <= 0 {
+            return Err("Nothing is left to refund on this order");
+        }
+        let amount = match self.amount.as_deref().map(str::trim) {
+            None | Some("") => return Ok(refundable),
+            Some(amount) => parse_price(amount)?,
+        };
+        match amount {
+            0 => Err("The refund must be more than zero"),
+            amount if amount > refundable => Err("The refund is more than what is left to refund"),
+            amount => Ok(amount),
+        }
+    }
+}
+
+// A line of an order, names and prices are kept as they were when it was placed
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct OrderItem {
+    pub order_item_id: i64,
+    pub order_id: i64,
+    pub product_id: i64,
+    pub variant_id: Option<i64>,
+    pub name: String,
+    pub sku: Option<String>,
+    pub quantity: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub unit_price: i64,
+    #[serde(serialize_with = "serialize_price")]
+    pub line_total: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrderDetails {
+    #[serde(flatten)]
+    pub order: Order,
+    pub items: Vec<OrderItem>,
+}
+
+fn serialize_price<S: Serializer>(amount: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_price(*amount))
+}
+
+#[cfg(test)]
+mod orders_tests {
+    use super::*;
+
+    #[test]
+    fn check_order_transitions() {
+        use OrderStatus::*;
+
+        assert!(Pending.can_become(Paid));
+        assert!(Pending.can_become(Cancelled));
+        assert!(Paid.can_become(Fulfilled));
+        assert!(Paid.can_become(Refunded));
+        assert!(Fulfilled.can_become(Refunded));
+
+        // No way back, and no refunds of what was never paid
+        assert!(!Paid.can_become(Pending));
+        assert!(!Paid.can_become(Cancelled));
+        assert!(!Pending.can_become(Refunded));
+        assert!(!Cancelled.can_become(Paid));
+        assert!(!Refunded.can_become(Fulfilled));
+        assert!(!Paid.can_become(Paid));
+
+        assert_eq!(serde_json::to_string(&Fulfilled).unwrap(), r#""fulfilled""#);
+    }
+
+    #[test]
+    fn check_refund_amounts() {
+        let refund = |amount: Option<&str>, refundable: i64| {
+            RefundIn {
+                amount: amount.map(str::to_string),
+            }
+            .validate(refundable)
+        };
+
+        assert_eq!(refund(None, 2500), Ok(2500));
+        assert_eq!(refund(Some(" "), 2500), Ok(2500));
+        assert_eq!(refund(Some("10.50"), 2500), Ok(1050));
+        assert_eq!(refund(Some("25"), 2500), Ok(2500));
+        assert!(refund(Some("25.01"), 2500).is_err());
+        assert!(refund(Some("0"), 2500).is_err());
+        assert!(refund(Some("-1"), 2500).is_err());
+        assert!(refund(None, 0).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::prelude::FromRow;
+
+use crate::modules::stripe::stripe::StripeCurrency;
+
+// Every supported currency has cents
+const PRICE_DECIMALS: u32 = 2;
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 5000;
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct Product {
+    pub product_id: i64,
+    #[serde(skip_serializing)]
+    pub shop_domain: String,
+    pub name: String,
+    pub description: Option<String>,
+    // In the smallest unit of the currency, like Stripe amounts. Sent as "12.50".
+    #[serde(serialize_with = "serialize_price")]
+    pub price: i64,
+    pub currency: String,
+    pub in_stock: bool,
+    pub created_on: Option<NaiveDateTime>,
+}
+
+// A product as sent by the API and forms
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProductIn {
+    pub name: String,
+    pub description: Option<String>,
+    // "12.50" or 12.5, JSON numbers are read as their text
+    #[serde(deserialize_with = "deserialize_price_text")]
+    pub price: String,
+    pub currency: String,
+    pub in_stock: Option<bool>,
+}
+impl ProductIn {
+    pub fn validate(&self) -> Result<NewProduct, &'static str> {
+        let name = self.name.trim();
+        let description = self
+            .description
+            .as_deref()
+            .map(str::trim)
+            .filter(|description| !description.is_empty());
+
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err("A name of at most 200 characters is required");
+        }
+        if description
+            .is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LENGTH)
+        {
+            return Err("The description is too long");
+        }
+        let currency = StripeCurrency::parse(&self.currency).ok_or("Unsupported currency")?;
+        let price = parse_price(&self.price)?;
+
+        Ok(NewProduct {
+            name: name.to_string(),
+            description: description.map(str::to_string),
+            price,
+            currency,
+            in_stock: self.in_stock.unwrap_or(true),
+        })
+    }
+}
+
+// A validated product, ready to be saved
+#[derive(Debug, Clone)]
+pub struct NewProduct {
+    pub name: String,
+    pub description: Option<String>,
+    pub price: i64,
+    pub currency: StripeCurrency,
+    pub in_stock: bool,
+}
+
+// "12.5" is 1250: non-negative, at most two decimals and no exponents or signs
+pub fn parse_price(text: &str) -> Result<i64, &'static str> {
+    const INVALID: &str = "The price must be a non-negative amount like 12.50";
+
+    let text = text.trim();
+    let (units, cents) = text.split_once('.').unwrap_or((text, ""));
+    if units.is_empty() && cents.is_empty() {
+        return Err(INVALID);
+    }
+    if !units.chars().all(|c| c.is_ascii_digit()) || !cents.chars().all(|c| c.is_ascii_digit()) {
+        return Err(INVALID);
+    }
+    if cents.len() > PRICE_DECIMALS as usize {
+        return Err("The price can have at most two decimals");
+    }
+
+    let scale = 10i64.pow(PRICE_DECIMALS);
+    let units: i64 = match units {
+        "" => 0,
+        units => units.parse().map_err(|_| "The price is too high")?,
+    };
+    let cents: i64 = format!("{:0<width$}", cents, width = PRICE_DECIMALS as usize)
+        .parse()
+        .map_err(|_| INVALID)?;
+
+    units
+        .checked_mul(scale)
+        .and_then(|amount| amount.checked_add(cents))
+        .ok_or("The price is too high")
+}
+
+// 1250 is "12.50"
+pub fn format_price(amount: i64) -> String {
+    let scale = 10i64.pow(PRICE_DECIMALS);
+    format!(
+        "{}.{:0width$}",
+        amount / scale,
+        amount % scale,
+        width = PRICE_DECIMALS as usize
+    )
+}
+
+fn serialize_price<S: Serializer>(amount: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_price(*amount))
+}
+
+fn deserialize_price_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PriceText {
+        Text(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(match PriceText::deserialize(deserializer)? {
+        PriceText::Text(text) => text,
+        PriceText::Number(number) => number.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod products_tests {
+    use super::*;
+
+    #[test]
+    fn check_price_parsing() {
+        assert_eq!(parse_price("12.50"), Ok(1250));
+        assert_eq!(parse_price(" 12.5 "), Ok(1250));
+        assert_eq!(parse_price("0"), Ok(0));
+        assert_eq!(parse_price(".99"), Ok(99));
+        assert_eq!(parse_price("7."), Ok(700));
+        assert_eq!(format_price(1250), "12.50");
+        assert_eq!(format_price(5), "0.05");
+
+        for invalid in [
+            "",
+            ".",
+            "-1",
+            "+1",
+            "1e3",
+            "12.345",
+            "1,50",
+            "abc",
+            "99999999999999999999",
+        ] {
+            assert!(parse_price(invalid).is_err(), "{} was accepted", invalid);
+        }
+
+        // JSON numbers and form strings both work
+        let product: ProductIn =
+            serde_json::from_str(r#"{"name":"Tea","price":4.2,"currency":"EUR"}"#).unwrap();
+        let product = product.validate().unwrap();
+        assert_eq!(product.price, 420);
+        assert_eq!(product.currency, StripeCurrency::EUR);
+        assert!(product.in_stock);
+
+        let product: ProductIn =
+            serde_urlencoded::from_str("name=Tea&price=4.20&currency=xyz").unwrap();
+        assert_eq!(product.validate().unwrap_err(), "Unsupported currency");
+    }
+}
@@ -1,3 +1,5 @@
+use crate::domain::subscriptions::{PlanFeature, ShopPlan};
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 // use uuid::Uuid;
@@ -7,10 +9,236 @@ pub struct ShopConfig {
     pub domain: String,
     pub name: String,
     pub product_type: String,
+    // The shop owner, None for shops run by the admins
+    pub user_id: Option<String>,
+    // Served on {slug}.PLATFORM_DOMAIN
+    pub slug: Option<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub theme: ShopTheme,
+}
+impl ShopConfig {
+    // The theme is only used while the plan of the shop includes it
+    pub fn to_shop(&self, plan: ShopPlan) -> Shop {
+        let theme = match plan.allows(PlanFeature::Themes) {
+            true => self.theme.clone(),
+            false => ShopTheme::default(),
+        };
+        Shop {
+            name: self.name.clone(),
+            product_type: self.product_type.clone(),
+            user_id: self.user_id.clone(),
+            slug: self.slug.clone(),
+            canonical_domain: None,
+            theme,
+            plan,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Shop {
     pub name: String,
     pub product_type: String,
+    pub user_id: Option<String>,
+    pub slug: Option<String>,
+    // The verified custom domain all pages redirect to, None for the shop domain itself
+    pub canonical_domain: Option<String>,
+    pub theme: ShopTheme,
+    pub plan: ShopPlan,
+}
+
+// How the pages of a shop look, unset values keep the base styling
+#[derive(Debug, Clone, Default, Deserialize, Serialize, FromRow)]
+pub struct ShopTheme {
+    pub primary_color: Option<String>,
+    pub background_color: Option<String>,
+    pub logo_url: Option<String>,
+}
+impl ShopTheme {
+    // Values end up in the style sheet and img tags of every page
+    pub fn validate(&self) -> Result<ShopTheme, &'static str> {
+        let primary_color = optional_field(&self.primary_color);
+        let background_color = optional_field(&self.background_color);
+        let logo_url = optional_field(&self.logo_url);
+
+        if [&primary_color, &background_color]
+            .iter()
+            .any(|color| color.as_deref().is_some_and(|color| !is_hex_color(color)))
+        {
+            return Err("Colors must look like #1a2b3c");
+        }
+        if logo_url.as_deref().is_some_and(|url| {
+            !(url.starts_with("https://") || (url.starts_with('/') && !url.starts_with("//")))
+                || url.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+        }) {
+            return Err("The logo must be an https or local URL");
+        }
+
+        Ok(ShopTheme {
+            primary_color,
+            background_color,
+            logo_url,
+        })
+    }
+}
+
+fn is_hex_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => {
+            (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+// Hosts arrive with ports, in any case and sometimes with www: "WWW.Shop.com:443" is "shop.com"
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim().to_lowercase();
+    let (host, _) = split_port(&host);
+    let host = host.trim_end_matches('.');
+    host.strip_prefix("www.").unwrap_or(host).to_string()
+}
+
+// Split "host:port", IPv6 literals keep their brackets
+pub fn split_port(host: &str) -> (&str, Option<&str>) {
+    let port_start = match host.rfind(']') {
+        Some(end) => host[end..].find(':').map(|index| end + index),
+        None => host.rfind(':'),
+    };
+    match port_start {
+        Some(index) => (&host[..index], Some(&host[index + 1..])),
+        None => (host, None),
+    }
+}
+
+// Slugs are a single DNS label
+pub fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 63
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty() && !domain.contains(|c: char| c.is_whitespace() || c == '/')
+}
+
+// A shop as sent by the admin API and forms
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShopConfigIn {
+    pub domain: String,
+    pub name: String,
+    pub product_type: String,
+    pub user_id: Option<String>,
+    pub slug: Option<String>,
+}
+impl ShopConfigIn {
+    // Domains are stored the way hosts are looked up, normalised
+    pub fn validate(&self) -> Result<ShopConfig, &'static str> {
+        let domain = normalize_host(&self.domain);
+        let user_id = optional_field(&self.user_id);
+        let slug = optional_field(&self.slug).map(|slug| slug.to_lowercase());
+
+        if !is_valid_domain(&domain) {
+            return Err("A valid domain is required");
+        }
+        if self.name.trim().is_empty() {
+            return Err("A name is required");
+        }
+        if slug.as_deref().is_some_and(|slug| !is_valid_slug(slug)) {
+            return Err("A slug may only contain lowercase letters, digits and dashes");
+        }
+
+        Ok(ShopConfig {
+            domain,
+            name: self.name.trim().to_string(),
+            product_type: self.product_type.trim().to_string(),
+            user_id,
+            slug,
+            theme: ShopTheme::default(),
+        })
+    }
+}
+
+fn optional_field(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+// Changes a shop owner may make to their own shop
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShopOwnerUpdate {
+    pub name: String,
+    pub product_type: String,
+}
+
+// A custom domain of a shop, served once its TXT record has been verified
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct ShopDomain {
+    pub domain: String,
+    pub shop_domain: String,
+    pub verification_token: String,
+    pub verified_on: Option<NaiveDateTime>,
+    pub is_canonical: bool,
+    pub created_on: Option<NaiveDateTime>,
+}
+
+// A custom domain as sent by a shop owner
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShopDomainIn {
+    pub domain: String,
+}
+impl ShopDomainIn {
+    pub fn validate(&self) -> Result<String, &'static str> {
+        let domain = normalize_host(&self.domain);
+        if !is_valid_domain(&domain) || !domain.contains('.') {
+            return Err("A valid domain is required");
+        }
+        Ok(domain)
+    }
+}
+
+#[cfg(test)]
+mod shops_tests {
+    use super::*;
+
+    #[test]
+    fn check_theme_validation() {
+        let theme = ShopTheme {
+            primary_color: Some(" #1A2b3c ".to_string()),
+            background_color: Some("".to_string()),
+            logo_url: Some("/static/logo.png".to_string()),
+        };
+        let valid = theme.validate().unwrap();
+        assert_eq!(valid.primary_color.as_deref(), Some("#1A2b3c"));
+        assert_eq!(valid.background_color, None);
+
+        // Values that could break out of the style sheet or point elsewhere are refused
+        for (color, logo_url) in [
+            ("red; } body { display: none", "/logo.png"),
+            ("#12345", "/logo.png"),
+            ("#123", "http://insecure.test/logo.png"),
+            ("#123", "//other.test/logo.png"),
+            ("#123", "javascript:alert(1)"),
+        ] {
+            let theme = ShopTheme {
+                primary_color: Some(color.to_string()),
+                background_color: None,
+                logo_url: Some(logo_url.to_string()),
+            };
+            assert!(
+                theme.validate().is_err(),
+                "{} {} was accepted",
+                color,
+                logo_url
+            );
+        }
+    }
 }
@@ -0,0 +1,35 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum StripeEventStatus {
+    // Waiting for the worker, also between retries
+    Pending,
+    Processed,
+    // Out of attempts, only a replay processes it again
+    Failed,
+}
+
+// A webhook event as Stripe sent it
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StripeEvent {
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: String,
+    pub status: StripeEventStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<NaiveDateTime>,
+    pub received_on: Option<NaiveDateTime>,
+    pub processed_on: Option<NaiveDateTime>,
+}
+impl StripeEvent {
+    // Each attempt waits twice as long as the one before, at most a day
+    pub fn retry_delay_seconds(&self, base_seconds: i64) -> i64 {
+        let factor = 1_i64 << self.attempts.clamp(0, 16);
+        base_seconds.saturating_mul(factor).min(24 * 60 * 60)
+    }
+}
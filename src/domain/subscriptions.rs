@@ -0,0 +1,140 @@
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+// The plans a shop can be on, ordered from the smallest to the largest
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum ShopPlan {
+    Free,
+    Starter,
+    Pro,
+}
+
+// What a plan unlocks for a shop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlanFeature {
+    Themes,
+    CustomDomains,
+}
+impl PlanFeature {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlanFeature::Themes => "themes",
+            PlanFeature::CustomDomains => "custom domains",
+        }
+    }
+}
+
+impl ShopPlan {
+    pub const ALL: [ShopPlan; 3] = [ShopPlan::Free, ShopPlan::Starter, ShopPlan::Pro];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShopPlan::Free => "free",
+            ShopPlan::Starter => "starter",
+            ShopPlan::Pro => "pro",
+        }
+    }
+
+    // None is unlimited
+    pub fn max_products(&self) -> Option<i64> {
+        match self {
+            ShopPlan::Free => Some(25),
+            ShopPlan::Starter => Some(500),
+            ShopPlan::Pro => None,
+        }
+    }
+
+    pub fn allows(&self, feature: PlanFeature) -> bool {
+        match feature {
+            PlanFeature::Themes => *self >= ShopPlan::Starter,
+            PlanFeature::CustomDomains => *self >= ShopPlan::Pro,
+        }
+    }
+
+    // Shops without an owner are run by the platform and get everything
+    pub fn for_shop(platform_shop: bool, plan: Option<ShopPlan>, status: Option<&str>) -> Self {
+        match (platform_shop, plan, status) {
+            (true, _, _) => ShopPlan::Pro,
+            (false, Some(plan), Some(status)) if is_active_status(status) => plan,
+            _ => ShopPlan::Free,
+        }
+    }
+}
+
+// Stripe statuses that keep the plan running, past_due while Stripe retries the payment
+pub fn is_active_status(status: &str) -> bool {
+    matches!(status, "active" | "trialing" | "past_due")
+}
+
+// The Stripe subscription of a shop, mirrored from the customer.subscription.* events
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ShopSubscription {
+    pub shop_domain: String,
+    pub plan: ShopPlan,
+    // As Stripe names it: active, past_due, canceled, ...
+    pub status: String,
+    #[serde(skip_serializing)]
+    pub stripe_customer_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub stripe_subscription_id: Option<String>,
+    pub current_period_end: Option<NaiveDateTime>,
+    pub cancel_at_period_end: bool,
+    // Unix time of the Stripe data, older data never replaces newer
+    #[serde(skip_serializing)]
+    pub synced_at: i64,
+}
+impl ShopSubscription {
+    // The plan the shop gets, ended subscriptions fall back to free
+    pub fn active_plan(&self) -> ShopPlan {
+        match is_active_status(&self.status) {
+            true => self.plan,
+            false => ShopPlan::Free,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlanChangeIn {
+    pub plan: ShopPlan,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CancelSubscriptionIn {
+    // By default the shop keeps its plan until the end of the paid period
+    pub immediately: Option<bool>,
+}
+
+#[cfg(test)]
+mod subscriptions_tests {
+    use super::*;
+
+    #[test]
+    fn check_plan_features() {
+        assert!(ShopPlan::Free < ShopPlan::Starter && ShopPlan::Starter < ShopPlan::Pro);
+        assert!(!ShopPlan::Free.allows(PlanFeature::Themes));
+        assert!(ShopPlan::Starter.allows(PlanFeature::Themes));
+        assert!(!ShopPlan::Starter.allows(PlanFeature::CustomDomains));
+        assert!(ShopPlan::Pro.allows(PlanFeature::CustomDomains));
+        assert_eq!(ShopPlan::Pro.max_products(), None);
+
+        let mut subscription = ShopSubscription {
+            shop_domain: "a.test".to_string(),
+            plan: ShopPlan::Pro,
+            status: "past_due".to_string(),
+            stripe_customer_id: None,
+            stripe_subscription_id: None,
+            current_period_end: None,
+            cancel_at_period_end: false,
+            synced_at: 0,
+        };
+        assert_eq!(subscription.active_plan(), ShopPlan::Pro);
+        subscription.status = "canceled".to_string();
+        assert_eq!(subscription.active_plan(), ShopPlan::Free);
+
+        let change: PlanChangeIn = serde_json::from_str(r#"{"plan":"starter"}"#).unwrap();
+        assert_eq!(change.plan, ShopPlan::Starter);
+    }
+}
@@ -3,25 +3,51 @@ pub mod controllers {
         pub mod index;
         pub mod login;
     }
+    pub mod api_keys;
+    pub mod cart;
+    pub mod disputes;
     pub mod login;
+    pub mod oidc;
+    pub mod orders;
+    pub mod payouts;
+    pub mod products;
+    pub mod sessions;
+    pub mod shops;
+    pub mod subscriptions;
+    pub mod two_factor;
     pub mod user;
+    pub mod verification;
 }
 
 pub mod db {
     pub mod db_setup;
     pub mod diesel;
     pub mod sqlite;
+    pub mod tenant;
 }
 
 pub mod domain {
+    pub mod cart;
+    pub mod connect_accounts;
     pub mod datatypes;
+    pub mod disputes;
+    pub mod inventory;
+    pub mod orders;
+    pub mod products;
     pub mod shops;
+    pub mod stripe_events;
+    pub mod subscriptions;
     pub mod user_domain;
 }
 
 pub mod routes {
+    pub mod api_key_routes;
     pub mod app_routes;
+    pub mod cart_routes;
+    pub mod order_routes;
+    pub mod product_routes;
     pub mod root_routes;
+    pub mod shop_routes;
     pub mod ui_routes;
     pub mod users_routes;
 }
@@ -33,21 +59,34 @@ pub mod models {
 }
 
 pub mod modules {
+    pub mod api_key;
     pub mod aws_s3;
+    pub mod cart;
     pub mod cookie;
     pub mod cuid;
+    pub mod domain_verification;
     pub mod email;
+    pub mod login_throttle;
     pub mod middleware;
+    pub mod middleware_csrf;
     pub mod middleware_domain;
     pub mod middleware_msg;
+    pub mod oidc;
     pub mod password_hash;
+    pub mod password_policy;
     pub mod pdf;
     pub mod redis;
+    pub mod session;
+    pub mod shop_registry;
     pub mod stripe {
+        pub mod connect;
+        pub mod refunds;
         pub mod stripe;
         pub mod stripe_webhooks;
+        pub mod subscriptions;
     }
     pub mod token_pub;
+    pub mod two_factor;
 }
 
 pub mod utils {
@@ -1,25 +1,27 @@
-use crate::utils::constants::SHOP_CONFIGS;
 use actix_web::{
     get, middleware::Logger, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use env_logger::Env;
 use lib::{
     db::sqlite::SqliteDB,
-    domain::shops::Shop,
     models::schema::create_schema,
     modules::{
         middleware,
+        middleware_csrf::CsrfProtection,
         middleware_domain::AddShopDomain, // middleware_domain::ShopLoader
         middleware_msg::AddMsg,
         redis::RedisDB,
-        stripe::stripe_webhooks::handle_webhook,
+        shop_registry,
+        stripe::stripe_webhooks,
+    },
+    routes::{
+        api_key_routes, app_routes, cart_routes, order_routes, product_routes, root_routes,
+        shop_routes, ui_routes, users_routes,
     },
-    routes::{app_routes, root_routes, ui_routes, users_routes},
-    utils,
     utils::constants::Config,
+    view,
 };
 use serde::Serialize;
-use sqlx::migrate::MigrateDatabase;
 
 // #[macro_use]
 // extern crate diesel_migrations;
@@ -39,34 +41,12 @@ async fn health() -> impl Responder {
 }
 
 #[post("/stripe_webhooks")]
-async fn webhook_handler(req: HttpRequest, payload: web::Bytes) -> HttpResponse {
-    handle_webhook(req, payload).unwrap();
-    HttpResponse::Ok().finish()
-}
-
-async fn load_shop_configs(database_url: &str) -> Result<(), sqlx::Error> {
-    let pool = SqliteDB::new(database_url).await;
-    let mut configs = SHOP_CONFIGS.lock().unwrap();
-    let shops = SqliteDB::get_all_shop_domains(&pool).await;
-
-    match shops {
-        Ok(shops) => {
-            for shop in shops {
-                configs.insert(
-                    shop.domain.to_string(),
-                    Shop {
-                        name: shop.name,
-                        product_type: shop.product_type,
-                    },
-                );
-            }
-            Ok(())
-        }
-        Err(e) => {
-            eprintln!("Failed to load shop configurations: {}", e);
-            return Err(e);
-        }
-    }
+async fn webhook_handler(
+    req: HttpRequest,
+    payload: web::Bytes,
+    db: web::Data<SqliteDB>,
+) -> HttpResponse {
+    stripe_webhooks::handle_webhook(req, payload, &db).await
 }
 
 #[actix_web::main]
@@ -82,18 +62,26 @@ async fn main() -> std::io::Result<()> {
     // let app_data_pg = web::Data::new(db_connection);
 
     // Setup Database Connection for SQLX
-    if !sqlx::Sqlite::database_exists(&config.sqlx_database_url)
-        .await
-        .expect("Something wrong 1")
-    {
-        if create_schema(&config.sqlx_database_url).await.is_ok() {
-            log::info!("Database created Sucessfully");
-        } else {
-            log::warn!("Failed to create database schema");
-            panic!();
-        }
+    // Existing databases are brought up to date as well
+    if let Err(err) = create_schema(&config.sqlx_database_url).await {
+        log::warn!("Failed to migrate database schema: {}", err);
+        panic!();
     }
+    log::info!("Database schema is up to date");
     let database_sqlx = SqliteDB::new(&config.sqlx_database_url).await;
+
+    // `backend replay-stripe-events [event_id]` retries failed webhook events and exits
+    if std::env::args().nth(1).as_deref() == Some("replay-stripe-events") {
+        let event_id = std::env::args().nth(2);
+        let shop_updates = match redis::Client::open(config.redis_url.as_str()) {
+            Ok(client) => client,
+            Err(e) => panic!("Invalid Redis URL: {}", e),
+        };
+        stripe_webhooks::replay_failed_events(&database_sqlx, &shop_updates, event_id.as_deref())
+            .await;
+        return Ok(());
+    }
+
     let app_data_sqlx = web::Data::new(database_sqlx);
     log::info!(
         "Sqlx Database pool created Sucessfully at {}",
@@ -114,28 +102,24 @@ async fn main() -> std::io::Result<()> {
     let app_data_redis = web::Data::new(redis_db);
     log::info!("Redis connection Sucessfull at {}", &&config.redis_url);
 
-    load_shop_configs(&config.sqlx_database_url)
-        .await
-        .expect("Failed to load shop configurations");
-
-    {
-        // Should go in Redis - need to connect user to shop
-        let mut shop_cfg = SHOP_CONFIGS.lock().unwrap();
-        shop_cfg.insert(
-            "localhost:3000".to_string(),
-            Shop {
-                name: "Localhost Shop".to_string(),
-                product_type: "Localhost Product".to_string(),
-            },
-        );
-        shop_cfg.insert(
-            "honeydragons.com".to_string(),
-            Shop {
-                name: "honeydragons Shop".to_string(),
-                product_type: "Fitness Products".to_string(),
-            },
-        );
+    // Shops live in the database, changes by any instance arrive through Redis
+    if let Err(e) = shop_registry::load_shop_configs(&app_data_sqlx).await {
+        eprintln!("Failed to load shop configurations: {}", e);
+        panic!("Failed to load shop configurations");
     }
+    actix_web::rt::spawn(shop_registry::listen_for_shop_changes(
+        app_data_redis.get_client(),
+        app_data_sqlx.get_ref().clone(),
+    ));
+
+    // Changed template overrides of the shops are picked up in the background
+    actix_web::rt::spawn(view::setup::watch_theme_changes());
+
+    // Stored Stripe events are processed and retried in the background
+    actix_web::rt::spawn(stripe_webhooks::run_event_worker(
+        app_data_sqlx.get_ref().clone(),
+        app_data_redis.get_client(),
+    ));
 
     // Log the server start
     log::info!("Starting HTTP server at http://localhost:{}", &config.port);
@@ -147,13 +131,19 @@ async fn main() -> std::io::Result<()> {
             .app_data(app_data_redis.clone())
             .wrap(Logger::default())
             .wrap(AddMsg::enabled()) // Test middleware
-            .wrap(AddShopDomain::enabled())
+            .wrap(CsrfProtection::enabled().exempt(&["/stripe_webhooks"]))
+            .wrap(AddShopDomain::enabled().exempt(&["/health", "/stripe_webhooks"]))
             .wrap(middleware::CheckLogin::disabled())
             .service(health)
             .service(webhook_handler)
             .configure(app_routes::app_config)
             .configure(ui_routes::ui_config)
             .configure(users_routes::users_config)
+            .configure(api_key_routes::api_keys_config)
+            .configure(shop_routes::shops_config)
+            .configure(product_routes::products_config)
+            .configure(cart_routes::cart_config)
+            .configure(order_routes::orders_config)
             .configure(root_routes::root_config)
             .service(root_routes::root::index_page)
     })
@@ -2,9 +2,11 @@ pub enum UserQueries {
     CreateOneUser,
     GetOneUser,
     GetOneUserWithUsername,
+    GetOneUserWithEmail,
     GetAllUsers,
     UpdateOneUser,
     UpdateOneUserPwd,
+    VerifyOneUser,
     DeleteOneUser,
 }
 
@@ -12,37 +14,513 @@ impl UserQueries {
     pub fn convert_to_str(&self) -> &'static str {
         match self {
             UserQueries::CreateOneUser => {
-                "INSERT INTO users (user_id, username, hashed_password, active) VALUES (?, ?, ?, ?)"
+                "INSERT INTO users (user_id, username, email, hashed_password, active, role, shop_domain) VALUES (?, ?, ?, ?, ?, ?, ?)"
             }
-            UserQueries::GetOneUser => "SELECT * FROM users WHERE user_id = ?",
-            UserQueries::GetOneUserWithUsername => "SELECT * FROM users WHERE username = ?",
-            UserQueries::GetAllUsers => "SELECT * FROM users",
+            UserQueries::GetOneUser => {
+                "SELECT * FROM users WHERE user_id = ? AND shop_domain = ?"
+            }
+            UserQueries::GetOneUserWithUsername => {
+                "SELECT * FROM users WHERE username = ? AND shop_domain = ?"
+            }
+            UserQueries::GetOneUserWithEmail => {
+                "SELECT * FROM users WHERE email = ? AND shop_domain = ?"
+            }
+            UserQueries::GetAllUsers => "SELECT * FROM users WHERE shop_domain = ?",
             UserQueries::UpdateOneUser => {
-                "UPDATE users SET username = ?, hashed_password = ?, active = ? WHERE user_id = ?"
+                "UPDATE users SET username = ?, email = ?, hashed_password = ?, active = ?, role = ? WHERE user_id = ? AND shop_domain = ?"
             }
             UserQueries::UpdateOneUserPwd => {
-                "UPDATE users SET hashed_password = ? WHERE user_id = ?"
+                "UPDATE users SET hashed_password = ? WHERE user_id = ? AND shop_domain = ?"
+            }
+            UserQueries::VerifyOneUser => {
+                "UPDATE users SET verified_at = datetime('now','localtime') WHERE user_id = ? AND shop_domain = ?"
+            }
+            UserQueries::DeleteOneUser => {
+                "DELETE FROM users WHERE user_id = ? AND shop_domain = ?"
+            }
+        }
+    }
+}
+
+pub enum ProductQueries {
+    GetPage,
+    CountAll,
+    GetOne,
+    CreateOne,
+    UpdateOne,
+    DeleteOne,
+}
+impl ProductQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            ProductQueries::GetPage => {
+                "SELECT * FROM products WHERE shop_domain = ? ORDER BY product_id LIMIT ? OFFSET ?"
+            }
+            ProductQueries::CountAll => "SELECT COUNT(*) FROM products WHERE shop_domain = ?",
+            ProductQueries::GetOne => {
+                "SELECT * FROM products WHERE product_id = ? AND shop_domain = ?"
+            }
+            ProductQueries::CreateOne => {
+                "INSERT INTO products (name, description, price, currency, in_stock, shop_domain) VALUES (?, ?, ?, ?, ?, ?) RETURNING *"
+            }
+            ProductQueries::UpdateOne => {
+                "UPDATE products SET name = ?, description = ?, price = ?, currency = ?, in_stock = ? WHERE product_id = ? AND shop_domain = ? RETURNING *"
+            }
+            ProductQueries::DeleteOne => {
+                "DELETE FROM products WHERE product_id = ? AND shop_domain = ?"
+            }
+        }
+    }
+}
+
+pub enum VariantQueries {
+    GetForProduct,
+    GetOne,
+    CreateOne,
+    UpdateOne,
+    DeleteOne,
+}
+impl VariantQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            VariantQueries::GetForProduct => {
+                "SELECT * FROM variant_stock WHERE product_id = ? AND shop_domain = ? ORDER BY variant_id"
+            }
+            VariantQueries::GetOne => {
+                "SELECT * FROM variant_stock WHERE variant_id = ? AND product_id = ? AND shop_domain = ?"
+            }
+            // Only for a product of the same shop
+            VariantQueries::CreateOne => {
+                "INSERT INTO product_variants (product_id, shop_domain, sku, attributes, price) SELECT product_id, shop_domain, ?, ?, ? FROM products WHERE product_id = ? AND shop_domain = ? RETURNING variant_id"
+            }
+            VariantQueries::UpdateOne => {
+                "UPDATE product_variants SET sku = ?, attributes = ?, price = ? WHERE variant_id = ? AND product_id = ? AND shop_domain = ?"
+            }
+            VariantQueries::DeleteOne => {
+                "DELETE FROM product_variants WHERE variant_id = ? AND product_id = ? AND shop_domain = ?"
+            }
+        }
+    }
+}
+
+pub enum StockQueries {
+    AdjustStock,
+    Sell,
+    CreateMovement,
+    GetMovements,
+    CountMovements,
+    Reserve,
+    ReleaseSession,
+    CompleteSession,
+    MoveReservations,
+}
+impl StockQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            // The stock of the shop never goes below zero
+            StockQueries::AdjustStock => {
+                "UPDATE product_variants SET stock = stock + ? WHERE variant_id = ? AND product_id = ? AND shop_domain = ? AND stock + ? >= 0"
+            }
+            // A paid checkout is always booked, even when it oversells
+            StockQueries::Sell => {
+                "UPDATE product_variants SET stock = stock - ? WHERE variant_id = ? AND shop_domain = ?"
+            }
+            StockQueries::CreateMovement => {
+                "INSERT INTO stock_movements (variant_id, shop_domain, change, reason, reference, user_id) VALUES (?, ?, ?, ?, ?, ?)"
+            }
+            StockQueries::GetMovements => {
+                "SELECT m.* FROM stock_movements m JOIN product_variants v ON v.variant_id = m.variant_id WHERE m.variant_id = ? AND v.product_id = ? AND m.shop_domain = ? ORDER BY m.movement_id DESC LIMIT ? OFFSET ?"
+            }
+            StockQueries::CountMovements => {
+                "SELECT COUNT(*) FROM stock_movements m JOIN product_variants v ON v.variant_id = m.variant_id WHERE m.variant_id = ? AND v.product_id = ? AND m.shop_domain = ?"
+            }
+            // Nothing is inserted when the variant does not have enough left
+            StockQueries::Reserve => {
+                "INSERT INTO stock_reservations (variant_id, shop_domain, quantity, checkout_session_id, expires_at) SELECT variant_id, shop_domain, ?, ?, ? FROM variant_stock WHERE variant_id = ? AND shop_domain = ? AND available >= ?"
+            }
+            StockQueries::ReleaseSession => {
+                "UPDATE stock_reservations SET released_on = CURRENT_TIMESTAMP WHERE checkout_session_id = ? AND released_on IS NULL AND completed_on IS NULL"
+            }
+            StockQueries::CompleteSession => {
+                "UPDATE stock_reservations SET completed_on = CURRENT_TIMESTAMP WHERE checkout_session_id = ? AND released_on IS NULL AND completed_on IS NULL RETURNING variant_id, shop_domain, quantity"
+            }
+            StockQueries::MoveReservations => {
+                "UPDATE stock_reservations SET checkout_session_id = ? WHERE checkout_session_id = ? AND shop_domain = ?"
+            }
+        }
+    }
+}
+
+pub enum OrderQueries {
+    CreateOne,
+    CreateItem,
+    GetPageForUser,
+    CountForUser,
+    GetOneForUser,
+    GetPendingForUser,
+    GetOne,
+    GetItems,
+    GetBySession,
+    SetCheckoutSession,
+    UpdateStatus,
+    GetByPaymentIntent,
+    SetRefunded,
+    SetDisputed,
+}
+impl OrderQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            OrderQueries::CreateOne => {
+                "INSERT INTO orders (shop_domain, user_id, currency, total, customer_email) VALUES (?, ?, ?, ?, ?) RETURNING *"
+            }
+            OrderQueries::CreateItem => {
+                "INSERT INTO order_items (order_id, product_id, variant_id, name, sku, quantity, unit_price, line_total) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            }
+            OrderQueries::GetPageForUser => {
+                "SELECT * FROM orders WHERE user_id = ? AND shop_domain = ? ORDER BY order_id DESC LIMIT ? OFFSET ?"
+            }
+            OrderQueries::CountForUser => {
+                "SELECT COUNT(*) FROM orders WHERE user_id = ? AND shop_domain = ?"
+            }
+            OrderQueries::GetOneForUser => {
+                "SELECT * FROM orders WHERE order_id = ? AND user_id = ? AND shop_domain = ?"
+            }
+            OrderQueries::GetPendingForUser => {
+                "SELECT * FROM orders WHERE user_id = ? AND shop_domain = ? AND status = 'pending'"
+            }
+            OrderQueries::GetOne => "SELECT * FROM orders WHERE order_id = ? AND shop_domain = ?",
+            OrderQueries::GetItems => {
+                "SELECT * FROM order_items WHERE order_id = ? ORDER BY order_item_id"
+            }
+            OrderQueries::GetBySession => "SELECT * FROM orders WHERE checkout_session_id = ?",
+            OrderQueries::SetCheckoutSession => {
+                "UPDATE orders SET checkout_session_id = ?, updated_on = CURRENT_TIMESTAMP WHERE order_id = ? AND shop_domain = ?"
+            }
+            // Only from the status the caller saw, so two events cannot both move the order
+            OrderQueries::UpdateStatus => {
+                "UPDATE orders SET status = ?, payment_intent_id = COALESCE(?, payment_intent_id), updated_on = CURRENT_TIMESTAMP WHERE order_id = ? AND status = ?"
+            }
+            OrderQueries::GetByPaymentIntent => "SELECT * FROM orders WHERE payment_intent_id = ?",
+            // The refunded amount only grows, so late or repeated events change nothing
+            OrderQueries::SetRefunded => {
+                "UPDATE orders SET refunded_amount = MIN(?, total), status = CASE WHEN ? + disputed_amount >= total THEN 'refunded' ELSE status END, updated_on = CURRENT_TIMESTAMP
+                WHERE order_id = ? AND status IN ('paid', 'fulfilled') AND refunded_amount < ?"
+            }
+            // Summed up from the stored disputes, so the same event twice adds nothing
+            OrderQueries::SetDisputed => {
+                "UPDATE orders SET disputed_amount = lost.amount, status = CASE WHEN status IN ('paid', 'fulfilled') AND refunded_amount + lost.amount >= total THEN 'refunded' ELSE status END, updated_on = CURRENT_TIMESTAMP
+                FROM (SELECT COALESCE(SUM(amount), 0) AS amount FROM order_disputes WHERE order_id = ? AND status = 'lost') AS lost
+                WHERE order_id = ? AND disputed_amount != lost.amount"
+            }
+        }
+    }
+}
+
+pub enum DisputeQueries {
+    Upsert,
+    GetPage,
+    Count,
+}
+impl DisputeQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            DisputeQueries::Upsert => {
+                "INSERT INTO order_disputes (dispute_id, shop_domain, order_id, amount, currency, reason, status, evidence_due_by, synced_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (dispute_id) DO UPDATE SET amount = excluded.amount, reason = excluded.reason, status = excluded.status, evidence_due_by = excluded.evidence_due_by, synced_at = excluded.synced_at, updated_on = CURRENT_TIMESTAMP
+                WHERE excluded.synced_at >= order_disputes.synced_at"
+            }
+            // Disputes waiting for evidence first, the closest due date on top
+            DisputeQueries::GetPage => {
+                "SELECT dispute_id, shop_domain, order_id, amount, currency, reason, status, evidence_due_by, synced_at, created_on FROM order_disputes WHERE shop_domain = ?
+                ORDER BY status IN ('needs_response', 'warning_needs_response') DESC, evidence_due_by IS NULL, evidence_due_by, created_on DESC LIMIT ? OFFSET ?"
+            }
+            DisputeQueries::Count => "SELECT COUNT(*) FROM order_disputes WHERE shop_domain = ?",
+        }
+    }
+}
+
+pub enum StripeEventQueries {
+    CreateOne,
+    GetDue,
+    Claim,
+    SetProcessed,
+    SetFailed,
+    ReplayFailed,
+    ReplayOne,
+}
+
+impl StripeEventQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            StripeEventQueries::CreateOne => {
+                "INSERT OR IGNORE INTO stripe_events (event_id, event_type, payload) VALUES (?, ?, ?)"
+            }
+            StripeEventQueries::GetDue => {
+                "SELECT * FROM stripe_events WHERE status = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP ORDER BY received_on LIMIT ?"
+            }
+            StripeEventQueries::Claim => {
+                "UPDATE stripe_events SET attempts = attempts + 1, next_attempt_at = DATETIME('now', ?) WHERE event_id = ? AND status = 'pending' AND attempts = ?"
+            }
+            StripeEventQueries::SetProcessed => {
+                "UPDATE stripe_events SET status = 'processed', last_error = NULL, processed_on = CURRENT_TIMESTAMP WHERE event_id = ?"
+            }
+            StripeEventQueries::SetFailed => {
+                "UPDATE stripe_events SET status = ?, last_error = ? WHERE event_id = ?"
+            }
+            StripeEventQueries::ReplayFailed => {
+                "UPDATE stripe_events SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP WHERE status = 'failed'"
+            }
+            StripeEventQueries::ReplayOne => {
+                "UPDATE stripe_events SET status = 'pending', attempts = 0, next_attempt_at = CURRENT_TIMESTAMP WHERE status = 'failed' AND event_id = ?"
             }
-            UserQueries::DeleteOneUser => "DELETE FROM users WHERE user_id = ?",
         }
     }
 }
 
 pub enum ShopQueries {
     GetAllShops,
+    GetUserShops,
     CreateOneShop,
     GetOneShop,
     UpdateOneShop,
+    UpdateShopTheme,
     DeleteOneShop,
 }
 impl ShopQueries {
     pub fn convert_to_str(&self) -> &'static str {
         match self {
             ShopQueries::GetAllShops => {
-                "SELECT domain, name, product_type FROM shop_configurations"
+                "SELECT domain, name, product_type, user_id, slug, primary_color, background_color, logo_url FROM shop_configurations ORDER BY domain"
+            }
+            ShopQueries::GetUserShops => {
+                "SELECT domain, name, product_type, user_id, slug, primary_color, background_color, logo_url FROM shop_configurations WHERE user_id = ? ORDER BY domain"
+            }
+            ShopQueries::CreateOneShop => {
+                "INSERT INTO shop_configurations (domain, name, product_type, user_id, slug) VALUES (?, ?, ?, ?, ?) RETURNING domain, name, product_type, user_id, slug, primary_color, background_color, logo_url"
+            }
+            ShopQueries::GetOneShop => {
+                "SELECT domain, name, product_type, user_id, slug, primary_color, background_color, logo_url FROM shop_configurations WHERE domain = ?"
+            }
+            ShopQueries::UpdateOneShop => {
+                "UPDATE shop_configurations SET name = ?, product_type = ?, user_id = ?, slug = ? WHERE domain = ? RETURNING domain, name, product_type, user_id, slug, primary_color, background_color, logo_url"
+            }
+            ShopQueries::UpdateShopTheme => {
+                "UPDATE shop_configurations SET primary_color = ?, background_color = ?, logo_url = ? WHERE domain = ? RETURNING domain, name, product_type, user_id, slug, primary_color, background_color, logo_url"
+            }
+            ShopQueries::DeleteOneShop => "DELETE FROM shop_configurations WHERE domain = ?",
+        }
+    }
+}
+
+pub enum SubscriptionQueries {
+    GetOne,
+    GetPlan,
+    GetAllPlans,
+    Upsert,
+}
+impl SubscriptionQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            SubscriptionQueries::GetOne => {
+                "SELECT shop_domain, plan, status, stripe_customer_id, stripe_subscription_id, current_period_end, cancel_at_period_end, synced_at FROM shop_subscriptions WHERE shop_domain = ?"
+            }
+            SubscriptionQueries::GetPlan => {
+                "SELECT c.user_id IS NULL, s.plan, s.status FROM shop_configurations c LEFT JOIN shop_subscriptions s ON s.shop_domain = c.domain WHERE c.domain = ?"
+            }
+            SubscriptionQueries::GetAllPlans => {
+                "SELECT c.domain, c.user_id IS NULL, s.plan, s.status FROM shop_configurations c LEFT JOIN shop_subscriptions s ON s.shop_domain = c.domain"
+            }
+            // Older data and other subscriptions of a shop that is still subscribed are ignored
+            SubscriptionQueries::Upsert => {
+                "INSERT INTO shop_subscriptions (shop_domain, plan, status, stripe_customer_id, stripe_subscription_id, current_period_end, cancel_at_period_end, synced_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (shop_domain) DO UPDATE SET plan = excluded.plan, status = excluded.status, stripe_customer_id = excluded.stripe_customer_id, stripe_subscription_id = excluded.stripe_subscription_id, current_period_end = excluded.current_period_end, cancel_at_period_end = excluded.cancel_at_period_end, synced_at = excluded.synced_at, updated_on = CURRENT_TIMESTAMP
+                WHERE excluded.synced_at >= shop_subscriptions.synced_at
+                AND (shop_subscriptions.stripe_subscription_id IS excluded.stripe_subscription_id OR shop_subscriptions.status NOT IN ('active', 'trialing', 'past_due'))"
+            }
+        }
+    }
+}
+
+pub enum ConnectAccountQueries {
+    GetOne,
+    GetDestination,
+    CreateOne,
+    UpdateStatus,
+}
+impl ConnectAccountQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            ConnectAccountQueries::GetOne => {
+                "SELECT shop_domain, stripe_account_id, charges_enabled, payouts_enabled, details_submitted, synced_at FROM shop_connect_accounts WHERE shop_domain = ?"
+            }
+            ConnectAccountQueries::GetDestination => {
+                "SELECT c.user_id IS NULL, a.stripe_account_id, a.charges_enabled FROM shop_configurations c LEFT JOIN shop_connect_accounts a ON a.shop_domain = c.domain WHERE c.domain = ?"
+            }
+            // A shop keeps the first account it was given
+            ConnectAccountQueries::CreateOne => {
+                "INSERT INTO shop_connect_accounts (shop_domain, stripe_account_id) VALUES (?, ?) ON CONFLICT (shop_domain) DO NOTHING"
+            }
+            ConnectAccountQueries::UpdateStatus => {
+                "UPDATE shop_connect_accounts SET charges_enabled = ?, payouts_enabled = ?, details_submitted = ?, synced_at = ?, updated_on = CURRENT_TIMESTAMP WHERE stripe_account_id = ? AND synced_at <= ?"
+            }
+        }
+    }
+}
+
+pub enum ShopDomainQueries {
+    GetVerifiedDomains,
+    GetShopDomains,
+    GetOneDomain,
+    CreateDomain,
+    VerifyDomain,
+    ClearCanonical,
+    SetCanonical,
+    DeleteDomain,
+}
+impl ShopDomainQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            ShopDomainQueries::GetVerifiedDomains => {
+                "SELECT * FROM shop_domains WHERE verified_on IS NOT NULL"
+            }
+            ShopDomainQueries::GetShopDomains => {
+                "SELECT * FROM shop_domains WHERE shop_domain = ? ORDER BY domain"
+            }
+            ShopDomainQueries::GetOneDomain => {
+                "SELECT * FROM shop_domains WHERE domain = ? AND shop_domain = ?"
+            }
+            ShopDomainQueries::CreateDomain => {
+                "INSERT INTO shop_domains (domain, shop_domain, verification_token) VALUES (?, ?, ?) RETURNING *"
+            }
+            ShopDomainQueries::VerifyDomain => {
+                "UPDATE shop_domains SET verified_on = CURRENT_TIMESTAMP WHERE domain = ? AND shop_domain = ? RETURNING *"
+            }
+            ShopDomainQueries::ClearCanonical => {
+                "UPDATE shop_domains SET is_canonical = FALSE WHERE shop_domain = ?"
+            }
+            ShopDomainQueries::SetCanonical => {
+                "UPDATE shop_domains SET is_canonical = TRUE WHERE domain = ? AND shop_domain = ? AND verified_on IS NOT NULL"
+            }
+            ShopDomainQueries::DeleteDomain => {
+                "DELETE FROM shop_domains WHERE domain = ? AND shop_domain = ?"
+            }
+        }
+    }
+}
+
+pub enum AuditQueries {
+    CreateOneEntry,
+}
+impl AuditQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            AuditQueries::CreateOneEntry => {
+                "INSERT INTO audit_log (shop_domain, event, subject, ip_address) VALUES (?, ?, ?, ?)"
+            }
+        }
+    }
+}
+
+pub enum IdentityQueries {
+    GetUserId,
+    CreateIdentity,
+}
+impl IdentityQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            IdentityQueries::GetUserId => {
+                "SELECT user_id FROM user_identities WHERE provider = ? AND subject = ? AND shop_domain = ?"
+            }
+            IdentityQueries::CreateIdentity => {
+                "INSERT INTO user_identities (user_id, provider, subject, email, shop_domain) VALUES (?, ?, ?, ?, ?)"
+            }
+        }
+    }
+}
+
+pub enum ApiKeyQueries {
+    CreateKey,
+    GetByPrefix,
+    GetWithOwnerByPrefix,
+    GetAllForShop,
+    GetAllForUser,
+    RevokeKey,
+    RevokeUserKey,
+    TouchLastUsed,
+}
+impl ApiKeyQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            ApiKeyQueries::CreateKey => {
+                "INSERT INTO api_keys (shop_domain, user_id, name, prefix, hashed_key, scopes) VALUES (?, ?, ?, ?, ?, ?) RETURNING *"
+            }
+            ApiKeyQueries::GetByPrefix => "SELECT * FROM api_keys WHERE prefix = ?",
+            ApiKeyQueries::GetWithOwnerByPrefix => {
+                "SELECT api_keys.*, users.active AS owner_active, users.role AS owner_role FROM api_keys JOIN users ON users.user_id = api_keys.user_id AND users.shop_domain = api_keys.shop_domain WHERE api_keys.prefix = ?"
+            }
+            ApiKeyQueries::GetAllForShop => {
+                "SELECT * FROM api_keys WHERE shop_domain = ? ORDER BY key_id"
+            }
+            ApiKeyQueries::GetAllForUser => {
+                "SELECT * FROM api_keys WHERE shop_domain = ? AND user_id = ? ORDER BY key_id"
+            }
+            ApiKeyQueries::RevokeKey => {
+                "UPDATE api_keys SET revoked_on = datetime('now','localtime') WHERE key_id = ? AND shop_domain = ? AND revoked_on IS NULL"
+            }
+            ApiKeyQueries::RevokeUserKey => {
+                "UPDATE api_keys SET revoked_on = datetime('now','localtime') WHERE key_id = ? AND shop_domain = ? AND user_id = ? AND revoked_on IS NULL"
+            }
+            ApiKeyQueries::TouchLastUsed => {
+                "UPDATE api_keys SET last_used_on = datetime('now','localtime') WHERE key_id = ?"
+            }
+        }
+    }
+}
+
+pub enum TwoFactorQueries {
+    GetSecret,
+    SetSecret,
+    DeleteSecret,
+    GetUnusedRecoveryCodes,
+    CreateRecoveryCode,
+    UseRecoveryCode,
+    DeleteRecoveryCodes,
+}
+impl TwoFactorQueries {
+    pub fn convert_to_str(&self) -> &'static str {
+        match self {
+            // The user has to belong to the shop
+            TwoFactorQueries::GetSecret => {
+                "SELECT t.encrypted_secret FROM two_factor t
+                JOIN users u ON u.user_id = t.user_id
+                WHERE t.user_id = ? AND u.shop_domain = ?"
+            }
+            TwoFactorQueries::SetSecret => {
+                "INSERT OR REPLACE INTO two_factor (user_id, encrypted_secret)
+                SELECT user_id, ? FROM users WHERE user_id = ? AND shop_domain = ?"
+            }
+            TwoFactorQueries::DeleteSecret => {
+                "DELETE FROM two_factor
+                WHERE user_id IN (SELECT user_id FROM users WHERE user_id = ? AND shop_domain = ?)"
+            }
+            TwoFactorQueries::GetUnusedRecoveryCodes => {
+                "SELECT r.code_id, r.hashed_code FROM recovery_codes r
+                JOIN users u ON u.user_id = r.user_id
+                WHERE r.user_id = ? AND u.shop_domain = ? AND r.used_on IS NULL"
+            }
+            TwoFactorQueries::CreateRecoveryCode => {
+                "INSERT INTO recovery_codes (user_id, hashed_code)
+                SELECT user_id, ? FROM users WHERE user_id = ? AND shop_domain = ?"
+            }
+            TwoFactorQueries::UseRecoveryCode => {
+                "UPDATE recovery_codes SET used_on = datetime('now','localtime')
+                WHERE code_id = ? AND used_on IS NULL
+                AND user_id IN (SELECT user_id FROM users WHERE shop_domain = ?)"
+            }
+            TwoFactorQueries::DeleteRecoveryCodes => {
+                "DELETE FROM recovery_codes
+                WHERE user_id IN (SELECT user_id FROM users WHERE user_id = ? AND shop_domain = ?)"
             }
-            ShopQueries::GetOneShop => "SELECT * FROM shop_configurations WHERE domain = ?",
-            _ => unreachable!("ShopQueries variant not implemented"),
         }
     }
 }
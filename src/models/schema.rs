@@ -1,53 +1,153 @@
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 use sqlx::SqlitePool;
 use std::result::Result;
+use std::str::FromStr;
 
+// Versioned migrations in migrations/sqlx, numbered after the request that changed the schema.
+// Databases from before the migrations match 0001 and are upgraded from there.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlx");
+
+// The users table of 0001, as the application created it before there were migrations
+const LEGACY_USERS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS users
+    (
+        user_id                 TEXT PRIMARY KEY NOT NULL,
+        username                TEXT UNIQUE NOT NULL,
+        hashed_password         TEXT NOT NULL,
+        created_on              DATETIME DEFAULT (datetime('now','localtime')),
+        updated_on              DATETIME DEFAULT (datetime('now','localtime')),
+        active                  BOOLEAN NOT NULL DEFAULT 1
+    );";
+
+// Databases without migration history were made by the application, which created the
+// users table of 0001 without running it. They are marked as migrated to 0001 instead,
+// running it would fail on the existing table.
+async fn adopt_legacy_database(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let has_history: bool = sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+    )
+    .fetch_one(pool)
+    .await?;
+    if has_history {
+        return Ok(());
+    }
+
+    let first = match MIGRATOR.iter().find(|migration| migration.version == 1) {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    sqlx::query(LEGACY_USERS_TABLE).execute(&mut *conn).await?;
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+        VALUES (?, ?, TRUE, ?, 0)",
+    )
+    .bind(first.version)
+    .bind(first.description.as_ref())
+    .bind(first.checksum.as_ref())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Create the database when it is missing and apply the migrations it does not have yet
 pub async fn create_schema(db_url: &str) -> Result<(), sqlx::Error> {
-    let pool = SqlitePool::connect(db_url).await?;
-    // Enable foreign key constraints
-    sqlx::query("PRAGMA foreign_keys = ON")
-        .execute(&pool)
+    // Foreign keys are off while migrating, so rebuilding a table does not cascade to its children
+    let options = SqliteConnectOptions::from_str(db_url)?
+        .create_if_missing(true)
+        .foreign_keys(false);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
         .await?;
+    adopt_legacy_database(&pool).await?;
+    MIGRATOR.run(&pool).await?;
 
-    // Create users table
-    let users_table_query = "
-        CREATE TABLE IF NOT EXISTS users
-        (
-            user_id                 TEXT PRIMARY KEY NOT NULL,
-            username                TEXT UNIQUE NOT NULL,
-            hashed_password         TEXT NOT NULL,
-            created_on              DATETIME DEFAULT (datetime('now','localtime')),
-            updated_on              DATETIME DEFAULT (datetime('now','localtime')),
-            active                  BOOLEAN NOT NULL DEFAULT 1
-        );";
-    sqlx::query(users_table_query).execute(&pool).await?;
-    println!("User table created.");
-
-    // Create shop_configurations table
-    let shop_configurations_query = "
-        CREATE TABLE IF NOT EXISTS shop_configurations
-        (
-            domain             TEXT PRIMARY KEY NOT NULL,
-            name               TEXT NOT NULL,
-            product_type       TEXT NOT NULL
-        );";
-    sqlx::query(shop_configurations_query)
-        .execute(&pool)
+    // The copied rows still have to match the foreign keys
+    let violations = sqlx::query("PRAGMA foreign_key_check")
+        .fetch_all(&pool)
         .await?;
-    println!("Shop_configuration table created.");
-
-    // Create shop_configurations table
-    let products_query = "
-        CREATE TABLE IF NOT EXISTS products
-        (
-            product_id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL,
-            description TEXT,
-            price DECIMAL NOT NULL,
-            in_stock BOOLEAN DEFAULT TRUE
-        );";
-    sqlx::query(products_query).execute(&pool).await?;
-    println!("products table created.");
+    if !violations.is_empty() {
+        log::warn!(
+            "{} rows do not match their foreign keys after migrating",
+            violations.len()
+        );
+    }
 
     pool.close().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod schema_tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_migrate_legacy_database() {
+        // Arrange, a database created before the migrations existed
+        let path = std::env::temp_dir().join(format!(
+            "legacy_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        let pool = SqlitePoolOptions::new().connect(&url).await.unwrap();
+        sqlx::query(LEGACY_USERS_TABLE)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE TABLE shop_configurations (domain TEXT PRIMARY KEY NOT NULL, name TEXT NOT NULL, product_type TEXT NOT NULL);
+            CREATE TABLE products (product_id INTEGER PRIMARY KEY, name TEXT NOT NULL, description TEXT, price DECIMAL NOT NULL, in_stock BOOLEAN DEFAULT TRUE);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO users (user_id, username, hashed_password) VALUES ('1234', 'eve', '');
+            INSERT INTO shop_configurations VALUES ('shop.test', 'Test Shop', 'Tests');
+            INSERT INTO products (product_id, name, price) VALUES (1, 'Mug', '12.99'), (2, 'Cap', 7);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+
+        // Act, twice to check nothing is applied again
+        create_schema(&url).await.expect("Migrating failed");
+        create_schema(&url).await.expect("Migrating again failed");
+
+        // Assert, the user kept its account and landed in the only shop
+        let pool = SqlitePoolOptions::new().connect(&url).await.unwrap();
+        let (shop_domain, email, role, verified): (String, String, String, bool) = sqlx::query_as(
+            "SELECT shop_domain, email, role, verified_at IS NOT NULL FROM users WHERE user_id = '1234'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(shop_domain, "shop.test");
+        assert_eq!(email, "1234@users.invalid");
+        assert_eq!(role, "customer");
+        assert!(
+            verified,
+            "Existing account has to verify an address it does not have"
+        );
+
+        // and the decimal prices are now in cents
+        let prices: Vec<i64> = sqlx::query_scalar("SELECT price FROM products ORDER BY product_id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(prices, vec![1299, 700]);
+
+        let applied: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(applied, MIGRATOR.iter().count() as i64);
+
+        pool.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
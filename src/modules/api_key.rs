@@ -0,0 +1,185 @@
+use crate::domain::datatypes::UserRole;
+use crate::modules::session;
+use actix_web::{dev::ServiceRequest, http};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Keys look like rbk_<prefix>_<secret>, the prefix is stored in plain text to find the key
+const KEY_MARKER: &str = "rbk";
+const PREFIX_BYTES: usize = 6;
+const SECRET_BYTES: usize = 32;
+
+// What a key may do, stored space separated with the key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ApiScope {
+    #[serde(rename = "users:read")]
+    ReadUsers,
+    #[serde(rename = "products:write")]
+    WriteProducts,
+    #[serde(rename = "orders:manage")]
+    ManageOrders,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ApiScope::ReadUsers => "users:read",
+            ApiScope::WriteProducts => "products:write",
+            ApiScope::ManageOrders => "orders:manage",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "users:read" => Some(ApiScope::ReadUsers),
+            "products:write" => Some(ApiScope::WriteProducts),
+            "orders:manage" => Some(ApiScope::ManageOrders),
+            _ => None,
+        }
+    }
+
+    // Reading users is for admins, shop owners can automate their catalog and orders
+    pub fn allowed_for(&self, role: &UserRole) -> bool {
+        match self {
+            ApiScope::ReadUsers => *role == UserRole::Admin,
+            ApiScope::WriteProducts | ApiScope::ManageOrders => {
+                matches!(role, UserRole::Admin | UserRole::ShopOwner)
+            }
+        }
+    }
+
+    // Read scopes never change anything
+    pub fn permits(&self, method: &http::Method) -> bool {
+        match self {
+            ApiScope::ReadUsers => matches!(*method, http::Method::GET | http::Method::HEAD),
+            ApiScope::WriteProducts | ApiScope::ManageOrders => true,
+        }
+    }
+}
+
+pub fn scopes_to_string(scopes: &[ApiScope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn parse_scopes(scopes: &str) -> Vec<ApiScope> {
+    scopes
+        .split_whitespace()
+        .filter_map(ApiScope::parse)
+        .collect()
+}
+
+// The caller of a request authenticated with an API key, added to the request extensions
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiKeyCaller {
+    pub key_id: i64,
+    pub user_id: String,
+    pub shop_domain: String,
+    // Current role of the owner of the key
+    pub role: UserRole,
+    pub scopes: Vec<ApiScope>,
+}
+
+impl ApiKeyCaller {
+    // The key holds one of the accepted scopes and that scope allows the method
+    pub fn allows(&self, accepted: &[ApiScope], method: &http::Method) -> bool {
+        self.scopes
+            .iter()
+            .any(|scope| accepted.contains(scope) && scope.permits(method))
+    }
+}
+
+// A new key, the full key is only shown once
+pub struct NewApiKey {
+    pub key: String,
+    pub prefix: String,
+    pub hashed_key: String,
+}
+
+pub fn generate_key() -> NewApiKey {
+    let prefix = session::random_hex(PREFIX_BYTES);
+    let key = format!(
+        "{}_{}_{}",
+        KEY_MARKER,
+        prefix,
+        session::random_hex(SECRET_BYTES)
+    );
+
+    NewApiKey {
+        hashed_key: hash_key(&key),
+        key,
+        prefix,
+    }
+}
+
+// Keys are long and random, a fast hash is enough to keep them out of the database
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// The lookup prefix of a well formed key
+pub fn key_prefix(key: &str) -> Option<&str> {
+    let mut parts = key.split('_');
+    let (marker, prefix, secret) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let is_well_formed = marker == KEY_MARKER
+        && parts.next().is_none()
+        && prefix.len() == PREFIX_BYTES * 2
+        && secret.len() == SECRET_BYTES * 2
+        && prefix
+            .chars()
+            .chain(secret.chars())
+            .all(|c| c.is_ascii_hexdigit());
+
+    is_well_formed.then_some(prefix)
+}
+
+pub fn verify_key(key: &str, hashed_key: &str) -> bool {
+    orion::util::secure_cmp(hash_key(key).as_bytes(), hashed_key.as_bytes()).is_ok()
+}
+
+// The key of an Authorization: Bearer header
+pub fn bearer_token(request: &ServiceRequest) -> Option<String> {
+    let header = request.headers().get(http::header::AUTHORIZATION)?;
+    let (scheme, token) = header.to_str().ok()?.split_once(' ')?;
+
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+#[cfg(test)]
+mod api_key_tests {
+    use super::*;
+
+    #[test]
+    fn check_api_key_operations() {
+        // A new key is found by its prefix and verified against its hash
+        let new_key = generate_key();
+        assert_eq!(key_prefix(&new_key.key), Some(new_key.prefix.as_str()));
+        assert!(verify_key(&new_key.key, &new_key.hashed_key));
+
+        // Other keys and malformed keys are refused
+        let other_key = generate_key();
+        assert!(!verify_key(&other_key.key, &new_key.hashed_key));
+        assert_eq!(key_prefix("rbk_1234"), None);
+        assert_eq!(key_prefix(&format!("{}_extra", new_key.key)), None);
+
+        // Scopes survive storage, read scopes only allow reading
+        let scopes = [ApiScope::ReadUsers, ApiScope::ManageOrders];
+        assert_eq!(parse_scopes(&scopes_to_string(&scopes)), scopes.to_vec());
+        assert!(ApiScope::ReadUsers.permits(&http::Method::GET));
+        assert!(!ApiScope::ReadUsers.permits(&http::Method::DELETE));
+        assert!(!ApiScope::ReadUsers.allowed_for(&UserRole::ShopOwner));
+        assert!(ApiScope::ManageOrders.allowed_for(&UserRole::ShopOwner));
+
+        // The key is taken from the Authorization header
+        let request = actix_web::test::TestRequest::get()
+            .insert_header((http::header::AUTHORIZATION, "Bearer rbk_key"))
+            .to_srv_request();
+        assert_eq!(bearer_token(&request), Some("rbk_key".to_string()));
+    }
+}
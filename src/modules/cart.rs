@@ -0,0 +1,314 @@
+use actix_web::cookie::Cookie;
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+
+use crate::db::tenant::TenantDB;
+use crate::domain::cart::{Cart, CartLine, PricedCart};
+use crate::domain::datatypes::{CookieVariations, Settings};
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::modules::session;
+use crate::utils::constants::CART_TTL_DAYS;
+
+// Whose cart a request works on
+#[derive(Debug, Clone, PartialEq)]
+pub enum CartOwner {
+    User(String),
+    // Guests are known by the id in the shopping_cart cookie
+    Guest(String),
+}
+impl CartOwner {
+    fn key(&self, shop_domain: &str) -> String {
+        match self {
+            CartOwner::User(user_id) => {
+                RedisKeyNames::Cart.get_key(&format!("{}:user:{}", shop_domain, user_id))
+            }
+            CartOwner::Guest(cart_id) => {
+                RedisKeyNames::Cart.get_key(&format!("{}:guest:{}", shop_domain, cart_id))
+            }
+        }
+    }
+}
+
+// The user logged in to this shop, else the guest with a cart cookie
+pub fn request_owner(
+    request: &HttpRequest,
+    redis: &RedisDB,
+    shop_domain: &str,
+) -> Option<CartOwner> {
+    let user = request
+        .cookie(CookieVariations::Auth.get_name().as_str())
+        .and_then(|cookie| session::authenticate(cookie.value(), redis))
+        .map(|(user, _)| user)
+        .filter(|user| user.shop_domain == shop_domain);
+
+    match user {
+        Some(user) => Some(CartOwner::User(user.user_id)),
+        None => guest_cart_id(request).map(CartOwner::Guest),
+    }
+}
+
+// The cart id of a guest, None without a cookie or when it was tampered with
+pub fn guest_cart_id(request: &HttpRequest) -> Option<String> {
+    request
+        .cookie(CookieVariations::ShoppingCarts.get_name().as_str())
+        .and_then(|cookie| CookieVariations::ShoppingCarts.open_value(cookie.value()))
+}
+
+pub fn new_guest() -> CartOwner {
+    CartOwner::Guest(session::random_hex(16))
+}
+
+pub fn cart_cookie(cart_id: &str) -> Cookie<'static> {
+    CookieVariations::ShoppingCarts.generate_cookie(Settings::new(cart_id, true))
+}
+
+pub fn load_cart(
+    redis: &RedisDB,
+    shop_domain: &str,
+    owner: &CartOwner,
+) -> Result<Cart, RedisDbError> {
+    let value: Option<String> = redis.get_value(&owner.key(shop_domain))?;
+    Ok(value
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default())
+}
+
+// Every change starts the expiry again, empty carts are removed
+pub fn save_cart(
+    redis: &RedisDB,
+    shop_domain: &str,
+    owner: &CartOwner,
+    cart: &Cart,
+) -> Result<(), RedisDbError> {
+    let key = owner.key(shop_domain);
+    if cart.is_empty() {
+        redis.delete_key(&key)?;
+        return Ok(());
+    }
+    redis.set_value_ex(
+        &key,
+        serde_json::to_string(cart)?,
+        *CART_TTL_DAYS * 24 * 60 * 60,
+    )
+}
+
+// After a login the guest cart moves into the cart of the user
+pub fn merge_guest_cart(
+    redis: &RedisDB,
+    shop_domain: &str,
+    cart_id: &str,
+    user_id: &str,
+) -> Result<(), RedisDbError> {
+    let guest_key = CartOwner::Guest(cart_id.to_string()).key(shop_domain);
+    let value: Option<String> = redis.take_value(&guest_key)?;
+    let Some(guest) = value.and_then(|value| serde_json::from_str::<Cart>(&value).ok()) else {
+        return Ok(());
+    };
+
+    let owner = CartOwner::User(user_id.to_string());
+    let mut cart = load_cart(redis, shop_domain, &owner)?;
+    cart.merge(guest);
+    save_cart(redis, shop_domain, &owner, &cart)
+}
+
+// Price every line from the catalog, whatever the client thinks it costs.
+// Also returns the cart without the lines that can no longer be bought: removed
+// products and variants, and products that moved to another currency than the rest.
+pub async fn price_cart(db: &TenantDB, cart: &Cart) -> Result<(PricedCart, Cart), sqlx::Error> {
+    let mut priced = PricedCart::default();
+    let mut kept = Cart::default();
+
+    for item in &cart.items {
+        let Some(product) = db.get_one_product(item.product_id).await? else {
+            continue;
+        };
+        let (unit_price, currency, sku) = match item.variant_id {
+            Some(variant_id) => match db.get_one_variant(item.product_id, variant_id).await? {
+                Some(variant) => (variant.unit_price, variant.currency, Some(variant.sku)),
+                None => continue,
+            },
+            None => (product.price, product.currency, None),
+        };
+        if *priced.currency.get_or_insert_with(|| currency.clone()) != currency {
+            continue;
+        }
+
+        let line_total = unit_price.saturating_mul(item.quantity);
+        priced.total = priced.total.saturating_add(line_total);
+        priced.item_count += item.quantity;
+        priced.lines.push(CartLine {
+            product_id: item.product_id,
+            variant_id: item.variant_id,
+            name: product.name,
+            sku,
+            quantity: item.quantity,
+            unit_price,
+            line_total,
+        });
+        kept.items.push(item.clone());
+    }
+
+    Ok((priced, kept))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CartError {
+    #[error("Product not found")]
+    ProductNotFound,
+    #[error("Variant not found")]
+    VariantNotFound,
+    #[error("Choose a variant of this product")]
+    VariantRequired,
+    #[error("Only {0} left")]
+    NotEnoughStock(i64),
+    #[error("This product is out of stock")]
+    OutOfStock,
+    #[error("A cart holds products of one currency")]
+    OtherCurrency,
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+impl CartError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            CartError::ProductNotFound | CartError::VariantNotFound => StatusCode::NOT_FOUND,
+            CartError::VariantRequired => StatusCode::BAD_REQUEST,
+            CartError::NotEnoughStock(_) | CartError::OutOfStock | CartError::OtherCurrency => {
+                StatusCode::CONFLICT
+            }
+            CartError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Whether a quantity of a product can go in a cart paid in the given currency
+pub async fn check_item(
+    db: &TenantDB,
+    product_id: i64,
+    variant_id: Option<i64>,
+    quantity: i64,
+    cart_currency: Option<&str>,
+) -> Result<(), CartError> {
+    let product = db
+        .get_one_product(product_id)
+        .await?
+        .ok_or(CartError::ProductNotFound)?;
+
+    match variant_id {
+        Some(variant_id) => {
+            let variant = db
+                .get_one_variant(product_id, variant_id)
+                .await?
+                .ok_or(CartError::VariantNotFound)?;
+            if quantity > variant.available {
+                return Err(CartError::NotEnoughStock(variant.available.max(0)));
+            }
+        }
+        None => {
+            if !db.get_variants(product_id).await?.is_empty() {
+                return Err(CartError::VariantRequired);
+            }
+            if !product.in_stock {
+                return Err(CartError::OutOfStock);
+            }
+        }
+    }
+
+    match cart_currency {
+        Some(currency) if currency != product.currency => Err(CartError::OtherCurrency),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod cart_tests {
+    use super::*;
+    use crate::db::sqlite::SqliteDB;
+    use crate::domain::inventory::{StockChangeIn, VariantIn};
+    use crate::domain::products::ProductIn;
+
+    #[actix_rt::test]
+    async fn check_cart_pricing() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "cart_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let database = SqliteDB::new(&url).await;
+        let shop = database.for_shop("cart.test");
+
+        let new_product = |json: &str| {
+            let product: ProductIn = serde_json::from_str(json).unwrap();
+            product.validate().unwrap()
+        };
+        let mug = shop
+            .create_product(&new_product(
+                r#"{"name":"Mug","price":"8.00","currency":"eur"}"#,
+            ))
+            .await
+            .unwrap();
+        let tea = shop
+            .create_product(&new_product(
+                r#"{"name":"Tea","price":"4.00","currency":"eur"}"#,
+            ))
+            .await
+            .unwrap();
+        let dollar_tea = shop
+            .create_product(&new_product(
+                r#"{"name":"Tea","price":"4.00","currency":"usd"}"#,
+            ))
+            .await
+            .unwrap();
+        let variant: VariantIn = serde_json::from_str(r#"{"sku":"TEA-1KG","price":"15"}"#).unwrap();
+        let kilo = shop
+            .create_variant(tea.product_id, &variant.validate().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let restock: StockChangeIn =
+            serde_json::from_str(r#"{"change":3,"reason":"restock"}"#).unwrap();
+        shop.adjust_stock(tea.product_id, kilo.variant_id, &restock, None)
+            .await
+            .unwrap();
+
+        // Lines are checked against the catalog and the stock
+        assert!(check_item(&shop, mug.product_id, None, 2, None)
+            .await
+            .is_ok());
+        assert!(matches!(
+            check_item(&shop, tea.product_id, None, 1, None).await,
+            Err(CartError::VariantRequired)
+        ));
+        assert!(matches!(
+            check_item(&shop, tea.product_id, Some(kilo.variant_id), 4, None).await,
+            Err(CartError::NotEnoughStock(3))
+        ));
+        assert!(matches!(
+            check_item(&shop, dollar_tea.product_id, None, 1, Some("eur")).await,
+            Err(CartError::OtherCurrency)
+        ));
+
+        // Prices come from the catalog, lines that cannot be bought are dropped
+        let mut cart = Cart::default();
+        cart.set_quantity(mug.product_id, None, 2).unwrap();
+        cart.set_quantity(tea.product_id, Some(kilo.variant_id), 1)
+            .unwrap();
+        cart.set_quantity(dollar_tea.product_id, None, 1).unwrap();
+        cart.set_quantity(999, None, 1).unwrap();
+        let (priced, kept) = price_cart(&shop, &cart).await.unwrap();
+        assert_eq!(priced.currency.as_deref(), Some("eur"));
+        assert_eq!((priced.total, priced.item_count), (3100, 3));
+        assert_eq!(priced.lines[1].sku.as_deref(), Some("TEA-1KG"));
+        assert_eq!(kept.items.len(), 2);
+
+        let json = serde_json::to_value(&priced).unwrap();
+        assert_eq!(json["total"], "31.00");
+        assert_eq!(json["lines"][0]["line_total"], "16.00");
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,20 +1,262 @@
 use crate::domain::datatypes::{CookieVariations, Settings};
-use actix_web::cookie::Cookie;
+use crate::modules::token_pub;
+use actix_web::cookie::{time, Cookie, SameSite};
 
-pub fn generate_cookie(variation: &CookieVariations, setting: Settings) -> Cookie {
+// How a cookie value is protected from reading or tampering by the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CookieProtection {
+    Plain,
+    Signed,
+    Encrypted,
+}
+
+// Attributes of one cookie variation
+#[derive(Debug, Clone)]
+pub struct CookiePolicy {
+    pub http_only: bool,
+    pub same_site: SameSite,
+    pub secure: bool,
+    pub path: &'static str,
+    // Share the cookie with every subdomain of the shop instead of only the exact host
+    pub tenant_domain: bool,
+    // None follows the remember me choice of the Settings
+    pub max_age: Option<time::Duration>,
+    pub protection: CookieProtection,
+}
+
+// Cookie policy table
+pub fn policy(variation: &CookieVariations) -> CookiePolicy {
     match variation {
-        &CookieVariations::Auth => Cookie::build("auth", setting.value)
-            .path("/")
-            .expires(setting.time.to_owned())
-            .secure(true)
-            .http_only(false)
-            .finish(),
-        &CookieVariations::ShoppingCarts => Cookie::build("shopping_carts", setting.value)
-            .path("/")
-            .expires(setting.time.to_owned())
-            .secure(true)
-            .http_only(false)
-            .finish(),
-        _ => unreachable!("Cookies section should be unreachable"),
+        // Holds a PASETO token or an opaque session id, both are protected already
+        CookieVariations::Auth | CookieVariations::Refresh => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/",
+            tenant_domain: false,
+            max_age: None,
+            protection: CookieProtection::Plain,
+        },
+        CookieVariations::TwoFactor => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/login",
+            tenant_domain: false,
+            max_age: Some(time::Duration::minutes(5)),
+            protection: CookieProtection::Plain,
+        },
+        CookieVariations::Csrf => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/",
+            tenant_domain: false,
+            max_age: Some(time::Duration::days(7)),
+            protection: CookieProtection::Plain,
+        },
+        // Ties an OpenID Connect login to the browser that started it, Lax so the
+        // redirect back from the provider still carries it
+        CookieVariations::OidcState => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/login/oidc",
+            tenant_domain: false,
+            max_age: Some(time::Duration::minutes(10)),
+            protection: CookieProtection::Plain,
+        },
+        CookieVariations::ShoppingCarts => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/",
+            tenant_domain: true,
+            max_age: Some(time::Duration::days(30)),
+            protection: CookieProtection::Signed,
+        },
+        // Display preferences, readable by the scripts on the page
+        CookieVariations::Personalization => CookiePolicy {
+            http_only: false,
+            same_site: SameSite::Lax,
+            secure: true,
+            path: "/",
+            tenant_domain: true,
+            max_age: Some(time::Duration::days(365)),
+            protection: CookieProtection::Signed,
+        },
+        CookieVariations::Payment => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Strict,
+            secure: true,
+            path: "/",
+            tenant_domain: true,
+            max_age: Some(time::Duration::hours(1)),
+            protection: CookieProtection::Encrypted,
+        },
+        // Name, address and residence of the customer
+        CookieVariations::NAW => CookiePolicy {
+            http_only: true,
+            same_site: SameSite::Strict,
+            secure: true,
+            path: "/",
+            tenant_domain: true,
+            max_age: Some(time::Duration::days(30)),
+            protection: CookieProtection::Encrypted,
+        },
+    }
+}
+
+pub fn generate_cookie(variation: &CookieVariations, setting: Settings) -> Cookie<'static> {
+    let policy = policy(variation);
+    let name = variation.get_name();
+    let value = seal_value(variation, &setting.value);
+
+    let mut cookie = Cookie::build(name, value)
+        .path(policy.path)
+        .secure(policy.secure)
+        .http_only(policy.http_only)
+        .same_site(policy.same_site)
+        .finish();
+
+    match policy.max_age {
+        Some(max_age) => {
+            cookie.set_max_age(max_age);
+            cookie.set_expires(time::OffsetDateTime::now_utc() + max_age);
+        }
+        None => cookie.set_expires(setting.time),
+    }
+
+    cookie
+}
+
+pub fn remove_cookie(variation: &CookieVariations) -> Cookie<'static> {
+    let policy = policy(variation);
+
+    Cookie::build(variation.get_name(), "")
+        .path(policy.path)
+        .expires(time::OffsetDateTime::now_utc())
+        .max_age(time::Duration::seconds(0))
+        .secure(policy.secure)
+        .http_only(policy.http_only)
+        .same_site(policy.same_site)
+        .finish()
+}
+
+// Scope a cookie to the domain of the shop, only for real domain names
+pub fn scope_to_domain(cookie: &mut Cookie<'static>, host: &str) {
+    let domain = host.split(':').next().unwrap_or_default().to_lowercase();
+    let is_ip = domain.parse::<std::net::IpAddr>().is_ok();
+
+    if !domain.is_empty() && domain != "localhost" && !is_ip {
+        cookie.set_domain(domain);
+    }
+}
+
+fn cookie_key(purpose: &str) -> Option<Vec<u8>> {
+    token_pub::derive_key(purpose)
+}
+
+// Protect a value according to the policy. The cookie name is bound to the value,
+// so a protected value cannot be moved to another cookie.
+pub fn seal_value(variation: &CookieVariations, value: &str) -> String {
+    let bound = format!("{}={}", variation.get_name(), value);
+
+    match policy(variation).protection {
+        CookieProtection::Plain => value.to_string(),
+        CookieProtection::Signed => {
+            let tag = cookie_key("cookie-signing")
+                .and_then(|key| orion::auth::SecretKey::from_slice(&key).ok())
+                .and_then(|key| orion::auth::authenticate(&key, bound.as_bytes()).ok());
+
+            match tag {
+                Some(tag) => format!("{}.{}", value, hex::encode(tag.unprotected_as_bytes())),
+                None => String::new(),
+            }
+        }
+        CookieProtection::Encrypted => cookie_key("cookie-encryption")
+            .and_then(|key| orion::aead::SecretKey::from_slice(&key).ok())
+            .and_then(|key| orion::aead::seal(&key, bound.as_bytes()).ok())
+            .map(hex::encode)
+            .unwrap_or_default(),
+    }
+}
+
+// Read a value written by seal_value, None when it was tampered with
+pub fn open_value(variation: &CookieVariations, raw: &str) -> Option<String> {
+    let prefix = format!("{}=", variation.get_name());
+
+    match policy(variation).protection {
+        CookieProtection::Plain => Some(raw.to_string()),
+        CookieProtection::Signed => {
+            let (value, tag) = raw.rsplit_once('.')?;
+            let key = orion::auth::SecretKey::from_slice(&cookie_key("cookie-signing")?).ok()?;
+            let tag = orion::auth::Tag::from_slice(&hex::decode(tag).ok()?).ok()?;
+            let bound = format!("{}{}", prefix, value);
+
+            orion::auth::authenticate_verify(&tag, &key, bound.as_bytes()).ok()?;
+            Some(value.to_string())
+        }
+        CookieProtection::Encrypted => {
+            let key = orion::aead::SecretKey::from_slice(&cookie_key("cookie-encryption")?).ok()?;
+            let opened = orion::aead::open(&key, &hex::decode(raw).ok()?).ok()?;
+            let bound = String::from_utf8(opened).ok()?;
+
+            bound.strip_prefix(&prefix).map(|value| value.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod cookie_tests {
+    use super::*;
+
+    #[test]
+    fn check_cookie_policy_operations() {
+        // Every variation can be generated and removed
+        for variation in [
+            CookieVariations::Auth,
+            CookieVariations::Refresh,
+            CookieVariations::TwoFactor,
+            CookieVariations::Csrf,
+            CookieVariations::OidcState,
+            CookieVariations::ShoppingCarts,
+            CookieVariations::Personalization,
+            CookieVariations::Payment,
+            CookieVariations::NAW,
+        ] {
+            let cookie = generate_cookie(&variation, Settings::new("value", false));
+            assert_eq!(cookie.name(), variation.get_name(), "Wrong cookie name");
+            assert!(cookie.secure().unwrap_or(false), "Cookie is not secure");
+            assert!(cookie.same_site().is_some(), "Cookie has no SameSite");
+            assert_eq!(
+                open_value(&variation, cookie.value()),
+                Some("value".to_string()),
+                "Cookie value does not survive the round trip"
+            );
+
+            let removed = remove_cookie(&variation);
+            assert_eq!(removed.max_age(), Some(time::Duration::seconds(0)));
+        }
+
+        // Signed values cannot be changed
+        let signed = seal_value(&CookieVariations::ShoppingCarts, "cart_1");
+        let tampered = signed.replacen("cart_1", "cart_2", 1);
+        assert_eq!(
+            open_value(&CookieVariations::ShoppingCarts, &tampered),
+            None
+        );
+
+        // Encrypted values do not show the value and cannot be moved to another cookie
+        let encrypted = seal_value(&CookieVariations::NAW, "Eve, Main Street 1");
+        assert!(!encrypted.contains("Eve"), "Encrypted value is readable");
+        assert_eq!(open_value(&CookieVariations::Payment, &encrypted), None);
+
+        // Only real domain names get a Domain attribute
+        let mut cookie = generate_cookie(&CookieVariations::NAW, Settings::new("value", false));
+        scope_to_domain(&mut cookie, "localhost:3000");
+        assert_eq!(cookie.domain(), None, "Localhost got a domain");
+        scope_to_domain(&mut cookie, "Honeydragons.com:443");
+        assert_eq!(cookie.domain(), Some("honeydragons.com"));
     }
 }
@@ -0,0 +1,238 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use tokio::net::UdpSocket;
+
+// Owners prove a custom domain is theirs with a TXT record like
+// _shop-verification.example.com TXT "shop-verification=<token>"
+const RECORD_PREFIX: &str = "_shop-verification";
+const VALUE_PREFIX: &str = "shop-verification=";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    #[error("DNS request failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid resolver address: {0}")]
+    InvalidResolver(String),
+    #[error("Invalid domain name")]
+    InvalidName,
+    #[error("The resolver did not answer in time")]
+    Timeout,
+    #[error("Malformed DNS response")]
+    Malformed,
+    #[error("The resolver failed with code {0}")]
+    ServerFailure(u16),
+}
+
+// Where the owner has to put the record
+pub fn record_name(domain: &str) -> String {
+    format!("{}.{}", RECORD_PREFIX, domain)
+}
+
+// What the record has to say
+pub fn record_value(token: &str) -> String {
+    format!("{}{}", VALUE_PREFIX, token)
+}
+
+// True when one of the TXT records of the domain carries the token
+pub async fn verify_domain(resolver: &str, domain: &str, token: &str) -> Result<bool, DnsError> {
+    let expected = record_value(token);
+    let records = lookup_txt(resolver, &record_name(domain)).await?;
+    Ok(records.iter().any(|record| record.trim() == expected))
+}
+
+// Ask the resolver for the TXT records of a name, an unknown name has none
+pub async fn lookup_txt(resolver: &str, name: &str) -> Result<Vec<String>, DnsError> {
+    let resolver: SocketAddr = resolver
+        .parse()
+        .map_err(|_| DnsError::InvalidResolver(resolver.to_string()))?;
+    let bind_address = match resolver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+
+    let id = OsRng.next_u32() as u16;
+    let query = build_query(id, name)?;
+
+    let socket = UdpSocket::bind(bind_address).await?;
+    socket.connect(resolver).await?;
+    socket.send(&query).await?;
+
+    let mut buffer = [0u8; 4096];
+    loop {
+        let length = tokio::time::timeout(LOOKUP_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| DnsError::Timeout)??;
+        // Answers to other queries are ignored
+        if let Some(records) = parse_response(id, &buffer[..length])? {
+            return Ok(records);
+        }
+    }
+}
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+// None when the response belongs to another query
+fn parse_response(id: u16, response: &[u8]) -> Result<Option<Vec<String>>, DnsError> {
+    let header = response.get(..12).ok_or(DnsError::Malformed)?;
+    let read_u16 = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+
+    let is_response = header[2] & 0x80 != 0;
+    if read_u16(0) != id || !is_response {
+        return Ok(None);
+    }
+    match read_u16(2) & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok(Some(Vec::new())),
+        rcode => return Err(DnsError::ServerFailure(rcode)),
+    }
+
+    let mut position = 12;
+    for _ in 0..read_u16(4) {
+        position = skip_name(response, position)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..read_u16(6) {
+        position = skip_name(response, position)?;
+        let fields = response
+            .get(position..position + 10)
+            .ok_or(DnsError::Malformed)?;
+        let record_type = u16::from_be_bytes([fields[0], fields[1]]);
+        let data_length = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+        position += 10;
+        let data = response
+            .get(position..position + data_length)
+            .ok_or(DnsError::Malformed)?;
+        position += data_length;
+
+        if record_type == TYPE_TXT {
+            records.push(read_txt_data(data)?);
+        }
+    }
+    Ok(Some(records))
+}
+
+// Names end with a zero length label or a pointer to an earlier name
+fn skip_name(response: &[u8], mut position: usize) -> Result<usize, DnsError> {
+    loop {
+        let length = *response.get(position).ok_or(DnsError::Malformed)?;
+        match length {
+            0 => return Ok(position + 1),
+            length if length & 0xc0 == 0xc0 => return Ok(position + 2),
+            length => position += 1 + length as usize,
+        }
+    }
+}
+
+// A TXT record is a list of length prefixed strings that belong together
+fn read_txt_data(data: &[u8]) -> Result<String, DnsError> {
+    let mut text = Vec::with_capacity(data.len());
+    let mut position = 0;
+    while position < data.len() {
+        let length = data[position] as usize;
+        let part = data
+            .get(position + 1..position + 1 + length)
+            .ok_or(DnsError::Malformed)?;
+        text.extend_from_slice(part);
+        position += 1 + length;
+    }
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod domain_verification_tests {
+    use super::*;
+
+    // Answers every query with the given TXT records, or NXDOMAIN when there are none
+    async fn stub_resolver(records: Vec<&'static str>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((length, client)) = socket.recv_from(&mut buffer).await {
+                let query = &buffer[..length];
+                let mut response = query[..2].to_vec();
+                let rcode = if records.is_empty() {
+                    RCODE_NXDOMAIN
+                } else {
+                    0
+                };
+                response.extend_from_slice(&(0x8180 | rcode).to_be_bytes());
+                response.extend_from_slice(&[0, 1]);
+                response.extend_from_slice(&(records.len() as u16).to_be_bytes());
+                response.extend_from_slice(&[0, 0, 0, 0]);
+                response.extend_from_slice(&query[12..]);
+
+                for record in &records {
+                    // Pointer to the name in the question
+                    response.extend_from_slice(&[0xc0, 0x0c]);
+                    response.extend_from_slice(&TYPE_TXT.to_be_bytes());
+                    response.extend_from_slice(&CLASS_IN.to_be_bytes());
+                    response.extend_from_slice(&300u32.to_be_bytes());
+                    response.extend_from_slice(&((record.len() + 1) as u16).to_be_bytes());
+                    response.push(record.len() as u8);
+                    response.extend_from_slice(record.as_bytes());
+                }
+                let _ = socket.send_to(&response, client).await;
+            }
+        });
+
+        address
+    }
+
+    #[actix_rt::test]
+    async fn check_domain_verification() {
+        // Arrange
+        let resolver = stub_resolver(vec!["v=spf1 -all", "shop-verification=abc123"]).await;
+
+        // The record with the token is found among the others
+        assert_eq!(
+            lookup_txt(&resolver, &record_name("shop.example"))
+                .await
+                .unwrap(),
+            vec!["v=spf1 -all", "shop-verification=abc123"]
+        );
+        assert!(verify_domain(&resolver, "shop.example", "abc123")
+            .await
+            .unwrap());
+        assert!(!verify_domain(&resolver, "shop.example", "other")
+            .await
+            .unwrap());
+
+        // Names without records cannot be verified
+        let empty_resolver = stub_resolver(Vec::new()).await;
+        assert!(!verify_domain(&empty_resolver, "shop.example", "abc123")
+            .await
+            .unwrap());
+
+        assert!(matches!(
+            lookup_txt("not an address", "shop.example").await,
+            Err(DnsError::InvalidResolver(_))
+        ));
+    }
+}
@@ -49,6 +49,7 @@ macro_rules! send_email {
 pub enum EmailType {
     UserVerification,
     PasswordReset,
+    OrderRefunded,
 }
 
 impl EmailType {
@@ -57,11 +58,10 @@ impl EmailType {
         settings: &EmailSettings,
     ) -> Result<(), lettre::transport::smtp::Error> {
         match self {
-            EmailType::PasswordReset => {
-                // Use the macro to send a password reset email and properly handle the result
+            EmailType::PasswordReset | EmailType::UserVerification | EmailType::OrderRefunded => {
+                // Use the macro to send the email and properly handle the result
                 send_email!(settings).await
             }
-            _ => todo!("Email type not implemented"),
         }
     }
 }
@@ -111,5 +111,29 @@ impl EmailSettings {
             ),
         }
     }
+
+    pub fn order_refund_template(
+        user_email: String,
+        host_email: String,
+        domain: String,
+        order_id: i64,
+        amount: String,
+        full: bool,
+    ) -> Self {
+        let refund = match full {
+            true => "Your order has been refunded",
+            false => "Part of your order has been refunded",
+        };
+        Self {
+            user_email,
+            host_email,
+            domain: domain.to_string(),
+            subject: format!("Refund for order #{}", order_id),
+            body: format!(
+                r#"<h1>{}</h1><p>{} is on its way back to your original payment method, it can take a few days to show up.</p><p>See the order <a href='http://{}/orders/show/{}' target="_blank" rel="noopener noreferrer">here</a></p>"#,
+                refund, amount, domain, order_id
+            ),
+        }
+    }
 }
 // "jissicko@gmail.com".to_string()
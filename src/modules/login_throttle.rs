@@ -0,0 +1,223 @@
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::utils::constants::{
+    LOGIN_ATTEMPT_WINDOW_MINUTES, LOGIN_LOCKOUT_MINUTES, LOGIN_MAX_ATTEMPTS, LOGIN_MAX_ATTEMPTS_IP,
+    TRUSTED_PROXIES,
+};
+use actix_web::{http::header, HttpRequest};
+use std::net::IpAddr;
+
+// Failed logins are counted per username of a shop and per client address
+pub enum LoginThrottle {
+    Username,
+    Address,
+}
+
+impl LoginThrottle {
+    pub fn subject(&self, value: &str) -> String {
+        match self {
+            LoginThrottle::Username => format!("user:{}", value.to_lowercase()),
+            LoginThrottle::Address => format!("ip:{}", value),
+        }
+    }
+
+    fn max_attempts(&self) -> isize {
+        match self {
+            LoginThrottle::Username => *LOGIN_MAX_ATTEMPTS,
+            LoginThrottle::Address => *LOGIN_MAX_ATTEMPTS_IP,
+        }
+    }
+
+    // Seconds the next attempt has to wait, an address shared by many users is not slowed down
+    fn backoff(&self, attempts: isize) -> Option<u64> {
+        match self {
+            LoginThrottle::Username => Some(backoff_delay(attempts)),
+            LoginThrottle::Address => None,
+        }
+    }
+}
+
+// Wait 1, 2, 4, 8, ... seconds after each failure, never longer than a lockout
+fn backoff_delay(attempts: isize) -> u64 {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    (1u64 << exponent).min(lockout_seconds())
+}
+
+fn lockout_seconds() -> u64 {
+    *LOGIN_LOCKOUT_MINUTES * 60
+}
+
+// Address of the client. Clients can send any X-Forwarded-For, so it is only read when the
+// connection comes from a trusted proxy, walking back past the proxies that added to it.
+pub fn client_ip(request: &HttpRequest) -> String {
+    let forwarded_for = request
+        .headers()
+        .get(header::X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok());
+    let peer = request.peer_addr().map(|address| address.ip());
+
+    match resolve_client_ip(peer, forwarded_for, &TRUSTED_PROXIES) {
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    let mut hops = forwarded_for
+        .unwrap_or_default()
+        .rsplit(',')
+        .map(|hop| hop.trim())
+        .filter(|hop| !hop.is_empty())
+        .map(|hop| hop.parse::<IpAddr>());
+
+    while trusted.contains(&client) {
+        match hops.next() {
+            Some(Ok(hop)) => client = hop,
+            // A proxy in front of us sent something that is not an address
+            Some(Err(_)) => return None,
+            None => break,
+        }
+    }
+    Some(client)
+}
+
+// Usernames are only unique within a shop, so the same name on another shop is counted apart
+fn shop_username(shop_domain: &str, username: &str) -> String {
+    format!("{}:{}", shop_domain, username)
+}
+
+fn throttles(shop_domain: &str, username: &str, client_ip: &str) -> [(LoginThrottle, String); 2] {
+    [
+        (
+            LoginThrottle::Username,
+            shop_username(shop_domain, username),
+        ),
+        (LoginThrottle::Address, client_ip.to_string()),
+    ]
+}
+
+// Check if the username or the client address has to wait before trying again
+pub fn is_locked_out(
+    redis: &RedisDB,
+    shop_domain: &str,
+    username: &str,
+    client_ip: &str,
+) -> Result<bool, RedisDbError> {
+    for (throttle, value) in throttles(shop_domain, username, client_ip) {
+        let lockout_key = RedisKeyNames::LoginLockout.get_key(&throttle.subject(&value));
+        if redis.exists(&lockout_key)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Count a failed attempt and block the next one until the back-off has passed.
+// Returns the subjects that reached their maximum attempts and are now locked out.
+pub fn register_failure(
+    redis: &RedisDB,
+    shop_domain: &str,
+    username: &str,
+    client_ip: &str,
+) -> Result<Vec<String>, RedisDbError> {
+    let mut locked_out = Vec::new();
+
+    for (throttle, value) in throttles(shop_domain, username, client_ip) {
+        let subject = throttle.subject(&value);
+        let attempts_key = RedisKeyNames::LoginAttempts.get_key(&subject);
+        let attempts = redis.incr(&attempts_key, 1)?;
+        redis.expire(&attempts_key, *LOGIN_ATTEMPT_WINDOW_MINUTES * 60)?;
+
+        let delay = if attempts >= throttle.max_attempts() {
+            locked_out.push(subject.clone());
+            Some(lockout_seconds())
+        } else {
+            throttle.backoff(attempts)
+        };
+
+        if let Some(seconds) = delay {
+            let lockout_key = RedisKeyNames::LoginLockout.get_key(&subject);
+            redis.set_value_ex(&lockout_key, attempts, seconds)?;
+        }
+    }
+
+    Ok(locked_out)
+}
+
+// A successful login starts the username with a clean slate
+pub fn clear_failures(
+    redis: &RedisDB,
+    shop_domain: &str,
+    username: &str,
+) -> Result<(), RedisDbError> {
+    let subject = LoginThrottle::Username.subject(&shop_username(shop_domain, username));
+    redis.delete_key(&RedisKeyNames::LoginAttempts.get_key(&subject))?;
+    redis.delete_key(&RedisKeyNames::LoginLockout.get_key(&subject))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod login_throttle_tests {
+    use super::*;
+
+    #[test]
+    fn check_backoff_operations() {
+        // The delay doubles with every failed attempt
+        assert_eq!(backoff_delay(1), 1, "First back-off is not one second");
+        assert_eq!(backoff_delay(2), 2, "Second back-off is not two seconds");
+        assert_eq!(backoff_delay(4), 8, "Fourth back-off is not eight seconds");
+
+        // But never grows past a lockout
+        assert_eq!(
+            backoff_delay(100),
+            lockout_seconds(),
+            "Back-off is longer than a lockout"
+        );
+
+        // Usernames are counted case insensitive, addresses are not throttled per attempt
+        assert_eq!(
+            LoginThrottle::Username.subject("Eve"),
+            LoginThrottle::Username.subject("eve"),
+            "Username subjects are case sensitive"
+        );
+
+        // The same username on another shop is counted on its own
+        assert_ne!(
+            LoginThrottle::Username.subject(&shop_username("a.test", "eve")),
+            LoginThrottle::Username.subject(&shop_username("b.test", "eve")),
+            "Username subjects are shared between shops"
+        );
+        assert!(
+            LoginThrottle::Address.backoff(3).is_none(),
+            "Address is slowed down per attempt"
+        );
+    }
+
+    #[test]
+    fn check_client_ip_operations() {
+        let peer: IpAddr = "203.0.113.7".parse().unwrap();
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // Without trusted proxies the header of the client is ignored
+        assert_eq!(
+            resolve_client_ip(Some(peer), Some("198.51.100.1"), &[]),
+            Some(peer)
+        );
+
+        // Behind a trusted proxy the last address it added is the client,
+        // whatever the client put in front of it
+        assert_eq!(
+            resolve_client_ip(Some(proxy), Some("198.51.100.1, 203.0.113.7"), &[proxy]),
+            Some(peer)
+        );
+        assert_eq!(resolve_client_ip(Some(proxy), None, &[proxy]), Some(proxy));
+        assert_eq!(
+            resolve_client_ip(Some(proxy), Some("not-an-address"), &[proxy]),
+            None
+        );
+    }
+}
@@ -1,31 +1,69 @@
 use std::future::{ready, Ready};
+use std::rc::Rc;
 
 use actix_web::{
     body::EitherBody,
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
-    http, Error, HttpResponse,
+    http, web, Error, HttpMessage, HttpResponse,
 };
 use futures_util::future::LocalBoxFuture;
 
-use crate::domain::datatypes::{CookieVariations, UserCookie};
+use crate::db::sqlite::SqliteDB;
+use crate::domain::datatypes::{CookieVariations, UserCookie, UserRole};
+use crate::modules::api_key::{self, ApiKeyCaller, ApiScope};
+use crate::modules::middleware_domain::Shop;
+use crate::modules::redis::RedisDB;
+use crate::modules::session;
 
 pub struct CheckLogin {
     enabled: bool,
+    roles: Vec<UserRole>,
+    api_scopes: Vec<ApiScope>,
+    operators: Option<Vec<String>>,
 }
 
 impl CheckLogin {
     pub fn enabled() -> Self {
-        Self { enabled: true }
+        Self {
+            enabled: true,
+            roles: Vec::new(),
+            api_scopes: Vec::new(),
+            operators: None,
+        }
     }
 
     pub fn disabled() -> Self {
-        Self { enabled: false }
+        Self {
+            enabled: false,
+            roles: Vec::new(),
+            api_scopes: Vec::new(),
+            operators: None,
+        }
+    }
+
+    // Only let logged in users with one of these roles through
+    pub fn roles(mut self, roles: &[UserRole]) -> Self {
+        self.roles = roles.to_vec();
+        self
+    }
+
+    // Also let API keys with one of these scopes through, sent as Authorization: Bearer
+    pub fn api_key(mut self, scopes: &[ApiScope]) -> Self {
+        self.api_scopes = scopes.to_vec();
+        self
+    }
+
+    // Only let these users through. Roles belong to a shop, so the admin of one shop
+    // is not trusted with the platform, an empty list lets nobody through.
+    pub fn operators(mut self, user_ids: &[String]) -> Self {
+        self.operators = Some(user_ids.to_vec());
+        self
     }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for CheckLogin
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -37,19 +75,25 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(CheckLoginMiddleware {
-            service,
+            service: Rc::new(service),
             enabled: self.enabled,
+            roles: self.roles.clone(),
+            api_scopes: self.api_scopes.clone(),
+            operators: self.operators.clone(),
         }))
     }
 }
 pub struct CheckLoginMiddleware<S> {
-    service: S,
+    service: Rc<S>,
     enabled: bool,
+    roles: Vec<UserRole>,
+    api_scopes: Vec<ApiScope>,
+    operators: Option<Vec<String>>,
 }
 
 impl<S, B> Service<ServiceRequest> for CheckLoginMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -61,35 +105,114 @@ where
 
     fn call(&self, request: ServiceRequest) -> Self::Future {
         // println!("Middleware was called");
-        if self.enabled {
-            let authentication_cookie = request.cookie(CookieVariations::Auth.get_name().as_str());
+        if self.enabled && !self.api_scopes.is_empty() {
+            // An outer CheckLogin may already have verified the key
+            let known_caller = request.extensions().get::<ApiKeyCaller>().cloned();
+            if let Some(caller) = known_caller {
+                if !self.roles.is_empty() && !self.roles.contains(&caller.role) {
+                    let response = HttpResponse::Forbidden().json("Forbidden");
+                    return deny(request, response);
+                }
+                if !caller.allows(&self.api_scopes, request.method()) {
+                    let response = HttpResponse::Forbidden().json("Insufficient scope");
+                    return deny(request, response);
+                }
+                let res = self.service.call(request);
+                return Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) });
+            }
 
-            let the_user: Option<UserCookie>;
+            if let Some(key) = api_key::bearer_token(&request) {
+                let service = Rc::clone(&self.service);
+                let accepted = self.api_scopes.clone();
+                let roles = self.roles.clone();
 
-            if let Some(cookie) = authentication_cookie {
-                the_user = crate::modules::token_pub::verify_token(cookie.value());
-            } else {
-                the_user = None;
+                return Box::pin(async move {
+                    match verify_api_key(&request, &key, &accepted, &roles).await {
+                        Ok(caller) => {
+                            request.extensions_mut().insert(caller);
+                            service
+                                .call(request)
+                                .await
+                                .map(ServiceResponse::map_into_left_body)
+                        }
+                        Err(response) => deny(request, response).await,
+                    }
+                });
             }
+        }
 
-            let unauthorized_paths = vec!["/login", "/register", "/forgot", "/reset"];
+        if self.enabled {
+            // An outer CheckLogin (app or scope) may already have verified the token
+            let known_user = request.extensions().get::<UserCookie>().cloned();
+            let the_user: Option<UserCookie> = match known_user {
+                Some(user) => Some(user),
+                None => verify_request_user(&request),
+            };
+            // A login is only valid in the shop it was made in
+            let the_user = the_user.filter(|user| is_request_shop(&request, &user.shop_domain));
+            let refresh_cookie = request.cookie(CookieVariations::Refresh.get_name().as_str());
+
+            let unauthorized_paths = vec!["/login", "/register", "/forgot", "/reset", "/refresh"];
 
             // Check if the current request path is unauthorized (should not require authentication)
             let path = request.path();
-            let is_unauthorized = unauthorized_paths
-                .iter()
-                .any(|&p| path == p || (p.ends_with("/*") && path.starts_with(&p[..p.len() - 1])))
-                || path.starts_with("/reset/"); // Special handling for reset paths
+            let is_unauthorized = matches_paths(path, &unauthorized_paths)
+                || path.starts_with("/reset/") // Special handling for reset paths
+                || path.starts_with("/verify/"); // Verification links are opened from the email
+
+            // Users that did not verify their email can only reach these paths
+            let unverified_paths = vec!["/", "/logout", "/verify", "/verify/*"];
 
-            if the_user.is_none() && !is_unauthorized {
-                let (request, _pl) = request.into_parts();
+            match &the_user {
+                None if !is_unauthorized => {
+                    // An expired access token on a page load is renewed with the refresh token
+                    let location =
+                        if refresh_cookie.is_some() && request.method() == http::Method::GET {
+                            let query = serde_urlencoded::to_string([("redirect", path)])
+                                .unwrap_or_default();
+                            format!("/refresh?{}", query)
+                        } else {
+                            "/login".to_string()
+                        };
 
-                let response = HttpResponse::Found()
-                    .insert_header((http::header::LOCATION, "/login"))
-                    .finish()
-                    .map_into_right_body();
+                    let response = if wants_html(&request) {
+                        redirect_to(location.as_str())
+                    } else {
+                        HttpResponse::Unauthorized().json("Unauthorized")
+                    };
+                    return deny(request, response);
+                }
+                Some(user)
+                    if !user.verified
+                        && !is_unauthorized
+                        && !matches_paths(path, &unverified_paths) =>
+                {
+                    let response = if wants_html(&request) {
+                        redirect_to("/verify")
+                    } else {
+                        HttpResponse::Forbidden().json("Account not verified")
+                    };
+                    return deny(request, response);
+                }
+                Some(user)
+                    if (!self.roles.is_empty() && !self.roles.contains(&user.role))
+                        || self
+                            .operators
+                            .as_ref()
+                            .is_some_and(|operators| !operators.contains(&user.user_id)) =>
+                {
+                    let response = if wants_html(&request) {
+                        redirect_to("/login")
+                    } else {
+                        HttpResponse::Forbidden().json("Forbidden")
+                    };
+                    return deny(request, response);
+                }
+                _ => {}
+            }
 
-                return Box::pin(async { Ok(ServiceResponse::new(request, response)) });
+            if let Some(user) = the_user {
+                request.extensions_mut().insert(user);
             }
         }
         let res = self.service.call(request);
@@ -100,3 +223,453 @@ where
         });
     }
 }
+
+fn verify_request_user(request: &ServiceRequest) -> Option<UserCookie> {
+    let authentication_cookie = request.cookie(CookieVariations::Auth.get_name().as_str());
+    let redis = request.app_data::<web::Data<RedisDB>>();
+
+    match (authentication_cookie, redis) {
+        (Some(cookie), Some(redis)) => {
+            let (user, session) = session::authenticate(cookie.value(), redis)?;
+            if let Some(session) = session {
+                request.extensions_mut().insert(session);
+            }
+            Some(user)
+        }
+        (Some(_), None) => {
+            log::warn!("No Redis connection, tokens cannot be checked for revocation");
+            None
+        }
+        _ => None,
+    }
+}
+
+// Check the key and what the caller may do with it, and record its use
+async fn verify_api_key(
+    request: &ServiceRequest,
+    key: &str,
+    accepted: &[ApiScope],
+    roles: &[UserRole],
+) -> Result<ApiKeyCaller, HttpResponse> {
+    let authenticated = request.extensions().get::<AuthenticatedApiKey>().cloned();
+    let caller = match authenticated {
+        Some(AuthenticatedApiKey(caller)) => caller,
+        None => authenticate_api_key(request, key).await?,
+    };
+    if !roles.is_empty() && !roles.contains(&caller.role) {
+        return Err(HttpResponse::Forbidden().json("Forbidden"));
+    }
+    if !caller.allows(accepted, request.method()) {
+        return Err(HttpResponse::Forbidden().json("Insufficient scope"));
+    }
+
+    if let Some(db) = request.app_data::<web::Data<SqliteDB>>() {
+        if let Err(err) = db.touch_api_key(caller.key_id).await {
+            eprintln!("Error updating API key last use: {:?}", err);
+        }
+    }
+    Ok(caller)
+}
+
+// A key the CSRF check already authenticated for this request, so CheckLogin does not look
+// it up again. What the key may do is still checked by CheckLogin.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedApiKey(pub ApiKeyCaller);
+
+// Look the key up by its prefix, it has to belong to the shop of the request.
+// The owner is checked on every use, keys stop working when the owner is deactivated
+// and lose the scopes the current role of the owner no longer allows.
+pub async fn authenticate_api_key(
+    request: &ServiceRequest,
+    key: &str,
+) -> Result<ApiKeyCaller, HttpResponse> {
+    let invalid = || HttpResponse::Unauthorized().json("Invalid API key");
+
+    let prefix = api_key::key_prefix(key).ok_or_else(invalid)?;
+    let db = match request.app_data::<web::Data<SqliteDB>>() {
+        Some(db) => db.clone(),
+        None => {
+            log::warn!("No database connection, API keys cannot be checked");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let stored = match db.get_api_key_with_owner(prefix).await {
+        Ok(Some(stored)) => stored,
+        Ok(None) => return Err(invalid()),
+        Err(err) => {
+            eprintln!("Error getting API key: {:?}", err);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let owner_role = stored.owner_role;
+    let stored_key = stored.key;
+    if stored_key.revoked_on.is_some()
+        || !stored.owner_active
+        || !is_request_shop(request, &stored_key.shop_domain)
+        || !api_key::verify_key(key, &stored_key.hashed_key)
+    {
+        return Err(invalid());
+    }
+
+    let scopes = api_key::parse_scopes(&stored_key.scopes)
+        .into_iter()
+        .filter(|scope| scope.allowed_for(&owner_role))
+        .collect();
+    Ok(ApiKeyCaller {
+        key_id: stored_key.key_id,
+        user_id: stored_key.user_id,
+        shop_domain: stored_key.shop_domain,
+        role: owner_role,
+        scopes,
+    })
+}
+
+fn is_request_shop(request: &ServiceRequest, shop_domain: &str) -> bool {
+    request
+        .extensions()
+        .get::<Shop>()
+        .map(|shop| shop.domain == shop_domain)
+        .unwrap_or(false)
+}
+
+fn matches_paths(path: &str, paths: &[&str]) -> bool {
+    paths
+        .iter()
+        .any(|&p| path == p || (p.ends_with("/*") && path.starts_with(&p[..p.len() - 1])))
+}
+
+// Browser and HTMX requests get redirected, API clients get a status code
+fn wants_html(request: &ServiceRequest) -> bool {
+    let accepts_html = request
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("text/html"))
+        .unwrap_or(false);
+
+    accepts_html || request.headers().contains_key("HX-Request")
+}
+
+fn redirect_to(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((http::header::LOCATION, location))
+        .finish()
+}
+
+fn deny<B>(
+    request: ServiceRequest,
+    response: HttpResponse,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>
+where
+    B: 'static,
+{
+    let (request, _pl) = request.into_parts();
+    let response = response.map_into_right_body();
+
+    Box::pin(async { Ok(ServiceResponse::new(request, response)) })
+}
+
+#[cfg(test)]
+mod middleware_tests {
+    use super::*;
+    use crate::modules::middleware_csrf::CsrfProtection;
+    use crate::modules::middleware_domain::AddShopDomain;
+    use crate::utils::constants::SHOP_CONFIGS;
+    use actix_web::cookie::Cookie;
+    use actix_web::{get, test, App, Responder};
+
+    #[get("/admin")]
+    async fn admin_only() -> impl Responder {
+        HttpResponse::Ok().body("Hello admin!")
+    }
+
+    fn test_user(role: UserRole) -> UserCookie {
+        UserCookie {
+            user_id: "1234".to_string(),
+            username: "eve".to_string(),
+            verified: true,
+            role,
+            shop_domain: "shop.test".to_string(),
+        }
+    }
+
+    fn test_shop(domain: &str) -> Shop {
+        Shop {
+            domain: domain.to_string(),
+            name: "Test Shop".to_string(),
+            product_type: "Tests".to_string(),
+            user_id: None,
+            canonical_domain: domain.to_string(),
+            theme: Default::default(),
+            plan: crate::domain::subscriptions::ShopPlan::Pro,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_check_login_roles() {
+        // Arrange
+        let app = test::init_service(
+            App::new().service(
+                web::scope("")
+                    .wrap(CheckLogin::enabled().roles(&[UserRole::Admin]))
+                    .service(admin_only),
+            ),
+        )
+        .await;
+
+        // Admins get through
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        req.extensions_mut().insert(test_shop("shop.test"));
+        req.extensions_mut().insert(test_user(UserRole::Admin));
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "Admin was not let through");
+
+        // Other roles are forbidden on JSON routes
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        req.extensions_mut().insert(test_shop("shop.test"));
+        req.extensions_mut().insert(test_user(UserRole::Customer));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            http::StatusCode::FORBIDDEN,
+            "Customer was let through"
+        );
+
+        // And redirected to the login page on HTML routes
+        let req = test::TestRequest::get()
+            .uri("/admin")
+            .insert_header((http::header::ACCEPT, "text/html"))
+            .to_request();
+        req.extensions_mut().insert(test_shop("shop.test"));
+        req.extensions_mut().insert(test_user(UserRole::ShopOwner));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            http::StatusCode::FOUND,
+            "Shop owner was not redirected"
+        );
+        assert_eq!(
+            resp.headers().get(http::header::LOCATION).unwrap(),
+            "/login",
+            "Shop owner was not redirected to login"
+        );
+
+        // A login of another shop does not count here
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        req.extensions_mut().insert(test_shop("other.test"));
+        req.extensions_mut().insert(test_user(UserRole::Admin));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            http::StatusCode::UNAUTHORIZED,
+            "Admin of another shop was let through"
+        );
+
+        // Without a user the request is unauthorized
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            http::StatusCode::UNAUTHORIZED,
+            "Anonymous was let through"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_check_login_operators() {
+        // Arrange
+        let app = test::init_service(
+            App::new().service(
+                web::scope("")
+                    .wrap(
+                        CheckLogin::enabled()
+                            .roles(&[UserRole::Admin])
+                            .operators(&["1234".to_string()]),
+                    )
+                    .service(admin_only),
+            ),
+        )
+        .await;
+
+        // Operators get through
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        req.extensions_mut().insert(test_shop("shop.test"));
+        req.extensions_mut().insert(test_user(UserRole::Admin));
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "Operator was not let through");
+
+        // Other admins are forbidden
+        let mut admin = test_user(UserRole::Admin);
+        admin.user_id = "5678".to_string();
+        let req = test::TestRequest::get().uri("/admin").to_request();
+        req.extensions_mut().insert(test_shop("shop.test"));
+        req.extensions_mut().insert(admin);
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            http::StatusCode::FORBIDDEN,
+            "Admin that is no operator was let through"
+        );
+    }
+
+    #[get("/users")]
+    async fn list_users() -> impl Responder {
+        HttpResponse::Ok().body("Users")
+    }
+
+    #[actix_rt::test]
+    async fn test_check_login_api_keys() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!("api_keys_{}.db", session::random_hex(8)));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let db = SqliteDB::new(&url).await;
+        sqlx::query("INSERT INTO users (user_id, shop_domain, username, email, hashed_password, role) VALUES ('1234', 'shop.test', 'eve', 'eve@example.com', '', 'admin')")
+            .execute(&db.db)
+            .await
+            .unwrap();
+
+        let new_key = api_key::generate_key();
+        let stored = db
+            .for_shop("shop.test")
+            .create_api_key(
+                "1234",
+                "sync",
+                &new_key.prefix,
+                &new_key.hashed_key,
+                "users:read",
+            )
+            .await
+            .unwrap();
+
+        for domain in ["shop.test", "other.test"] {
+            SHOP_CONFIGS.lock().unwrap().insert(
+                domain.to_string(),
+                crate::domain::shops::Shop {
+                    name: "Test Shop".to_string(),
+                    product_type: "Tests".to_string(),
+                    user_id: None,
+                    slug: None,
+                    canonical_domain: None,
+                    theme: Default::default(),
+                    plan: crate::domain::subscriptions::ShopPlan::Pro,
+                },
+            );
+        }
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(db.clone()))
+                .wrap(CsrfProtection::enabled())
+                .wrap(AddShopDomain::enabled())
+                .service(
+                    web::scope("")
+                        .wrap(
+                            CheckLogin::enabled()
+                                .roles(&[UserRole::Admin])
+                                .api_key(&[ApiScope::ReadUsers]),
+                        )
+                        .service(list_users),
+                ),
+        )
+        .await;
+        let bearer = format!("Bearer {}", new_key.key);
+
+        // A valid key for this shop gets through and is marked as used
+        let req = test::TestRequest::get()
+            .uri("/users")
+            .insert_header((http::header::HOST, "shop.test"))
+            .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success(), "Valid key was not let through");
+        let used = db
+            .get_api_key_by_prefix(&stored.prefix)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(used.last_used_on.is_some(), "Last use was not recorded");
+
+        // A read scope cannot change anything, a valid key gets past the CSRF check to find out
+        let req = test::TestRequest::delete()
+            .uri("/users")
+            .insert_header((http::header::HOST, "shop.test"))
+            .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body, "\"Insufficient scope\"",
+            "Valid key was stopped by CSRF"
+        );
+
+        // Next to a login cookie the key does not skip the CSRF check
+        let req = test::TestRequest::delete()
+            .uri("/users")
+            .insert_header((http::header::HOST, "shop.test"))
+            .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+            .cookie(Cookie::new(CookieVariations::Auth.get_name(), "session"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body, "Invalid CSRF token",
+            "Key skipped CSRF next to a login cookie"
+        );
+
+        // The key only works on its own shop
+        let req = test::TestRequest::get()
+            .uri("/users")
+            .insert_header((http::header::HOST, "other.test"))
+            .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        // Keys follow the current role and status of their owner
+        let get_users = || {
+            test::TestRequest::get()
+                .uri("/users")
+                .insert_header((http::header::HOST, "shop.test"))
+                .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+                .to_request()
+        };
+        sqlx::query("UPDATE users SET role = 'shop_owner' WHERE user_id = '1234'")
+            .execute(&db.db)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, get_users()).await;
+        assert_eq!(resp.status(), http::StatusCode::FORBIDDEN);
+        sqlx::query("UPDATE users SET role = 'admin', active = FALSE WHERE user_id = '1234'")
+            .execute(&db.db)
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, get_users()).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+        sqlx::query("UPDATE users SET active = TRUE WHERE user_id = '1234'")
+            .execute(&db.db)
+            .await
+            .unwrap();
+
+        // Revoked keys are refused
+        assert!(db
+            .for_shop("shop.test")
+            .revoke_api_key(stored.key_id, None)
+            .await
+            .unwrap());
+        let req = test::TestRequest::get()
+            .uri("/users")
+            .insert_header((http::header::HOST, "shop.test"))
+            .insert_header((http::header::AUTHORIZATION, bearer.as_str()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), http::StatusCode::UNAUTHORIZED);
+
+        db.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
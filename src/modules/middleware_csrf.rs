@@ -0,0 +1,335 @@
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{self, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::PayloadError,
+    http::Method,
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+
+use crate::domain::datatypes::{CookieVariations, Settings};
+use crate::modules::cookie::generate_cookie;
+use crate::modules::redis::RedisDB;
+use crate::modules::{api_key, middleware, session, token_pub};
+
+// Name of the hidden form field and of the header HTMX sends the token in
+pub const CSRF_FIELD: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "HX-CSRF-Token";
+// Form bodies are read to find the token, larger bodies are refused
+const MAX_FORM_SIZE: usize = 256 * 1024;
+
+tokio::task_local! {
+    // Token of the request being handled, added to every rendered template
+    static CSRF_TOKEN: String;
+}
+
+// CSRF token of the current request, None outside of the middleware
+pub fn current_token() -> Option<String> {
+    CSRF_TOKEN.try_with(|token| token.clone()).ok()
+}
+
+pub struct CsrfProtection {
+    enabled: bool,
+    exempt_paths: Vec<String>,
+}
+
+impl CsrfProtection {
+    pub fn enabled() -> Self {
+        Self {
+            enabled: true,
+            exempt_paths: Vec::new(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            exempt_paths: Vec::new(),
+        }
+    }
+
+    // Paths that are not posted from our own pages, like signed webhooks
+    pub fn exempt(mut self, paths: &[&str]) -> Self {
+        self.exempt_paths = paths.iter().map(|path| path.to_string()).collect();
+        self
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfProtectionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfProtectionMiddleware {
+            service: Rc::new(service),
+            enabled: self.enabled,
+            exempt_paths: self.exempt_paths.clone(),
+        }))
+    }
+}
+
+pub struct CsrfProtectionMiddleware<S> {
+    service: Rc<S>,
+    enabled: bool,
+    exempt_paths: Vec<String>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        if !self.enabled {
+            return Box::pin(async move {
+                service
+                    .call(request)
+                    .await
+                    .map(ServiceResponse::map_into_left_body)
+            });
+        }
+
+        let is_exempt = self.exempt_paths.iter().any(|path| request.path() == path);
+
+        Box::pin(async move {
+            // Browsers without a secret get a new one with the response
+            let (secret, is_new) = match request_secret(&request) {
+                Some(secret) => (secret, false),
+                None => (session::random_hex(32), true),
+            };
+            let token = token_for(&secret).unwrap_or_default();
+
+            if is_unsafe(request.method()) && !is_exempt && !is_api_call(&request).await {
+                let submitted = match request
+                    .headers()
+                    .get(CSRF_HEADER)
+                    .and_then(|header| header.to_str().ok())
+                {
+                    Some(header) => Some(header.to_string()),
+                    None => read_form_token(&mut request).await?,
+                };
+
+                let is_valid = match submitted {
+                    Some(submitted) => !is_new && verify_token(&secret, &submitted),
+                    None => false,
+                };
+                if !is_valid {
+                    let response = HttpResponse::Forbidden().body("Invalid CSRF token");
+                    return Ok(request.into_response(response).map_into_right_body());
+                }
+            }
+
+            let mut response = CSRF_TOKEN.scope(token, service.call(request)).await?;
+
+            if is_new {
+                let cookie = generate_cookie(
+                    &CookieVariations::Csrf,
+                    Settings::new(secret.as_str(), true),
+                );
+                let _ = response.response_mut().add_cookie(&cookie);
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn is_unsafe(method: &Method) -> bool {
+    !matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    )
+}
+
+// A browser cannot be tricked into sending an API key, but it does send the login cookies,
+// so only calls with a valid key and without those cookies go without a token
+async fn is_api_call(request: &ServiceRequest) -> bool {
+    let has_login_cookie = [CookieVariations::Auth, CookieVariations::Refresh]
+        .iter()
+        .any(|variation| request.cookie(variation.get_name().as_str()).is_some());
+    if has_login_cookie {
+        return false;
+    }
+
+    let key = match api_key::bearer_token(request) {
+        Some(key) => key,
+        None => return false,
+    };
+    // Kept for CheckLogin, so the key is only looked up once
+    match middleware::authenticate_api_key(request, &key).await {
+        Ok(caller) => {
+            request
+                .extensions_mut()
+                .insert(middleware::AuthenticatedApiKey(caller));
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+// Logged in server-side sessions carry their own secret, everyone else gets one in a cookie
+fn request_secret(request: &ServiceRequest) -> Option<String> {
+    if session::sessions_enabled() {
+        let auth_cookie = request.cookie(CookieVariations::Auth.get_name().as_str());
+        let redis = request.app_data::<web::Data<RedisDB>>();
+
+        if let (Some(cookie), Some(redis)) = (auth_cookie, redis) {
+            if let Ok(Some(data)) = session::load_session(redis, cookie.value()) {
+                return Some(data.csrf_secret);
+            }
+        }
+    }
+
+    request
+        .cookie(CookieVariations::Csrf.get_name().as_str())
+        .map(|cookie| cookie.value().to_string())
+        .filter(|secret| secret.len() == 64 && secret.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn csrf_key() -> Option<orion::auth::SecretKey> {
+    let derived = token_pub::derive_key("csrf-token")?;
+    orion::auth::SecretKey::from_slice(&derived).ok()
+}
+
+// The token handed to the page is a MAC of the secret, so a planted cookie is not enough
+fn token_for(secret: &str) -> Option<String> {
+    let tag = orion::auth::authenticate(&csrf_key()?, secret.as_bytes()).ok()?;
+    Some(hex::encode(tag.unprotected_as_bytes()))
+}
+
+fn verify_token(secret: &str, submitted: &str) -> bool {
+    let (key, tag) = match (csrf_key(), hex::decode(submitted.trim())) {
+        (Some(key), Ok(tag)) => (key, tag),
+        _ => return false,
+    };
+
+    match orion::auth::Tag::from_slice(&tag) {
+        Ok(tag) => orion::auth::authenticate_verify(&tag, &key, secret.as_bytes()).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Look for the token in an urlencoded form body and put the body back for the handler
+async fn read_form_token(request: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if request.content_type() != "application/x-www-form-urlencoded" {
+        return Ok(None);
+    }
+
+    let mut payload = request.take_payload();
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > MAX_FORM_SIZE {
+            return Ok(None);
+        }
+    }
+    let body = body.freeze();
+
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .ok()
+        .and_then(|fields| {
+            fields
+                .into_iter()
+                .find(|(name, _)| name == CSRF_FIELD)
+                .map(|(_, value)| value)
+        });
+
+    let body_stream = futures_util::stream::once(async move { Ok::<_, PayloadError>(body) });
+    request.set_payload(Payload::Stream {
+        payload: Box::pin(body_stream),
+    });
+    Ok(token)
+}
+
+#[cfg(test)]
+mod csrf_tests {
+    use super::*;
+    use actix_web::{post, test, App, HttpResponse};
+
+    #[post("/form")]
+    async fn form_handler(body: String) -> HttpResponse {
+        HttpResponse::Ok().body(body)
+    }
+
+    #[actix_rt::test]
+    async fn test_csrf_protection() {
+        let app = test::init_service(
+            App::new()
+                .wrap(CsrfProtection::enabled())
+                .service(form_handler),
+        )
+        .await;
+        let secret = session::random_hex(32);
+        let token = token_for(&secret).expect("Creating token failed");
+        let cookie = generate_cookie(&CookieVariations::Csrf, Settings::new(&secret, true));
+
+        // No secret and no token
+        let request = test::TestRequest::post().uri("/form").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 403, "Post without token is accepted");
+
+        // Token in the header
+        let request = test::TestRequest::post()
+            .uri("/form")
+            .cookie(cookie.clone())
+            .insert_header((CSRF_HEADER, token.as_str()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200, "Header token is refused");
+
+        // Token in the form, the handler still gets the whole body
+        let form = format!("name=eve&{}={}", CSRF_FIELD, token);
+        let request = test::TestRequest::post()
+            .uri("/form")
+            .cookie(cookie.clone())
+            .insert_header(("content-type", "application/x-www-form-urlencoded"))
+            .set_payload(form.clone())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200, "Form token is refused");
+        let body = test::read_body(response).await;
+        assert_eq!(body, form.as_bytes(), "Form body was not put back");
+
+        // Token made for another secret
+        let other_token = token_for(&session::random_hex(32)).expect("Creating token failed");
+        let request = test::TestRequest::post()
+            .uri("/form")
+            .cookie(cookie)
+            .insert_header((CSRF_HEADER, other_token.as_str()))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.status(),
+            403,
+            "Token of another secret is accepted"
+        );
+
+        // A bearer header alone does not skip the check, the key has to be valid
+        let request = test::TestRequest::post()
+            .uri("/form")
+            .insert_header(("Authorization", "Bearer not-a-key"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 403, "Unchecked API key skips the token");
+    }
+}
@@ -4,32 +4,56 @@ use std::{
 };
 
 use actix_web::{
+    body::EitherBody,
+    cookie::Cookie,
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    http::{header, Method},
+    Error, HttpMessage, HttpResponse,
 };
+use futures_util::future::LocalBoxFuture;
 
-use crate::utils::constants::SHOP_CONFIGS;
+use crate::domain::datatypes::CookieVariations;
+use crate::domain::shops::{split_port, ShopTheme};
+use crate::domain::subscriptions::ShopPlan;
+use crate::modules::{api_key, cookie, shop_registry};
+use crate::utils::constants::{UNKNOWN_SHOP_REDIRECT, UNKNOWN_SHOP_TEMPLATE};
+use crate::view;
 
 #[derive(Clone, Debug)]
 pub struct AddShopDomain {
     enabled: bool,
+    exempt_paths: Vec<String>,
 }
 
 impl AddShopDomain {
     pub fn enabled() -> Self {
-        Self { enabled: true }
+        Self {
+            enabled: true,
+            exempt_paths: Vec::new(),
+        }
     }
 
     pub fn disabled() -> Self {
-        Self { enabled: false }
+        Self {
+            enabled: false,
+            exempt_paths: Vec::new(),
+        }
+    }
+
+    // Paths that are served on every host, like health checks and webhooks
+    pub fn exempt(mut self, paths: &[&str]) -> Self {
+        self.exempt_paths = paths.iter().map(|path| path.to_string()).collect();
+        self
     }
 }
 
 impl<S, B> Transform<S, ServiceRequest> for AddShopDomain
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
     type Transform = AddShopDomainService<S>;
@@ -39,59 +63,274 @@ where
         ready(Ok(AddShopDomainService {
             service,
             enabled: self.enabled,
+            exempt_paths: self.exempt_paths.clone(),
         }))
     }
 }
 
-#[derive(Debug, Clone)]
+tokio::task_local! {
+    // Shop of the request being handled, decides how templates are rendered
+    static CURRENT_SHOP: Shop;
+}
+
+// Shop of the current request, None outside of the middleware or for unknown hosts
+pub fn current_shop() -> Option<Shop> {
+    CURRENT_SHOP.try_with(|shop| shop.clone()).ok()
+}
+
+// The shop of the request, every tenant query is scoped to its domain
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Shop {
     pub domain: String,
     pub name: String,
     pub product_type: String,
+    // Owner of the shop, kept out of the page context
+    #[serde(skip_serializing)]
+    pub user_id: Option<String>,
+    // The host links and redirects of the shop use
+    pub canonical_domain: String,
+    pub theme: ShopTheme,
+    #[serde(skip_serializing)]
+    pub plan: ShopPlan,
 }
 
 pub struct AddShopDomainService<S> {
     service: S,
     enabled: bool,
+    exempt_paths: Vec<String>,
 }
 
 impl<S, B> Service<ServiceRequest> for AddShopDomainService<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
-    type Future = S::Future;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         self.service.poll_ready(ctx)
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        log::info!("request is passing through the AddMsg middleware");
+        log::info!("request is passing through the AddShopDomain middleware");
+
+        // Cookies of a known shop are scoped to its domain
+        let mut tenant_host = None;
+        let mut current_shop = None;
 
         // insert data into extensions if enabled
         if self.enabled {
             let host = req.connection_info().host().to_string();
 
-            // Using a mutex to guard global state
-            let shop_configs = SHOP_CONFIGS.lock().unwrap();
-
-            match shop_configs.get(&host) {
-                Some(config) => {
-                    let shop = Shop {
-                        domain: host.to_string(),
-                        name: config.name.clone(),
-                        product_type: config.product_type.clone(),
-                    };
-                    req.extensions_mut().insert((host, Some(shop)));
+            let shop = shop_registry::resolve_host(&host).map(|(domain, config)| Shop {
+                canonical_domain: config.canonical_domain.unwrap_or_else(|| domain.clone()),
+                domain,
+                name: config.name,
+                product_type: config.product_type,
+                user_id: config.user_id,
+                theme: config.theme,
+                plan: config.plan,
+            });
+
+            match shop {
+                Some(shop) => {
+                    if let Some(location) =
+                        canonical_redirect(&req, &host, &shop, &self.exempt_paths)
+                    {
+                        let response = HttpResponse::MovedPermanently()
+                            .insert_header((header::LOCATION, location))
+                            .finish();
+                        return Box::pin(async move {
+                            Ok(req.into_response(response).map_into_right_body())
+                        });
+                    }
+                    tenant_host = Some(host);
+                    current_shop = Some(shop.clone());
+                    req.extensions_mut().insert(shop);
                 }
+                None if is_exempt(req.path(), &self.exempt_paths) => {}
                 None => {
-                    req.extensions_mut().insert((host, None::<Shop>));
+                    log::warn!("Request for unknown shop {}", host);
+                    let response = unknown_shop_response();
+                    return Box::pin(async move {
+                        Ok(req.into_response(response).map_into_right_body())
+                    });
                 }
             }
         }
 
-        self.service.call(req)
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = match current_shop {
+                Some(shop) => CURRENT_SHOP.scope(shop, response).await?,
+                None => response.await?,
+            };
+            if let Some(host) = tenant_host {
+                scope_tenant_cookies(&mut response, &host);
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+// Pages on www, slug or other custom domains move to the canonical domain. Only
+// reads are redirected: forms, API clients and webhooks are served where they arrive.
+fn canonical_redirect(
+    req: &ServiceRequest,
+    host: &str,
+    shop: &Shop,
+    exempt_paths: &[String],
+) -> Option<String> {
+    let lowercase_host = host.to_lowercase();
+    let (hostname, port) = split_port(&lowercase_host);
+    if hostname.trim_end_matches('.') == shop.canonical_domain
+        || !matches!(*req.method(), Method::GET | Method::HEAD)
+        || api_key::bearer_token(req).is_some()
+        || is_exempt(req.path(), exempt_paths)
+    {
+        return None;
+    }
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let port = port.map(|port| format!(":{}", port)).unwrap_or_default();
+    Some(format!(
+        "{}://{}{}{}",
+        req.connection_info().scheme(),
+        shop.canonical_domain,
+        port,
+        path
+    ))
+}
+
+fn is_exempt(path: &str, exempt_paths: &[String]) -> bool {
+    exempt_paths
+        .iter()
+        .any(|exempt| match exempt.strip_suffix("/*") {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == exempt,
+        })
+}
+
+// Unknown hosts are sent to the configured page, or get the fallback template
+fn unknown_shop_response() -> HttpResponse {
+    if !UNKNOWN_SHOP_REDIRECT.is_empty() {
+        return HttpResponse::Found()
+            .insert_header((header::LOCATION, UNKNOWN_SHOP_REDIRECT.as_str()))
+            .finish();
+    }
+
+    match view::setup::TEMPLATES.render(UNKNOWN_SHOP_TEMPLATE.as_str(), &tera::Context::new()) {
+        Ok(content) => HttpResponse::NotFound()
+            .content_type("text/html; charset=utf-8")
+            .body(content),
+        Err(err) => {
+            eprintln!("Error rendering unknown shop page: {}", err);
+            HttpResponse::NotFound().body("Shop not found")
+        }
+    }
+}
+
+// Give the cookies whose policy asks for it the Domain of the shop
+fn scope_tenant_cookies<B>(response: &mut ServiceResponse<B>, host: &str) {
+    let tenant_cookies: Vec<Cookie<'static>> = response
+        .response()
+        .cookies()
+        .filter(|cookie| cookie.domain().is_none())
+        .filter(|cookie| {
+            CookieVariations::from_name(cookie.name())
+                .map(|variation| cookie::policy(&variation).tenant_domain)
+                .unwrap_or(false)
+        })
+        .map(|cookie| cookie.into_owned())
+        .collect();
+
+    for mut tenant_cookie in tenant_cookies {
+        response.response_mut().del_cookie(tenant_cookie.name());
+        cookie::scope_to_domain(&mut tenant_cookie, host);
+        let _ = response.response_mut().add_cookie(&tenant_cookie);
+    }
+}
+
+#[cfg(test)]
+mod middleware_domain_tests {
+    use super::*;
+    use crate::domain::shops::Shop as ShopConfig;
+    use crate::utils::constants::{SHOP_CONFIGS, SHOP_HOSTS};
+    use actix_web::{http::StatusCode, route, test, web, App};
+
+    #[route("/products", method = "GET", method = "POST")]
+    async fn products(shop: web::ReqData<Shop>) -> HttpResponse {
+        HttpResponse::Ok().body(shop.domain.clone())
+    }
+
+    #[actix_rt::test]
+    async fn test_canonical_redirects() {
+        // Arrange
+        SHOP_CONFIGS.lock().unwrap().insert(
+            "redirect.test".to_string(),
+            ShopConfig {
+                name: "Redirect Shop".to_string(),
+                product_type: "Tests".to_string(),
+                user_id: None,
+                slug: None,
+                canonical_domain: Some("custom.test".to_string()),
+                theme: Default::default(),
+                plan: ShopPlan::Pro,
+            },
+        );
+        SHOP_HOSTS
+            .lock()
+            .unwrap()
+            .insert("custom.test".to_string(), "redirect.test".to_string());
+        let app = test::init_service(
+            App::new()
+                .wrap(AddShopDomain::enabled().exempt(&["/health"]))
+                .service(products),
+        )
+        .await;
+
+        // The canonical domain serves the shop, keyed by its shop domain
+        let req = test::TestRequest::get()
+            .uri("/products")
+            .insert_header((header::HOST, "custom.test"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "redirect.test");
+
+        // Other hosts of the shop redirect, keeping the port, path and query
+        let req = test::TestRequest::get()
+            .uri("/products?page=2")
+            .insert_header((header::HOST, "WWW.redirect.test:8080"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "http://custom.test:8080/products?page=2"
+        );
+
+        // Writes are served where they arrive
+        let req = test::TestRequest::post()
+            .uri("/products")
+            .insert_header((header::HOST, "www.custom.test"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Unknown hosts do not reach the shop
+        let req = test::TestRequest::get()
+            .uri("/products")
+            .insert_header((header::HOST, "unknown.test"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
     }
 }
@@ -0,0 +1,537 @@
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::utils::constants::{OIDC_CACHE_MINUTES, OIDC_PROVIDERS};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// How long the browser has to come back from the identity provider
+const STATE_TTL_SECONDS: u64 = 10 * 60;
+// Only signatures with the provider's public keys are accepted, never shared secrets
+const ALLOWED_ALGORITHMS: [Algorithm; 6] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+lazy_static! {
+    // Discovery documents and key sets change rarely, they are kept per issuer
+    static ref DISCOVERY_CACHE: Mutex<HashMap<String, (Instant, Discovery)>> =
+        Mutex::new(HashMap::new());
+    static ref JWKS_CACHE: Mutex<HashMap<String, (Instant, JwkSet)>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("Unknown provider: {0}")]
+    UnknownProvider(String),
+    #[error("Request to the provider failed: {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Provider answered with an error: {0}")]
+    ProviderError(String),
+    #[error("Invalid ID token: {0}")]
+    InvalidToken(String),
+    #[error("Unknown or expired login state")]
+    InvalidState,
+    #[error("Redis error: {0}")]
+    RedisError(#[from] RedisDbError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+}
+
+impl OidcProvider {
+    // A provider from the environment, None when it is not listed or not fully configured
+    pub fn configured(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if !provider_names().contains(&name) {
+            return None;
+        }
+
+        let setting = |key: &str| {
+            let variable = format!("OIDC_{}_{}", name.to_uppercase(), key);
+            dotenvy::dotenv().ok();
+            std::env::var(variable).unwrap_or_default()
+        };
+        let scopes = match setting("SCOPES") {
+            scopes if scopes.is_empty() => "openid email profile".to_string(),
+            scopes => scopes,
+        };
+        let provider = Self {
+            issuer: setting("ISSUER").trim_end_matches('/').to_string(),
+            client_id: setting("CLIENT_ID"),
+            client_secret: setting("CLIENT_SECRET"),
+            scopes,
+            name,
+        };
+
+        match provider.issuer.is_empty() || provider.client_id.is_empty() {
+            true => None,
+            false => Some(provider),
+        }
+    }
+}
+
+// Names of the providers shown on the login page
+pub fn provider_names() -> Vec<String> {
+    OIDC_PROVIDERS
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+// What is remembered between sending the browser away and the callback
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingLogin {
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String,
+    pub redirect_uri: String,
+    pub remember: bool,
+    // Hash of the token in the state cookie of the browser that started the login
+    pub browser_hash: String,
+    // The logged in user that started the login to link an identity, None for a login
+    pub user_id: Option<String>,
+}
+
+impl PendingLogin {
+    // Only the browser holding the state cookie may finish the login
+    pub fn started_by(&self, browser_token: Option<&str>) -> bool {
+        browser_token.is_some_and(|token| browser_hash(token) == self.browser_hash)
+    }
+}
+
+// The claims of the ID token we use
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// 32 random bytes, url safe, used for state, nonce and the PKCE verifier
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn browser_hash(browser_token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(browser_token.as_bytes()))
+}
+
+// S256 code challenge of RFC 7636
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn cache_ttl() -> Duration {
+    Duration::from_secs(*OIDC_CACHE_MINUTES * 60)
+}
+
+fn cached<T: Clone>(cache: &Mutex<HashMap<String, (Instant, T)>>, key: &str) -> Option<T> {
+    let cache = cache.lock().unwrap();
+    match cache.get(key) {
+        Some((fetched_on, value)) if fetched_on.elapsed() < cache_ttl() => Some(value.clone()),
+        _ => None,
+    }
+}
+
+async fn fetch_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+    let response = reqwest::get(url).await?.error_for_status()?;
+    Ok(response.json::<T>().await?)
+}
+
+// Discovery document of the provider, from the cache when possible
+pub async fn discover(provider: &OidcProvider) -> Result<Discovery, OidcError> {
+    if let Some(discovery) = cached(&DISCOVERY_CACHE, &provider.issuer) {
+        return Ok(discovery);
+    }
+
+    let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+    let discovery: Discovery = fetch_json(&url).await?;
+    if discovery.issuer.trim_end_matches('/') != provider.issuer {
+        return Err(OidcError::ProviderError(format!(
+            "Discovery document is for issuer {}",
+            discovery.issuer
+        )));
+    }
+
+    DISCOVERY_CACHE.lock().unwrap().insert(
+        provider.issuer.to_string(),
+        (Instant::now(), discovery.clone()),
+    );
+    Ok(discovery)
+}
+
+async fn jwks(discovery: &Discovery, refresh: bool) -> Result<JwkSet, OidcError> {
+    if !refresh {
+        if let Some(jwks) = cached(&JWKS_CACHE, &discovery.jwks_uri) {
+            return Ok(jwks);
+        }
+    }
+
+    let jwks: JwkSet = fetch_json(&discovery.jwks_uri).await?;
+    JWKS_CACHE.lock().unwrap().insert(
+        discovery.jwks_uri.to_string(),
+        (Instant::now(), jwks.clone()),
+    );
+    Ok(jwks)
+}
+
+pub fn authorization_url(
+    provider: &OidcProvider,
+    discovery: &Discovery,
+    state: &str,
+    pending: &PendingLogin,
+) -> String {
+    let challenge = pkce_challenge(&pending.code_verifier);
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", provider.client_id.as_str()),
+        ("redirect_uri", pending.redirect_uri.as_str()),
+        ("scope", provider.scopes.as_str()),
+        ("state", state),
+        ("nonce", pending.nonce.as_str()),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ])
+    .unwrap_or_default();
+
+    match discovery.authorization_endpoint.contains('?') {
+        true => format!("{}&{}", discovery.authorization_endpoint, query),
+        false => format!("{}?{}", discovery.authorization_endpoint, query),
+    }
+}
+
+// Remember the login in Redis. Returns where to send the browser and the token for its state cookie.
+pub async fn start_login(
+    redis: &RedisDB,
+    provider: &OidcProvider,
+    redirect_uri: &str,
+    remember: bool,
+    user_id: Option<String>,
+) -> Result<(String, String), OidcError> {
+    let discovery = discover(provider).await?;
+    let state = random_token();
+    let browser_token = random_token();
+    let pending = PendingLogin {
+        provider: provider.name.to_string(),
+        nonce: random_token(),
+        code_verifier: random_token(),
+        redirect_uri: redirect_uri.to_string(),
+        remember,
+        browser_hash: browser_hash(&browser_token),
+        user_id,
+    };
+
+    redis.set_value_ex(
+        &RedisKeyNames::OidcState.get_key(&state),
+        serde_json::to_string(&pending)?,
+        STATE_TTL_SECONDS,
+    )?;
+    Ok((
+        authorization_url(provider, &discovery, &state, &pending),
+        browser_token,
+    ))
+}
+
+// Every state can be used once, for the provider and by the browser it was started with
+pub fn take_pending(
+    redis: &RedisDB,
+    provider: &OidcProvider,
+    state: &str,
+    browser_token: Option<&str>,
+) -> Result<PendingLogin, OidcError> {
+    let value: Option<String> = redis.take_value(&RedisKeyNames::OidcState.get_key(state))?;
+    let pending: PendingLogin = match value {
+        Some(value) => serde_json::from_str(&value)?,
+        None => return Err(OidcError::InvalidState),
+    };
+
+    match pending.provider == provider.name && pending.started_by(browser_token) {
+        true => Ok(pending),
+        false => Err(OidcError::InvalidState),
+    }
+}
+
+// Trade the authorization code for the ID token
+pub async fn exchange_code(
+    provider: &OidcProvider,
+    discovery: &Discovery,
+    code: &str,
+    pending: &PendingLogin,
+) -> Result<String, OidcError> {
+    let response = reqwest::Client::new()
+        .post(&discovery.token_endpoint)
+        .header("Accept", "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", pending.redirect_uri.as_str()),
+            ("client_id", provider.client_id.as_str()),
+            ("client_secret", provider.client_secret.as_str()),
+            ("code_verifier", pending.code_verifier.as_str()),
+        ])
+        .send()
+        .await?;
+    let token: TokenResponse = response.json().await?;
+
+    match (token.id_token, token.error) {
+        (Some(id_token), None) => Ok(id_token),
+        (_, Some(error)) => Err(OidcError::ProviderError(format!(
+            "{} {}",
+            error,
+            token.error_description.unwrap_or_default()
+        ))),
+        (None, None) => Err(OidcError::ProviderError("No ID token returned".to_string())),
+    }
+}
+
+// Check signature, issuer, audience, expiry and nonce of the ID token
+pub async fn verify_id_token(
+    provider: &OidcProvider,
+    discovery: &Discovery,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = jsonwebtoken::decode_header(id_token)
+        .map_err(|err| OidcError::InvalidToken(err.to_string()))?;
+    if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+        return Err(OidcError::InvalidToken(format!(
+            "Algorithm {:?} is not allowed",
+            header.alg
+        )));
+    }
+    let kid = header
+        .kid
+        .ok_or_else(|| OidcError::InvalidToken("No key id".to_string()))?;
+
+    // A key we do not know yet might have been rotated in, so fetch the set again once
+    let mut jwks = jwks(discovery, false).await?;
+    if jwks.find(&kid).is_none() {
+        jwks = self::jwks(discovery, true).await?;
+    }
+    let jwk = jwks
+        .find(&kid)
+        .ok_or_else(|| OidcError::InvalidToken(format!("Unknown key id {}", kid)))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|err| OidcError::InvalidToken(err.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[provider.client_id.as_str()]);
+    validation.set_issuer(&[discovery.issuer.as_str()]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let claims = jsonwebtoken::decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|err| OidcError::InvalidToken(err.to_string()))?
+        .claims;
+
+    match claims.nonce.as_deref() == Some(nonce) {
+        true => Ok(claims),
+        false => Err(OidcError::InvalidToken("Nonce does not match".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod oidc_tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use jsonwebtoken::{EncodingKey, Header};
+
+    const MOCK_KEY: &str = include_str!("oidc_mock_key.pem");
+    const MOCK_JWKS: &str = include_str!("oidc_mock_jwks.json");
+
+    fn sign(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("mock-key".to_string());
+        let key = EncodingKey::from_rsa_pem(MOCK_KEY.as_bytes()).expect("Invalid mock key");
+        jsonwebtoken::encode(&header, &claims, &key).expect("Signing failed")
+    }
+
+    // A local identity provider with discovery, keys and a token endpoint
+    async fn start_mock_provider() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Binding failed");
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mock_issuer = issuer.to_string();
+
+        let server = HttpServer::new(move || {
+            let issuer = mock_issuer.to_string();
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(move || {
+                        let issuer = issuer.to_string();
+                        async move {
+                            HttpResponse::Ok().json(serde_json::json!({
+                                "issuer": issuer,
+                                "authorization_endpoint": format!("{}/authorize", issuer),
+                                "token_endpoint": format!("{}/token", issuer),
+                                "jwks_uri": format!("{}/jwks", issuer),
+                            }))
+                        }
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .content_type("application/json")
+                            .body(MOCK_JWKS)
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(|form: web::Form<HashMap<String, String>>| async move {
+                        // The code is only good together with the verifier it was requested with
+                        let challenge = pkce_challenge(&form["code_verifier"]);
+                        match form["code"] == format!("code-for-{}", challenge) {
+                            true => HttpResponse::Ok()
+                                .json(serde_json::json!({ "id_token": "mock-id-token" })),
+                            false => HttpResponse::BadRequest()
+                                .json(serde_json::json!({ "error": "invalid_grant" })),
+                        }
+                    }),
+                )
+        })
+        .listen(listener)
+        .expect("Listening failed")
+        .run();
+        actix_rt::spawn(server);
+
+        issuer
+    }
+
+    #[actix_rt::test]
+    async fn test_oidc_operations() {
+        // Example of RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+
+        let issuer = start_mock_provider().await;
+        let provider = OidcProvider {
+            name: "mock".to_string(),
+            issuer: issuer.to_string(),
+            client_id: "shop-client".to_string(),
+            client_secret: "shop-secret".to_string(),
+            scopes: "openid email".to_string(),
+        };
+        let discovery = discover(&provider).await.expect("Discovery failed");
+        let pending = PendingLogin {
+            provider: "mock".to_string(),
+            nonce: random_token(),
+            code_verifier: random_token(),
+            redirect_uri: "http://localhost/login/oidc/mock/callback".to_string(),
+            remember: false,
+            browser_hash: browser_hash("browser-token"),
+            user_id: None,
+        };
+
+        // The callback is only accepted from the browser with the state cookie
+        assert!(pending.started_by(Some("browser-token")));
+        assert!(!pending.started_by(Some("other-browser")));
+        assert!(!pending.started_by(None));
+
+        // The browser is sent to the provider with state, nonce and challenge
+        let url = authorization_url(&provider, &discovery, "the-state", &pending);
+        assert!(url.starts_with(&format!("{}/authorize?", issuer)));
+        assert!(url.contains("state=the-state") && url.contains("code_challenge_method=S256"));
+
+        // The mock provider hands back the ID token only with the right verifier
+        let code = format!("code-for-{}", pkce_challenge(&pending.code_verifier));
+        let wrong_verifier = PendingLogin {
+            code_verifier: random_token(),
+            ..pending.clone()
+        };
+        assert!(exchange_code(&provider, &discovery, &code, &wrong_verifier)
+            .await
+            .is_err());
+        let returned = exchange_code(&provider, &discovery, &code, &pending)
+            .await
+            .expect("Code exchange failed");
+        assert_eq!(returned, "mock-id-token");
+
+        let claims = |nonce: &str, audience: &str| {
+            serde_json::json!({
+                "iss": issuer,
+                "aud": audience,
+                "sub": "mock-user-1",
+                "email": "eve@example.com",
+                "email_verified": true,
+                "nonce": nonce,
+                "exp": chrono::Utc::now().timestamp() + 300,
+            })
+        };
+
+        // A correctly signed token is accepted
+        let id_token = sign(claims(&pending.nonce, "shop-client"));
+        let verified = verify_id_token(&provider, &discovery, &id_token, &pending.nonce)
+            .await
+            .expect("Valid ID token refused");
+        assert_eq!(verified.sub, "mock-user-1");
+        assert_eq!(verified.email_verified, Some(true));
+
+        // Another nonce, another audience or a changed payload are refused
+        let replayed = sign(claims("other-nonce", "shop-client"));
+        assert!(
+            verify_id_token(&provider, &discovery, &replayed, &pending.nonce)
+                .await
+                .is_err()
+        );
+        let other_client = sign(claims(&pending.nonce, "other-client"));
+        assert!(
+            verify_id_token(&provider, &discovery, &other_client, &pending.nonce)
+                .await
+                .is_err()
+        );
+        let mut tampered = claims(&pending.nonce, "shop-client");
+        tampered["sub"] = serde_json::json!("mock-admin");
+        let mut parts: Vec<String> = id_token.split('.').map(|part| part.to_string()).collect();
+        parts[1] = URL_SAFE_NO_PAD.encode(tampered.to_string());
+        assert!(
+            verify_id_token(&provider, &discovery, &parts.join("."), &pending.nonce)
+                .await
+                .is_err()
+        );
+    }
+}
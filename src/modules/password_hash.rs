@@ -1,8 +1,117 @@
+use crate::utils::constants::{
+    ARGON2_ITERATIONS, ARGON2_MEMORY_KIB, ARGON2_PARALLELISM, PASSWORD_PEPPER, PASSWORD_PEPPER_ID,
+};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, KeyId, ParamsBuilder, Version,
 };
 
+// Cost parameters and pepper for new hashes
+#[derive(Debug, Clone)]
+pub struct HashPolicy {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub pepper: String,
+    pub pepper_id: String,
+}
+
+impl HashPolicy {
+    pub fn configured() -> Self {
+        Self {
+            memory_kib: *ARGON2_MEMORY_KIB,
+            iterations: *ARGON2_ITERATIONS,
+            parallelism: *ARGON2_PARALLELISM,
+            pepper: PASSWORD_PEPPER.to_string(),
+            pepper_id: PASSWORD_PEPPER_ID.to_string(),
+        }
+    }
+
+    fn argon2(&self) -> Result<Argon2<'_>, argon2::password_hash::Error> {
+        let mut builder = ParamsBuilder::new();
+        builder
+            .m_cost(self.memory_kib)
+            .t_cost(self.iterations)
+            .p_cost(self.parallelism);
+
+        // The keyid tells a later verify which pepper the hash was made with
+        if self.pepper.is_empty() {
+            let params = builder.build()?;
+            return Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params));
+        }
+        builder.keyid(KeyId::new(self.pepper_id.as_bytes())?);
+        let params = builder.build()?;
+        Ok(Argon2::new_with_secret(
+            self.pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )?)
+    }
+
+    // Verifier for a stored hash, None when it was peppered with a pepper we no longer have
+    fn verifier(&self, hash: &PasswordHash) -> Option<Argon2<'_>> {
+        match hash.params.get_str("keyid") {
+            None => Some(Argon2::default()),
+            Some(keyid) if !self.pepper.is_empty() && keyid_matches(keyid, &self.pepper_id) => {
+                Argon2::new_with_secret(
+                    self.pepper.as_bytes(),
+                    Algorithm::Argon2id,
+                    Version::V0x13,
+                    Default::default(),
+                )
+                .ok()
+            }
+            Some(_) => None,
+        }
+    }
+
+    fn hash_password(&self, password: &str) -> Result<Password, argon2::password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self.argon2()?.hash_password(password.as_bytes(), &salt)?;
+        Ok(Password(hash.to_string()))
+    }
+
+    fn verify_password(
+        &self,
+        hashed_password: &str,
+        client_password: &str,
+    ) -> Result<bool, argon2::password_hash::Error> {
+        let hash = PasswordHash::new(hashed_password)?;
+
+        match self.verifier(&hash) {
+            Some(argon2) => Ok(argon2
+                .verify_password(client_password.as_bytes(), &hash)
+                .is_ok()),
+            None => Ok(false),
+        }
+    }
+
+    // A hash made with another algorithm, lower costs or without the current pepper
+    fn needs_rehash(&self, hashed_password: &str) -> bool {
+        let hash = match PasswordHash::new(hashed_password) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        let cost = |name: &str| hash.params.get_decimal(name).unwrap_or(0);
+        let pepper_missing = match hash.params.get_str("keyid") {
+            Some(keyid) => !keyid_matches(keyid, &self.pepper_id) || self.pepper.is_empty(),
+            None => !self.pepper.is_empty(),
+        };
+
+        hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+            || cost("m") < self.memory_kib
+            || cost("t") < self.iterations
+            || cost("p") < self.parallelism
+            || pepper_missing
+    }
+}
+
+fn keyid_matches(stored: &str, pepper_id: &str) -> bool {
+    stored.parse::<KeyId>().ok() == KeyId::new(pepper_id.as_bytes()).ok()
+}
+
 pub struct Password(pub String);
 
 impl Password {
@@ -13,18 +122,7 @@ impl Password {
 
     // Password hashing
     pub fn hash_password(password: &str) -> Result<Self, argon2::password_hash::Error> {
-        // Generate salt
-        let salt = SaltString::generate(&mut OsRng);
-
-        // Hash the password
-        let argon2 = Argon2::default();
-
-        match argon2.hash_password(password.as_bytes(), &salt) {
-            // Return the hashed password
-            Ok(pass) => Ok(Password(pass.to_string())),
-            // Return the error
-            Err(err) => Err(err),
-        }
+        HashPolicy::configured().hash_password(password)
     }
 
     // Get the string
@@ -37,16 +135,12 @@ impl Password {
         &self,
         client_password: &str,
     ) -> Result<bool, argon2::password_hash::Error> {
-        match PasswordHash::new(&self.0) {
-            Ok(pass) => {
-                let verified_password = Argon2::default()
-                    .verify_password(client_password.as_bytes(), &pass)
-                    .is_ok();
+        HashPolicy::configured().verify_password(&self.0, client_password)
+    }
 
-                Ok(verified_password)
-            }
-            Err(err) => Err(err),
-        }
+    // True when the hash is weaker than the configured policy and should be replaced
+    pub fn needs_rehash(&self) -> bool {
+        HashPolicy::configured().needs_rehash(&self.0)
     }
 }
 
@@ -135,5 +229,56 @@ mod password_tests {
             answer, false,
             "Expecting Eve to be found in the list of users"
         );
+
+        // Hashes with lower costs than the policy are upgraded
+        let policy = HashPolicy {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+            pepper: String::new(),
+            pepper_id: "1".to_string(),
+        };
+        assert!(
+            !policy.needs_rehash(&password_hash),
+            "Current hash is rehashed"
+        );
+        let stronger = HashPolicy {
+            iterations: 3,
+            ..policy.clone()
+        };
+        assert!(
+            stronger.needs_rehash(&password_hash),
+            "Weak hash is not rehashed"
+        );
+
+        // Peppered hashes only verify with the pepper
+        let peppered = HashPolicy {
+            pepper: "server-pepper".to_string(),
+            ..policy.clone()
+        };
+        let peppered_hash = peppered
+            .hash_password("hunter42")
+            .expect("Hashing with pepper failed")
+            .get_password_string();
+        assert_eq!(
+            peppered.verify_password(&peppered_hash, "hunter42"),
+            Ok(true)
+        );
+        assert_eq!(
+            peppered.verify_password(&peppered_hash, "hunter43"),
+            Ok(false)
+        );
+        assert_eq!(
+            policy.verify_password(&peppered_hash, "hunter42"),
+            Ok(false)
+        );
+        assert!(
+            !peppered.needs_rehash(&peppered_hash),
+            "Peppered hash is rehashed"
+        );
+        assert!(
+            peppered.needs_rehash(&password_hash),
+            "Hash without pepper is kept"
+        );
     }
 }
@@ -0,0 +1,178 @@
+use crate::utils::constants::{
+    PASSWORD_BREACH_CHECK, PASSWORD_MAX_LENGTH, PASSWORD_MIN_LENGTH, PASSWORD_REQUIRE_DIGIT,
+    PASSWORD_REQUIRE_LOWERCASE, PASSWORD_REQUIRE_SYMBOL, PASSWORD_REQUIRE_UPPERCASE,
+};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+
+// SHA-1 hashes of breached passwords as PREFIX:SUFFIX, bundled so the check works offline
+const BREACHED_PASSWORDS: &str = include_str!("breached_passwords.txt");
+// Length of the hash prefix a range is looked up by
+const RANGE_PREFIX_LENGTH: usize = 5;
+
+// Validation messages per form field, rendered next to the field they belong to
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct ValidationErrors(BTreeMap<String, Vec<String>>);
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.0
+            .entry(field.to_string())
+            .or_default()
+            .push(message.to_string());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn field(&self, field: &str) -> Vec<String> {
+        self.0.get(field).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub breach_check: bool,
+}
+
+impl PasswordPolicy {
+    // The policy configured in the environment
+    pub fn configured() -> Self {
+        Self {
+            min_length: *PASSWORD_MIN_LENGTH,
+            max_length: *PASSWORD_MAX_LENGTH,
+            require_lowercase: *PASSWORD_REQUIRE_LOWERCASE,
+            require_uppercase: *PASSWORD_REQUIRE_UPPERCASE,
+            require_digit: *PASSWORD_REQUIRE_DIGIT,
+            require_symbol: *PASSWORD_REQUIRE_SYMBOL,
+            breach_check: *PASSWORD_BREACH_CHECK,
+        }
+    }
+
+    // Check a new password and its confirmation, messages are added under the field names
+    pub fn validate(
+        &self,
+        username: &str,
+        password: &str,
+        confirm_password: &str,
+        errors: &mut ValidationErrors,
+    ) {
+        let length = password.chars().count();
+
+        if length < self.min_length {
+            errors.add(
+                "password",
+                &format!("Use at least {} characters", self.min_length),
+            );
+        }
+        if length > self.max_length {
+            errors.add(
+                "password",
+                &format!("Use at most {} characters", self.max_length),
+            );
+        }
+        if self.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            errors.add("password", "Use at least one lowercase letter");
+        }
+        if self.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            errors.add("password", "Use at least one uppercase letter");
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.add("password", "Use at least one digit");
+        }
+        if self.require_symbol && !password.chars().any(|c| !c.is_alphanumeric()) {
+            errors.add("password", "Use at least one symbol");
+        }
+        if !username.trim().is_empty() && password.trim().eq_ignore_ascii_case(username.trim()) {
+            errors.add("password", "The password can not be your username");
+        }
+        if self.breach_check && !password.is_empty() && is_breached(password) {
+            errors.add(
+                "password",
+                "This password is known from data breaches, please choose another one",
+            );
+        }
+        if password != confirm_password {
+            errors.add("confirm_password", "Passwords do not match");
+        }
+    }
+}
+
+// Look the hash up the way a k-anonymity range query would: by prefix, then by suffix
+pub fn is_breached(password: &str) -> bool {
+    let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(RANGE_PREFIX_LENGTH);
+
+    let is_breached = breached_range(prefix).any(|breached_suffix| breached_suffix == suffix);
+    is_breached
+}
+
+fn breached_range(prefix: &str) -> impl Iterator<Item = &'static str> + '_ {
+    BREACHED_PASSWORDS
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.trim().split_once(':'))
+        .filter(move |(range, _)| *range == prefix)
+        .map(|(_, suffix)| suffix)
+}
+
+#[cfg(test)]
+mod password_policy_tests {
+    use super::*;
+
+    #[test]
+    fn check_password_policy_operations() {
+        let policy = PasswordPolicy {
+            min_length: 10,
+            max_length: 64,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: false,
+            breach_check: true,
+        };
+
+        // A good password passes
+        let mut errors = ValidationErrors::new();
+        policy.validate("eve", "Correct1Horse", "Correct1Horse", &mut errors);
+        assert!(errors.is_empty(), "Good password refused: {:?}", errors);
+
+        // An empty password fails on several rules
+        let mut errors = ValidationErrors::new();
+        policy.validate("eve", "", "", &mut errors);
+        assert!(
+            errors.field("password").len() >= 3,
+            "Empty password accepted"
+        );
+
+        // The username and a mismatching confirmation are caught per field
+        let mut errors = ValidationErrors::new();
+        policy.validate("Eve1234567A", "eve1234567a", "other", &mut errors);
+        assert!(errors
+            .field("password")
+            .contains(&"The password can not be your username".to_string()));
+        assert_eq!(
+            errors.field("confirm_password"),
+            vec!["Passwords do not match".to_string()]
+        );
+
+        // Breached passwords are found in the bundled list
+        assert!(is_breached("Password123"), "Breached password not found");
+        assert!(!is_breached("Correct1Horse"), "Unknown password found");
+        let mut errors = ValidationErrors::new();
+        policy.validate("eve", "Password123", "Password123", &mut errors);
+        assert_eq!(errors.field("password").len(), 1);
+    }
+}
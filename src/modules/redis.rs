@@ -1,6 +1,6 @@
 use redis::aio::MultiplexedConnection;
 use redis::AsyncCommands;
-use redis::{Client, Commands, RedisError};
+use redis::{Client, Commands, ExistenceCheck, RedisError, SetExpiry, SetOptions};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
@@ -8,6 +8,8 @@ use std::fmt::Debug;
 pub enum RedisDbError {
     #[error("Database error: {0}")]
     RedisError(#[from] RedisError),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
 }
 
 pub enum RedisKeyNames {
@@ -19,6 +21,14 @@ pub enum RedisKeyNames {
     Cart,
     Stack,
     Queue,
+    TokenRevocation,
+    LoginAttempts,
+    LoginLockout,
+    TwoFactor,
+    TwoFactorEnrolment,
+    TwoFactorUsedCode,
+    UserSessions,
+    OidcState,
 }
 impl RedisKeyNames {
     pub fn get_key(&self, domain: &str) -> String {
@@ -31,6 +41,14 @@ impl RedisKeyNames {
             RedisKeyNames::Cart => format!("cart:{}", domain),
             RedisKeyNames::Stack => format!("stack:{}", domain),
             RedisKeyNames::Queue => format!("queue:{}", domain),
+            RedisKeyNames::TokenRevocation => format!("token_revocation:{}", domain),
+            RedisKeyNames::LoginAttempts => format!("login_attempts:{}", domain),
+            RedisKeyNames::LoginLockout => format!("login_lockout:{}", domain),
+            RedisKeyNames::TwoFactor => format!("two_factor:{}", domain),
+            RedisKeyNames::TwoFactorEnrolment => format!("two_factor_enrolment:{}", domain),
+            RedisKeyNames::TwoFactorUsedCode => format!("two_factor_used_code:{}", domain),
+            RedisKeyNames::UserSessions => format!("user_sessions:{}", domain),
+            RedisKeyNames::OidcState => format!("oidc_state:{}", domain),
         }
     }
 
@@ -44,6 +62,14 @@ impl RedisKeyNames {
             RedisKeyNames::Cart => "cart",
             RedisKeyNames::Stack => "stack",
             RedisKeyNames::Queue => "queue",
+            RedisKeyNames::TokenRevocation => "token_revocation",
+            RedisKeyNames::LoginAttempts => "login_attempts",
+            RedisKeyNames::LoginLockout => "login_lockout",
+            RedisKeyNames::TwoFactor => "two_factor",
+            RedisKeyNames::TwoFactorEnrolment => "two_factor_enrolment",
+            RedisKeyNames::TwoFactorUsedCode => "two_factor_used_code",
+            RedisKeyNames::UserSessions => "user_sessions",
+            RedisKeyNames::OidcState => "oidc_state",
         }
     }
 }
@@ -76,7 +102,7 @@ impl RedisDbAsync {
         let key: &str = RedisKeyNames::Shops.as_str();
         let field: String = RedisKeyNames::Shops.get_key(&shop.domain);
         let value: String = serde_json::to_string(shop).unwrap();
-        self.client.hset(key, field, value).await?;
+        self.client.hset::<_, _, _, ()>(key, field, value).await?;
         Ok(())
     }
 
@@ -84,7 +110,7 @@ impl RedisDbAsync {
     pub async fn set_shop_config_multi(&mut self, shop: &Shop) -> Result<(), RedisDbError> {
         let key: String = RedisKeyNames::Shops.get_key(&shop.domain);
         self.client
-            .hset_multiple(
+            .hset_multiple::<_, _, _, ()>(
                 key,
                 &[
                     ("domain", shop.domain.as_str()),
@@ -123,71 +149,127 @@ impl RedisDB {
     }
 
     // Return Redis Client
+    // Every command on a cloned Client opens its own connection, so the helpers below only
+    // need `&self` and can be called through the shared `web::Data<RedisDB>`
     pub fn get_client(&self) -> Client {
         self.client.clone()
     }
 
     // Set a value
     pub fn set_value<T: redis::ToRedisArgs>(
-        &mut self,
+        &self,
         key: &str,
         value: T,
     ) -> Result<(), RedisDbError> {
-        self.client.set(key, value).map_err(RedisDbError::from)
+        self.get_client()
+            .set(key, value)
+            .map_err(RedisDbError::from)
+    }
+
+    // Set a value that expires after the given amount of seconds
+    pub fn set_value_ex<T: redis::ToRedisArgs>(
+        &self,
+        key: &str,
+        value: T,
+        seconds: u64,
+    ) -> Result<(), RedisDbError> {
+        self.get_client()
+            .set_ex(key, value, seconds)
+            .map_err(RedisDbError::from)
+    }
+
+    // Set a value that expires, only when the key does not exist yet.
+    // False when it already did, so only one caller can claim a key.
+    pub fn set_value_nx_ex<T: redis::ToRedisArgs>(
+        &self,
+        key: &str,
+        value: T,
+        seconds: u64,
+    ) -> Result<bool, RedisDbError> {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(seconds as usize));
+        let stored: Option<String> = self
+            .get_client()
+            .set_options(key, value, options)
+            .map_err(RedisDbError::from)?;
+        Ok(stored.is_some())
     }
 
     // Get a value by key
-    pub fn get_value<T: redis::FromRedisValue>(&mut self, key: &str) -> Result<T, RedisDbError> {
-        self.client.get(key).map_err(RedisDbError::from)
+    pub fn get_value<T: redis::FromRedisValue>(&self, key: &str) -> Result<T, RedisDbError> {
+        self.get_client().get(key).map_err(RedisDbError::from)
+    }
+
+    // Get a value and delete it in one step, so only one caller can take it
+    pub fn take_value<T: redis::FromRedisValue>(&self, key: &str) -> Result<T, RedisDbError> {
+        self.get_client().get_del(key).map_err(RedisDbError::from)
+    }
+
+    // Check if a key exists
+    pub fn exists(&self, key: &str) -> Result<bool, RedisDbError> {
+        self.get_client().exists(key).map_err(RedisDbError::from)
+    }
+
+    // Let a key expire after the given amount of seconds
+    pub fn expire(&self, key: &str, seconds: u64) -> Result<(), RedisDbError> {
+        self.get_client()
+            .expire(key, seconds as i64)
+            .map_err(RedisDbError::from)
     }
 
     // Increment an integer value
-    pub fn incr(&mut self, key: &str, increment: isize) -> redis::RedisResult<isize> {
-        self.client.incr(key, increment)
+    pub fn incr(&self, key: &str, increment: isize) -> redis::RedisResult<isize> {
+        self.get_client().incr(key, increment)
     }
 
     // Get a value by key
-    pub fn delete_key(&mut self, key: &str) -> Result<bool, RedisDbError> {
-        let removed_count: isize = self.client.del(key).map_err(RedisDbError::from)?;
+    pub fn delete_key(&self, key: &str) -> Result<bool, RedisDbError> {
+        let removed_count: isize = self.get_client().del(key).map_err(RedisDbError::from)?;
         Ok(removed_count > 0)
     }
 
+    // Publish a message to every subscriber of a channel
+    pub fn publish<T: redis::ToRedisArgs>(
+        &self,
+        channel: &str,
+        message: T,
+    ) -> redis::RedisResult<()> {
+        self.get_client().publish(channel, message)
+    }
+
     // Add a value to a set
-    pub fn sadd<T: redis::ToRedisArgs>(&mut self, key: &str, value: T) -> redis::RedisResult<()> {
-        self.client.sadd(key, value)
+    pub fn sadd<T: redis::ToRedisArgs>(&self, key: &str, value: T) -> redis::RedisResult<()> {
+        self.get_client().sadd(key, value)
     }
 
     // Get all members of a set
-    pub fn smembers<T: redis::FromRedisValue>(&mut self, key: &str) -> redis::RedisResult<Vec<T>> {
-        self.client.smembers(key)
+    pub fn smembers<T: redis::FromRedisValue>(&self, key: &str) -> redis::RedisResult<Vec<T>> {
+        self.get_client().smembers(key)
     }
 
     // Remove a value from a set
-    pub fn srem<T: redis::ToRedisArgs>(
-        &mut self,
-        key: &str,
-        value: T,
-    ) -> redis::RedisResult<isize> {
-        self.client.srem(key, value)
+    pub fn srem<T: redis::ToRedisArgs>(&self, key: &str, value: T) -> redis::RedisResult<isize> {
+        self.get_client().srem(key, value)
     }
 
     // Set a field in a hash
     pub fn hset<T: redis::ToRedisArgs>(
-        &mut self,
+        &self,
         key: &str,
         field: &str,
         value: T,
     ) -> redis::RedisResult<()> {
-        self.client.hset(key, field, value)
+        self.get_client().hset(key, field, value)
     }
 
     // Get a value from a hash field
     pub fn hget<T: redis::FromRedisValue>(
-        &mut self,
+        &self,
         key: &str,
         field: &str,
     ) -> redis::RedisResult<Option<T>> {
-        self.client.hget(key, field)
+        self.get_client().hget(key, field)
     }
 
     //? Bug in the code below - it doesn't work as expected - it doesn't convert the value to a string
@@ -242,7 +324,7 @@ impl RedisDB {
         redis::cmd("LPUSH")
             .arg(key)
             .arg(value)
-            .query(&mut self.low_client)?;
+            .query::<()>(&mut self.low_client)?;
         Ok(())
     }
 
@@ -261,7 +343,7 @@ impl RedisDB {
         redis::cmd("RPUSH")
             .arg(key)
             .arg(value)
-            .query(&mut self.low_client)?;
+            .query::<()>(&mut self.low_client)?;
         Ok(())
     }
 
@@ -280,7 +362,7 @@ mod redis_tests {
     fn test_redis_operations() {
         // Setup Redis Connection
         let redis_url = crate::utils::constants::REDIS_URL.clone();
-        let mut redis_db = match RedisDB::new(&redis_url) {
+        let redis_db = match RedisDB::new(&redis_url) {
             Ok(db) => db,
             Err(e) => {
                 eprintln!("Failed to connect to Redis: {}", e);
@@ -0,0 +1,245 @@
+use crate::domain::datatypes::{LoginClient, UserCookie, UserRole, UserServer};
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::modules::token_pub;
+use crate::utils;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+// Last-seen is only written back once a minute, not on every request
+const SESSION_TOUCH_SECONDS: i64 = 60;
+
+// Everything the server keeps about a logged in browser, stored as JSON in Redis
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionData {
+    pub user_id: String,
+    pub username: String,
+    pub verified: bool,
+    pub role: UserRole,
+    pub shop_domain: String,
+    pub csrf_secret: String,
+    pub user_agent: String,
+    pub created_on: i64,
+    pub last_seen: i64,
+    pub idle_seconds: u64,
+}
+impl SessionData {
+    pub fn user_cookie(&self) -> UserCookie {
+        UserCookie {
+            user_id: self.user_id.to_string(),
+            username: self.username.to_string(),
+            verified: self.verified,
+            role: self.role.clone(),
+            shop_domain: self.shop_domain.to_string(),
+        }
+    }
+}
+
+// The session of the current request, put in the request extensions by CheckLogin
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub id: String,
+    pub data: SessionData,
+}
+
+pub fn sessions_enabled() -> bool {
+    *utils::constants::SERVER_SESSIONS
+}
+
+pub fn random_hex(length: usize) -> String {
+    let mut bytes = vec![0u8; length];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Sessions are listed and terminated by a handle, the session id itself never leaves the cookie
+pub fn session_handle(session_id: &str) -> String {
+    match orion::hash::digest(session_id.as_bytes()) {
+        Ok(digest) => hex::encode(&digest.as_ref()[..8]),
+        Err(_) => String::new(),
+    }
+}
+
+fn user_sessions_key(user_id: &str) -> String {
+    RedisKeyNames::UserSessions.get_key(user_id)
+}
+
+fn save_session(redis: &RedisDB, session_id: &str, data: &SessionData) -> Result<(), RedisDbError> {
+    let value = serde_json::to_string(data)?;
+    redis.set_value_ex(
+        &RedisKeyNames::Session.get_key(session_id),
+        value,
+        data.idle_seconds,
+    )
+}
+
+pub fn create_session(
+    redis: &RedisDB,
+    user: &UserServer,
+    client: &LoginClient,
+    remember: bool,
+) -> Result<String, RedisDbError> {
+    let session_id = random_hex(32);
+    let now = chrono::Utc::now().timestamp();
+    let idle_seconds = match remember {
+        true => *utils::constants::REFRESH_TOKEN_TTL_DAYS * 24 * 60 * 60,
+        false => *utils::constants::SESSION_IDLE_MINUTES * 60,
+    };
+
+    let data = SessionData {
+        user_id: user.user_id.to_string(),
+        username: user.username.to_string(),
+        verified: user.verified_at.is_some(),
+        role: user.role.clone(),
+        shop_domain: client.shop_domain.to_string(),
+        csrf_secret: random_hex(32),
+        user_agent: client.user_agent.to_string(),
+        created_on: now,
+        last_seen: now,
+        idle_seconds,
+    };
+
+    save_session(redis, &session_id, &data)?;
+    redis.sadd(&user_sessions_key(&user.user_id), session_id.as_str())?;
+
+    Ok(session_id)
+}
+
+// Load a session and slide its expiry forward
+pub fn load_session(
+    redis: &RedisDB,
+    session_id: &str,
+) -> Result<Option<SessionData>, RedisDbError> {
+    let value: Option<String> = redis.get_value(&RedisKeyNames::Session.get_key(session_id))?;
+    let mut data: SessionData = match value.and_then(|value| serde_json::from_str(&value).ok()) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if now - data.last_seen >= SESSION_TOUCH_SECONDS {
+        data.last_seen = now;
+        save_session(redis, session_id, &data)?;
+    }
+
+    Ok(Some(data))
+}
+
+// All live sessions of a user as (handle, data), newest first
+pub fn user_sessions(
+    redis: &RedisDB,
+    user_id: &str,
+) -> Result<Vec<(String, SessionData)>, RedisDbError> {
+    let session_ids: Vec<String> = redis.smembers(&user_sessions_key(user_id))?;
+    let mut sessions = Vec::new();
+
+    for session_id in session_ids {
+        let value: Option<String> =
+            redis.get_value(&RedisKeyNames::Session.get_key(&session_id))?;
+        match value.and_then(|value| serde_json::from_str::<SessionData>(&value).ok()) {
+            Some(data) => sessions.push((session_handle(&session_id), data)),
+            // Expired sessions are cleaned up from the index as we go
+            None => {
+                redis.srem(&user_sessions_key(user_id), session_id.as_str())?;
+            }
+        }
+    }
+
+    sessions.sort_by_key(|(_, data)| std::cmp::Reverse(data.last_seen));
+    Ok(sessions)
+}
+
+pub fn delete_session(
+    redis: &RedisDB,
+    user_id: &str,
+    session_id: &str,
+) -> Result<(), RedisDbError> {
+    redis.delete_key(&RedisKeyNames::Session.get_key(session_id))?;
+    redis.srem(&user_sessions_key(user_id), session_id)?;
+    Ok(())
+}
+
+// Terminate one session of a user by its handle, false when no session matched
+pub fn delete_session_by_handle(
+    redis: &RedisDB,
+    user_id: &str,
+    handle: &str,
+) -> Result<bool, RedisDbError> {
+    let session_ids: Vec<String> = redis.smembers(&user_sessions_key(user_id))?;
+
+    match session_ids
+        .iter()
+        .find(|session_id| session_handle(session_id) == handle)
+    {
+        Some(session_id) => {
+            delete_session(redis, user_id, session_id)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Terminate every session of a user, except the one to keep
+pub fn delete_user_sessions(
+    redis: &RedisDB,
+    user_id: &str,
+    keep: Option<&str>,
+) -> Result<(), RedisDbError> {
+    let session_ids: Vec<String> = redis.smembers(&user_sessions_key(user_id))?;
+
+    for session_id in session_ids.iter() {
+        if Some(session_id.as_str()) != keep {
+            delete_session(redis, user_id, session_id)?;
+        }
+    }
+    Ok(())
+}
+
+// Resolve the value of the auth cookie to a user, whatever session mode is configured
+pub fn authenticate(auth_value: &str, redis: &RedisDB) -> Option<(UserCookie, Option<Session>)> {
+    if !sessions_enabled() {
+        return token_pub::verify_token(auth_value, redis).map(|user| (user, None));
+    }
+
+    match load_session(redis, auth_value) {
+        Ok(Some(data)) => Some((
+            data.user_cookie(),
+            Some(Session {
+                id: auth_value.to_string(),
+                data,
+            }),
+        )),
+        Ok(None) => None,
+        Err(err) => {
+            log::warn!("Loading session failed: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+
+    #[test]
+    fn check_session_handle_operations() {
+        let session_id = random_hex(32);
+
+        // Handles are stable, short and do not reveal the session id
+        assert_eq!(session_id.len(), 64, "Session id is not 32 random bytes");
+        assert_eq!(
+            session_handle(&session_id),
+            session_handle(&session_id),
+            "Handle is not stable"
+        );
+        assert_eq!(session_handle(&session_id).len(), 16, "Handle is not short");
+        assert!(
+            !session_id.contains(&session_handle(&session_id)),
+            "Handle is part of the session id"
+        );
+        assert_ne!(
+            session_handle(&session_id),
+            session_handle(&random_hex(32)),
+            "Different sessions share a handle"
+        );
+    }
+}
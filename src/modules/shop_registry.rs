@@ -0,0 +1,339 @@
+use crate::db::sqlite::SqliteDB;
+use crate::domain::shops::{normalize_host, Shop, ShopConfig, ShopDomain};
+use crate::domain::subscriptions::{PlanFeature, ShopPlan};
+use crate::modules::redis::RedisDB;
+use crate::utils::constants::{PLATFORM_DOMAIN, SHOP_CONFIGS, SHOP_HOSTS};
+use futures_util::StreamExt;
+use redis::Commands;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Every instance listens on this channel, the message is the domain that changed
+pub const SHOP_UPDATES_CHANNEL: &str = "shop_updates";
+// Wait before connecting again after the subscription was lost
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+// The shop served on a domain, from the in-process cache
+pub fn get_shop(domain: &str) -> Option<Shop> {
+    SHOP_CONFIGS.lock().unwrap().get(domain).cloned()
+}
+
+// The shop domain and shop served on a host: the shop domain itself, one of its
+// verified custom domains or a subdomain of the platform named after its slug
+pub fn resolve_host(host: &str) -> Option<(String, Shop)> {
+    find_shop(&normalize_host(host), &normalize_host(&PLATFORM_DOMAIN))
+}
+
+fn find_shop(host: &str, platform_domain: &str) -> Option<(String, Shop)> {
+    // SHOP_CONFIGS is always locked before SHOP_HOSTS
+    let configs = SHOP_CONFIGS.lock().unwrap();
+    if let Some(shop) = configs.get(host) {
+        return Some((host.to_string(), shop.clone()));
+    }
+
+    let custom_domain = SHOP_HOSTS.lock().unwrap().get(host).cloned();
+    if let Some(domain) = custom_domain {
+        return configs.get(&domain).map(|shop| (domain, shop.clone()));
+    }
+
+    let slug = platform_slug(host, platform_domain)?;
+    configs
+        .iter()
+        .find(|(_, shop)| shop.slug.as_deref() == Some(slug))
+        .map(|(domain, shop)| (domain.clone(), shop.clone()))
+}
+
+// "shop1" for shop1.ourplatform.com, deeper subdomains are not routed
+fn platform_slug<'a>(host: &'a str, platform_domain: &str) -> Option<&'a str> {
+    if platform_domain.is_empty() {
+        return None;
+    }
+    let slug = host.strip_suffix(platform_domain)?.strip_suffix('.')?;
+    (!slug.is_empty() && !slug.contains('.')).then_some(slug)
+}
+
+// Replace the whole cache with the shops in the database
+pub async fn load_shop_configs(db: &SqliteDB) -> Result<(), sqlx::Error> {
+    let shops = db.get_all_shop_domains().await?;
+    let custom_domains = db.get_verified_domains().await?;
+    let plans = db.get_shop_plans().await?;
+
+    let mut configs: HashMap<String, Shop> = shops
+        .iter()
+        .map(|shop| {
+            let plan = plans.get(&shop.domain).copied().unwrap_or(ShopPlan::Free);
+            (shop.domain.clone(), shop.to_shop(plan))
+        })
+        .collect();
+    let mut hosts = HashMap::new();
+    for custom_domain in custom_domains {
+        let allowed = configs
+            .get(&custom_domain.shop_domain)
+            .is_some_and(|shop| shop.plan.allows(PlanFeature::CustomDomains));
+        if !allowed {
+            continue;
+        }
+        if custom_domain.is_canonical {
+            if let Some(shop) = configs.get_mut(&custom_domain.shop_domain) {
+                shop.canonical_domain = Some(custom_domain.domain.clone());
+            }
+        }
+        hosts.insert(custom_domain.domain, custom_domain.shop_domain);
+    }
+
+    let mut cached_configs = SHOP_CONFIGS.lock().unwrap();
+    *cached_configs = configs;
+    *SHOP_HOSTS.lock().unwrap() = hosts;
+    Ok(())
+}
+
+// Reload one shop, a shop that no longer exists is dropped from the cache
+pub async fn refresh_shop(db: &SqliteDB, domain: &str) -> Result<(), sqlx::Error> {
+    match db.get_one_shop_domain(domain).await {
+        Ok(shop) => {
+            let custom_domains = db.get_shop_domains(domain).await?;
+            let plan = db.get_shop_plan(domain).await?;
+            cache_shop(&shop, &custom_domains, plan);
+            Ok(())
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            let mut configs = SHOP_CONFIGS.lock().unwrap();
+            configs.remove(domain);
+            SHOP_HOSTS
+                .lock()
+                .unwrap()
+                .retain(|_, shop_domain| shop_domain != domain);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Only verified custom domains are served, and only while the plan includes them
+fn cache_shop(shop: &ShopConfig, custom_domains: &[ShopDomain], plan: ShopPlan) {
+    let verified: Vec<&ShopDomain> = custom_domains
+        .iter()
+        .filter(|custom_domain| custom_domain.verified_on.is_some())
+        .filter(|_| plan.allows(PlanFeature::CustomDomains))
+        .collect();
+    let mut cached = shop.to_shop(plan);
+    cached.canonical_domain = verified
+        .iter()
+        .find(|custom_domain| custom_domain.is_canonical)
+        .map(|custom_domain| custom_domain.domain.clone());
+
+    let mut configs = SHOP_CONFIGS.lock().unwrap();
+    let mut hosts = SHOP_HOSTS.lock().unwrap();
+    hosts.retain(|_, shop_domain| shop_domain != &shop.domain);
+    for custom_domain in verified {
+        hosts.insert(custom_domain.domain.clone(), shop.domain.clone());
+    }
+    configs.insert(shop.domain.clone(), cached);
+}
+
+// After a change: update this instance right away and tell the others
+pub async fn shop_changed(db: &SqliteDB, redis: &RedisDB, domain: &str) {
+    publish_shop_change(db, &redis.get_client(), domain).await
+}
+
+// The same for callers that only hold a client, like the Stripe event worker
+pub async fn publish_shop_change(db: &SqliteDB, client: &redis::Client, domain: &str) {
+    if let Err(err) = refresh_shop(db, domain).await {
+        eprintln!("Error refreshing shop {}: {:?}", domain, err);
+    }
+    let published: redis::RedisResult<()> = client.clone().publish(SHOP_UPDATES_CHANNEL, domain);
+    if let Err(err) = published {
+        log::warn!("Shop change of {} was not published: {}", domain, err);
+    }
+}
+
+// Keep the cache in sync with changes made by other instances. Messages sent while the
+// subscription was down are lost, so every new subscription starts with a full reload.
+pub async fn listen_for_shop_changes(client: redis::Client, db: SqliteDB) {
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => {
+                if let Err(err) = pubsub.subscribe(SHOP_UPDATES_CHANNEL).await {
+                    log::warn!("Failed to subscribe to shop updates: {}", err);
+                } else {
+                    if let Err(err) = load_shop_configs(&db).await {
+                        eprintln!("Failed to reload shop configurations: {}", err);
+                    }
+
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let domain: String = match message.get_payload() {
+                            Ok(domain) => domain,
+                            Err(err) => {
+                                log::warn!("Invalid shop update message: {}", err);
+                                continue;
+                            }
+                        };
+                        if let Err(err) = refresh_shop(&db, &domain).await {
+                            eprintln!("Error refreshing shop {}: {:?}", domain, err);
+                        }
+                    }
+                    log::warn!("Shop updates subscription closed");
+                }
+            }
+            Err(err) => log::warn!("Failed to connect for shop updates: {}", err),
+        }
+
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod shop_registry_tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn check_shop_registry_operations() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "shops_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let db = SqliteDB::new(&url).await;
+        let domain = format!("{}.registry.test", crate::modules::session::random_hex(4));
+
+        // A new shop is picked up by a refresh
+        let shop = ShopConfig {
+            domain: domain.clone(),
+            name: "Registry Shop".to_string(),
+            product_type: "Tea".to_string(),
+            user_id: None,
+            slug: None,
+            theme: Default::default(),
+        };
+        db.create_shop(&shop).await.unwrap();
+        refresh_shop(&db, &domain).await.unwrap();
+        assert_eq!(get_shop(&domain).unwrap().name, "Registry Shop");
+
+        // Updates replace the cached shop
+        let renamed = ShopConfig {
+            name: "Renamed Shop".to_string(),
+            ..shop
+        };
+        assert!(db.update_shop(&renamed).await.unwrap().is_some());
+        refresh_shop(&db, &domain).await.unwrap();
+        assert_eq!(get_shop(&domain).unwrap().name, "Renamed Shop");
+
+        // Custom domains are served once verified, the canonical one is remembered
+        let custom = format!("shop.{}", domain);
+        db.create_shop_domain(&domain, &custom, "token")
+            .await
+            .unwrap();
+        refresh_shop(&db, &domain).await.unwrap();
+        assert!(
+            resolve_host(&custom).is_none(),
+            "Unverified domain is served"
+        );
+        db.verify_shop_domain(&domain, &custom).await.unwrap();
+        assert!(db
+            .set_canonical_domain(&domain, Some(&custom))
+            .await
+            .unwrap());
+        refresh_shop(&db, &domain).await.unwrap();
+        let (shop_domain, shop) = resolve_host(&format!("WWW.{}:8080", custom)).unwrap();
+        assert_eq!(shop_domain, domain);
+        assert_eq!(shop.canonical_domain, Some(custom.clone()));
+
+        // An owned shop only keeps its custom domains and theme while its plan includes them
+        sqlx::query("INSERT INTO users (user_id, shop_domain, username, email, hashed_password, role) VALUES ('registry_owner', ?, 'olga', 'olga@example.com', '', 'shop_owner')")
+            .bind(&domain)
+            .execute(&db.db)
+            .await
+            .unwrap();
+        let owned = ShopConfig {
+            user_id: Some("registry_owner".to_string()),
+            ..renamed
+        };
+        assert!(db.update_shop(&owned).await.unwrap().is_some());
+        let theme = crate::domain::shops::ShopTheme {
+            primary_color: Some("#112233".to_string()),
+            ..Default::default()
+        };
+        assert!(db
+            .update_shop_theme(&domain, &theme)
+            .await
+            .unwrap()
+            .is_some());
+        refresh_shop(&db, &domain).await.unwrap();
+        assert!(
+            resolve_host(&custom).is_none(),
+            "Custom domain is served on the free plan"
+        );
+        assert_eq!(get_shop(&domain).unwrap().theme.primary_color, None);
+
+        let subscription = crate::domain::subscriptions::ShopSubscription {
+            shop_domain: domain.clone(),
+            plan: ShopPlan::Pro,
+            status: "active".to_string(),
+            stripe_customer_id: None,
+            stripe_subscription_id: Some("sub_registry".to_string()),
+            current_period_end: None,
+            cancel_at_period_end: false,
+            synced_at: 1,
+        };
+        assert!(db.upsert_shop_subscription(&subscription).await.unwrap());
+        refresh_shop(&db, &domain).await.unwrap();
+        assert!(resolve_host(&custom).is_some());
+        assert_eq!(
+            get_shop(&domain).unwrap().theme.primary_color.as_deref(),
+            Some("#112233")
+        );
+
+        // Deleted shops leave the cache
+        assert!(db.delete_shop(&domain).await.unwrap());
+        refresh_shop(&db, &domain).await.unwrap();
+        assert!(get_shop(&domain).is_none(), "Deleted shop is still served");
+        assert!(
+            resolve_host(&custom).is_none(),
+            "Deleted shop domain is still served"
+        );
+
+        db.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn check_host_resolution() {
+        // Ports, case, trailing dots and www do not matter
+        assert_eq!(normalize_host("WWW.Shop.com.:443"), "shop.com");
+        assert_eq!(normalize_host("localhost:3000"), "localhost");
+        assert_eq!(normalize_host("[::1]:3000"), "[::1]");
+
+        // Slugs are one label below the platform domain
+        assert_eq!(
+            platform_slug("shop1.platform.test", "platform.test"),
+            Some("shop1")
+        );
+        assert_eq!(
+            platform_slug("a.shop1.platform.test", "platform.test"),
+            None
+        );
+        assert_eq!(platform_slug("shop1platform.test", "platform.test"), None);
+        assert_eq!(platform_slug("shop1.platform.test", ""), None);
+
+        let domain = format!("{}.slug.test", crate::modules::session::random_hex(4));
+        let slug = format!("s{}", crate::modules::session::random_hex(4));
+        let shop = ShopConfig {
+            domain: domain.clone(),
+            name: "Slug Shop".to_string(),
+            product_type: "Tea".to_string(),
+            user_id: None,
+            slug: Some(slug.clone()),
+            theme: Default::default(),
+        };
+        cache_shop(&shop, &[], ShopPlan::Free);
+
+        let (shop_domain, _) =
+            find_shop(&format!("{}.platform.test", slug), "platform.test").unwrap();
+        assert_eq!(shop_domain, domain);
+        assert!(find_shop("unknown.platform.test", "platform.test").is_none());
+        SHOP_CONFIGS.lock().unwrap().remove(&domain);
+    }
+}
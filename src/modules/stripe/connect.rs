@@ -0,0 +1,17 @@
+use crate::db::sqlite::SqliteDB;
+
+// Store the capabilities of a Connect account as Stripe reported them at synced_at
+pub async fn sync_connect_account(
+    db: &SqliteDB,
+    account: &stripe::Account,
+    synced_at: i64,
+) -> Result<bool, sqlx::Error> {
+    db.update_connect_account_status(
+        account.id.as_str(),
+        account.charges_enabled.unwrap_or(false),
+        account.payouts_enabled.unwrap_or(false),
+        account.details_submitted.unwrap_or(false),
+        synced_at,
+    )
+    .await
+}
@@ -0,0 +1,98 @@
+use crate::db::sqlite::SqliteDB;
+use crate::db::tenant::TenantDB;
+use crate::domain::disputes::OrderDispute;
+use crate::domain::orders::Order;
+use crate::domain::products::format_price;
+use crate::modules::email::{EmailSettings, EmailType};
+use crate::modules::shop_registry;
+
+// Store that refunded_amount of the order was refunded in total. The customer is told
+// about each refund once, whether the webhook or the refund request stores it first.
+pub async fn record_refund(
+    db: &TenantDB,
+    order: &Order,
+    refunded_amount: i64,
+) -> Result<bool, sqlx::Error> {
+    if !db.set_order_refunded(order, refunded_amount).await? {
+        return Ok(false);
+    }
+
+    let refunded_now = refunded_amount.min(order.total) - order.refunded_amount;
+    if let Some(customer_email) = &order.customer_email {
+        send_refund_email(
+            order,
+            customer_email,
+            refunded_now,
+            refunded_amount + order.disputed_amount >= order.total,
+        )
+        .await;
+    }
+    Ok(true)
+}
+
+async fn send_refund_email(order: &Order, customer_email: &str, amount: i64, full: bool) {
+    let domain = shop_registry::get_shop(&order.shop_domain)
+        .and_then(|shop| shop.canonical_domain)
+        .unwrap_or_else(|| order.shop_domain.to_string());
+    let settings = crate::utils::constants::get_email_settings();
+    let email_settings = EmailSettings::order_refund_template(
+        customer_email.to_string(),
+        settings.email,
+        domain,
+        order.order_id,
+        format!("{} {}", format_price(amount), order.currency.to_uppercase()),
+        full,
+    );
+
+    if EmailType::OrderRefunded
+        .send_email(&email_settings)
+        .await
+        .is_err()
+    {
+        eprintln!("Error sending refund email for order {}", order.order_id);
+    }
+}
+
+// Our copy of a Stripe dispute. None for disputes on payments that are not for an order,
+// e.g. the subscriptions of the shops.
+pub async fn mirror_dispute(
+    db: &SqliteDB,
+    dispute: &stripe::Dispute,
+    synced_at: i64,
+) -> Result<Option<(OrderDispute, Order)>, sqlx::Error> {
+    let order = match &dispute.payment_intent {
+        Some(payment_intent) => {
+            db.get_order_by_payment_intent(payment_intent.id().as_str())
+                .await?
+        }
+        None => None,
+    };
+    let Some(order) = order else {
+        println!("Dispute {} is not for an order, ignoring it", dispute.id);
+        return Ok(None);
+    };
+
+    let record = OrderDispute {
+        dispute_id: dispute.id.to_string(),
+        shop_domain: order.shop_domain.to_string(),
+        order_id: order.order_id,
+        amount: dispute.amount,
+        currency: dispute.currency.to_string(),
+        reason: dispute.reason.to_string(),
+        status: dispute.status.as_str().to_string(),
+        evidence_due_by: dispute
+            .evidence_details
+            .due_by
+            .and_then(|due_by| chrono::DateTime::from_timestamp(due_by, 0))
+            .map(|time| time.naive_utc()),
+        synced_at,
+        created_on: None,
+    };
+    if !db.upsert_order_dispute(&record).await? {
+        println!(
+            "Dispute {} of order {} is older than the stored one",
+            dispute.id, order.order_id
+        );
+    }
+    Ok(Some((record, order)))
+}
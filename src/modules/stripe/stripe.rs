@@ -1,4 +1,7 @@
-use crate::utils::constants::STRIPE_SECRET;
+use crate::domain::connect_accounts::{application_fee, PaymentDestination};
+use crate::domain::orders::{OrderDetails, OrderItem};
+use crate::domain::subscriptions::ShopPlan;
+use crate::utils::constants::{STRIPE_APPLICATION_FEE_BPS, STRIPE_SECRET};
 use actix_web::{post, web, App, HttpRequest, HttpResponse, HttpServer};
 use serde_json::json;
 use std::borrow::Borrow;
@@ -7,16 +10,38 @@ use stripe::{
     CheckoutSessionMode, Client, CreateAccountLink, CreateCheckoutSession,
     CreateCheckoutSessionLineItems, CreatePrice, CreatePriceRecurring,
     CreatePriceRecurringInterval, CreateProduct, Currency, IdOrCreate, PaymentIntent, Price,
-    Product, StripeError, SubscriptionId, UpdateSubscription, UpdateSubscriptionItems,
+    Product, StripeError, Subscription, SubscriptionId, UpdateSubscription,
+    UpdateSubscriptionItems,
 };
+// Also exported for subscription items, the bare name is ambiguous
+pub use stripe::generated::billing::subscription::SubscriptionProrationBehavior;
 use stripe::{EventObject, EventType, Webhook, WebhookError};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StripeCurrency {
     USD,
     EUR,
     GBP,
 }
 impl StripeCurrency {
+    // Lowercase ISO code, the way Stripe and our tables store it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StripeCurrency::USD => "usd",
+            StripeCurrency::EUR => "eur",
+            StripeCurrency::GBP => "gbp",
+        }
+    }
+
+    pub fn parse(code: &str) -> Option<Self> {
+        match code.trim().to_lowercase().as_str() {
+            "usd" => Some(StripeCurrency::USD),
+            "eur" => Some(StripeCurrency::EUR),
+            "gbp" => Some(StripeCurrency::GBP),
+            _ => None,
+        }
+    }
+
     pub fn currency(&self) -> Currency {
         match self {
             StripeCurrency::USD => Currency::USD,
@@ -35,9 +60,12 @@ impl Stripe {
         let client = stripe::Client::new(STRIPE_SECRET.as_str());
         Stripe { client }
     }
-    // Create new Account for Stripe Connect
-    async fn create_account_stripe_connect(&self) -> Result<stripe::Account, StripeError> {
-        let account = stripe::Account::create(
+    // Create new Account for Stripe Connect, the metadata tells which shop it pays out
+    pub async fn create_account_stripe_connect(
+        &self,
+        shop_domain: &str,
+    ) -> Result<stripe::Account, StripeError> {
+        stripe::Account::create(
             &self.client,
             stripe::CreateAccount {
                 type_: Some(stripe::AccountType::Express),
@@ -50,28 +78,29 @@ impl Stripe {
                     }),
                     ..Default::default()
                 }),
+                metadata: Some(std::collections::HashMap::from([(
+                    String::from("shop_domain"),
+                    shop_domain.to_string(),
+                )])),
                 ..Default::default()
             },
         )
-        .await;
-        account
+        .await
     }
 
-    // Linking Account with Stripe Connect
+    // Linking Account with Stripe Connect. Links are single use and expire within minutes,
+    // Stripe sends the owner to ref_url for a new one and to ret_url when done.
     pub async fn create_account_link_stripe_connect(
         &self,
+        account_id: &str,
         ref_url: &str,
         ret_url: &str,
     ) -> Result<stripe::AccountLink, StripeError> {
-        let account = self.create_account_stripe_connect().await;
-        if account.is_err() {
-            return Err(account.err().unwrap());
-        }
-        let account = account.unwrap();
-        let link = stripe::AccountLink::create(
+        let account = parse_account_id(account_id)?;
+        stripe::AccountLink::create(
             &self.client,
             stripe::CreateAccountLink {
-                account: account.id,
+                account,
                 type_: AccountLinkType::AccountOnboarding,
                 collect: None,
                 expand: &[],
@@ -80,8 +109,12 @@ impl Stripe {
                 collection_options: None,
             },
         )
-        .await;
-        link
+        .await
+    }
+
+    pub async fn retrieve_account(&self, account_id: &str) -> Result<stripe::Account, StripeError> {
+        let account_id = parse_account_id(account_id)?;
+        stripe::Account::retrieve(&self.client, &account_id, &[]).await
     }
 
     // Create a new product
@@ -123,4 +156,221 @@ impl Stripe {
 
         Ok(price)
     }
+
+    // Line items priced by our own catalog, so no Stripe products or prices are needed
+    pub fn checkout_line_items(
+        items: &[OrderItem],
+        currency: StripeCurrency,
+    ) -> Vec<CreateCheckoutSessionLineItems> {
+        items
+            .iter()
+            .map(|item| CreateCheckoutSessionLineItems {
+                quantity: Some(item.quantity as u64),
+                price_data: Some(stripe::CreateCheckoutSessionLineItemsPriceData {
+                    currency: currency.currency(),
+                    unit_amount: Some(item.unit_price),
+                    product_data: Some(
+                        stripe::CreateCheckoutSessionLineItemsPriceDataProductData {
+                            name: match &item.sku {
+                                Some(sku) => format!("{} ({})", item.name, sku),
+                                None => item.name.to_string(),
+                            },
+                            ..Default::default()
+                        },
+                    ),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    // Create the checkout session that pays for an order. The session expires at the same
+    // time as the stock reserved for it, Stripe accepts 30 minutes to 24 hours.
+    // Payments for a Connect account are transferred to it, minus the application fee.
+    pub async fn create_order_checkout(
+        &self,
+        details: &OrderDetails,
+        currency: StripeCurrency,
+        success_url: &str,
+        cancel_url: &str,
+        expires_at: i64,
+        destination: &PaymentDestination,
+    ) -> Result<CheckoutSession, StripeError> {
+        let order_id = details.order.order_id.to_string();
+
+        let mut params = CreateCheckoutSession::new();
+        params.mode = Some(CheckoutSessionMode::Payment);
+        params.success_url = Some(success_url);
+        params.cancel_url = Some(cancel_url);
+        params.client_reference_id = Some(order_id.as_str());
+        params.customer_email = details.order.customer_email.as_deref();
+        params.expires_at = Some(expires_at);
+        params.line_items = Some(Self::checkout_line_items(&details.items, currency));
+        if let PaymentDestination::Account(account_id) = destination {
+            params.payment_intent_data = Some(stripe::CreateCheckoutSessionPaymentIntentData {
+                application_fee_amount: Some(application_fee(
+                    details.order.total,
+                    *STRIPE_APPLICATION_FEE_BPS,
+                )),
+                transfer_data: Some(stripe::CreateCheckoutSessionPaymentIntentDataTransferData {
+                    destination: account_id.to_string(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        params.metadata = Some(std::collections::HashMap::from([
+            (String::from("order_id"), order_id.to_string()),
+            (
+                String::from("shop_domain"),
+                details.order.shop_domain.to_string(),
+            ),
+        ]));
+
+        CheckoutSession::create(&self.client, params).await
+    }
+
+    // Close the checkout session of an order that will not be paid. Stripe refuses
+    // once the customer completed it.
+    pub async fn expire_checkout_session(
+        &self,
+        checkout_session_id: &str,
+    ) -> Result<CheckoutSession, StripeError> {
+        let checkout_session_id: stripe::CheckoutSessionId =
+            checkout_session_id.parse().map_err(|_| {
+                StripeError::ClientError(format!(
+                    "Invalid checkout session id {}",
+                    checkout_session_id
+                ))
+            })?;
+        CheckoutSession::expire(&self.client, &checkout_session_id).await
+    }
+
+    // Pay back part or all of an order. Payments that went to a Connect account are taken back
+    // from it, the application fee is returned in proportion.
+    pub async fn refund_order_payment(
+        &self,
+        payment_intent_id: &str,
+        amount: i64,
+        order_id: i64,
+    ) -> Result<stripe::Refund, StripeError> {
+        let payment_intent_id: stripe::PaymentIntentId =
+            payment_intent_id.parse().map_err(|_| {
+                StripeError::ClientError(format!("Invalid payment intent id {}", payment_intent_id))
+            })?;
+        let payment_intent = PaymentIntent::retrieve(&self.client, &payment_intent_id, &[]).await?;
+        let transferred = payment_intent.transfer_data.is_some();
+
+        let mut params = stripe::CreateRefund::new();
+        params.payment_intent = Some(payment_intent_id);
+        params.amount = Some(amount);
+        params.reason = Some(stripe::RefundReasonFilter::RequestedByCustomer);
+        params.metadata = Some(std::collections::HashMap::from([(
+            String::from("order_id"),
+            order_id.to_string(),
+        )]));
+        if transferred {
+            params.reverse_transfer = Some(true);
+            params.refund_application_fee = Some(true);
+        }
+
+        stripe::Refund::create(&self.client, params).await
+    }
+
+    // Create the checkout session of the first subscription of a shop. The metadata follows
+    // the subscription into the customer.subscription.* events, that is how they find the shop.
+    // Paid or not, the owner comes back to return_url.
+    pub async fn create_subscription_checkout(
+        &self,
+        shop_domain: &str,
+        plan: ShopPlan,
+        price_id: &str,
+        customer_id: Option<&str>,
+        customer_email: Option<&str>,
+        return_url: &str,
+    ) -> Result<CheckoutSession, StripeError> {
+        let metadata = std::collections::HashMap::from([
+            (String::from("shop_domain"), shop_domain.to_string()),
+            (String::from("plan"), plan.as_str().to_string()),
+        ]);
+
+        let mut params = CreateCheckoutSession::new();
+        params.mode = Some(CheckoutSessionMode::Subscription);
+        params.success_url = Some(return_url);
+        params.cancel_url = Some(return_url);
+        params.client_reference_id = Some(shop_domain);
+        // Stripe takes either a known customer or the email of a new one
+        params.customer = customer_id.and_then(|id| id.parse().ok());
+        if params.customer.is_none() {
+            params.customer_email = customer_email;
+        }
+        params.line_items = Some(vec![CreateCheckoutSessionLineItems {
+            price: Some(price_id.to_string()),
+            quantity: Some(1),
+            ..Default::default()
+        }]);
+        params.subscription_data = Some(stripe::CreateCheckoutSessionSubscriptionData {
+            metadata: Some(metadata.clone()),
+            ..Default::default()
+        });
+        params.metadata = Some(metadata);
+
+        CheckoutSession::create(&self.client, params).await
+    }
+
+    // Move a subscription to the price of another plan, a pending cancellation is withdrawn
+    pub async fn change_subscription_plan(
+        &self,
+        subscription_id: &str,
+        price_id: &str,
+        proration: SubscriptionProrationBehavior,
+    ) -> Result<Subscription, StripeError> {
+        let subscription_id = parse_subscription_id(subscription_id)?;
+        let subscription = Subscription::retrieve(&self.client, &subscription_id, &[]).await?;
+        let Some(item) = subscription.items.data.first() else {
+            return Err(StripeError::ClientError(
+                "The subscription has no items".to_string(),
+            ));
+        };
+
+        let mut params = UpdateSubscription::new();
+        params.items = Some(vec![UpdateSubscriptionItems {
+            id: Some(item.id.to_string()),
+            price: Some(price_id.to_string()),
+            ..Default::default()
+        }]);
+        params.cancel_at_period_end = Some(false);
+        params.proration_behavior = Some(proration);
+        Subscription::update(&self.client, &subscription_id, params).await
+    }
+
+    // Cancel a subscription now, or when the paid period ends
+    pub async fn cancel_subscription(
+        &self,
+        subscription_id: &str,
+        immediately: bool,
+    ) -> Result<Subscription, StripeError> {
+        let subscription_id = parse_subscription_id(subscription_id)?;
+        if immediately {
+            return Subscription::cancel(&self.client, &subscription_id, CancelSubscription::new())
+                .await;
+        }
+
+        let mut params = UpdateSubscription::new();
+        params.cancel_at_period_end = Some(true);
+        Subscription::update(&self.client, &subscription_id, params).await
+    }
+}
+
+fn parse_subscription_id(subscription_id: &str) -> Result<SubscriptionId, StripeError> {
+    subscription_id.parse().map_err(|_| {
+        StripeError::ClientError(format!("Invalid subscription id {}", subscription_id))
+    })
+}
+
+fn parse_account_id(account_id: &str) -> Result<stripe::AccountId, StripeError> {
+    account_id
+        .parse()
+        .map_err(|_| StripeError::ClientError(format!("Invalid account id {}", account_id)))
 }
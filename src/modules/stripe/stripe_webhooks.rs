@@ -1,52 +1,886 @@
-use actix_web::{web, HttpRequest};
-use stripe::{EventObject, EventType, Webhook, WebhookError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use stripe::{CheckoutSessionPaymentStatus, Event, EventObject, EventType, Webhook};
+
+use crate::db::sqlite::SqliteDB;
+use crate::domain::orders::OrderStatus;
+use crate::domain::stripe_events::StripeEvent;
+use crate::modules::stripe::connect::sync_connect_account;
+use crate::modules::stripe::refunds::{mirror_dispute, record_refund};
+use crate::modules::stripe::subscriptions::mirror_subscription;
+use crate::utils::constants::{
+    STRIPE_EVENT_MAX_ATTEMPTS, STRIPE_EVENT_POLL_SECONDS, STRIPE_EVENT_RETRY_SECONDS,
+    STRIPE_WEBHOOK_SECRET,
+};
 
 use std::borrow::Borrow;
 
-pub fn handle_webhook(req: HttpRequest, payload: web::Bytes) -> Result<(), WebhookError> {
-    let payload_str = std::str::from_utf8(payload.borrow()).unwrap();
+// How many events the worker takes on each round
+const EVENT_BATCH: i64 = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StripeEventError {
+    #[error("Invalid event payload: {0}")]
+    Payload(#[from] serde_json::Error),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
 
+// Verify the event and store it for the worker. Stripe gets a 400 for events that are not
+// from Stripe and a 500 when the event could not be stored, it then sends the event again.
+pub async fn handle_webhook(req: HttpRequest, payload: web::Bytes, db: &SqliteDB) -> HttpResponse {
+    let Ok(payload_str) = std::str::from_utf8(payload.borrow()) else {
+        return HttpResponse::BadRequest().json("Invalid payload");
+    };
     let stripe_signature = get_header_value(&req, "Stripe-Signature").unwrap_or_default();
 
-    if let Ok(event) = Webhook::construct_event(payload_str, stripe_signature, "whsec_xxxxx") {
-        match event.type_ {
-            EventType::AccountUpdated => {
-                if let EventObject::Account(account) = event.data.object {
-                    handle_account_updated(account)?;
-                }
-            }
-            EventType::CheckoutSessionCompleted => {
-                if let EventObject::CheckoutSession(session) = event.data.object {
-                    handle_checkout_session(session)?;
-                }
-            }
-            _ => {
-                println!("Unknown event encountered in webhook: {:?}", event.type_);
+    let event =
+        match Webhook::construct_event(payload_str, stripe_signature, &STRIPE_WEBHOOK_SECRET) {
+            Ok(event) => event,
+            Err(err) => {
+                println!("Failed to construct webhook event: {:?}", err);
+                return HttpResponse::BadRequest().json("Invalid signature");
             }
+        };
+
+    let event_type = event.type_.to_string();
+    match db
+        .create_stripe_event(event.id.as_str(), event_type.trim_matches('"'), payload_str)
+        .await
+    {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => {
+            println!("Skipping duplicate webhook event {}", event.id);
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            eprintln!("Error storing webhook event {}: {:?}", event.id, err);
+            HttpResponse::InternalServerError().finish()
         }
-    } else {
-        println!("Failed to construct webhook event, ensure your webhook secret is correct.");
     }
-
-    Ok(())
 }
 
 fn get_header_value<'b>(req: &'b HttpRequest, key: &'b str) -> Option<&'b str> {
     req.headers().get(key)?.to_str().ok()
 }
 
-fn handle_account_updated(account: stripe::Account) -> Result<(), WebhookError> {
+// Runs next to the server and processes the stored events as they become due.
+// Plan changes go out to the shop caches through shop_updates.
+pub async fn run_event_worker(db: SqliteDB, shop_updates: redis::Client) {
+    let poll = std::time::Duration::from_secs(*STRIPE_EVENT_POLL_SECONDS);
+    loop {
+        process_due_events(&db, &shop_updates).await;
+        tokio::time::sleep(poll).await;
+    }
+}
+
+// Process the events that are due, returns how many were processed
+pub async fn process_due_events(db: &SqliteDB, shop_updates: &redis::Client) -> usize {
+    let events = match db.get_due_stripe_events(EVENT_BATCH).await {
+        Ok(events) => events,
+        Err(err) => {
+            eprintln!("Error getting webhook events: {:?}", err);
+            return 0;
+        }
+    };
+
+    let mut processed = 0;
+    for event in events {
+        if process_stored_event(db, shop_updates, &event).await {
+            processed += 1;
+        }
+    }
+    processed
+}
+
+async fn process_stored_event(
+    db: &SqliteDB,
+    shop_updates: &redis::Client,
+    stored: &StripeEvent,
+) -> bool {
+    match db
+        .claim_stripe_event(
+            stored,
+            stored.retry_delay_seconds(*STRIPE_EVENT_RETRY_SECONDS),
+        )
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => return false,
+        Err(err) => {
+            eprintln!(
+                "Error claiming webhook event {}: {:?}",
+                stored.event_id, err
+            );
+            return false;
+        }
+    }
+
+    let result = match serde_json::from_str::<Event>(&stored.payload) {
+        Ok(event) => process_event(event, db, shop_updates).await,
+        Err(err) => Err(StripeEventError::Payload(err)),
+    };
+    let update = match &result {
+        Ok(()) => db.set_stripe_event_processed(&stored.event_id).await,
+        Err(err) => {
+            eprintln!(
+                "Error processing webhook event {}: {}",
+                stored.event_id, err
+            );
+            let give_up = stored.attempts + 1 >= *STRIPE_EVENT_MAX_ATTEMPTS;
+            db.set_stripe_event_failed(&stored.event_id, &err.to_string(), give_up)
+                .await
+        }
+    };
+    if let Err(err) = update {
+        eprintln!(
+            "Error updating webhook event {}: {:?}",
+            stored.event_id, err
+        );
+    }
+    result.is_ok()
+}
+
+// `backend replay-stripe-events [event_id]`: failed events go back in the queue
+// and are processed right away
+pub async fn replay_failed_events(
+    db: &SqliteDB,
+    shop_updates: &redis::Client,
+    event_id: Option<&str>,
+) {
+    match db.replay_stripe_events(event_id).await {
+        Ok(replayed) => println!("{} webhook events queued again", replayed),
+        Err(err) => {
+            eprintln!("Error replaying webhook events: {:?}", err);
+            return;
+        }
+    }
+    let processed = process_due_events(db, shop_updates).await;
+    println!("{} webhook events processed", processed);
+}
+
+async fn process_event(
+    event: Event,
+    db: &SqliteDB,
+    shop_updates: &redis::Client,
+) -> Result<(), StripeEventError> {
+    match event.type_ {
+        EventType::AccountUpdated => {
+            if let EventObject::Account(account) = event.data.object {
+                handle_account_updated(account, db, event.created).await?;
+            }
+        }
+        EventType::CheckoutSessionCompleted => {
+            if let EventObject::CheckoutSession(session) = event.data.object {
+                handle_checkout_session(session, db).await?;
+            }
+        }
+        EventType::CheckoutSessionAsyncPaymentSucceeded => {
+            if let EventObject::CheckoutSession(session) = event.data.object {
+                handle_checkout_session_paid(session, db).await?;
+            }
+        }
+        EventType::CheckoutSessionExpired | EventType::CheckoutSessionAsyncPaymentFailed => {
+            if let EventObject::CheckoutSession(session) = event.data.object {
+                handle_checkout_session_expired(session, db).await?;
+            }
+        }
+        EventType::CustomerSubscriptionCreated
+        | EventType::CustomerSubscriptionUpdated
+        | EventType::CustomerSubscriptionDeleted
+        | EventType::CustomerSubscriptionPaused
+        | EventType::CustomerSubscriptionResumed => {
+            if let EventObject::Subscription(subscription) = event.data.object {
+                mirror_subscription(db, shop_updates, &subscription, event.created).await?;
+            }
+        }
+        EventType::ChargeRefunded => {
+            if let EventObject::Charge(charge) = event.data.object {
+                handle_charge_refunded(charge, db).await?;
+            }
+        }
+        EventType::ChargeDisputeCreated
+        | EventType::ChargeDisputeUpdated
+        | EventType::ChargeDisputeClosed
+        | EventType::ChargeDisputeFundsWithdrawn
+        | EventType::ChargeDisputeFundsReinstated => {
+            if let EventObject::Dispute(dispute) = event.data.object {
+                handle_dispute(dispute, db, event.created).await?;
+            }
+        }
+        _ => {
+            println!("Unknown event encountered in webhook: {:?}", event.type_);
+        }
+    }
+
+    Ok(())
+}
+
+// Onboarding and later reviews by Stripe turn charges and payouts on and off
+async fn handle_account_updated(
+    account: stripe::Account,
+    db: &SqliteDB,
+    synced_at: i64,
+) -> Result<(), StripeEventError> {
     println!(
         "Received account updated webhook for account: {:?}",
         account.id
     );
+    if !sync_connect_account(db, &account, synced_at).await? {
+        println!(
+            "Account {} is unknown or older than the stored status",
+            account.id
+        );
+    }
+    Ok(())
+}
+
+// Refunds from our admins and from the Stripe dashboard alike, amount_refunded is the total so far
+async fn handle_charge_refunded(
+    charge: stripe::Charge,
+    db: &SqliteDB,
+) -> Result<(), StripeEventError> {
+    println!("Received charge refunded webhook with id: {:?}", charge.id);
+    let Some(payment_intent) = &charge.payment_intent else {
+        return Ok(());
+    };
+    let Some(order) = db
+        .get_order_by_payment_intent(payment_intent.id().as_str())
+        .await?
+    else {
+        println!("Charge {} is not for an order, ignoring it", charge.id);
+        return Ok(());
+    };
+
+    let db = db.for_shop(&order.shop_domain);
+    record_refund(&db, &order, charge.amount_refunded).await?;
     Ok(())
 }
 
-fn handle_checkout_session(session: stripe::CheckoutSession) -> Result<(), WebhookError> {
+// A lost dispute takes the disputed amount back from the shop. It is kept apart
+// from the refunds, which Stripe reports as a running total of their own.
+async fn handle_dispute(
+    dispute: stripe::Dispute,
+    db: &SqliteDB,
+    synced_at: i64,
+) -> Result<(), StripeEventError> {
+    println!("Received dispute webhook with id: {:?}", dispute.id);
+    let Some((_, order)) = mirror_dispute(db, &dispute, synced_at).await? else {
+        return Ok(());
+    };
+
+    db.set_order_disputed(order.order_id).await?;
+    Ok(())
+}
+
+// Card payments are paid when the session completes, bank debits and the like
+// only later with an async_payment_succeeded event
+async fn handle_checkout_session(
+    session: stripe::CheckoutSession,
+    db: &SqliteDB,
+) -> Result<(), StripeEventError> {
     println!(
         "Received checkout session completed webhook with id: {:?}",
         session.id
     );
+    match session.payment_status {
+        CheckoutSessionPaymentStatus::Paid | CheckoutSessionPaymentStatus::NoPaymentRequired => {
+            handle_checkout_session_paid(session, db).await
+        }
+        CheckoutSessionPaymentStatus::Unpaid => Ok(()),
+    }
+}
+
+// The order is paid and the reserved stock is sold
+async fn handle_checkout_session_paid(
+    session: stripe::CheckoutSession,
+    db: &SqliteDB,
+) -> Result<(), StripeEventError> {
+    let payment_intent_id = session.payment_intent.as_ref().map(|intent| intent.id());
+    update_session_order(
+        db,
+        session.id.as_str(),
+        OrderStatus::Paid,
+        payment_intent_id.as_ref().map(|id| id.as_str()),
+    )
+    .await?;
+
+    db.complete_reservations(session.id.as_str()).await?;
     Ok(())
 }
+
+// The order is cancelled and the reserved stock goes back on sale
+async fn handle_checkout_session_expired(
+    session: stripe::CheckoutSession,
+    db: &SqliteDB,
+) -> Result<(), StripeEventError> {
+    println!(
+        "Received checkout session expired or failed webhook with id: {:?}",
+        session.id
+    );
+    update_session_order(db, session.id.as_str(), OrderStatus::Cancelled, None).await?;
+
+    db.release_reservations(session.id.as_str()).await?;
+    Ok(())
+}
+
+// Sessions without an order only held stock. An order already
+// in the new status is left alone.
+async fn update_session_order(
+    db: &SqliteDB,
+    checkout_session_id: &str,
+    status: OrderStatus,
+    payment_intent_id: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let Some(order) = db.get_order_by_session(checkout_session_id).await? else {
+        return Ok(());
+    };
+    if order.status == status {
+        return Ok(());
+    }
+
+    if !db
+        .update_order_status(&order, status, payment_intent_id)
+        .await?
+    {
+        eprintln!(
+            "Order {} cannot go from {} to {}",
+            order.order_id,
+            order.status.as_str(),
+            status.as_str()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod stripe_webhooks_tests {
+    use super::*;
+    use crate::domain::cart::Cart;
+    use crate::domain::connect_accounts::PaymentDestination;
+    use crate::domain::stripe_events::StripeEventStatus;
+    use crate::domain::subscriptions::ShopPlan;
+    use actix_web::http::StatusCode;
+    use hmac::{Hmac, Mac};
+
+    const CHECKOUT_COMPLETED: &str = include_str!("checkout_session_completed.json");
+    const SUBSCRIPTION_UPDATED: &str = include_str!("customer_subscription_updated.json");
+    const ACCOUNT_UPDATED: &str = include_str!("account_updated.json");
+    const CHARGE_REFUNDED: &str = include_str!("charge_refunded.json");
+    const DISPUTE_CREATED: &str = include_str!("charge_dispute_created.json");
+
+    // Sign like Stripe does, with the secret of the webhook endpoint
+    fn sign(payload: &str) -> String {
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut mac =
+            Hmac::<sha2::Sha256>::new_from_slice(STRIPE_WEBHOOK_SECRET.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+
+    async fn deliver(db: &SqliteDB, payload: &str, signature: &str) -> StatusCode {
+        let req = actix_web::test::TestRequest::post()
+            .uri("/stripe_webhooks")
+            .insert_header(("Stripe-Signature", signature))
+            .to_http_request();
+        handle_webhook(req, web::Bytes::from(payload.to_string()), db)
+            .await
+            .status()
+    }
+
+    async fn stored_event(db: &SqliteDB, event_id: &str) -> StripeEvent {
+        sqlx::query_as::<_, StripeEvent>("SELECT * FROM stripe_events WHERE event_id = ?")
+            .bind(event_id)
+            .fetch_one(&db.db)
+            .await
+            .unwrap()
+    }
+
+    // Stripe events are large, parsing them in debug builds needs more than the stack of a test thread
+    #[test]
+    fn check_webhook_events() {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| actix_rt::System::new().block_on(webhook_events()))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    // Nothing listens in the tests, publishing the shop changes fails quietly
+    fn shop_updates() -> redis::Client {
+        redis::Client::open("redis://127.0.0.1:1/").unwrap()
+    }
+
+    async fn test_database() -> (SqliteDB, std::path::PathBuf) {
+        if std::env::var("STRIPE_WEBHOOK_SECRET").is_err() {
+            std::env::set_var("STRIPE_WEBHOOK_SECRET", "whsec_test_secret");
+        }
+        let path = std::env::temp_dir().join(format!(
+            "webhooks_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        (SqliteDB::new(&url).await, path)
+    }
+
+    // a.test, run by its owner
+    async fn owned_shop(db: &SqliteDB) {
+        sqlx::query("INSERT INTO users (user_id, shop_domain, username, email, hashed_password, role) VALUES ('owner', 'a.test', 'olga', 'olga@example.com', '', 'shop_owner')")
+            .execute(&db.db)
+            .await
+            .unwrap();
+        let shop = crate::domain::shops::ShopConfig {
+            domain: "a.test".to_string(),
+            name: "Owned Shop".to_string(),
+            product_type: "Tea".to_string(),
+            user_id: Some("owner".to_string()),
+            slug: None,
+            theme: Default::default(),
+        };
+        db.create_shop(&shop).await.unwrap();
+    }
+
+    async fn webhook_events() {
+        // Arrange
+        let (database, path) = test_database().await;
+        let shop = database.for_shop("a.test");
+        let product: crate::domain::products::ProductIn =
+            serde_json::from_str(r#"{"name":"Mug","price":"15","currency":"usd"}"#).unwrap();
+        let mug = shop
+            .create_product(&product.validate().unwrap())
+            .await
+            .unwrap();
+        let mut cart = Cart::default();
+        cart.set_quantity(mug.product_id, None, 2).unwrap();
+        let (priced, _) = crate::modules::cart::price_cart(&shop, &cart)
+            .await
+            .unwrap();
+        let order = shop.create_order("customer", None, &priced).await.unwrap();
+        shop.set_checkout_session(&order, "cs_test_fixture")
+            .await
+            .unwrap();
+
+        // Events that Stripe did not sign are refused
+        assert_eq!(
+            deliver(&database, CHECKOUT_COMPLETED, "t=1,v1=00").await,
+            StatusCode::BAD_REQUEST
+        );
+        let tampered = CHECKOUT_COMPLETED.replace("pi_test_fixture", "pi_other");
+        assert_eq!(
+            deliver(&database, &tampered, &sign(CHECKOUT_COMPLETED)).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // A delivery is stored once, however often it arrives
+        let signature = sign(CHECKOUT_COMPLETED);
+        assert_eq!(
+            deliver(&database, CHECKOUT_COMPLETED, &signature).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            deliver(&database, CHECKOUT_COMPLETED, &signature).await,
+            StatusCode::OK
+        );
+        let event = stored_event(&database, "evt_test_checkout_completed").await;
+        assert_eq!(event.event_type, "checkout.session.completed");
+        assert_eq!(event.status, StripeEventStatus::Pending);
+
+        // The worker pays the order, processed events are not due again
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 0);
+        let paid = shop.get_one_order(order.order_id).await.unwrap().unwrap();
+        assert_eq!(paid.status, OrderStatus::Paid);
+        assert_eq!(paid.payment_intent_id.as_deref(), Some("pi_test_fixture"));
+        let event = stored_event(&database, "evt_test_checkout_completed").await;
+        assert_eq!(
+            (event.status, event.attempts),
+            (StripeEventStatus::Processed, 1)
+        );
+
+        // Failing events wait longer after every attempt and are given up after the last
+        database
+            .create_stripe_event("evt_broken", "checkout.session.completed", "{}")
+            .await
+            .unwrap();
+        for _ in 0..*STRIPE_EVENT_MAX_ATTEMPTS {
+            assert_eq!(process_due_events(&database, &shop_updates()).await, 0);
+            assert!(database.get_due_stripe_events(10).await.unwrap().is_empty());
+            sqlx::query("UPDATE stripe_events SET next_attempt_at = CURRENT_TIMESTAMP")
+                .execute(&database.db)
+                .await
+                .unwrap();
+        }
+        let broken = stored_event(&database, "evt_broken").await;
+        assert_eq!(broken.status, StripeEventStatus::Failed);
+        assert_eq!(broken.attempts, *STRIPE_EVENT_MAX_ATTEMPTS);
+        assert!(broken
+            .last_error
+            .unwrap()
+            .starts_with("Invalid event payload"));
+        assert!(database.get_due_stripe_events(10).await.unwrap().is_empty());
+
+        // A replay starts over
+        assert_eq!(
+            database
+                .replay_stripe_events(Some("evt_other"))
+                .await
+                .unwrap(),
+            0
+        );
+        replay_failed_events(&database, &shop_updates(), Some("evt_broken")).await;
+        let replayed = stored_event(&database, "evt_broken").await;
+        assert_eq!(
+            (replayed.status, replayed.attempts),
+            (StripeEventStatus::Pending, 1)
+        );
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn check_subscription_events() {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| actix_rt::System::new().block_on(subscription_events()))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    async fn subscription_events() {
+        // Arrange
+        if std::env::var("STRIPE_PRICE_STARTER").is_err() {
+            std::env::set_var("STRIPE_PRICE_STARTER", "price_test_starter");
+        }
+        let (database, path) = test_database().await;
+        owned_shop(&database).await;
+        assert_eq!(
+            database.get_shop_plan("a.test").await.unwrap(),
+            ShopPlan::Free
+        );
+
+        // The subscription is mirrored and unlocks its plan
+        let signature = sign(SUBSCRIPTION_UPDATED);
+        assert_eq!(
+            deliver(&database, SUBSCRIPTION_UPDATED, &signature).await,
+            StatusCode::OK
+        );
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        let subscription = database
+            .get_shop_subscription("a.test")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (subscription.plan, subscription.status.as_str()),
+            (ShopPlan::Starter, "active")
+        );
+        assert_eq!(
+            subscription.stripe_customer_id.as_deref(),
+            Some("cus_test_fixture")
+        );
+        assert_eq!(
+            database.get_shop_plan("a.test").await.unwrap(),
+            ShopPlan::Starter
+        );
+
+        // Events that arrive late do not replace newer data
+        let late = SUBSCRIPTION_UPDATED
+            .replace("evt_test_subscription_updated", "evt_test_late")
+            .replace("\"created\": 1760000000", "\"created\": 1750000000")
+            .replace("\"status\": \"active\"", "\"status\": \"incomplete\"");
+        deliver(&database, &late, &sign(&late)).await;
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        assert_eq!(
+            database.get_shop_plan("a.test").await.unwrap(),
+            ShopPlan::Starter
+        );
+
+        // Other subscriptions cannot take over a running one
+        let other = SUBSCRIPTION_UPDATED
+            .replace("evt_test_subscription_updated", "evt_test_other")
+            .replace("\"created\": 1760000000", "\"created\": 1760000100")
+            .replace("sub_test_fixture", "sub_test_other")
+            .replace(
+                "\"status\": \"active\"",
+                "\"status\": \"incomplete_expired\"",
+            );
+        deliver(&database, &other, &sign(&other)).await;
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        let subscription = database
+            .get_shop_subscription("a.test")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            subscription.stripe_subscription_id.as_deref(),
+            Some("sub_test_fixture")
+        );
+
+        // Deleted subscriptions fall back to the free plan
+        let deleted = SUBSCRIPTION_UPDATED
+            .replace("evt_test_subscription_updated", "evt_test_deleted")
+            .replace("\"created\": 1760000000", "\"created\": 1760000200")
+            .replace(
+                "customer.subscription.updated",
+                "customer.subscription.deleted",
+            )
+            .replace("\"status\": \"active\"", "\"status\": \"canceled\"");
+        deliver(&database, &deleted, &sign(&deleted)).await;
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        assert_eq!(
+            database.get_shop_plan("a.test").await.unwrap(),
+            ShopPlan::Free
+        );
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn check_account_events() {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| actix_rt::System::new().block_on(account_events()))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    async fn account_events() {
+        // Arrange
+        let (database, path) = test_database().await;
+        owned_shop(&database).await;
+        assert_eq!(
+            database.get_payment_destination("a.test").await.unwrap(),
+            PaymentDestination::Unavailable
+        );
+        assert_eq!(
+            database
+                .get_payment_destination("unknown.test")
+                .await
+                .unwrap(),
+            PaymentDestination::Unavailable
+        );
+        let account = database
+            .create_connect_account("a.test", "acct_test_fixture")
+            .await
+            .unwrap();
+        assert!(!account.charges_enabled);
+        // Shops keep their first account
+        let again = database
+            .create_connect_account("a.test", "acct_test_other")
+            .await
+            .unwrap();
+        assert_eq!(again.stripe_account_id, "acct_test_fixture");
+
+        // Onboarding turns the charges on, the shop is paid through its account
+        assert_eq!(
+            deliver(&database, ACCOUNT_UPDATED, &sign(ACCOUNT_UPDATED)).await,
+            StatusCode::OK
+        );
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        let account = database
+            .get_connect_account("a.test")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (
+                account.charges_enabled,
+                account.payouts_enabled,
+                account.details_submitted
+            ),
+            (true, false, true)
+        );
+        assert_eq!(
+            database.get_payment_destination("a.test").await.unwrap(),
+            PaymentDestination::Account("acct_test_fixture".to_string())
+        );
+
+        // Events that arrive late do not replace newer data
+        let late = ACCOUNT_UPDATED
+            .replace("evt_test_account_updated", "evt_test_late")
+            .replace("\"created\": 1760000000", "\"created\": 1750000000")
+            .replace("\"charges_enabled\": true", "\"charges_enabled\": false");
+        deliver(&database, &late, &sign(&late)).await;
+        assert_eq!(process_due_events(&database, &shop_updates()).await, 1);
+        assert!(
+            database
+                .get_connect_account("a.test")
+                .await
+                .unwrap()
+                .unwrap()
+                .charges_enabled
+        );
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn check_refund_and_dispute_events() {
+        std::thread::Builder::new()
+            .stack_size(16 * 1024 * 1024)
+            .spawn(|| actix_rt::System::new().block_on(refund_and_dispute_events()))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    // Deliver and process one event
+    async fn process(db: &SqliteDB, payload: &str) {
+        assert_eq!(deliver(db, payload, &sign(payload)).await, StatusCode::OK);
+        assert_eq!(process_due_events(db, &shop_updates()).await, 1);
+    }
+
+    async fn refund_and_dispute_events() {
+        // Arrange
+        let (database, path) = test_database().await;
+        let shop = database.for_shop("a.test");
+        let product: crate::domain::products::ProductIn =
+            serde_json::from_str(r#"{"name":"Mug","price":"15","currency":"usd"}"#).unwrap();
+        let mug = shop
+            .create_product(&product.validate().unwrap())
+            .await
+            .unwrap();
+        let mut cart = Cart::default();
+        cart.set_quantity(mug.product_id, None, 2).unwrap();
+        let (priced, _) = crate::modules::cart::price_cart(&shop, &cart)
+            .await
+            .unwrap();
+        let paid_order = |payment_intent_id: &'static str| {
+            let shop = shop.clone();
+            let priced = priced.clone();
+            async move {
+                let order = shop.create_order("customer", None, &priced).await.unwrap();
+                shop.update_order_status(&order, OrderStatus::Paid, Some(payment_intent_id))
+                    .await
+                    .unwrap();
+                order.order_id
+            }
+        };
+        let refunded = paid_order("pi_test_refund").await;
+        let disputed = paid_order("pi_test_dispute").await;
+
+        // Partial refunds are added up, the order stays paid
+        process(&database, CHARGE_REFUNDED).await;
+        let order = shop.get_one_order(refunded).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                order.status,
+                order.refunded_amount,
+                order.refundable_amount()
+            ),
+            (OrderStatus::Paid, 1000, 2000)
+        );
+        // An event that reports less than is stored changes nothing
+        let late = CHARGE_REFUNDED
+            .replace("evt_test_charge_refunded", "evt_test_late")
+            .replace("\"amount_refunded\": 1000", "\"amount_refunded\": 500");
+        process(&database, &late).await;
+        let order = shop.get_one_order(refunded).await.unwrap().unwrap();
+        assert_eq!(order.refunded_amount, 1000);
+
+        // Refunding the rest refunds the order
+        let full = CHARGE_REFUNDED
+            .replace("evt_test_charge_refunded", "evt_test_full")
+            .replace("\"amount_refunded\": 1000", "\"amount_refunded\": 3000")
+            .replace("\"refunded\": false", "\"refunded\": true");
+        process(&database, &full).await;
+        let order = shop.get_one_order(refunded).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                order.status,
+                order.refunded_amount,
+                order.refundable_amount()
+            ),
+            (OrderStatus::Refunded, 3000, 0)
+        );
+
+        // Disputes are listed with their evidence due date
+        process(&database, DISPUTE_CREATED).await;
+        let page = crate::domain::datatypes::PageQuery {
+            page: None,
+            per_page: None,
+        };
+        let (disputes, total) = shop.get_disputes(&page).await.unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(disputes[0].order_id, disputed);
+        assert_eq!(disputes[0].status, "needs_response");
+        assert_eq!(
+            disputes[0].evidence_due_by,
+            chrono::DateTime::from_timestamp(1761000000, 0).map(|time| time.naive_utc())
+        );
+        assert!(database
+            .for_shop("b.test")
+            .get_disputes(&page)
+            .await
+            .unwrap()
+            .0
+            .is_empty());
+
+        // A won dispute leaves the order alone, a lost one takes its amount back
+        let won = DISPUTE_CREATED
+            .replace("evt_test_dispute_created", "evt_test_dispute_won")
+            .replace("\"amount\": 3000", "\"amount\": 1000")
+            .replace("\"created\": 1760000000", "\"created\": 1760000100")
+            .replace("charge.dispute.created", "charge.dispute.closed")
+            .replace("\"status\": \"needs_response\"", "\"status\": \"won\"");
+        process(&database, &won).await;
+        let order = shop.get_one_order(disputed).await.unwrap().unwrap();
+        assert_eq!(order.status, OrderStatus::Paid);
+
+        let lost = won
+            .replace("evt_test_dispute_won", "evt_test_dispute_lost")
+            .replace("\"status\": \"won\"", "\"status\": \"lost\"");
+        process(&database, &lost).await;
+        let order = shop.get_one_order(disputed).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                order.status,
+                order.refunded_amount,
+                order.disputed_amount,
+                order.refundable_amount()
+            ),
+            (OrderStatus::Paid, 0, 1000, 2000)
+        );
+        let (disputes, _) = shop.get_disputes(&page).await.unwrap();
+        assert_eq!(disputes[0].status, "lost");
+
+        // Later events on the lost dispute do not take it again
+        let withdrawn = lost
+            .replace("evt_test_dispute_lost", "evt_test_dispute_withdrawn")
+            .replace("\"created\": 1760000100", "\"created\": 1760000200")
+            .replace("charge.dispute.closed", "charge.dispute.funds_withdrawn");
+        process(&database, &withdrawn).await;
+        let order = shop.get_one_order(disputed).await.unwrap().unwrap();
+        assert_eq!(order.disputed_amount, 1000);
+
+        // Refunds of the charge still count on their own, up to the total
+        let refund = CHARGE_REFUNDED
+            .replace("evt_test_charge_refunded", "evt_test_refund_disputed")
+            .replace("pi_test_refund", "pi_test_dispute")
+            .replace("\"amount_refunded\": 1000", "\"amount_refunded\": 2000");
+        process(&database, &refund).await;
+        let order = shop.get_one_order(disputed).await.unwrap().unwrap();
+        assert_eq!(
+            (
+                order.status,
+                order.refunded_amount,
+                order.disputed_amount,
+                order.refundable_amount()
+            ),
+            (OrderStatus::Refunded, 2000, 1000, 0)
+        );
+
+        database.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
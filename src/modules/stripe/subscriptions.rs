@@ -0,0 +1,88 @@
+use crate::db::sqlite::SqliteDB;
+use crate::domain::subscriptions::{ShopPlan, ShopSubscription};
+use crate::modules::shop_registry;
+use crate::utils::constants::{STRIPE_PRICE_PRO, STRIPE_PRICE_STARTER};
+
+// The recurring Stripe price of a plan, None for free and for plans without a price
+pub fn plan_price(plan: ShopPlan) -> Option<&'static str> {
+    let price = match plan {
+        ShopPlan::Free => return None,
+        ShopPlan::Starter => STRIPE_PRICE_STARTER.as_str(),
+        ShopPlan::Pro => STRIPE_PRICE_PRO.as_str(),
+    };
+    (!price.is_empty()).then_some(price)
+}
+
+pub fn plan_for_price(price_id: &str) -> Option<ShopPlan> {
+    ShopPlan::ALL
+        .into_iter()
+        .find(|plan| plan_price(*plan) == Some(price_id))
+}
+
+// When Stripe last changed the subscription, as far as the subscription itself tells
+pub fn last_changed_at(subscription: &stripe::Subscription) -> i64 {
+    [
+        Some(subscription.created),
+        Some(subscription.start_date),
+        Some(subscription.current_period_start),
+        subscription.canceled_at,
+        subscription.ended_at,
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .unwrap_or_default()
+}
+
+// Our copy of a Stripe subscription. None for subscriptions that are not for a shop plan.
+pub fn subscription_record(
+    subscription: &stripe::Subscription,
+    synced_at: i64,
+) -> Option<ShopSubscription> {
+    let shop_domain = subscription.metadata.get("shop_domain")?;
+    let plan = subscription
+        .items
+        .data
+        .first()
+        .and_then(|item| item.price.as_ref())
+        .and_then(|price| plan_for_price(price.id.as_str()))?;
+
+    Some(ShopSubscription {
+        shop_domain: shop_domain.to_string(),
+        plan,
+        status: subscription.status.as_str().to_string(),
+        stripe_customer_id: Some(subscription.customer.id().to_string()),
+        stripe_subscription_id: Some(subscription.id.to_string()),
+        current_period_end: chrono::DateTime::from_timestamp(subscription.current_period_end, 0)
+            .map(|time| time.naive_utc()),
+        cancel_at_period_end: subscription.cancel_at_period_end,
+        synced_at,
+    })
+}
+
+// Store the subscription as Stripe reported it at synced_at, returns what is stored now.
+// The shop caches pick up the plan, the features it includes are served from there.
+pub async fn mirror_subscription(
+    db: &SqliteDB,
+    shop_updates: &redis::Client,
+    subscription: &stripe::Subscription,
+    synced_at: i64,
+) -> Result<Option<ShopSubscription>, sqlx::Error> {
+    let Some(record) = subscription_record(subscription, synced_at) else {
+        println!(
+            "Subscription {} is not for a shop plan, ignoring it",
+            subscription.id
+        );
+        return Ok(None);
+    };
+
+    if db.upsert_shop_subscription(&record).await? {
+        shop_registry::publish_shop_change(db, shop_updates, &record.shop_domain).await;
+    } else {
+        println!(
+            "Subscription {} of {} is older than the stored one",
+            subscription.id, record.shop_domain
+        );
+    }
+    db.get_shop_subscription(&record.shop_domain).await
+}
@@ -1,21 +1,96 @@
 use crate::domain::datatypes::{UserCookie, UserServer};
+use crate::modules::cuid::Cuid;
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::modules::session;
 use crate::utils;
 use core::convert::TryFrom;
 use dotenvy::dotenv;
 use pasetors::claims::{Claims, ClaimsValidationRules};
-use pasetors::keys::{Generate, SymmetricKey};
-use pasetors::paserk::FormatAsPaserk;
+use pasetors::keys::SymmetricKey;
 use pasetors::token::UntrustedToken;
 use pasetors::{local, version4::V4, Local};
+use std::time::Duration;
+
+// What a token may be used for, stored in the `purpose` claim
+pub enum TokenPurpose {
+    Access,
+    Refresh,
+    PasswordReset,
+    Verification,
+    TwoFactor,
+}
+impl TokenPurpose {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TokenPurpose::Access => "access",
+            TokenPurpose::Refresh => "refresh",
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::Verification => "verification",
+            TokenPurpose::TwoFactor => "two_factor",
+        }
+    }
+
+    pub fn lifetime(&self) -> Duration {
+        match self {
+            TokenPurpose::Access => {
+                Duration::from_secs(*utils::constants::ACCESS_TOKEN_TTL_MINUTES * 60)
+            }
+            TokenPurpose::Refresh => {
+                Duration::from_secs(*utils::constants::REFRESH_TOKEN_TTL_DAYS * 24 * 60 * 60)
+            }
+            // Reset links are kept between 15 minutes and an hour, whatever is configured
+            TokenPurpose::PasswordReset => {
+                Duration::from_secs(utils::constants::PASSWORD_RESET_TTL_MINUTES.clamp(15, 60) * 60)
+            }
+            TokenPurpose::Verification => {
+                Duration::from_secs(*utils::constants::VERIFICATION_TTL_HOURS * 60 * 60)
+            }
+            // Only long enough to type the code from the authenticator app
+            TokenPurpose::TwoFactor => Duration::from_secs(5 * 60),
+        }
+    }
+
+    // Redis entry that keeps a single use token alive until it is consumed
+    fn single_use_key(&self, token_id: &str) -> Option<String> {
+        match self {
+            TokenPurpose::PasswordReset => Some(RedisKeyNames::PasswordReset.get_key(token_id)),
+            TokenPurpose::Verification => Some(RedisKeyNames::Verification.get_key(token_id)),
+            TokenPurpose::TwoFactor => Some(RedisKeyNames::TwoFactor.get_key(token_id)),
+            _ => None,
+        }
+    }
+}
+
+pub struct TokenPair {
+    pub access: String,
+    pub refresh: String,
+}
 
 // Generate Trusted Token for Client
-pub fn generete_public_token(user: &UserServer) -> String {
-    dotenv().expect(".env file not found");
-    let token_secret: &[u8] = utils::constants::TOKEN_SECRET.as_bytes();
-    let token_sk = utils::constants::TOKEN_SK.to_string();
+pub fn generete_public_token(user: &UserServer, purpose: TokenPurpose, generation: i64) -> String {
+    generete_token_with_id(user, &purpose, generation, Cuid::create_cuid().as_str())
+}
 
+fn generete_token_with_id(
+    user: &UserServer,
+    purpose: &TokenPurpose,
+    generation: i64,
+    token_id: &str,
+) -> String {
+    encrypt_claims(&token_claims(user, purpose, generation, token_id))
+}
+
+fn token_claims(
+    user: &UserServer,
+    purpose: &TokenPurpose,
+    generation: i64,
+    token_id: &str,
+) -> Claims {
     // Add a custom `data` claims.
-    let mut claims = Claims::new().expect("Creating claim failed");
+    let mut claims = Claims::new_expires_in(&purpose.lifetime()).expect("Creating claim failed");
+    claims
+        .token_identifier(token_id)
+        .expect("Token identifier claim failed");
     claims
         .add_additional("user_id", user.user_id.to_string())
         .expect("Addition 1 failed");
@@ -23,28 +98,86 @@ pub fn generete_public_token(user: &UserServer) -> String {
         .add_additional("username", user.username.to_string())
         .expect("Addition 1 fail");
     claims
-        .expiration("2039-01-01T00:00:00+00:00")
-        .expect("Experation claim failed");
+        .add_additional("purpose", purpose.as_str())
+        .expect("Purpose claim failed");
+    claims
+        .add_additional("generation", generation)
+        .expect("Generation claim failed");
+    claims
+        .add_additional("verified", user.verified_at.is_some())
+        .expect("Verified claim failed");
+    claims
+        .add_additional("role", user.role.as_str())
+        .expect("Role claim failed");
+    claims
+        .add_additional("shop_domain", user.shop_domain.as_str())
+        .expect("Shop claim failed");
+    claims
+}
+
+fn encrypt_claims(claims: &Claims) -> String {
+    let token_secret: &[u8] = utils::constants::TOKEN_SECRET.as_bytes();
+    let token_sk = utils::constants::TOKEN_SK.to_string();
 
     // Generate the key and encrypt the claims.
     let sk = SymmetricKey::<V4>::try_from(token_sk.as_str()).expect("Generating Key failed");
 
     // Create Token
-    let token =
-        local::encrypt(&sk, &claims, None, Some(token_secret)).expect("Creating token failed");
+    local::encrypt(&sk, claims, None, Some(token_secret)).expect("Creating token failed")
+}
+
+// Generate the access and refresh token for a user that just authenticated
+pub fn generete_token_pair(
+    user: &UserServer,
+    remember: bool,
+    redis: &RedisDB,
+) -> Result<TokenPair, RedisDbError> {
+    let generation = get_token_generation(redis, &user.user_id)?;
+
+    // The refresh token carries the remember choice, so the renewed cookies keep it
+    let mut refresh = token_claims(
+        user,
+        &TokenPurpose::Refresh,
+        generation,
+        Cuid::create_cuid().as_str(),
+    );
+    refresh
+        .add_additional("remember", remember)
+        .expect("Remember claim failed");
 
-    return token;
+    Ok(TokenPair {
+        access: generete_public_token(user, TokenPurpose::Access, generation),
+        refresh: encrypt_claims(&refresh),
+    })
 }
 
-// Verify Untrusted Token from Client
-pub fn verify_token(untrusted_inc_token: &str) -> Option<UserCookie> {
-    dotenv().expect(".env file not found");
+// Generate a single use token (password reset, verification), valid while its Redis entry exists
+pub fn generete_single_use_token(
+    user: &UserServer,
+    purpose: TokenPurpose,
+    redis: &RedisDB,
+) -> Result<String, RedisDbError> {
+    let generation = get_token_generation(redis, &user.user_id)?;
+    let token_id = Cuid::create_cuid();
+    let key = purpose
+        .single_use_key(&token_id)
+        .expect("Token purpose is not single use");
+
+    redis.set_value_ex(&key, user.user_id.as_str(), purpose.lifetime().as_secs())?;
+
+    Ok(generete_token_with_id(
+        user, &purpose, generation, &token_id,
+    ))
+}
+
+// Decrypt the token and check its expiration and purpose, the revocation list is not consulted
+fn decrypt_token(untrusted_inc_token: &str, purpose: &TokenPurpose) -> Option<Claims> {
     let token_secret: &[u8] = utils::constants::TOKEN_SECRET.as_bytes();
     let token_sk = utils::constants::TOKEN_SK.to_string();
 
+    // The default rules reject tokens whose `exp` claim lies in the past
     let validation_rules = ClaimsValidationRules::new();
-    let untrusted_token = UntrustedToken::<Local, V4>::try_from(untrusted_inc_token)
-        .expect("Parsing of Untrusted token failed");
+    let untrusted_token = UntrustedToken::<Local, V4>::try_from(untrusted_inc_token).ok()?;
 
     // Generate the key and encrypt the claims.
     let sk = SymmetricKey::<V4>::try_from(token_sk.as_str()).expect("Generating Key failed");
@@ -54,15 +187,207 @@ pub fn verify_token(untrusted_inc_token: &str) -> Option<UserCookie> {
         &validation_rules,
         None,
         Some(token_secret),
-    );
-    match trusted_token {
-        Ok(token) => match token.payload_claims().to_owned() {
-            Some(info) => Some(UserCookie::new(info)),
-            None => None,
-        },
-        Err(_) => None,
+    )
+    .ok()?;
+
+    let claims = trusted_token.payload_claims()?.to_owned();
+    match claims.get_claim("purpose").and_then(|p| p.as_str()) {
+        Some(token_purpose) if token_purpose == purpose.as_str() => Some(claims),
+        _ => None,
+    }
+}
+
+// Verify Untrusted Token from Client
+pub fn verify_token(untrusted_inc_token: &str, redis: &RedisDB) -> Option<UserCookie> {
+    verify_token_purpose(untrusted_inc_token, TokenPurpose::Access, redis)
+}
+
+// Verify Untrusted Refresh Token from Client
+pub fn verify_refresh_token(untrusted_inc_token: &str, redis: &RedisDB) -> Option<UserCookie> {
+    verify_token_purpose(untrusted_inc_token, TokenPurpose::Refresh, redis)
+}
+
+fn verify_token_purpose(
+    untrusted_inc_token: &str,
+    purpose: TokenPurpose,
+    redis: &RedisDB,
+) -> Option<UserCookie> {
+    let claims = decrypt_token(untrusted_inc_token, &purpose)?;
+    verify_claims(&claims, redis)
+}
+
+fn verify_claims(claims: &Claims, redis: &RedisDB) -> Option<UserCookie> {
+    let user = UserCookie::new(claims);
+
+    match is_revoked(claims, &user, redis) {
+        Ok(false) => Some(user),
+        Ok(true) => None,
+        Err(err) => {
+            // Fail closed, a token that cannot be checked is not trusted
+            log::warn!("Checking token revocation failed: {}", err);
+            None
+        }
+    }
+}
+
+// Verify Untrusted Single Use Token from Client, without using it up
+pub fn verify_single_use_token(
+    untrusted_inc_token: &str,
+    purpose: TokenPurpose,
+    redis: &RedisDB,
+) -> Option<UserCookie> {
+    let (user, key) = single_use_claims(untrusted_inc_token, &purpose, redis)?;
+
+    match redis.exists(&key) {
+        Ok(true) => Some(user),
+        Ok(false) => None,
+        Err(err) => {
+            log::warn!("Checking {} token failed: {}", purpose.as_str(), err);
+            None
+        }
+    }
+}
+
+// Use up a single use token. Deleting the Redis entry is atomic, so only one request wins.
+pub fn consume_single_use_token(
+    untrusted_inc_token: &str,
+    purpose: TokenPurpose,
+    redis: &RedisDB,
+) -> Option<UserCookie> {
+    let (user, key) = single_use_claims(untrusted_inc_token, &purpose, redis)?;
+
+    match redis.delete_key(&key) {
+        Ok(true) => Some(user),
+        Ok(false) => None,
+        Err(err) => {
+            log::warn!("Consuming {} token failed: {}", purpose.as_str(), err);
+            None
+        }
+    }
+}
+
+fn single_use_claims(
+    untrusted_inc_token: &str,
+    purpose: &TokenPurpose,
+    redis: &RedisDB,
+) -> Option<(UserCookie, String)> {
+    let claims = decrypt_token(untrusted_inc_token, purpose)?;
+    let key = purpose.single_use_key(&token_id(&claims)?)?;
+    let user = verify_claims(&claims, redis)?;
+    Some((user, key))
+}
+
+// Exchange a refresh token for its user and whether the login is remembered, and put the token
+// on the revocation list. A refresh token that is presented twice has been stolen, so every token
+// of the user is revoked.
+pub fn rotate_refresh_token(
+    untrusted_inc_token: &str,
+    redis: &RedisDB,
+) -> Option<(UserCookie, bool)> {
+    let claims = decrypt_token(untrusted_inc_token, &TokenPurpose::Refresh)?;
+    let user = UserCookie::new(&claims);
+    let token_id = token_id(&claims)?;
+    let remember = claims
+        .get_claim("remember")
+        .and_then(|remember| remember.as_bool())
+        .unwrap_or(false);
+
+    let rotated = (|| -> Result<bool, RedisDbError> {
+        if !generation_is_current(&claims, &user, redis)? {
+            return Ok(false);
+        }
+        // Claimed in one step, so of two requests with the same token only one gets new tokens
+        if !claim_token_id(redis, &token_id, &TokenPurpose::Refresh)? {
+            log::warn!("Refresh token reused for user {}", user.user_id);
+            revoke_user_tokens(redis, &user.user_id)?;
+            return Ok(false);
+        }
+        Ok(true)
+    })();
+
+    match rotated {
+        Ok(true) => Some((user, remember)),
+        Ok(false) => None,
+        Err(err) => {
+            log::warn!("Rotating refresh token failed: {}", err);
+            None
+        }
+    }
+}
+
+// Invalidate every outstanding token of the user by moving them to the next generation
+pub fn revoke_user_tokens(redis: &RedisDB, user_id: &str) -> Result<(), RedisDbError> {
+    redis.incr(&generation_key(user_id), 1)?;
+    // Server-side sessions are ended together with the tokens
+    session::delete_user_sessions(redis, user_id, None)?;
+    Ok(())
+}
+
+pub fn get_token_generation(redis: &RedisDB, user_id: &str) -> Result<i64, RedisDbError> {
+    let generation: Option<i64> = redis.get_value(&generation_key(user_id))?;
+    Ok(generation.unwrap_or(0))
+}
+
+fn generation_key(user_id: &str) -> String {
+    RedisKeyNames::TokenRevocation.get_key(format!("user:{}", user_id).as_str())
+}
+
+fn token_id_key(token_id: &str) -> String {
+    RedisKeyNames::TokenRevocation.get_key(format!("jti:{}", token_id).as_str())
+}
+
+// Derive a key for one purpose from TOKEN_SK, so the token key itself is only used for tokens
+pub fn derive_key(purpose: &str) -> Option<Vec<u8>> {
+    let token_sk = utils::constants::TOKEN_SK.to_string();
+    let sk = SymmetricKey::<V4>::try_from(token_sk.as_str()).ok()?;
+    let master_key = orion::auth::SecretKey::from_slice(sk.as_bytes()).ok()?;
+    let derived = orion::auth::authenticate(&master_key, purpose.as_bytes()).ok()?;
+
+    Some(derived.unprotected_as_bytes().to_vec())
+}
+
+fn token_id(claims: &Claims) -> Option<String> {
+    claims
+        .get_claim("jti")
+        .and_then(|jti| jti.as_str())
+        .map(|jti| jti.to_string())
+}
+
+// Put the token id on the revocation list, false when it already was.
+// Revoked token ids only have to be remembered until the token would have expired anyway.
+fn claim_token_id(
+    redis: &RedisDB,
+    token_id: &str,
+    purpose: &TokenPurpose,
+) -> Result<bool, RedisDbError> {
+    redis.set_value_nx_ex(&token_id_key(token_id), 1, purpose.lifetime().as_secs())
+}
+
+fn token_id_is_revoked(token_id: &str, redis: &RedisDB) -> Result<bool, RedisDbError> {
+    redis.exists(&token_id_key(token_id))
+}
+
+fn generation_is_current(
+    claims: &Claims,
+    user: &UserCookie,
+    redis: &RedisDB,
+) -> Result<bool, RedisDbError> {
+    let token_generation = claims
+        .get_claim("generation")
+        .and_then(|generation| generation.as_i64())
+        .unwrap_or(0);
+
+    Ok(token_generation == get_token_generation(redis, &user.user_id)?)
+}
+
+fn is_revoked(claims: &Claims, user: &UserCookie, redis: &RedisDB) -> Result<bool, RedisDbError> {
+    if !generation_is_current(claims, user, redis)? {
+        return Ok(true);
+    }
+    match token_id(claims) {
+        Some(token_id) => token_id_is_revoked(&token_id, redis),
+        None => Ok(true),
     }
-    // .expect("Untrusted token failed");
 }
 
 pub fn generete_public_token_test() {
@@ -86,16 +411,6 @@ pub fn generete_public_token_test() {
     let token_sk = "k4.local.JvUcBYO9vWzStfoaGdvuWAEBgLJDxIq1mgVAKIQLmH8";
     let sk = SymmetricKey::<V4>::try_from(token_sk).expect("Generating Key failed");
 
-    // Generate Token SK
-    fn create_sk_token() -> String {
-        let sk = SymmetricKey::<V4>::generate().expect("Generating Key failed");
-        let mut paserk = String::new();
-        sk.fmt(&mut paserk).expect("failed to park");
-        format!("Paserk: {:?}", paserk)
-    }
-
-    let sk_token = create_sk_token();
-
     let token =
         local::encrypt(&sk, &claims, None, Some(token_secret)).expect("Creating token failed");
 
@@ -119,13 +434,79 @@ pub fn generete_public_token_test() {
     .expect("Untrusted token failed");
 
     assert_eq!(&claims, trusted_token.payload_claims().unwrap());
+}
 
-    let claims = trusted_token.payload_claims().unwrap();
+#[cfg(test)]
+mod token_tests {
+    use super::*;
+    use crate::domain::datatypes::UserRole;
 
-    println!("{:?}", claims);
-    println!("Secret Key: {:?}", sk_token);
-    println!("Secret Key: {:?}", sk);
-    println!("{:?}", claims.get_claim("data"));
-    println!("{:?}", claims.get_claim("user_id"));
-    println!("{:?}", claims.get_claim("iat"));
+    fn test_user() -> UserServer {
+        UserServer {
+            user_id: "1234".to_string(),
+            username: "eve".to_string(),
+            hashed_password: "".to_string(),
+            email: "eve@example.com".to_string(),
+            verified_at: None,
+            active: true,
+            role: UserRole::ShopOwner,
+            shop_domain: "shop.test".to_string(),
+        }
+    }
+
+    #[test]
+    fn check_token_purpose_operations() {
+        let user = test_user();
+
+        // An access token decrypts as an access token and carries the user
+        let access = generete_public_token(&user, TokenPurpose::Access, 3);
+        let claims = decrypt_token(&access, &TokenPurpose::Access).expect("Access token rejected");
+        let cookie = UserCookie::new(&claims);
+        assert_eq!(cookie.user_id, "1234", "User id does not match");
+        assert_eq!(cookie.username, "eve", "Username does not match");
+        assert!(!cookie.verified, "Unverified user has a verified token");
+        assert_eq!(cookie.role, UserRole::ShopOwner, "Role does not match");
+        assert_eq!(cookie.shop_domain, "shop.test", "Shop does not match");
+        assert_eq!(
+            claims.get_claim("generation").and_then(|g| g.as_i64()),
+            Some(3),
+            "Generation does not match"
+        );
+        assert!(token_id(&claims).is_some(), "Token has no identifier");
+
+        // Tokens cannot be used for another purpose
+        assert!(
+            decrypt_token(&access, &TokenPurpose::Refresh).is_none(),
+            "Access token accepted as refresh token"
+        );
+        let refresh = generete_public_token(&user, TokenPurpose::Refresh, 0);
+        assert!(
+            decrypt_token(&refresh, &TokenPurpose::Access).is_none(),
+            "Refresh token accepted as access token"
+        );
+
+        // Password reset tokens only work on the reset endpoints
+        let reset = generete_token_with_id(&user, &TokenPurpose::PasswordReset, 0, "reset_id");
+        assert!(
+            decrypt_token(&reset, &TokenPurpose::Access).is_none(),
+            "Reset token accepted as access token"
+        );
+        assert!(
+            decrypt_token(&access, &TokenPurpose::PasswordReset).is_none(),
+            "Access token accepted as reset token"
+        );
+        let claims =
+            decrypt_token(&reset, &TokenPurpose::PasswordReset).expect("Reset token rejected");
+        assert_eq!(
+            token_id(&claims),
+            Some("reset_id".to_string()),
+            "Reset token identifier does not match"
+        );
+
+        // Garbage does not panic
+        assert!(
+            decrypt_token("not.a.token", &TokenPurpose::Access).is_none(),
+            "Garbage token accepted"
+        );
+    }
 }
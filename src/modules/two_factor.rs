@@ -0,0 +1,161 @@
+use crate::modules::password_hash::Password;
+use crate::modules::redis::{RedisDB, RedisDbError, RedisKeyNames};
+use crate::modules::token_pub;
+use crate::utils;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use orion::aead;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use thiserror::Error;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+// Amount of recovery codes handed out when two-factor authentication is enabled
+pub const RECOVERY_CODE_COUNT: usize = 10;
+// Codes are accepted one step before and after the current 30 second window
+const TOTP_SKEW: u8 = 1;
+const TOTP_STEP: u64 = 30;
+
+#[derive(Error, Debug)]
+pub enum TwoFactorError {
+    #[error("Invalid TOTP secret: {0}")]
+    InvalidSecret(String),
+    #[error("Encryption error")]
+    EncryptionError,
+    #[error("QR code error: {0}")]
+    QrCodeError(String),
+    #[error("Hashing error: {0}")]
+    HashingError(String),
+    #[error("Redis error: {0}")]
+    RedisError(#[from] RedisDbError),
+}
+
+// Generate a new random secret, base32 encoded as authenticator apps expect it
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+fn build_totp(secret: &str, account_name: &str) -> Result<TOTP, TwoFactorError> {
+    let secret_bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| TwoFactorError::InvalidSecret(err.to_string()))?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        TOTP_SKEW,
+        TOTP_STEP,
+        secret_bytes,
+        Some(utils::constants::TOTP_ISSUER.to_string()),
+        account_name.to_string(),
+    )
+    .map_err(|err| TwoFactorError::InvalidSecret(err.to_string()))
+}
+
+// The otpauth:// URI scanned by the authenticator app
+pub fn provisioning_uri(secret: &str, account_name: &str) -> Result<String, TwoFactorError> {
+    Ok(build_totp(secret, account_name)?.get_url())
+}
+
+// Render the provisioning URI as an inline SVG QR code
+pub fn qr_code_svg(uri: &str) -> Result<String, TwoFactorError> {
+    let code =
+        QrCode::new(uri.as_bytes()).map_err(|err| TwoFactorError::QrCodeError(err.to_string()))?;
+
+    Ok(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+// Check a code against the secret, every code can only be used once
+pub fn verify_code(
+    redis: &RedisDB,
+    user_id: &str,
+    secret: &str,
+    code: &str,
+) -> Result<bool, TwoFactorError> {
+    let totp = build_totp(secret, user_id)?;
+    let code = code.trim();
+
+    match totp.check_current(code) {
+        Ok(true) => {}
+        _ => return Ok(false),
+    }
+
+    // Remember the code for as long as it would be accepted to stop replays. Claimed in
+    // one step, so two requests with the same code cannot both pass.
+    let used_key = RedisKeyNames::TwoFactorUsedCode.get_key(&format!("{}:{}", user_id, code));
+    let claimed =
+        redis.set_value_nx_ex(&used_key, "used", TOTP_STEP * (2 * TOTP_SKEW as u64 + 1))?;
+    Ok(claimed)
+}
+
+// The secret is stored encrypted with a key derived from TOKEN_SK
+fn encryption_key() -> Result<aead::SecretKey, TwoFactorError> {
+    let derived =
+        token_pub::derive_key("two-factor-secret").ok_or(TwoFactorError::EncryptionError)?;
+
+    aead::SecretKey::from_slice(&derived).map_err(|_| TwoFactorError::EncryptionError)
+}
+
+pub fn encrypt_secret(secret: &str) -> Result<String, TwoFactorError> {
+    let sealed = aead::seal(&encryption_key()?, secret.as_bytes())
+        .map_err(|_| TwoFactorError::EncryptionError)?;
+
+    Ok(hex::encode(sealed))
+}
+
+pub fn decrypt_secret(encrypted_secret: &str) -> Result<String, TwoFactorError> {
+    let sealed = hex::decode(encrypted_secret).map_err(|_| TwoFactorError::EncryptionError)?;
+    let secret =
+        aead::open(&encryption_key()?, &sealed).map_err(|_| TwoFactorError::EncryptionError)?;
+
+    String::from_utf8(secret).map_err(|_| TwoFactorError::EncryptionError)
+}
+
+// Generate one time recovery codes, returned in plain text and hashed for storage
+pub fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), TwoFactorError> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    let mut hashed_codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let mut bytes = [0u8; 5];
+        OsRng.fill_bytes(&mut bytes);
+        let code = hex::encode(bytes);
+        let code = format!("{}-{}", &code[..5], &code[5..]);
+
+        let hashed = Password::hash_password(&code)
+            .map_err(|err| TwoFactorError::HashingError(err.to_string()))?;
+        hashed_codes.push(hashed.get_password_string());
+        codes.push(code);
+    }
+
+    Ok((codes, hashed_codes))
+}
+
+#[cfg(test)]
+mod two_factor_tests {
+    use super::*;
+
+    #[test]
+    fn check_two_factor_operations() {
+        // Setup
+        let secret = generate_secret();
+
+        // The secret survives encryption
+        let encrypted = encrypt_secret(&secret).expect("Encrypting secret failed");
+        assert_ne!(encrypted, secret, "Secret is stored in plain text");
+        let decrypted = decrypt_secret(&encrypted).expect("Decrypting secret failed");
+        assert_eq!(decrypted, secret, "Secret changed after decryption");
+
+        // The provisioning URI carries the secret and the issuer
+        let uri = provisioning_uri(&secret, "eve@example.com").expect("Creating URI failed");
+        assert!(uri.starts_with("otpauth://totp/"), "Not an otpauth URI");
+        assert!(uri.contains(&secret), "Secret missing from the URI");
+
+        // Recovery codes are hashed with the password hasher
+        let (codes, hashed_codes) = generate_recovery_codes().expect("Creating codes failed");
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT, "Wrong amount of codes");
+        let verified = Password::new(&hashed_codes[0])
+            .verify_password(&codes[0])
+            .expect("Verifying code failed");
+        assert!(verified, "Recovery code does not match its hash");
+    }
+}
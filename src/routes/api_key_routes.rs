@@ -0,0 +1,47 @@
+use crate::controllers;
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{ApiKeyCreate, UserCookie, UserRole};
+use crate::modules::middleware::CheckLogin;
+use actix_web::web::ReqData;
+use actix_web::*;
+
+// Keys are managed from a logged in browser, a key cannot create other keys
+pub fn api_keys_config(config: &mut web::ServiceConfig) {
+    config.service(
+        web::scope("/api-keys")
+            .wrap(CheckLogin::enabled().roles(&[UserRole::Admin, UserRole::ShopOwner]))
+            .service(api_keys::create_api_key)
+            .service(api_keys::list_api_keys)
+            .service(api_keys::revoke_api_key),
+    );
+}
+
+pub mod api_keys {
+    use super::*;
+
+    // POST New key for the shop of this domain
+    #[post("")]
+    pub async fn create_api_key(
+        db: TenantDB,
+        user: ReqData<UserCookie>,
+        body: web::Json<ApiKeyCreate>,
+    ) -> HttpResponse {
+        controllers::api_keys::create_api_key(db, user.into_inner(), body.into_inner()).await
+    }
+
+    // GET Keys of the shop of this domain
+    #[get("")]
+    pub async fn list_api_keys(db: TenantDB, user: ReqData<UserCookie>) -> HttpResponse {
+        controllers::api_keys::list_api_keys(db, user.into_inner()).await
+    }
+
+    // DELETE Revoke a key, it stays listed with its revocation time
+    #[delete("/{key_id}")]
+    pub async fn revoke_api_key(
+        db: TenantDB,
+        user: ReqData<UserCookie>,
+        path: web::Path<i64>,
+    ) -> HttpResponse {
+        controllers::api_keys::revoke_api_key(db, user.into_inner(), path.into_inner()).await
+    }
+}
@@ -1,5 +1,9 @@
-use crate::db::sqlite::SqliteDB;
-use crate::domain::datatypes::{UserClientIn, UserServer};
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{UserClientIn, UserRole, UserServer};
+use crate::modules::api_key::ApiScope;
+use crate::modules::middleware::CheckLogin;
+use crate::modules::redis::RedisDB;
+use crate::modules::token_pub;
 use actix_web::*;
 
 use crate::controllers::ui_controller;
@@ -8,6 +12,12 @@ use crate::controllers::ui_controller;
 pub fn app_config(config: &mut web::ServiceConfig) {
     config.service(
         web::scope("/app")
+            // Listing and changing every user is for admins only, keys may only read
+            .wrap(
+                CheckLogin::enabled()
+                    .roles(&[UserRole::Admin])
+                    .api_key(&[ApiScope::ReadUsers]),
+            )
             .service(sqlite::app)
             .service(sqlite::post_app)
             .service(sqlite::sqlite_get_all_user)
@@ -38,7 +48,7 @@ pub mod sqlite {
 
     // GET
     #[get("/sqlite/users")]
-    pub async fn sqlite_get_all_user(db: web::Data<SqliteDB>) -> impl Responder {
+    pub async fn sqlite_get_all_user(db: TenantDB) -> impl Responder {
         match db.get_all_users().await {
             Ok(users) => {
                 return HttpResponse::Ok().json(users);
@@ -52,10 +62,7 @@ pub mod sqlite {
 
     // GET
     #[get("/sqlite/users/{id}")]
-    pub async fn sqlite_get_one_user(
-        db: web::Data<SqliteDB>,
-        path: web::Path<String>,
-    ) -> impl Responder {
+    pub async fn sqlite_get_one_user(db: TenantDB, path: web::Path<String>) -> impl Responder {
         let user_id: String = path.into_inner();
 
         match db.get_one_user(&user_id).await {
@@ -71,10 +78,7 @@ pub mod sqlite {
 
     // POST
     #[post("/sqlite/create")]
-    pub async fn sqlite_create_one(
-        db: web::Data<SqliteDB>,
-        user: web::Json<UserClientIn>,
-    ) -> impl Responder {
+    pub async fn sqlite_create_one(db: TenantDB, user: web::Json<UserClientIn>) -> impl Responder {
         let user = UserServer::process_for_server(user.into_inner());
 
         match db.create_one_user(&user).await {
@@ -91,13 +95,27 @@ pub mod sqlite {
     // PUT
     #[put("/sqlite/users")]
     pub async fn sqlite_update_one(
-        db: web::Data<SqliteDB>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
         user: web::Json<UserServer>,
     ) -> impl Responder {
         let user = user.into_inner();
+        let previous_role = match db.get_one_user(&user.user_id).await {
+            Ok(previous) => previous.role,
+            Err(err) => {
+                eprintln!("Error getting user: {:?}", err);
+                return HttpResponse::NotFound().json("User not found");
+            }
+        };
 
         match db.update_one_user(&user).await {
             Ok(content) => {
+                // A deactivated user is logged out everywhere, a new role needs a new token
+                if !content.active || content.role != previous_role {
+                    if let Err(err) = token_pub::revoke_user_tokens(&redis, &content.user_id) {
+                        eprintln!("Error revoking tokens of updated user: {:?}", err);
+                    }
+                }
                 return HttpResponse::Ok().json(content);
             }
             Err(err) => {
@@ -109,10 +127,7 @@ pub mod sqlite {
 
     // DELETE
     #[delete("/sqlite/users/{id}")]
-    pub async fn sqlite_delete_one(
-        db: web::Data<SqliteDB>,
-        path: web::Path<String>,
-    ) -> impl Responder {
+    pub async fn sqlite_delete_one(db: TenantDB, path: web::Path<String>) -> impl Responder {
         let user_id: String = path.into_inner();
 
         match db.delete_one_user(&user_id).await {
@@ -126,10 +141,7 @@ pub mod sqlite {
 
     // TRANSACTION
     #[post("/sqlite/transaction")]
-    pub async fn sqlite_transaction(
-        db: web::Data<SqliteDB>,
-        user: web::Json<UserClientIn>,
-    ) -> impl Responder {
+    pub async fn sqlite_transaction(db: TenantDB, user: web::Json<UserClientIn>) -> impl Responder {
         let user = UserServer::process_for_server(user.into_inner());
 
         match db.transaction(&user).await {
@@ -147,15 +159,12 @@ pub mod sqlite {
         use super::*;
 
         #[get("/sqlite/show/users")]
-        pub async fn show_all_user_list(db: web::Data<SqliteDB>) -> impl Responder {
+        pub async fn show_all_user_list(db: TenantDB) -> impl Responder {
             return ui_controller::index::index_ui_controller::show_all_user_list(db).await;
         }
 
         #[delete("/sqlite/show/{id}")]
-        pub async fn delete_one_user(
-            db: web::Data<SqliteDB>,
-            path: web::Path<String>,
-        ) -> impl Responder {
+        pub async fn delete_one_user(db: TenantDB, path: web::Path<String>) -> impl Responder {
             let user_id: String = path.into_inner();
 
             return ui_controller::index::index_ui_controller::deleted_user_sqlite(user_id, db)
@@ -0,0 +1,124 @@
+use crate::controllers;
+use crate::controllers::cart::{CartChange, CartView};
+use crate::db::tenant::TenantDB;
+use crate::domain::cart::{CartItemIn, CartLineRef};
+use crate::modules::redis::RedisDB;
+use actix_web::*;
+
+// Guests and logged in users each have a cart per shop
+pub fn cart_config(config: &mut web::ServiceConfig) {
+    config.service(
+        web::scope("/cart")
+            .service(ui::cart_page)
+            .service(ui::cart_fragment)
+            .service(ui::add_item)
+            .service(ui::update_item)
+            .service(ui::remove_item)
+            .service(get_cart)
+            .service(add_item)
+            .service(update_item)
+            .service(remove_item),
+    );
+}
+
+// GET The Cart with prices from the catalog
+#[get("")]
+pub async fn get_cart(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    request: HttpRequest,
+) -> HttpResponse {
+    controllers::cart::show_cart(db, &redis, &request, CartView::Json).await
+}
+
+// POST One Item, added to what is already in the cart
+#[post("/items")]
+pub async fn add_item(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    request: HttpRequest,
+    item: web::Json<CartItemIn>,
+) -> HttpResponse {
+    let change = CartChange::Add(item.into_inner());
+    controllers::cart::change_cart(db, &redis, &request, change, CartView::Json).await
+}
+
+// PUT The Quantity of One Item
+#[put("/items")]
+pub async fn update_item(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    request: HttpRequest,
+    item: web::Json<CartItemIn>,
+) -> HttpResponse {
+    let change = CartChange::Set(item.into_inner());
+    controllers::cart::change_cart(db, &redis, &request, change, CartView::Json).await
+}
+
+// DELETE One Item
+#[delete("/items")]
+pub async fn remove_item(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    request: HttpRequest,
+    line: web::Query<CartLineRef>,
+) -> HttpResponse {
+    let change = CartChange::Remove(line.into_inner());
+    controllers::cart::change_cart(db, &redis, &request, change, CartView::Json).await
+}
+
+pub mod ui {
+    use super::*;
+
+    #[get("/show")]
+    pub async fn cart_page(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        controllers::cart::show_cart(db, &redis, &request, CartView::Page).await
+    }
+
+    // HTMX swaps in the cart after every change
+    #[get("/show/items")]
+    pub async fn cart_fragment(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        controllers::cart::show_cart(db, &redis, &request, CartView::Fragment).await
+    }
+
+    #[post("/show/items")]
+    pub async fn add_item(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
+        item: web::Form<CartItemIn>,
+    ) -> HttpResponse {
+        let change = CartChange::Add(item.into_inner());
+        controllers::cart::change_cart(db, &redis, &request, change, CartView::Fragment).await
+    }
+
+    #[put("/show/items")]
+    pub async fn update_item(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
+        item: web::Form<CartItemIn>,
+    ) -> HttpResponse {
+        let change = CartChange::Set(item.into_inner());
+        controllers::cart::change_cart(db, &redis, &request, change, CartView::Fragment).await
+    }
+
+    #[delete("/show/items")]
+    pub async fn remove_item(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
+        line: web::Query<CartLineRef>,
+    ) -> HttpResponse {
+        let change = CartChange::Remove(line.into_inner());
+        controllers::cart::change_cart(db, &redis, &request, change, CartView::Fragment).await
+    }
+}
@@ -0,0 +1,141 @@
+use crate::controllers;
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{PageQuery, UserCookie, UserRole};
+use crate::domain::orders::RefundIn;
+use crate::modules::middleware::CheckLogin;
+use crate::modules::redis::RedisDB;
+use actix_web::*;
+use serde::Deserialize;
+
+pub fn orders_config(config: &mut web::ServiceConfig) {
+    config
+        .service(
+            // Customers only see their own orders
+            web::scope("/orders")
+                .wrap(CheckLogin::enabled())
+                .service(ui::orders_page)
+                .service(ui::order_page)
+                .service(ui::checkout_cancelled)
+                .service(checkout)
+                .service(list_orders)
+                .service(get_order),
+        )
+        .service(
+            web::scope("/account/orders")
+                .wrap(CheckLogin::enabled().roles(&[UserRole::ShopOwner, UserRole::Admin]))
+                .service(fulfil_order)
+                .service(refund_order),
+        )
+        .service(
+            web::scope("/account/disputes")
+                .wrap(CheckLogin::enabled().roles(&[UserRole::ShopOwner, UserRole::Admin]))
+                .service(ui::disputes_page)
+                .service(list_disputes),
+        );
+}
+
+// POST The Cart as a new Order, answered with the Stripe checkout page
+#[post("/checkout")]
+pub async fn checkout(
+    db: TenantDB,
+    redis: web::Data<RedisDB>,
+    user: web::ReqData<UserCookie>,
+    request: HttpRequest,
+) -> HttpResponse {
+    controllers::orders::checkout(db, &redis, &user, &request).await
+}
+
+// GET One Page of Orders
+#[get("")]
+pub async fn list_orders(
+    db: TenantDB,
+    user: web::ReqData<UserCookie>,
+    page: web::Query<PageQuery>,
+) -> HttpResponse {
+    controllers::orders::list_orders(db, &user, page.into_inner()).await
+}
+
+// GET One Order with its Items
+#[get("/{order_id}")]
+pub async fn get_order(
+    db: TenantDB,
+    user: web::ReqData<UserCookie>,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    controllers::orders::get_order(db, &user, path.into_inner()).await
+}
+
+// PUT One Order as sent to the customer
+#[put("/{order_id}/fulfil")]
+pub async fn fulfil_order(db: TenantDB, path: web::Path<i64>) -> HttpResponse {
+    controllers::orders::fulfil_order(db, path.into_inner()).await
+}
+
+// POST A full or partial Refund of One Order
+#[post("/{order_id}/refund")]
+pub async fn refund_order(
+    db: TenantDB,
+    path: web::Path<i64>,
+    refund: web::Json<RefundIn>,
+) -> HttpResponse {
+    controllers::orders::refund_order(db, path.into_inner(), refund.into_inner()).await
+}
+
+// GET One Page of Disputes, those waiting for evidence first
+#[get("")]
+pub async fn list_disputes(db: TenantDB, page: web::Query<PageQuery>) -> HttpResponse {
+    controllers::disputes::list_disputes(db, page.into_inner()).await
+}
+
+#[derive(Deserialize)]
+pub struct CheckoutReturn {
+    pub session_id: Option<String>,
+}
+
+pub mod ui {
+    use super::*;
+
+    #[get("/show")]
+    pub async fn orders_page(
+        db: TenantDB,
+        user: web::ReqData<UserCookie>,
+        page: web::Query<PageQuery>,
+    ) -> HttpResponse {
+        controllers::orders::ui::orders_page(db, &user, page.into_inner()).await
+    }
+
+    // Also where Stripe sends the customer after paying
+    #[get("/show/{order_id}")]
+    pub async fn order_page(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        user: web::ReqData<UserCookie>,
+        path: web::Path<i64>,
+        query: web::Query<CheckoutReturn>,
+    ) -> HttpResponse {
+        controllers::orders::ui::order_page(
+            db,
+            &redis,
+            &user,
+            path.into_inner(),
+            query.into_inner().session_id,
+        )
+        .await
+    }
+
+    // Where Stripe sends the customer who left the checkout
+    #[get("/cancel/{order_id}")]
+    pub async fn checkout_cancelled(
+        db: TenantDB,
+        user: web::ReqData<UserCookie>,
+        path: web::Path<i64>,
+    ) -> HttpResponse {
+        controllers::orders::ui::checkout_cancelled(db, &user, path.into_inner()).await
+    }
+
+    // The dispute dashboard of the shop, with the evidence due dates
+    #[get("/show")]
+    pub async fn disputes_page(db: TenantDB, page: web::Query<PageQuery>) -> HttpResponse {
+        controllers::disputes::ui::disputes_page(db, page.into_inner()).await
+    }
+}
@@ -0,0 +1,288 @@
+use crate::controllers;
+use crate::db::tenant::TenantDB;
+use crate::domain::datatypes::{PageQuery, UserCookie, UserRole};
+use crate::domain::inventory::{StockChangeIn, VariantIn};
+use crate::domain::products::ProductIn;
+use crate::modules::api_key::{ApiKeyCaller, ApiScope};
+use crate::modules::middleware::CheckLogin;
+use actix_web::*;
+
+pub fn products_config(config: &mut web::ServiceConfig) {
+    config
+        .service(
+            // The catalog of the shop is public
+            web::scope("/products")
+                .service(catalog::ui::products_page)
+                .service(catalog::ui::product_list)
+                .service(catalog::ui::product_page)
+                .service(catalog::list_products)
+                .service(catalog::get_product)
+                .service(catalog::list_variants),
+        )
+        .service(
+            web::scope("/account/products")
+                .wrap(
+                    CheckLogin::enabled()
+                        .roles(&[UserRole::ShopOwner, UserRole::Admin])
+                        .api_key(&[ApiScope::WriteProducts]),
+                )
+                .service(manage::create_product)
+                .service(manage::update_product)
+                .service(manage::delete_product)
+                .service(manage::create_variant)
+                .service(manage::update_variant)
+                .service(manage::delete_variant)
+                .service(manage::adjust_stock)
+                .service(manage::list_stock_movements),
+        );
+}
+
+pub mod catalog {
+    use super::*;
+
+    // GET One Page of Products
+    #[get("")]
+    pub async fn list_products(db: TenantDB, page: web::Query<PageQuery>) -> HttpResponse {
+        controllers::products::list_products(db, page.into_inner()).await
+    }
+
+    // GET One Product
+    #[get("/{product_id}")]
+    pub async fn get_product(db: TenantDB, path: web::Path<i64>) -> HttpResponse {
+        controllers::products::get_product(db, path.into_inner()).await
+    }
+
+    // GET All Variants of a Product
+    #[get("/{product_id}/variants")]
+    pub async fn list_variants(db: TenantDB, path: web::Path<i64>) -> HttpResponse {
+        controllers::products::list_variants(db, path.into_inner()).await
+    }
+
+    pub mod ui {
+        use super::*;
+
+        #[get("/show")]
+        pub async fn products_page(db: TenantDB, page: web::Query<PageQuery>) -> HttpResponse {
+            controllers::products::ui::products_page(db, page.into_inner(), false).await
+        }
+
+        // HTMX swaps in another page of the list
+        #[get("/show/list")]
+        pub async fn product_list(db: TenantDB, page: web::Query<PageQuery>) -> HttpResponse {
+            controllers::products::ui::products_page(db, page.into_inner(), true).await
+        }
+
+        #[get("/show/{product_id}")]
+        pub async fn product_page(db: TenantDB, path: web::Path<i64>) -> HttpResponse {
+            controllers::products::ui::product_page(db, path.into_inner()).await
+        }
+    }
+}
+
+pub mod manage {
+    use super::*;
+
+    // POST One Product
+    #[post("")]
+    pub async fn create_product(db: TenantDB, product: web::Json<ProductIn>) -> HttpResponse {
+        controllers::products::create_product(db, product.into_inner()).await
+    }
+
+    // PUT One Product
+    #[put("/{product_id}")]
+    pub async fn update_product(
+        db: TenantDB,
+        path: web::Path<i64>,
+        product: web::Json<ProductIn>,
+    ) -> HttpResponse {
+        controllers::products::update_product(db, path.into_inner(), product.into_inner()).await
+    }
+
+    // DELETE One Product
+    #[delete("/{product_id}")]
+    pub async fn delete_product(db: TenantDB, path: web::Path<i64>) -> HttpResponse {
+        controllers::products::delete_product(db, path.into_inner()).await
+    }
+
+    // POST One Variant
+    #[post("/{product_id}/variants")]
+    pub async fn create_variant(
+        db: TenantDB,
+        path: web::Path<i64>,
+        variant: web::Json<VariantIn>,
+    ) -> HttpResponse {
+        controllers::products::create_variant(db, path.into_inner(), variant.into_inner()).await
+    }
+
+    // PUT One Variant
+    #[put("/{product_id}/variants/{variant_id}")]
+    pub async fn update_variant(
+        db: TenantDB,
+        path: web::Path<(i64, i64)>,
+        variant: web::Json<VariantIn>,
+    ) -> HttpResponse {
+        let (product_id, variant_id) = path.into_inner();
+        controllers::products::update_variant(db, product_id, variant_id, variant.into_inner())
+            .await
+    }
+
+    // DELETE One Variant
+    #[delete("/{product_id}/variants/{variant_id}")]
+    pub async fn delete_variant(db: TenantDB, path: web::Path<(i64, i64)>) -> HttpResponse {
+        let (product_id, variant_id) = path.into_inner();
+        controllers::products::delete_variant(db, product_id, variant_id).await
+    }
+
+    // POST One Stock Movement, recorded with the user or the owner of the API key
+    #[post("/{product_id}/variants/{variant_id}/stock")]
+    pub async fn adjust_stock(
+        db: TenantDB,
+        path: web::Path<(i64, i64)>,
+        change: web::Json<StockChangeIn>,
+        user: Option<web::ReqData<UserCookie>>,
+        caller: Option<web::ReqData<ApiKeyCaller>>,
+    ) -> HttpResponse {
+        let (product_id, variant_id) = path.into_inner();
+        let user_id = user
+            .map(|user| user.user_id.clone())
+            .or(caller.map(|caller| caller.user_id.clone()));
+        controllers::products::adjust_stock(
+            db,
+            product_id,
+            variant_id,
+            change.into_inner(),
+            user_id,
+        )
+        .await
+    }
+
+    // GET One Page of Stock Movements
+    #[get("/{product_id}/variants/{variant_id}/movements")]
+    pub async fn list_stock_movements(
+        db: TenantDB,
+        path: web::Path<(i64, i64)>,
+        page: web::Query<PageQuery>,
+    ) -> HttpResponse {
+        let (product_id, variant_id) = path.into_inner();
+        controllers::products::list_stock_movements(db, product_id, variant_id, page.into_inner())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod product_routes_tests {
+    use super::*;
+    use crate::db::sqlite::SqliteDB;
+    use crate::domain::shops::Shop;
+    use crate::modules::middleware_domain::AddShopDomain;
+    use crate::utils::constants::SHOP_CONFIGS;
+    use actix_web::http::{header, StatusCode};
+
+    #[actix_rt::test]
+    async fn test_product_catalog() {
+        // Arrange
+        let path = std::env::temp_dir().join(format!(
+            "products_{}.db",
+            crate::modules::session::random_hex(8)
+        ));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        crate::models::schema::create_schema(&url).await.unwrap();
+        let db = SqliteDB::new(&url).await;
+        SHOP_CONFIGS.lock().unwrap().insert(
+            "catalog.test".to_string(),
+            Shop {
+                name: "Catalog Shop".to_string(),
+                product_type: "Tea".to_string(),
+                user_id: None,
+                slug: None,
+                canonical_domain: None,
+                theme: Default::default(),
+                plan: crate::domain::subscriptions::ShopPlan::Pro,
+            },
+        );
+        for name in ["Green Tea", "Black Tea", "White Tea"] {
+            let product: ProductIn = serde_json::from_value(serde_json::json!({
+                "name": name, "price": "3.50", "currency": "gbp"
+            }))
+            .unwrap();
+            db.for_shop("catalog.test")
+                .create_product(&product.validate().unwrap())
+                .await
+                .unwrap();
+        }
+        let catalog = db.for_shop("catalog.test");
+        let (products, _) = catalog
+            .get_products(&PageQuery {
+                page: None,
+                per_page: None,
+            })
+            .await
+            .unwrap();
+        let variant: VariantIn =
+            serde_json::from_str(r#"{"sku":"GREEN-50","attributes":{"size":"50g"},"price":"2"}"#)
+                .unwrap();
+        catalog
+            .create_variant(products[0].product_id, &variant.validate().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(db.clone()))
+                .wrap(AddShopDomain::enabled())
+                .configure(products_config),
+        )
+        .await;
+        let get = |uri: &str| {
+            actix_web::test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::HOST, "catalog.test"))
+                .to_request()
+        };
+
+        // The JSON list is paged
+        let page: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, get("/products?page=2&per_page=2"))
+                .await;
+        assert_eq!(page["total"], 3);
+        assert_eq!(page["total_pages"], 2);
+        assert_eq!(page["items"][0]["name"], "White Tea");
+        assert_eq!(page["items"][0]["price"], "3.50");
+
+        // The pages render the catalog
+        let body = actix_web::test::call_and_read_body(&app, get("/products/show")).await;
+        assert!(String::from_utf8_lossy(&body).contains("Green Tea"));
+        let body =
+            actix_web::test::call_and_read_body(&app, get("/products/show/list?page=2&per_page=2"))
+                .await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("White Tea") && !body.contains("Green Tea"));
+        // Variants show their own price and what is left
+        let uri = format!("/products/{}/variants", products[0].product_id);
+        let variants: serde_json::Value =
+            actix_web::test::call_and_read_body_json(&app, get(&uri)).await;
+        assert_eq!(variants[0]["sku"], "GREEN-50");
+        assert_eq!(variants[0]["attributes"]["size"], "50g");
+        assert_eq!(variants[0]["unit_price"], "2.00");
+        let uri = format!("/products/show/{}", products[0].product_id);
+        let body = actix_web::test::call_and_read_body(&app, get(&uri)).await;
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("size: 50g") && body.contains("Sold out"));
+        let resp = actix_web::test::call_service(&app, get("/products/999/variants")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = actix_web::test::call_service(&app, get("/products/show/999")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Changes need a login
+        let req = actix_web::test::TestRequest::post()
+            .uri("/account/products")
+            .insert_header((header::HOST, "catalog.test"))
+            .set_json(serde_json::json!({ "name": "Tea", "price": "1", "currency": "usd" }))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        db.db.close().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
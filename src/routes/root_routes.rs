@@ -1,15 +1,20 @@
 use crate::domain::datatypes::UserClientSignIn;
 use crate::modules::email::EmailType;
+use crate::modules::middleware::CheckLogin;
 use crate::modules::middleware_msg::Msg;
-use crate::modules::token_pub;
+use crate::modules::password_policy::ValidationErrors;
+use crate::modules::redis::RedisDB;
+use crate::modules::session::{self, Session};
+use crate::modules::token_pub::{self, TokenPurpose};
 use crate::{controllers, view};
 use actix_web::web::{self, ReqData};
 use actix_web::*;
 
 use crate::{
-    db::sqlite::SqliteDB,
+    db::tenant::TenantDB,
     domain::datatypes::{
-        CookieVariations, UserClientForgot, UserClientRegister, UserPassWordReset,
+        CookieVariations, LoginClient, OidcCallback, OidcStart, RefreshRedirect, TwoFactorCode,
+        TwoFactorPending, UserClientForgot, UserClientRegister, UserCookie, UserPassWordReset,
     },
     modules::{email::EmailSettings, middleware_domain::Shop},
 };
@@ -22,15 +27,30 @@ pub fn root_config(config: &mut web::ServiceConfig) {
             .service(root::endpoints_page)
             .service(root::login_page)
             .service(root::login_post)
+            .service(root::oidc_start)
+            .service(root::oidc_callback)
+            .service(root::two_factor_page)
+            .service(root::two_factor_post)
+            .service(root::two_factor_settings)
+            .service(root::two_factor_enrol)
+            .service(root::two_factor_confirm)
+            .service(root::two_factor_disable)
+            .service(root::sessions_page)
+            .service(root::sessions_terminate_others)
+            .service(root::sessions_terminate)
             .service(root::forget_page)
             .service(root::forgot_post)
             .service(root::logout)
+            .service(root::refresh)
             .service(root::register_page)
             .service(root::shop_handler)
             .service(root::msg)
             .service(root::post_register)
             .service(root::reset_page)
             .service(root::reset_post)
+            .service(root::verify_page)
+            .service(root::verify_resend)
+            .service(root::verify_token)
             .service(root::echo)
             .service(root::hello)
             .service(root::json_post),
@@ -79,9 +99,11 @@ pub mod root {
 
         context.insert("register_msg", "Please register to continue");
         context.insert("register_value_username", "");
+        context.insert("register_value_email", "");
         context.insert("register_value_password", "");
         context.insert("register_value_confirm_password", "");
         context.insert("register_failed_msg", "");
+        context.insert("register_errors", &ValidationErrors::new());
         match view::setup::TEMPLATES.render("pages/register/register.html", &context) {
             Ok(content) => return HttpResponse::Ok().body(content),
             Err(err) => {
@@ -94,35 +116,50 @@ pub mod root {
     #[post("/register")]
     pub async fn post_register(
         info: web::Form<UserClientRegister>,
-        db: web::Data<SqliteDB>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        request: HttpRequest,
     ) -> HttpResponse {
         let user_info = info.into_inner();
-        let user = user_info.verify_password();
         let mut context = tera::Context::new();
+        context.insert("register_failed_msg", "");
+        context.insert("register_errors", &ValidationErrors::new());
+
+        match user_info.verify_password() {
+            Ok(user) => match db.create_one_user(&user).await {
+                Ok(created_user) => {
+                    // The account works without verification, but only on a few pages
+                    let domain = request.connection_info().host().to_string();
+                    if !controllers::verification::send_verification_email(
+                        &created_user,
+                        &redis,
+                        domain,
+                    )
+                    .await
+                    {
+                        eprintln!("Error sending verification email to {}", created_user.email);
+                    }
 
-        if user.is_ok() {
-            let created_user = db.create_one_user(&user.unwrap()).await;
-
-            if created_user.is_ok() {
-                return HttpResponse::SeeOther()
-                    .append_header(("Location", "/login"))
-                    .finish();
-            } else {
-                context.insert(
+                    return HttpResponse::SeeOther()
+                        .append_header(("Location", "/login"))
+                        .finish();
+                }
+                Err(_) => context.insert(
                     "register_failed_msg",
                     "Something went wrong when creating you account",
-                );
+                ),
+            },
+            Err(errors) => {
+                context.insert("register_failed_msg", "Please correct the marked fields");
+                context.insert("register_errors", &errors);
             }
-        } else {
-            context.insert("register_failed_msg", "Password is not the same");
         }
         context.insert("register_msg", "Please register to continue");
         context.insert("register_value_username", &user_info.username);
-        context.insert("register_value_password", &user_info.password);
-        context.insert(
-            "register_value_confirm_password",
-            &user_info.confirm_password,
-        );
+        context.insert("register_value_email", &user_info.email);
+        // Passwords are never sent back to the browser
+        context.insert("register_value_password", "");
+        context.insert("register_value_confirm_password", "");
         match view::setup::TEMPLATES.render("pages/register/register.html", &context) {
             Ok(content) => return HttpResponse::Ok().body(content),
             Err(err) => {
@@ -159,39 +196,238 @@ pub mod root {
 
     #[get("/login")]
     pub async fn login_page() -> HttpResponse {
-        let mut context = tera::Context::new();
-
-        context.insert("login_msg", "Please login to continue");
-        context.insert("login_value_username", "");
-        context.insert("login_value_password", "");
-        context.insert("login_failed_msg", "");
-        match view::setup::TEMPLATES.render("pages/login/login.html", &context) {
-            Ok(content) => return HttpResponse::Ok().body(content),
-            Err(err) => {
-                eprintln!("Error rendering login page: {}", err);
-                return HttpResponse::InternalServerError().finish(); // Return 500 Internal Server Error
-            }
-        }
+        controllers::login::render_login_page("", "")
     }
 
     // POST Login info with remember field optional
     #[post("/login")]
     pub async fn login_post(
-        db: web::Data<SqliteDB>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
         login_info: web::Form<UserClientSignIn>,
+        request: HttpRequest,
     ) -> impl Responder {
         let user = login_info.into_inner();
+        let client = LoginClient::from_request(&request);
+
+        controllers::login::verify_login(db, redis, user, &client).await
+    }
+
+    // Send the browser to an OpenID Connect provider
+    #[get("/login/oidc/{provider}")]
+    pub async fn oidc_start(
+        redis: web::Data<RedisDB>,
+        path: web::Path<String>,
+        query: web::Query<OidcStart>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        let remember = query.remember.unwrap_or(false);
+        // A logged in user links an identity to the current account
+        let current_user = request
+            .cookie(CookieVariations::Auth.get_name().as_str())
+            .and_then(|cookie| session::authenticate(cookie.value(), &redis))
+            .map(|(user, _)| user);
+
+        controllers::oidc::start(&redis, &path.into_inner(), remember, current_user, &request).await
+    }
+
+    #[get("/login/oidc/{provider}/callback")]
+    pub async fn oidc_callback(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        path: web::Path<String>,
+        query: web::Query<OidcCallback>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        let current_user = request
+            .cookie(CookieVariations::Auth.get_name().as_str())
+            .and_then(|cookie| session::authenticate(cookie.value(), &redis))
+            .map(|(user, _)| user);
+        let browser_token = request
+            .cookie(CookieVariations::OidcState.get_name().as_str())
+            .map(|cookie| cookie.value().to_string());
+        let client = LoginClient::from_request(&request);
+
+        controllers::oidc::callback(
+            &db,
+            &redis,
+            &path.into_inner(),
+            query.into_inner(),
+            browser_token,
+            &client,
+            current_user,
+        )
+        .await
+    }
+
+    // Second login step for accounts with two-factor authentication
+    #[get("/login/two-factor")]
+    pub async fn two_factor_page(query: web::Query<TwoFactorPending>) -> HttpResponse {
+        controllers::two_factor::render_second_step(query.remember.unwrap_or(false), "")
+    }
+
+    #[post("/login/two-factor")]
+    pub async fn two_factor_post(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        form: web::Form<TwoFactorCode>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        let pending_token = request
+            .cookie(CookieVariations::TwoFactor.get_name().as_str())
+            .map(|cookie| cookie.value().to_string());
+        let client = LoginClient::from_request(&request);
+
+        controllers::two_factor::verify_second_step(
+            db,
+            redis,
+            pending_token,
+            form.into_inner(),
+            &client,
+        )
+        .await
+    }
+
+    #[get("/account/two-factor", wrap = "CheckLogin::enabled()")]
+    pub async fn two_factor_settings(db: TenantDB, user: ReqData<UserCookie>) -> HttpResponse {
+        controllers::two_factor::settings_page(db, user.into_inner()).await
+    }
+
+    #[post("/account/two-factor/enrol", wrap = "CheckLogin::enabled()")]
+    pub async fn two_factor_enrol(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+    ) -> HttpResponse {
+        controllers::two_factor::start_enrolment(db, redis, user.into_inner()).await
+    }
+
+    #[post("/account/two-factor/confirm", wrap = "CheckLogin::enabled()")]
+    pub async fn two_factor_confirm(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        form: web::Form<TwoFactorCode>,
+    ) -> HttpResponse {
+        controllers::two_factor::confirm_enrolment(db, redis, user.into_inner(), form.into_inner())
+            .await
+    }
+
+    #[post("/account/two-factor/disable", wrap = "CheckLogin::enabled()")]
+    pub async fn two_factor_disable(
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        form: web::Form<TwoFactorCode>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        let client = LoginClient::from_request(&request);
+        controllers::two_factor::disable(db, redis, user.into_inner(), form.into_inner(), &client)
+            .await
+    }
+
+    // Active sessions of the logged in user
+    #[get("/account/sessions", wrap = "CheckLogin::enabled()")]
+    pub async fn sessions_page(
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        current: Option<ReqData<Session>>,
+    ) -> HttpResponse {
+        controllers::sessions::sessions_page(
+            redis,
+            user.into_inner(),
+            current.map(|current| current.into_inner()),
+            "",
+        )
+    }
+
+    #[post("/account/sessions/terminate", wrap = "CheckLogin::enabled()")]
+    pub async fn sessions_terminate_others(
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        current: Option<ReqData<Session>>,
+    ) -> HttpResponse {
+        controllers::sessions::terminate_other_sessions(
+            redis,
+            user.into_inner(),
+            current.map(|current| current.into_inner()),
+        )
+    }
 
-        controllers::login::verify_login(db, user).await
+    #[post("/account/sessions/{handle}/terminate", wrap = "CheckLogin::enabled()")]
+    pub async fn sessions_terminate(
+        path: web::Path<String>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        current: Option<ReqData<Session>>,
+    ) -> HttpResponse {
+        controllers::sessions::terminate_session(
+            redis,
+            user.into_inner(),
+            current.map(|current| current.into_inner()),
+            path.into_inner(),
+        )
+    }
+
+    // Renew the access token with the refresh cookie
+    #[get("/refresh")]
+    pub async fn refresh(
+        request: HttpRequest,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+        query: web::Query<RefreshRedirect>,
+    ) -> HttpResponse {
+        let refresh_token = request
+            .cookie(CookieVariations::Refresh.get_name().as_str())
+            .map(|cookie| cookie.value().to_string());
+
+        controllers::login::refresh_login(db, redis, refresh_token, query.into_inner().redirect)
+            .await
     }
 
     // Logout
     #[get("/logout")]
-    pub async fn logout() -> HttpResponse {
-        let cookie = CookieVariations::Auth.remove_cookie();
+    pub async fn logout(request: HttpRequest, redis: web::Data<RedisDB>) -> HttpResponse {
+        // A server-side session only ends the session of this browser
+        let auth_value = request
+            .cookie(CookieVariations::Auth.get_name().as_str())
+            .map(|cookie| cookie.value().to_string());
+        if session::sessions_enabled() {
+            if let Some((user, Some(current))) = auth_value
+                .as_deref()
+                .and_then(|value| session::authenticate(value, &redis))
+            {
+                if let Err(err) = session::delete_session(&redis, &user.user_id, &current.id) {
+                    eprintln!("Error ending session on logout: {}", err);
+                }
+            }
+
+            return HttpResponse::SeeOther()
+                .append_header(("Location", "/login"))
+                .cookie(CookieVariations::Auth.remove_cookie())
+                .finish();
+        }
+
+        // Find the user with either token, the access token may already be expired
+        let the_user = auth_value
+            .as_deref()
+            .and_then(|value| token_pub::verify_token(value, &redis))
+            .or_else(|| {
+                request
+                    .cookie(CookieVariations::Refresh.get_name().as_str())
+                    .and_then(|cookie| token_pub::verify_refresh_token(cookie.value(), &redis))
+            });
+
+        if let Some(user) = the_user {
+            if let Err(err) = token_pub::revoke_user_tokens(&redis, &user.user_id) {
+                eprintln!("Error revoking tokens on logout: {}", err);
+            }
+        }
+
         HttpResponse::SeeOther()
             .append_header(("Location", "/login"))
-            .cookie(cookie)
+            .cookie(CookieVariations::Auth.remove_cookie())
+            .cookie(CookieVariations::Refresh.remove_cookie())
             .finish()
     }
 
@@ -215,7 +451,8 @@ pub mod root {
     // POST Login info with remember field optional
     #[post("/forgot")]
     pub async fn forgot_post(
-        db: web::Data<SqliteDB>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
         user_info: web::Form<UserClientForgot>,
         request: HttpRequest,
     ) -> impl Responder {
@@ -227,11 +464,20 @@ pub mod root {
         match user_db {
             Ok(user) => match user {
                 Some(user) => {
-                    let token = token_pub::generete_public_token(&user);
+                    let token = match token_pub::generete_single_use_token(
+                        &user,
+                        TokenPurpose::PasswordReset,
+                        &redis,
+                    ) {
+                        Ok(token) => token,
+                        Err(_) => {
+                            return HttpResponse::InternalServerError()
+                                .body("Something went wrong. Please try again.")
+                        }
+                    };
                     let settings = crate::utils::constants::get_email_settings();
-                    let user_email = "jissicko@gmail.com".to_string(); // Hardcoded for now - will be user.email
                     let email_settings = EmailSettings::password_reset_template(
-                        user_email,
+                        user.email.to_string(),
                         settings.email,
                         domain,
                         token,
@@ -292,10 +538,11 @@ pub mod root {
     }
 
     #[get("/reset/{token}")]
-    pub async fn reset_page(path: web::Path<String>) -> HttpResponse {
+    pub async fn reset_page(path: web::Path<String>, redis: web::Data<RedisDB>) -> HttpResponse {
         let token = path.into_inner();
 
-        let verified_token = token_pub::verify_token(&token);
+        let verified_token =
+            token_pub::verify_single_use_token(&token, TokenPurpose::PasswordReset, &redis);
         if verified_token.is_none() {
             return HttpResponse::InternalServerError().finish();
         }
@@ -307,6 +554,7 @@ pub mod root {
         context.insert("reset_value_password", "");
         context.insert("reset_value_confirm_password", "");
         context.insert("reset_failed_msg", "");
+        context.insert("reset_errors", &ValidationErrors::new());
         match view::setup::TEMPLATES.render("pages/reset/reset.html", &context) {
             Ok(content) => return HttpResponse::Ok().body(content),
             Err(err) => {
@@ -318,13 +566,15 @@ pub mod root {
 
     #[post("/reset/{token}")]
     pub async fn reset_post(
-        db: web::Data<SqliteDB>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
         path: web::Path<String>,
         info: web::Form<UserPassWordReset>,
     ) -> impl Responder {
         let token = path.into_inner();
         let pwds = info.into_inner();
-        let verified_token = token_pub::verify_token(&token);
+        let verified_token =
+            token_pub::verify_single_use_token(&token, TokenPurpose::PasswordReset, &redis);
 
         let the_token = match verified_token {
             Some(token) => token,
@@ -333,27 +583,147 @@ pub mod root {
 
         let user = match UserPassWordReset::verify_password(&pwds, &the_token) {
             Ok(user) => user,
-            Err(_) => return render_reset_page(&token, "Passwords do not match"),
+            Err(errors) => {
+                return render_reset_page(&token, "Please correct the marked fields", &errors)
+            }
         };
 
+        // The link is used up now, a second submit or a parallel request is rejected
+        if token_pub::consume_single_use_token(&token, TokenPurpose::PasswordReset, &redis)
+            .is_none()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+
         match db.update_one_user_password(&user).await {
-            Ok(_) => HttpResponse::SeeOther()
-                .append_header(("Location", "/login"))
-                .finish(),
-            Err(e) => render_reset_page(
-                &token,
-                format!("Something went wrong. Please try again: {}", e.to_string()).as_str(),
+            Ok(_) => {
+                // Sessions started with the old password stop working
+                if let Err(err) = token_pub::revoke_user_tokens(&redis, &user.user_id) {
+                    eprintln!("Error revoking tokens after password reset: {}", err);
+                }
+                HttpResponse::SeeOther()
+                    .append_header(("Location", "/login"))
+                    .finish()
+            }
+            Err(err) => {
+                eprintln!("Error resetting password: {}", err);
+                render_reset_page(
+                    &token,
+                    "Something went wrong, please request a new reset link",
+                    &ValidationErrors::new(),
+                )
+            }
+        }
+    }
+
+    // Landing page for logged in users that did not verify their email yet
+    #[get("/verify")]
+    pub async fn verify_page() -> HttpResponse {
+        controllers::verification::render_verify_page(
+            "Please verify your email address to use all pages",
+            true,
+            "",
+        )
+    }
+
+    #[post("/verify/resend")]
+    pub async fn verify_resend(
+        request: HttpRequest,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+    ) -> HttpResponse {
+        let the_user = request
+            .cookie(CookieVariations::Auth.get_name().as_str())
+            .and_then(|cookie| session::authenticate(cookie.value(), &redis))
+            .map(|(user, _)| user);
+
+        let user = match the_user {
+            Some(the_user) => match db.get_one_user(&the_user.user_id).await {
+                Ok(user) => user,
+                Err(_) => return HttpResponse::InternalServerError().finish(),
+            },
+            None => {
+                return HttpResponse::SeeOther()
+                    .append_header(("Location", "/login"))
+                    .finish()
+            }
+        };
+
+        if user.verified_at.is_some() {
+            return controllers::verification::render_verify_page(
+                "Your account is already verified",
+                false,
+                "",
+            );
+        }
+
+        let domain = request.connection_info().host().to_string();
+        if controllers::verification::send_verification_email(&user, &redis, domain).await {
+            controllers::verification::render_verify_page(
+                "Email sent successfully. Please check your email.",
+                true,
+                "",
+            )
+        } else {
+            controllers::verification::render_verify_page(
+                "Please verify your email address to use all pages",
+                true,
+                "Something went wrong. Please try again.",
+            )
+        }
+    }
+
+    #[get("/verify/{token}")]
+    pub async fn verify_token(
+        path: web::Path<String>,
+        db: TenantDB,
+        redis: web::Data<RedisDB>,
+    ) -> HttpResponse {
+        let token = path.into_inner();
+
+        let the_user =
+            match token_pub::consume_single_use_token(&token, TokenPurpose::Verification, &redis) {
+                Some(user) => user,
+                None => {
+                    return controllers::verification::render_verify_page(
+                        "This verification link is invalid or has expired",
+                        true,
+                        "",
+                    )
+                }
+            };
+
+        match db.verify_one_user(&the_user.user_id).await {
+            Ok(_) => {
+                // Tokens still claim the account is unverified, so the user logs in again
+                if let Err(err) = token_pub::revoke_user_tokens(&redis, &the_user.user_id) {
+                    eprintln!("Error revoking tokens after verification: {}", err);
+                }
+                let mut response = controllers::verification::render_verify_page(
+                    "Your account is verified. Please log in again.",
+                    false,
+                    "",
+                );
+                let _ = response.add_cookie(&CookieVariations::Auth.remove_cookie());
+                let _ = response.add_cookie(&CookieVariations::Refresh.remove_cookie());
+                response
+            }
+            Err(_) => controllers::verification::render_verify_page(
+                "Please verify your email address to use all pages",
+                true,
+                "Something went wrong. Please try again.",
             ),
         }
     }
 
-    fn render_reset_page(token: &str, message: &str) -> HttpResponse {
+    fn render_reset_page(token: &str, message: &str, errors: &ValidationErrors) -> HttpResponse {
         let mut context = tera::Context::new();
         context.insert("reset_msg", "Reset Password");
         context.insert("token", token);
         context.insert("reset_value_password", "");
         context.insert("reset_value_confirm_password", "");
         context.insert("reset_failed_msg", message);
+        context.insert("reset_errors", errors);
 
         match view::setup::TEMPLATES.render("pages/reset/reset.html", &context) {
             Ok(content) => HttpResponse::Ok().body(content),
@@ -0,0 +1,375 @@
+use crate::controllers;
+use crate::db::sqlite::SqliteDB;
+use crate::domain::datatypes::{UserCookie, UserRole};
+use crate::domain::shops::{ShopConfigIn, ShopDomainIn, ShopOwnerUpdate, ShopTheme};
+use crate::domain::subscriptions::{CancelSubscriptionIn, PlanChangeIn};
+use crate::modules::middleware::CheckLogin;
+use crate::modules::redis::RedisDB;
+use crate::utils::constants::PLATFORM_OPERATORS;
+use actix_web::web::ReqData;
+use actix_web::*;
+
+pub fn shops_config(config: &mut web::ServiceConfig) {
+    config
+        .service(
+            web::scope("/admin/shops")
+                // The shop registry is managed by the platform operators, not the admins of a shop
+                .wrap(
+                    CheckLogin::enabled()
+                        .roles(&[UserRole::Admin])
+                        .operators(&PLATFORM_OPERATORS),
+                )
+                .service(admin::list_shops)
+                .service(admin::create_shop)
+                .service(admin::ui::shops_page)
+                .service(admin::ui::create_shop)
+                .service(admin::ui::delete_shop)
+                .service(admin::update_shop)
+                .service(admin::delete_shop),
+        )
+        .service(
+            web::scope("/account/shops")
+                .wrap(CheckLogin::enabled().roles(&[UserRole::ShopOwner, UserRole::Admin]))
+                .service(owner::list_own_shops)
+                .service(owner::update_own_shop)
+                .service(owner::update_shop_theme)
+                .service(owner::list_shop_domains)
+                .service(owner::add_shop_domain)
+                .service(owner::verify_shop_domain)
+                .service(owner::set_canonical_domain)
+                .service(owner::delete_shop_domain)
+                .service(owner::get_subscription)
+                .service(owner::subscribe)
+                .service(owner::change_plan)
+                .service(owner::cancel_subscription)
+                .service(owner::get_payouts)
+                .service(owner::start_onboarding)
+                .service(owner::refresh_onboarding)
+                .service(owner::onboarding_return),
+        );
+}
+
+pub mod admin {
+    use super::*;
+
+    // GET All Shops
+    #[get("")]
+    pub async fn list_shops(db: web::Data<SqliteDB>) -> HttpResponse {
+        controllers::shops::list_shops(db).await
+    }
+
+    // POST One Shop
+    #[post("")]
+    pub async fn create_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        shop: web::Json<ShopConfigIn>,
+    ) -> HttpResponse {
+        controllers::shops::create_shop(db, redis, shop.into_inner()).await
+    }
+
+    // PUT One Shop
+    #[put("/{domain}")]
+    pub async fn update_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        path: web::Path<String>,
+        shop: web::Json<ShopConfigIn>,
+    ) -> HttpResponse {
+        controllers::shops::update_shop(db, redis, path.into_inner(), shop.into_inner()).await
+    }
+
+    // DELETE One Shop
+    #[delete("/{domain}")]
+    pub async fn delete_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        controllers::shops::delete_shop(db, redis, path.into_inner()).await
+    }
+
+    pub mod ui {
+        use super::*;
+
+        #[get("/show")]
+        pub async fn shops_page(db: web::Data<SqliteDB>) -> HttpResponse {
+            controllers::shops::ui::shops_page(db, "").await
+        }
+
+        #[post("/show")]
+        pub async fn create_shop(
+            db: web::Data<SqliteDB>,
+            redis: web::Data<RedisDB>,
+            form: web::Form<ShopConfigIn>,
+        ) -> HttpResponse {
+            controllers::shops::ui::create_shop(db, redis, form.into_inner()).await
+        }
+
+        #[delete("/show/{domain}")]
+        pub async fn delete_shop(
+            db: web::Data<SqliteDB>,
+            redis: web::Data<RedisDB>,
+            path: web::Path<String>,
+        ) -> HttpResponse {
+            controllers::shops::ui::delete_shop(db, redis, path.into_inner()).await
+        }
+    }
+}
+
+pub mod owner {
+    use super::*;
+
+    // GET Shops of the logged in owner
+    #[get("")]
+    pub async fn list_own_shops(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+    ) -> HttpResponse {
+        controllers::shops::list_own_shops(db, user.into_inner()).await
+    }
+
+    // PUT One Shop of the logged in owner
+    #[put("/{domain}")]
+    pub async fn update_own_shop(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        update: web::Json<ShopOwnerUpdate>,
+    ) -> HttpResponse {
+        controllers::shops::update_own_shop(
+            db,
+            redis,
+            user.into_inner(),
+            path.into_inner(),
+            update.into_inner(),
+        )
+        .await
+    }
+
+    // PUT Theme of a shop of the logged in owner
+    #[put("/{domain}/theme")]
+    pub async fn update_shop_theme(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        theme: web::Json<ShopTheme>,
+    ) -> HttpResponse {
+        controllers::shops::update_shop_theme(
+            db,
+            redis,
+            user.into_inner(),
+            path.into_inner(),
+            theme.into_inner(),
+        )
+        .await
+    }
+
+    // GET Custom Domains of a shop of the logged in owner
+    #[get("/{domain}/domains")]
+    pub async fn list_shop_domains(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        controllers::shops::list_shop_domains(db, user.into_inner(), path.into_inner()).await
+    }
+
+    // POST One Custom Domain
+    #[post("/{domain}/domains")]
+    pub async fn add_shop_domain(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        request: web::Json<ShopDomainIn>,
+    ) -> HttpResponse {
+        controllers::shops::add_shop_domain(
+            db,
+            user.into_inner(),
+            path.into_inner(),
+            request.into_inner(),
+        )
+        .await
+    }
+
+    // POST Verify One Custom Domain by its TXT record
+    #[post("/{domain}/domains/{custom_domain}/verify")]
+    pub async fn verify_shop_domain(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<(String, String)>,
+    ) -> HttpResponse {
+        let (domain, custom_domain) = path.into_inner();
+        controllers::shops::verify_shop_domain(db, redis, user.into_inner(), domain, custom_domain)
+            .await
+    }
+
+    // PUT Canonical Domain of a shop
+    #[put("/{domain}/domains/{custom_domain}/canonical")]
+    pub async fn set_canonical_domain(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<(String, String)>,
+    ) -> HttpResponse {
+        let (domain, custom_domain) = path.into_inner();
+        controllers::shops::set_canonical_domain(
+            db,
+            redis,
+            user.into_inner(),
+            domain,
+            custom_domain,
+        )
+        .await
+    }
+
+    // DELETE One Custom Domain
+    #[delete("/{domain}/domains/{custom_domain}")]
+    pub async fn delete_shop_domain(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<(String, String)>,
+    ) -> HttpResponse {
+        let (domain, custom_domain) = path.into_inner();
+        controllers::shops::delete_shop_domain(db, redis, user.into_inner(), domain, custom_domain)
+            .await
+    }
+
+    // GET The Plan and Subscription of a shop
+    #[get("/{domain}/subscription")]
+    pub async fn get_subscription(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        controllers::subscriptions::get_subscription(&db, &user, &path).await
+    }
+
+    // POST One Subscription, answered with the Stripe checkout page
+    #[post("/{domain}/subscription")]
+    pub async fn subscribe(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        change: web::Json<PlanChangeIn>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        controllers::subscriptions::subscribe(&db, &user, &path, change.plan, &request).await
+    }
+
+    // PUT The Plan of the Subscription, to upgrade or downgrade
+    #[put("/{domain}/subscription")]
+    pub async fn change_plan(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        change: web::Json<PlanChangeIn>,
+    ) -> HttpResponse {
+        controllers::subscriptions::change_plan(&db, &redis, &user, &path, change.plan).await
+    }
+
+    // DELETE The Subscription, at the end of the paid period unless immediately=true
+    #[delete("/{domain}/subscription")]
+    pub async fn cancel_subscription(
+        db: web::Data<SqliteDB>,
+        redis: web::Data<RedisDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        query: web::Query<CancelSubscriptionIn>,
+    ) -> HttpResponse {
+        let immediately = query.immediately.unwrap_or(false);
+        controllers::subscriptions::cancel_subscription(&db, &redis, &user, &path, immediately)
+            .await
+    }
+
+    // GET The Payout Account of a shop and whether it can take payments
+    #[get("/{domain}/payouts")]
+    pub async fn get_payouts(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        controllers::payouts::get_payouts(&db, &user, &path).await
+    }
+
+    // POST The Payout Account of a shop, answered with the Stripe onboarding page
+    #[post("/{domain}/payouts")]
+    pub async fn start_onboarding(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        controllers::payouts::start_onboarding(&db, &user, &path, &request).await
+    }
+
+    // GET A new onboarding link, for Stripe to send expired links to
+    #[get("/{domain}/payouts/refresh")]
+    pub async fn refresh_onboarding(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+        request: HttpRequest,
+    ) -> HttpResponse {
+        controllers::payouts::refresh_onboarding(&db, &user, &path, &request).await
+    }
+
+    // GET The Payout Account after onboarding, where Stripe returns the owner to
+    #[get("/{domain}/payouts/return")]
+    pub async fn onboarding_return(
+        db: web::Data<SqliteDB>,
+        user: ReqData<UserCookie>,
+        path: web::Path<String>,
+    ) -> HttpResponse {
+        controllers::payouts::onboarding_return(&db, &user, &path).await
+    }
+}
+
+#[cfg(test)]
+mod shop_routes_tests {
+    use super::*;
+    use crate::modules::middleware_domain::Shop;
+    use actix_web::http::StatusCode;
+
+    #[actix_rt::test]
+    async fn test_shop_registry_needs_operator() {
+        // Arrange, the admin of a.test and no operators configured
+        let app = test::init_service(App::new().configure(shops_config)).await;
+        let admin = UserCookie {
+            user_id: "1234".to_string(),
+            username: "eve".to_string(),
+            verified: true,
+            role: UserRole::Admin,
+            shop_domain: "a.test".to_string(),
+        };
+        let shop = Shop {
+            domain: "a.test".to_string(),
+            name: "Shop A".to_string(),
+            product_type: "Tests".to_string(),
+            user_id: None,
+            canonical_domain: "a.test".to_string(),
+            theme: Default::default(),
+            plan: crate::domain::subscriptions::ShopPlan::Pro,
+        };
+
+        // Act
+        let req = test::TestRequest::delete()
+            .uri("/admin/shops/b.test")
+            .to_request();
+        req.extensions_mut().insert(shop);
+        req.extensions_mut().insert(admin);
+        let resp = test::call_service(&app, req).await;
+
+        // Assert
+        assert_eq!(
+            resp.status(),
+            StatusCode::FORBIDDEN,
+            "Admin of another shop can delete shops"
+        );
+    }
+}
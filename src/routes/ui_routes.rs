@@ -1,11 +1,14 @@
 use crate::controllers::ui_controller::*;
 use crate::db::diesel::Database;
+use crate::domain::datatypes::UserRole;
+use crate::modules::middleware::CheckLogin;
 use actix_web::*;
 
 // this function could be located in a different module
 pub fn ui_config(config: &mut web::ServiceConfig) {
     config.service(
         web::scope("/ui")
+            .wrap(CheckLogin::enabled())
             .service(index_ui::hello)
             .service(index_ui::ping_pong)
             .service(index_ui::show_all_user_list)
@@ -31,12 +34,18 @@ pub mod index_ui {
         return index::index_ui_controller::ping_pong(ping_pong);
     }
 
-    #[get("/index/show/users")]
+    #[get(
+        "/index/show/users",
+        wrap = "CheckLogin::enabled().roles(&[UserRole::Admin])"
+    )]
     pub async fn show_all_user_list(db: web::Data<Database>) -> impl Responder {
         return index::index_ui_controller::show_all_user_list_diesel(db);
     }
 
-    #[delete("/index/delete/{id}")]
+    #[delete(
+        "/index/delete/{id}",
+        wrap = "CheckLogin::enabled().roles(&[UserRole::Admin])"
+    )]
     pub async fn delete_one_user(
         path: web::Path<String>,
         db: web::Data<Database>,
@@ -1,12 +1,19 @@
 use crate::controllers;
 use crate::db::diesel::Database;
-use crate::domain::user_domain;
+use crate::domain::{datatypes::UserRole, user_domain};
+use crate::modules::api_key::ApiScope;
+use crate::modules::middleware::CheckLogin;
 use actix_web::*;
 
 // this function could be located in a different module
 pub fn users_config(config: &mut web::ServiceConfig) {
     config.service(
         web::scope("/users")
+            .wrap(
+                CheckLogin::enabled()
+                    .roles(&[UserRole::Admin])
+                    .api_key(&[ApiScope::ReadUsers]),
+            )
             .service(user::get_all_user)
             .service(user::get_one_user)
             .service(user::post_one_user)
@@ -36,6 +36,47 @@ lazy_static! {
     // Setup Token Constants
     pub static ref TOKEN_SECRET: String = load_settings!("TOKEN_SECRET");
     pub static ref TOKEN_SK: String = load_settings!("TOKEN_SK");
+    pub static ref ACCESS_TOKEN_TTL_MINUTES: u64 = load_settings!("ACCESS_TOKEN_TTL_MINUTES", 15).parse().expect("Access token TTL is not a number");
+    pub static ref PASSWORD_RESET_TTL_MINUTES: u64 = load_settings!("PASSWORD_RESET_TTL_MINUTES", 30).parse().expect("Password reset TTL is not a number");
+    pub static ref VERIFICATION_TTL_HOURS: u64 = load_settings!("VERIFICATION_TTL_HOURS", 24).parse().expect("Verification TTL is not a number");
+    pub static ref REFRESH_TOKEN_TTL_DAYS: u64 = load_settings!("REFRESH_TOKEN_TTL_DAYS", 7).parse().expect("Refresh token TTL is not a number");
+    // Keep sessions in Redis and only put the session id in the auth cookie
+    pub static ref SERVER_SESSIONS: bool = load_settings!("SERVER_SESSIONS", false).parse().expect("Server sessions is not true or false");
+    pub static ref SESSION_IDLE_MINUTES: u64 = load_settings!("SESSION_IDLE_MINUTES", 60).parse().expect("Session idle time is not a number");
+    // Setup Login Throttling Constants
+    pub static ref LOGIN_MAX_ATTEMPTS: isize = load_settings!("LOGIN_MAX_ATTEMPTS", 5).parse().expect("Login max attempts is not a number");
+    pub static ref LOGIN_MAX_ATTEMPTS_IP: isize = load_settings!("LOGIN_MAX_ATTEMPTS_IP", 20).parse().expect("Login max attempts per IP is not a number");
+    pub static ref LOGIN_ATTEMPT_WINDOW_MINUTES: u64 = load_settings!("LOGIN_ATTEMPT_WINDOW_MINUTES", 15).parse().expect("Login attempt window is not a number");
+    pub static ref LOGIN_LOCKOUT_MINUTES: u64 = load_settings!("LOGIN_LOCKOUT_MINUTES", 15).parse().expect("Login lockout is not a number");
+    // Comma separated addresses of the reverse proxies whose X-Forwarded-For is believed, empty trusts none
+    pub static ref TRUSTED_PROXIES: Vec<std::net::IpAddr> = load_settings!("TRUSTED_PROXIES", "")
+        .split(',')
+        .map(|proxy| proxy.trim())
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| proxy.parse().expect("Trusted proxy is not an IP address"))
+        .collect();
+    // Setup Password Hashing Constants, the defaults are the Argon2id parameters recommended by OWASP
+    pub static ref ARGON2_MEMORY_KIB: u32 = load_settings!("ARGON2_MEMORY_KIB", 19456).parse().expect("Argon2 memory is not a number");
+    pub static ref ARGON2_ITERATIONS: u32 = load_settings!("ARGON2_ITERATIONS", 2).parse().expect("Argon2 iterations is not a number");
+    pub static ref ARGON2_PARALLELISM: u32 = load_settings!("ARGON2_PARALLELISM", 1).parse().expect("Argon2 parallelism is not a number");
+    // Server-side secret mixed into every new hash, empty disables it
+    pub static ref PASSWORD_PEPPER: String = load_settings!("PASSWORD_PEPPER", "");
+    // Stored as keyid in peppered hashes, hashes without it are rehashed with the pepper on login
+    pub static ref PASSWORD_PEPPER_ID: String = load_settings!("PASSWORD_PEPPER_ID", "1");
+    // Setup Password Policy Constants
+    pub static ref PASSWORD_MIN_LENGTH: usize = load_settings!("PASSWORD_MIN_LENGTH", 10).parse().expect("Password min length is not a number");
+    pub static ref PASSWORD_MAX_LENGTH: usize = load_settings!("PASSWORD_MAX_LENGTH", 128).parse().expect("Password max length is not a number");
+    pub static ref PASSWORD_REQUIRE_LOWERCASE: bool = load_settings!("PASSWORD_REQUIRE_LOWERCASE", true).parse().expect("Password require lowercase is not true or false");
+    pub static ref PASSWORD_REQUIRE_UPPERCASE: bool = load_settings!("PASSWORD_REQUIRE_UPPERCASE", true).parse().expect("Password require uppercase is not true or false");
+    pub static ref PASSWORD_REQUIRE_DIGIT: bool = load_settings!("PASSWORD_REQUIRE_DIGIT", true).parse().expect("Password require digit is not true or false");
+    pub static ref PASSWORD_REQUIRE_SYMBOL: bool = load_settings!("PASSWORD_REQUIRE_SYMBOL", false).parse().expect("Password require symbol is not true or false");
+    pub static ref PASSWORD_BREACH_CHECK: bool = load_settings!("PASSWORD_BREACH_CHECK", true).parse().expect("Password breach check is not true or false");
+    // Setup OpenID Connect Constants, each provider in the list is configured with
+    // OIDC_<NAME>_ISSUER, OIDC_<NAME>_CLIENT_ID, OIDC_<NAME>_CLIENT_SECRET and optionally OIDC_<NAME>_SCOPES
+    pub static ref OIDC_PROVIDERS: String = load_settings!("OIDC_PROVIDERS", "");
+    pub static ref OIDC_CACHE_MINUTES: u64 = load_settings!("OIDC_CACHE_MINUTES", 60).parse().expect("OIDC cache time is not a number");
+    // Setup Two-Factor Constants
+    pub static ref TOTP_ISSUER: String = load_settings!("TOTP_ISSUER", "Rust Backend");
     // Setup Email Constants
     pub static ref SMTP_HOST: String = load_settings!("SMTP_HOST");
     pub static ref EMAIL_HOST: String = load_settings!("EMAIL_HOST");
@@ -51,8 +92,40 @@ lazy_static! {
     // Stripe Constants
     pub static ref STRIPE_SECRET: String = load_settings!("STRIPE_SECRET");
     pub static ref STRIPE_WEBHOOK_SECRET: String = load_settings!("STRIPE_WEBHOOK_SECRET");
+    // Failed webhook events are retried with a doubling delay, then given up until replayed
+    pub static ref STRIPE_EVENT_MAX_ATTEMPTS: i64 = load_settings!("STRIPE_EVENT_MAX_ATTEMPTS", 8).parse().expect("Stripe event attempts is not a number");
+    pub static ref STRIPE_EVENT_RETRY_SECONDS: i64 = load_settings!("STRIPE_EVENT_RETRY_SECONDS", 30).parse().expect("Stripe event retry delay is not a number");
+    pub static ref STRIPE_EVENT_POLL_SECONDS: u64 = load_settings!("STRIPE_EVENT_POLL_SECONDS", 5).parse().expect("Stripe event poll interval is not a number");
+    // Recurring Stripe prices of the paid shop plans, a plan without a price cannot be chosen
+    pub static ref STRIPE_PRICE_STARTER: String = load_settings!("STRIPE_PRICE_STARTER", "");
+    pub static ref STRIPE_PRICE_PRO: String = load_settings!("STRIPE_PRICE_PRO", "");
+    // Share of every order the platform keeps when paying shops out, in basis points
+    pub static ref STRIPE_APPLICATION_FEE_BPS: i64 = load_settings!("STRIPE_APPLICATION_FEE_BPS", 500).parse().expect("Application fee is not a number");
+    // Carts left alone expire from Redis after this many days
+    pub static ref CART_TTL_DAYS: u64 = load_settings!("CART_TTL_DAYS", 30).parse().expect("Cart TTL is not a number");
+    // Checkout sessions and the stock reserved for them expire together, Stripe wants at least 30 minutes
+    pub static ref CHECKOUT_EXPIRY_MINUTES: i64 = load_settings!("CHECKOUT_EXPIRY_MINUTES", 31).parse().expect("Checkout expiry is not a number");
     // Setup Shop Configurations
     pub static ref SHOP_CONFIGS: Mutex<HashMap<String, Shop>> = Mutex::new(HashMap::new());
+    // Verified custom domains and the shop domain they serve
+    pub static ref SHOP_HOSTS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    // Comma separated user ids of the platform operators, the only users that manage the shop registry
+    pub static ref PLATFORM_OPERATORS: Vec<String> = load_settings!("PLATFORM_OPERATORS", "")
+        .split(',')
+        .map(|user_id| user_id.trim().to_string())
+        .filter(|user_id| !user_id.is_empty())
+        .collect();
+    // Shops with a slug are served on its subdomains, like shop1.ourplatform.com
+    pub static ref PLATFORM_DOMAIN: String = load_settings!("PLATFORM_DOMAIN", "");
+    // Name server asked for the TXT records of custom domains
+    pub static ref DNS_RESOLVER: String = load_settings!("DNS_RESOLVER", "1.1.1.1:53");
+    // Requests for hosts without a shop are redirected here when set, otherwise they get the template
+    pub static ref UNKNOWN_SHOP_REDIRECT: String = load_settings!("UNKNOWN_SHOP_REDIRECT", "");
+    // Shops override templates in <THEMES_DIR>/<shop domain>
+    pub static ref THEMES_DIR: String = load_settings!("THEMES_DIR", "src/view/themes");
+    // How often the override directories are checked for changed templates
+    pub static ref THEMES_RELOAD_SECONDS: u64 = load_settings!("THEMES_RELOAD_SECONDS", 30).parse().expect("Themes reload interval is not a number");
+    pub static ref UNKNOWN_SHOP_TEMPLATE: String = load_settings!("UNKNOWN_SHOP_TEMPLATE", "pages/unknown_shop.html");
 }
 
 pub struct EmailSettings {
@@ -1,9 +1,17 @@
+use crate::domain::subscriptions::PlanFeature;
+use crate::modules::middleware_csrf;
+use crate::modules::middleware_domain::{self, Shop};
+use crate::utils::constants::{THEMES_DIR, THEMES_RELOAD_SECONDS};
 use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tera::Tera;
 
 lazy_static! {
     pub static ref TITLE: String = ui_index_title();
-    pub static ref TEMPLATES: Tera = {
+    pub static ref TEMPLATES: Templates = {
         let mut tera = match Tera::new("src/view/templates/**/*") {
             Ok(t) => t,
             Err(e) => {
@@ -12,13 +20,276 @@ lazy_static! {
             }
         };
         tera.autoescape_on(vec![".html", ".sql"]);
-        tera
+        Templates::new(tera, THEMES_DIR.as_str())
     };
 }
 
+// Tera with the CSRF token and the shop of the current request added to every context.
+// Shops can override any template in <themes dir>/<shop domain>, the rest comes from the base set.
+pub struct Templates {
+    base: Tera,
+    themes_dir: PathBuf,
+    // Overrides of each shop, read once and again after reload_changed found a change
+    shops: Mutex<HashMap<String, ShopOverrides>>,
+}
+
+struct ShopOverrides {
+    // Newest modification in the directory when it was read, None without a directory
+    modified: Option<SystemTime>,
+    // None for shops without overrides
+    templates: Option<Arc<Tera>>,
+}
+
+impl Templates {
+    pub fn new(base: Tera, themes_dir: impl AsRef<Path>) -> Self {
+        Templates {
+            base,
+            themes_dir: themes_dir.as_ref().to_path_buf(),
+            shops: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn render(&self, template_name: &str, context: &tera::Context) -> tera::Result<String> {
+        let shop = middleware_domain::current_shop();
+        self.render_for(shop.as_ref(), template_name, context)
+    }
+
+    fn render_for(
+        &self,
+        shop: Option<&Shop>,
+        template_name: &str,
+        context: &tera::Context,
+    ) -> tera::Result<String> {
+        let mut context = context.clone();
+        context.insert(
+            middleware_csrf::CSRF_FIELD,
+            &middleware_csrf::current_token().unwrap_or_default(),
+        );
+
+        let Some(shop) = shop else {
+            return self.base.render(template_name, &context);
+        };
+        context.insert("shop", shop);
+        // Template overrides are a theme as well, shops on a smaller plan get the base set
+        let templates = match shop.plan.allows(PlanFeature::Themes) {
+            true => self.shop_templates(&shop.domain),
+            false => None,
+        };
+        match templates {
+            Some(templates) => templates.render(template_name, &context),
+            None => self.base.render(template_name, &context),
+        }
+    }
+
+    fn shop_templates(&self, domain: &str) -> Option<Arc<Tera>> {
+        if let Some(cached) = self.shops.lock().unwrap().get(domain) {
+            return cached.templates.clone();
+        }
+
+        let modified = self.directory_modified(domain);
+        let templates = match modified {
+            Some(_) => self.load_overrides(domain).map(Arc::new),
+            None => None,
+        };
+        self.shops.lock().unwrap().insert(
+            domain.to_string(),
+            ShopOverrides {
+                modified,
+                templates: templates.clone(),
+            },
+        );
+        templates
+    }
+
+    // Forget the overrides of shops whose directory changed since they were read,
+    // the next page of the shop reads them again
+    pub fn reload_changed(&self) {
+        let cached: Vec<(String, Option<SystemTime>)> = self
+            .shops
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, overrides)| (domain.clone(), overrides.modified))
+            .collect();
+
+        let changed: Vec<String> = cached
+            .into_iter()
+            .filter(|(domain, modified)| self.directory_modified(domain) != *modified)
+            .map(|(domain, _)| domain)
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+
+        let mut shops = self.shops.lock().unwrap();
+        for domain in changed {
+            shops.remove(&domain);
+        }
+    }
+
+    fn directory_modified(&self, domain: &str) -> Option<SystemTime> {
+        self.overrides_dir(domain)
+            .and_then(|directory| last_modified(&directory))
+    }
+
+    fn overrides_dir(&self, domain: &str) -> Option<PathBuf> {
+        if domain.is_empty() || domain.starts_with('.') || domain.contains(['/', '\\']) {
+            return None;
+        }
+        let directory = self.themes_dir.join(domain);
+        directory.is_dir().then_some(directory)
+    }
+
+    // The overrides are parsed on their own, the base templates fill in the rest so
+    // an override can extend a base layout and the other way around
+    fn load_overrides(&self, domain: &str) -> Option<Tera> {
+        let directory = self.overrides_dir(domain)?;
+
+        let glob = format!("{}/**/*", directory.display());
+        let mut tera = match Tera::parse(&glob) {
+            Ok(tera) => tera,
+            Err(err) => {
+                eprintln!("Error parsing templates of {}: {}", domain, err);
+                return None;
+            }
+        };
+        tera.autoescape_on(vec![".html", ".sql"]);
+        if let Err(err) = tera.extend(&self.base) {
+            eprintln!("Error combining templates of {}: {}", domain, err);
+            return None;
+        }
+        Some(tera)
+    }
+}
+
+// Newest modification time of a directory and everything in it, None when it does not exist.
+// Adding or removing a file changes the time of the directory holding it.
+fn last_modified(path: &Path) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let mut modified = metadata.modified().ok();
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path).ok()?.flatten() {
+            modified = modified.max(last_modified(&entry.path()));
+        }
+    }
+    modified
+}
+
+// Runs next to the server and picks up changed template overrides
+pub async fn watch_theme_changes() {
+    let interval = std::time::Duration::from_secs(*THEMES_RELOAD_SECONDS);
+    loop {
+        tokio::time::sleep(interval).await;
+        TEMPLATES.reload_changed();
+    }
+}
+
 fn ui_index_title() -> String {
     let title: String =
         "<h1>This is the Title 2</h1><h2>This is a smaller Title 2</h2><p>This is a paragraaf 2</p>"
             .to_owned();
     return title;
 }
+
+#[cfg(test)]
+mod setup_tests {
+    use super::*;
+    use crate::domain::subscriptions::ShopPlan;
+
+    fn test_shop(domain: &str) -> Shop {
+        Shop {
+            domain: domain.to_string(),
+            name: format!("Shop {}", domain),
+            product_type: "Tests".to_string(),
+            user_id: None,
+            canonical_domain: domain.to_string(),
+            theme: Default::default(),
+            plan: ShopPlan::Pro,
+        }
+    }
+
+    #[test]
+    fn check_shop_template_overrides() {
+        // Arrange
+        let themes_dir =
+            std::env::temp_dir().join(format!("themes_{}", crate::modules::session::random_hex(8)));
+        std::fs::create_dir_all(themes_dir.join("themed.test")).unwrap();
+        std::fs::write(
+            themes_dir.join("themed.test/layout.html"),
+            "<h1>{{ shop.name }}</h1>{% block content %}{% endblock content %}",
+        )
+        .unwrap();
+
+        let mut base = Tera::default();
+        base.add_raw_templates(vec![
+            (
+                "layout.html",
+                "<h1>Base</h1>{% block content %}{% endblock content %}",
+            ),
+            (
+                "page.html",
+                "{% extends 'layout.html' %}{% block content %}Page{% endblock content %}",
+            ),
+        ])
+        .unwrap();
+        let templates = Templates::new(base, &themes_dir);
+        let context = tera::Context::new();
+
+        // Base pages use the overridden layout of the shop
+        let themed = templates
+            .render_for(Some(&test_shop("themed.test")), "page.html", &context)
+            .unwrap();
+        assert_eq!(themed, "<h1>Shop themed.test</h1>Page");
+
+        // Shops without overrides and requests without a shop get the base set
+        let plain = templates
+            .render_for(Some(&test_shop("plain.test")), "page.html", &context)
+            .unwrap();
+        assert_eq!(plain, "<h1>Base</h1>Page");
+        let no_shop = templates.render_for(None, "page.html", &context).unwrap();
+        assert_eq!(no_shop, "<h1>Base</h1>Page");
+
+        // So do shops whose plan does not include themes
+        let free = Shop {
+            plan: ShopPlan::Free,
+            ..test_shop("themed.test")
+        };
+        let unthemed = templates
+            .render_for(Some(&free), "page.html", &context)
+            .unwrap();
+        assert_eq!(unthemed, "<h1>Base</h1>Page");
+
+        // Changed and new overrides are picked up without a restart, once they were looked for
+        let later = SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::write(
+            themes_dir.join("themed.test/layout.html"),
+            "<h2>{{ shop.name }}</h2>{% block content %}{% endblock content %}",
+        )
+        .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(themes_dir.join("themed.test/layout.html"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let cached = templates
+            .render_for(Some(&test_shop("themed.test")), "page.html", &context)
+            .unwrap();
+        assert_eq!(cached, "<h1>Shop themed.test</h1>Page");
+        templates.reload_changed();
+        let changed = templates
+            .render_for(Some(&test_shop("themed.test")), "page.html", &context)
+            .unwrap();
+        assert_eq!(changed, "<h2>Shop themed.test</h2>Page");
+
+        std::fs::create_dir_all(themes_dir.join("plain.test")).unwrap();
+        std::fs::write(themes_dir.join("plain.test/page.html"), "Plain override").unwrap();
+        templates.reload_changed();
+        let added = templates
+            .render_for(Some(&test_shop("plain.test")), "page.html", &context)
+            .unwrap();
+        assert_eq!(added, "Plain override");
+
+        let _ = std::fs::remove_dir_all(themes_dir);
+    }
+}